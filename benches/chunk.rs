@@ -0,0 +1,54 @@
+//! RTMP分片读取和拆分的性能，`cargo bench --bench chunk`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use river::protocol::rtmp::{ChunkMessageType, RtmpContext, RtmpMessage};
+use smol::io::AsyncWriteExt;
+use smol::net::{TcpListener, TcpStream};
+
+/// 视频消息的大小，按默认的128字节分片
+const MESSAGE_LENGTHS: [usize; 2] = [4 * 1024, 16 * 1024];
+
+fn video_message(len: usize) -> RtmpMessage {
+    let mut body = vec![0x27, 0x01, 0, 0, 0];
+    body.resize(len, 0xAB);
+    RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 40, body)
+}
+
+fn split_chunks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("split_chunks_bytes");
+    for len in MESSAGE_LENGTHS {
+        let message = video_message(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &message, |b, message| {
+            b.iter(|| message.split_chunks_bytes(128))
+        });
+    }
+    group.finish();
+}
+
+fn read_message(c: &mut Criterion) {
+    let (mut client, mut ctx) = smol::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, RtmpContext::new(server))
+    });
+
+    let mut group = c.benchmark_group("read_from");
+    for len in MESSAGE_LENGTHS {
+        let bytes = video_message(len).split_chunks_bytes(128).concat();
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &bytes, |b, bytes| {
+            b.iter(|| {
+                smol::block_on(async {
+                    client.write_all(bytes).await.unwrap();
+                    RtmpMessage::read_from(&mut ctx).await.unwrap()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, split_chunks, read_message);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+//! 推流和播放会话的访问日志，会话开始和结束时各输出一行JSON，例如
+//!
+//! ```text
+//! {"ts":1700000000000,"event":"end","role":"play","protocol":"http-flv","conn_id":7,"stream":"live/test","peer":"127.0.0.1:5000","duration_ms":60000,"bytes":1048576}
+//! ```
+//!
+//! 指定`--access-log`时追加写入文件，否则输出到target为`access`的普通日志
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::Local;
+use crossbeam_utils::atomic::AtomicCell;
+use once_cell::sync::OnceCell;
+
+use crate::ws_common::json_escape;
+
+static ACCESS_LOG: OnceCell<Mutex<File>> = OnceCell::new();
+
+/// 启动时打开访问日志文件，追加写入，只能设置一次
+pub fn init_access_log(path: &str) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("failed to open access log {}, {}", path, e))?;
+    if ACCESS_LOG.set(Mutex::new(file)).is_err() {
+        log::warn!("access log has been initialized");
+    }
+    Ok(())
+}
+
+fn write_line(line: &str) {
+    match ACCESS_LOG.get() {
+        Some(file) => {
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                log::warn!("failed to write access log, {}", e);
+            }
+        }
+        None => log::info!(target: "access", "{}", line),
+    }
+}
+
+/// 一个推流或者播放会话，创建时记录开始，drop时记录结束
+#[derive(Debug)]
+pub struct AccessSession {
+    /// `publish`或者`play`
+    role: &'static str,
+    /// 例如`rtmp`、`http-flv`
+    protocol: &'static str,
+    conn_id: u64,
+    stream_name: String,
+    peer_addr: String,
+    begin: Instant,
+    /// 推流时为收到的字节数，播放时为发送的字节数
+    pub bytes: AtomicCell<u64>,
+}
+
+impl AccessSession {
+    pub fn start(role: &'static str, protocol: &'static str, conn_id: u64, stream_name: &str, peer_addr: &str) -> Self {
+        let session = Self {
+            role,
+            protocol,
+            conn_id,
+            stream_name: stream_name.to_string(),
+            peer_addr: peer_addr.to_string(),
+            begin: Instant::now(),
+            bytes: Default::default(),
+        };
+        write_line(&session.to_json("start", ""));
+        session
+    }
+
+    fn to_json(&self, event: &str, extra: &str) -> String {
+        format!(
+            r#"{{"ts":{},"event":"{}","role":"{}","protocol":"{}","conn_id":{},"stream":"{}","peer":"{}"{}}}"#,
+            Local::now().timestamp_millis(),
+            event,
+            self.role,
+            self.protocol,
+            self.conn_id,
+            json_escape(&self.stream_name),
+            json_escape(&self.peer_addr),
+            extra
+        )
+    }
+}
+
+impl Drop for AccessSession {
+    fn drop(&mut self) {
+        let extra = format!(r#","duration_ms":{},"bytes":{}"#, self.begin.elapsed().as_millis(), self.bytes.load());
+        write_line(&self.to_json("end", &extra));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_start_and_end_lines() {
+        let session = AccessSession::start("play", "http-flv", 7, "live/test_access", "127.0.0.1:5000");
+        let start = session.to_json("start", "");
+        assert!(start.contains(r#""event":"start","role":"play","protocol":"http-flv","conn_id":7,"stream":"live/test_access","peer":"127.0.0.1:5000"}"#));
+        session.bytes.fetch_add(1024);
+        let path = std::env::temp_dir().join(format!("river_test_access_{}.log", std::process::id()));
+        init_access_log(path.to_str().unwrap()).unwrap();
+        drop(session);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let end = content
+            .lines()
+            .find(|x| x.contains(r#""event":"end""#) && x.contains("live/test_access"))
+            .unwrap();
+        assert!(end.ends_with(r#","bytes":1024}"#));
+        assert!(end.contains(r#""duration_ms":"#));
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,200 @@
+//! `--config river.toml`，配置文件中的值转换成命令行参数，再交给clap解析
+//!
+//! key和命令行参数的长名称相同，`-`和`_`都可以使用，例如：
+//!
+//! ```toml
+//! rtmp-port = 1935
+//! http_flv_port = 8080
+//! finalize-recording = true
+//! push = ["live/test=rtmp://cdn.example.com/live/key"]
+//!
+//! # 按app配置，流名称不需要带app前缀
+//! [apps.live]
+//! push = { test = "rtmp://cdn.example.com/live/key" }
+//! alias = { cam1 = "internal/camera_1" }
+//! wall-clock-timestamp = ["test"]
+//! ```
+
+use std::ffi::OsString;
+
+use clap::{App, ArgSettings};
+use toml::Value;
+
+/// `[apps.<app>]`中支持的key
+const APP_KEYS: [&str; 3] = ["push", "alias", "wall-clock-timestamp"];
+
+/// 配置文件中的一个参数，`name`是clap中的参数名
+#[derive(Debug, PartialEq)]
+pub struct FileArg {
+    pub name: String,
+    pub args: Vec<OsString>,
+}
+
+/// 读取配置文件，返回每个参数对应的命令行参数
+pub fn load(path: &str, app: &App) -> anyhow::Result<Vec<FileArg>> {
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("failed to read {}, {}", path, e))?;
+    parse(&content, app).map_err(|e| anyhow::anyhow!("invalid config file {}, {}", path, e))
+}
+
+/// 解析TOML，未知的key和类型不对的值返回Error，值的格式由clap校验
+pub fn parse(content: &str, app: &App) -> anyhow::Result<Vec<FileArg>> {
+    let table = match content.parse::<Value>()? {
+        Value::Table(table) => table,
+        _ => return Err(anyhow::anyhow!("expect a table")),
+    };
+    let mut file_args: Vec<FileArg> = vec![];
+    for (key, value) in table {
+        let key = key.replace('_', "-");
+        let values = if key == "apps" {
+            app_values(&value)?
+        } else {
+            vec![(key.clone(), value)]
+        };
+        for (key, value) in values {
+            let arg = app
+                .get_arguments()
+                .find(|x| x.get_long() == Some(key.as_str()) && key != "config")
+                .ok_or_else(|| anyhow::anyhow!("unknown key `{}`", key))?;
+            let args = to_args(&key, &value, arg.is_set(ArgSettings::TakesValue))?;
+            match file_args.iter_mut().find(|x| x.name == arg.get_name()) {
+                Some(file_arg) => file_arg.args.extend(args),
+                None => file_args.push(FileArg {
+                    name: arg.get_name().to_string(),
+                    args,
+                }),
+            }
+        }
+    }
+    Ok(file_args)
+}
+
+/// `[apps.<app>]`转换成带app前缀的参数
+fn app_values(value: &Value) -> anyhow::Result<Vec<(String, Value)>> {
+    let apps = value.as_table().ok_or_else(|| anyhow::anyhow!("`apps` should be a table"))?;
+    let mut values = vec![];
+    for (app_name, settings) in apps {
+        let settings = settings
+            .as_table()
+            .ok_or_else(|| anyhow::anyhow!("`apps.{}` should be a table", app_name))?;
+        let app_name = app_name.trim_matches('/');
+        for (key, value) in settings {
+            let key = key.replace('_', "-");
+            let prefixed = match (key.as_str(), value) {
+                ("push", Value::Table(rules)) | ("alias", Value::Table(rules)) => rules
+                    .iter()
+                    .map(|(stream, to)| {
+                        let to = to
+                            .as_str()
+                            .ok_or_else(|| anyhow::anyhow!("`apps.{}.{}.{}` should be a string", app_name, key, stream))?;
+                        Ok(Value::String(format!("{}/{}={}", app_name, stream, to)))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                ("wall-clock-timestamp", Value::Array(streams)) => streams
+                    .iter()
+                    .map(|stream| {
+                        let stream = stream
+                            .as_str()
+                            .ok_or_else(|| anyhow::anyhow!("`apps.{}.{}` should be strings", app_name, key))?;
+                        Ok(Value::String(format!("{}/{}", app_name, stream)))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                _ if APP_KEYS.contains(&key.as_str()) => {
+                    return Err(anyhow::anyhow!("unexpected type of `apps.{}.{}`", app_name, key));
+                }
+                _ => return Err(anyhow::anyhow!("unknown key `apps.{}.{}`, expect one of {:?}", app_name, key, APP_KEYS)),
+            };
+            values.push((key, Value::Array(prefixed)));
+        }
+    }
+    Ok(values)
+}
+
+fn to_args(key: &str, value: &Value, takes_value: bool) -> anyhow::Result<Vec<OsString>> {
+    let flag = OsString::from(format!("--{}", key));
+    if !takes_value {
+        return match value {
+            Value::Boolean(true) => Ok(vec![flag]),
+            Value::Boolean(false) => Ok(vec![]),
+            _ => Err(anyhow::anyhow!("`{}` should be a boolean", key)),
+        };
+    }
+    let items = match value {
+        Value::Array(items) => items.iter().collect(),
+        value => vec![value],
+    };
+    let mut args = vec![];
+    for item in items {
+        let item = match item {
+            Value::String(x) => x.clone(),
+            Value::Integer(x) => x.to_string(),
+            Value::Float(x) => x.to_string(),
+            _ => return Err(anyhow::anyhow!("unexpected type of `{}`", key)),
+        };
+        args.push(flag.clone());
+        args.push(item.into());
+    }
+    Ok(args)
+}
+
+/// 合并配置文件和命令行参数，命令行中出现过的参数覆盖配置文件中的同名参数
+///
+/// `cli_args`包含程序名称
+pub fn merge_args(app: App, cli_args: Vec<OsString>, file_args: Vec<FileArg>) -> anyhow::Result<Vec<OsString>> {
+    let matches = app.try_get_matches_from(&cli_args)?;
+    let mut cli_args = cli_args.into_iter();
+    let mut args: Vec<OsString> = cli_args.next().into_iter().collect();
+    for file_arg in file_args {
+        if matches.occurrences_of(file_arg.name.as_str()) == 0 {
+            args.extend(file_arg.args);
+        }
+    }
+    args.extend(cli_args);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Arg;
+
+    use super::*;
+
+    fn app() -> App<'static> {
+        App::new("river")
+            .arg(Arg::new("config").long("config").takes_value(true))
+            .arg(Arg::new("rtmp_port").long("rtmp-port").takes_value(true))
+            .arg(Arg::new("finalize_recording").long("finalize-recording"))
+            .arg(Arg::new("push").long("push").takes_value(true).multiple_occurrences(true))
+            .arg(Arg::new("wall_clock_timestamp").long("wall-clock-timestamp").takes_value(true).multiple_occurrences(true))
+    }
+
+    #[test]
+    fn file_values_overridden_by_cli() {
+        let content = r#"
+            rtmp_port = 1936
+            finalize-recording = true
+            push = ["live/a=rtmp://cdn/live/a"]
+
+            [apps.live]
+            push = { b = "rtmp://cdn/live/b" }
+            wall-clock-timestamp = ["c"]
+        "#;
+        let file_args = parse(content, &app()).unwrap();
+        let cli_args = vec!["river".into(), "--rtmp-port".into(), "1937".into()];
+        let args = merge_args(app(), cli_args, file_args).unwrap();
+        let matches = app().get_matches_from(&args);
+        assert_eq!(matches.value_of("rtmp_port"), Some("1937"));
+        assert!(matches.is_present("finalize_recording"));
+        let mut push = matches.values_of("push").unwrap().collect::<Vec<_>>();
+        push.sort_unstable();
+        assert_eq!(push, vec!["live/a=rtmp://cdn/live/a", "live/b=rtmp://cdn/live/b"]);
+        assert_eq!(matches.value_of("wall_clock_timestamp"), Some("live/c"));
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        assert!(parse("rtmp-prot = 1935", &app()).is_err());
+        assert!(parse("config = \"other.toml\"", &app()).is_err());
+        assert!(parse("[apps.live]\npull = []", &app()).is_err());
+        assert!(parse("finalize-recording = \"yes\"", &app()).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request};
+use async_tungstenite::tungstenite::http::StatusCode;
+use once_cell::sync::OnceCell;
+
+use crate::http_common::{empty_response, header};
+
+/// 允许的`Origin`，为空时允许所有来源
+static ALLOWED_ORIGINS: OnceCell<Vec<String>> = OnceCell::new();
+
+/// 启动时设置允许的来源，`--allow-origin`，只能设置一次
+pub fn init_allowed_origins(origins: Vec<String>) {
+    if ALLOWED_ORIGINS.set(parse_origins(origins)).is_err() {
+        log::warn!("allowed origins has been initialized");
+    }
+}
+
+/// 浏览器发送的`Origin`结尾没有`/`，配置中的`/`需要去掉
+fn parse_origins(origins: Vec<String>) -> Vec<String> {
+    origins.into_iter().map(|x| x.trim_end_matches('/').to_string()).collect()
+}
+
+fn allowed_origins() -> &'static [String] {
+    ALLOWED_ORIGINS.get().map(|x| x.as_slice()).unwrap_or_default()
+}
+
+/// 请求是否允许，没有`Origin`的请求不是浏览器跨域请求，总是允许
+pub fn is_allowed(origin: Option<&str>) -> bool {
+    is_allowed_by(allowed_origins(), origin)
+}
+
+fn is_allowed_by(origins: &[String], origin: Option<&str>) -> bool {
+    match origin {
+        Some(origin) => origins.is_empty() || origins.iter().any(|x| x == origin),
+        None => true,
+    }
+}
+
+/// 响应中的`Access-Control-Allow-Origin`，包括结尾的换行
+///
+/// 没有配置时为`*`，否则回显允许的`Origin`
+pub fn allow_origin_header(origin: Option<&str>) -> String {
+    allow_origin_header_by(allowed_origins(), origin)
+}
+
+fn allow_origin_header_by(origins: &[String], origin: Option<&str>) -> String {
+    if origins.is_empty() {
+        return "Access-Control-Allow-Origin: *\r\n".to_string();
+    }
+    match origin {
+        Some(origin) if is_allowed_by(origins, Some(origin)) => {
+            format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin)
+        }
+        _ => String::new(),
+    }
+}
+
+/// 读取原始HTTP请求中的`Origin`
+pub fn request_origin(req: &str) -> Option<&str> {
+    header(req, "origin")
+}
+
+/// WebSocket握手时检查`Origin`，不允许时返回403
+#[allow(clippy::result_large_err)]
+pub fn check_ws_origin(req: &Request) -> Result<(), ErrorResponse> {
+    let origin = req.headers().get("Origin").and_then(|x| x.to_str().ok());
+    if is_allowed(origin) {
+        return Ok(());
+    }
+    log::warn!("[WebSocket] reject origin={:?}, uri={}", origin, req.uri());
+    let mut err = ErrorResponse::new(Some("origin not allowed".to_string()));
+    *err.status_mut() = StatusCode::FORBIDDEN;
+    Err(err)
+}
+
+/// HTTP请求的`Origin`不允许时的响应
+pub fn forbidden_response() -> String {
+    empty_response("403 Forbidden", "")
+}
+
+/// 浏览器跨域请求的预检响应，`methods`例如`GET, OPTIONS`，请求的头部原样允许
+pub fn preflight_response(req: &str, methods: &str) -> String {
+    let allow_headers = header(req, "access-control-request-headers")
+        .map(|x| format!("Access-Control-Allow-Headers: {}\r\n", x))
+        .unwrap_or_default();
+    let headers = format!(
+        "{}Access-Control-Allow-Methods: {}\r\n{}Access-Control-Max-Age: 86400\r\n",
+        allow_origin_header(request_origin(req)),
+        methods,
+        allow_headers
+    );
+    empty_response("204 No Content", &headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_allowed_origin() {
+        // 没有配置时允许所有来源
+        assert!(is_allowed_by(&[], Some("https://example.com")));
+        assert_eq!(allow_origin_header_by(&[], Some("https://example.com")), "Access-Control-Allow-Origin: *\r\n");
+
+        let origins = parse_origins(vec!["https://example.com/".to_string()]);
+        assert!(is_allowed_by(&origins, Some("https://example.com")));
+        assert!(!is_allowed_by(&origins, Some("https://evil.com")));
+        assert!(is_allowed_by(&origins, None));
+        assert_eq!(
+            allow_origin_header_by(&origins, Some("https://example.com")),
+            "Access-Control-Allow-Origin: https://example.com\r\nVary: Origin\r\n"
+        );
+        assert_eq!(allow_origin_header_by(&origins, None), "");
+
+        let req = "GET /live/test HTTP/1.1\r\nHost: localhost\r\norigin: https://example.com\r\n\r\n";
+        assert_eq!(request_origin(req), Some("https://example.com"));
+    }
+}
@@ -1,11 +1,112 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 use crossbeam_utils::atomic::AtomicCell;
 use dashmap::DashMap;
-use smol::channel::{Receiver, Sender};
+use smol::channel::{Receiver, Sender, TrySendError};
+use smol::stream::Stream;
 
 pub struct EventBus<E> {
     label: String,
     incr_val: AtomicCell<u64>,
     tx_map: DashMap<u64, Sender<E>>,
+    latest_map: DashMap<u64, LatestSender<E>>,
+}
+
+/// 只保留最新消息的接收者，慢的接收者直接跳到最新的消息，不会积压
+struct LatestSender<E> {
+    slot: Arc<Mutex<LatestSlot<E>>>,
+    /// 容量为1，只用来唤醒接收者，EventBus销毁时接收者随之结束
+    notify_tx: Sender<()>,
+    is_key_frame: fn(&E) -> bool,
+    track: fn(&E) -> u8,
+}
+
+/// 没有被读取的消息和它们的发送顺序
+#[derive(Default)]
+struct LatestSlot<E> {
+    /// 还没有被读取的最近一个关键帧，之后的帧依赖它才能解码
+    key_frame: Option<(u64, E)>,
+    /// 每个轨道最新的一条消息，音频和视频互不覆盖
+    latest: HashMap<u8, (u64, E)>,
+    seq: u64,
+}
+
+impl<E> LatestSlot<E> {
+    /// 按发送顺序取出最早的一条
+    fn take(&mut self) -> Option<E> {
+        let latest = self.latest.iter().min_by_key(|(_, (seq, _))| *seq).map(|(track, (seq, _))| (*track, *seq));
+        match (&self.key_frame, latest) {
+            (Some((key_frame_seq, _)), Some((_, seq))) if *key_frame_seq > seq => {}
+            (Some(_), _) => return self.key_frame.take().map(|(_, val)| val),
+            _ => {}
+        }
+        latest.and_then(|(track, _)| self.latest.remove(&track)).map(|(_, val)| val)
+    }
+
+    fn len(&self) -> usize {
+        self.key_frame.iter().count() + self.latest.len()
+    }
+}
+
+impl<E: Clone> LatestSender<E> {
+    /// 返回false表示接收者已经drop
+    fn send(&self, val: &E) -> bool {
+        {
+            let mut slot = self.slot.lock().unwrap();
+            slot.seq += 1;
+            let seq = slot.seq;
+            let track = (self.track)(val);
+            // 新的关键帧之前的同一轨道的消息不再需要
+            if (self.is_key_frame)(val) {
+                slot.key_frame = Some((seq, val.clone()));
+                slot.latest.remove(&track);
+            } else {
+                slot.latest.insert(track, (seq, val.clone()));
+            }
+        }
+        !matches!(self.notify_tx.try_send(()), Err(TrySendError::Closed(_)))
+    }
+}
+
+/// `EventBus::register_latest_receiver`的接收端
+pub struct LatestReceiver<E> {
+    slot: Arc<Mutex<LatestSlot<E>>>,
+    notify_rx: Receiver<()>,
+}
+
+impl<E> LatestReceiver<E> {
+    /// 按发送顺序返回还没有读取的关键帧和每个轨道最新的消息，EventBus销毁之后返回None
+    pub async fn recv(&self) -> Option<E> {
+        loop {
+            if let Some(val) = self.take() {
+                return Some(val);
+            }
+            if self.notify_rx.recv().await.is_err() {
+                return self.take();
+            }
+        }
+    }
+
+    fn take(&self) -> Option<E> {
+        self.slot.lock().unwrap().take()
+    }
+
+    /// 还没有读取的消息数量，最多为关键帧加上每个轨道一条
+    pub fn len(&self) -> usize {
+        self.slot.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = E> {
+        smol::stream::unfold(self, |rx| async move {
+            let val = rx.recv().await?;
+            Some((val, rx))
+        })
+    }
 }
 
 impl<E: 'static + Clone> EventBus<E> {
@@ -14,10 +115,20 @@ impl<E: 'static + Clone> EventBus<E> {
             label,
             incr_val: Default::default(),
             tx_map: Default::default(),
+            latest_map: Default::default(),
         }
     }
 
     pub async fn publish(&self, val: E) {
+        // 只保留最新消息的接收者不会阻塞
+        self.latest_map.retain(|key, sender| {
+            let alive = sender.send(&val);
+            if !alive {
+                log::info!("[EventBus][{}] remove latest receiver {}", self.label, key);
+            }
+            alive
+        });
+
         let mut dropped_senders: Vec<u64> = vec![];
 
         let mut keys: Vec<u64> = self.tx_map.iter().map(|x| x.key().to_owned()).collect();
@@ -25,7 +136,7 @@ impl<E: 'static + Clone> EventBus<E> {
 
         for key in keys {
             if let Some(entry) = self.tx_map.get(&key) {
-                if let Err(_) = entry.send(val.clone()).await {
+                if entry.send(val.clone()).await.is_err() {
                     dropped_senders.push(key);
                 }
             }
@@ -33,7 +144,7 @@ impl<E: 'static + Clone> EventBus<E> {
         // 最后一个元素可以直接发送，减少一次clone
         if let Some(key) = last_key {
             if let Some(entry) = self.tx_map.get(&key) {
-                if let Err(_) = entry.send(val).await {
+                if entry.send(val).await.is_err() {
                     dropped_senders.push(key);
                 }
             }
@@ -54,4 +165,84 @@ impl<E: 'static + Clone> EventBus<E> {
         log::info!("[EventBus][{}] add receiver {}", self.label, key);
         rx
     }
+
+    /// 注册只保留最新消息的接收者，适合可以接受丢帧、但是不能积压的播放者
+    ///
+    /// 接收者读取较慢时只能读到最近一个关键帧和每个轨道最新的一条消息，中间的消息被丢弃；
+    /// 跟得上时和普通接收者一样读到所有消息。`is_key_frame`用来判断需要保留的关键帧，
+    /// `track`区分音频、视频等轨道，不同轨道的消息互不覆盖
+    pub fn register_latest_receiver(&self, is_key_frame: fn(&E) -> bool, track: fn(&E) -> u8) -> LatestReceiver<E> {
+        let slot = Arc::new(Mutex::new(LatestSlot { key_frame: None, latest: HashMap::new(), seq: 0 }));
+        let (notify_tx, notify_rx) = smol::channel::bounded(1);
+
+        let key = self.incr_val.fetch_add(1);
+        self.latest_map.insert(key, LatestSender { slot: slot.clone(), notify_tx, is_key_frame, track });
+
+        log::info!("[EventBus][{}] add latest receiver {}", self.label, key);
+        LatestReceiver { slot, notify_rx }
+    }
+
+    /// 当前仍然存活的接收者数量
+    pub fn receiver_count(&self) -> usize {
+        self.tx_map.iter().filter(|x| !x.value().is_closed()).count()
+            + self.latest_map.iter().filter(|x| !x.value().notify_tx.is_closed()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_receiver_skips_to_key_frame_and_latest() {
+        smol::block_on(async {
+            let bus = EventBus::with_label("latest".to_string());
+            // 偶数为关键帧
+            let rx = bus.register_latest_receiver(|x: &u32| x.is_multiple_of(2), |_| 0);
+
+            for i in 1..=5 {
+                bus.publish(i).await;
+            }
+            assert_eq!(rx.recv().await, Some(4));
+            assert_eq!(rx.recv().await, Some(5));
+
+            // 跟得上时不丢消息
+            bus.publish(7).await;
+            assert_eq!(rx.recv().await, Some(7));
+            bus.publish(9).await;
+            assert_eq!(rx.recv().await, Some(9));
+
+            bus.publish(10).await;
+            assert_eq!(bus.receiver_count(), 1);
+            drop(bus);
+            assert_eq!(rx.recv().await, Some(10));
+            assert_eq!(rx.recv().await, None);
+        });
+    }
+
+    /// 视频一直覆盖时音频仍然保留最新的一条，按发送顺序读取
+    #[test]
+    fn latest_receiver_keeps_each_track() {
+        smol::block_on(async {
+            let bus = EventBus::with_label("latest_tracks".to_string());
+            // 100以上为音频，偶数为视频关键帧
+            let rx = bus.register_latest_receiver(|x: &u32| *x < 100 && x.is_multiple_of(2), |x| (*x >= 100) as u8);
+
+            for val in [2, 101, 3, 102, 5, 103, 7] {
+                bus.publish(val).await;
+            }
+            assert_eq!(rx.len(), 3);
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, Some(103));
+            assert_eq!(rx.recv().await, Some(7));
+
+            // 关键帧只清除同一轨道的消息
+            for val in [9, 104, 10] {
+                bus.publish(val).await;
+            }
+            assert_eq!(rx.recv().await, Some(104));
+            assert_eq!(rx.recv().await, Some(10));
+            assert!(rx.is_empty());
+        });
+    }
 }
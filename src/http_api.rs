@@ -0,0 +1,243 @@
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{SocketAddr, TcpStream};
+use smol::stream::StreamExt;
+
+use crate::cors;
+use crate::http_common::{bad_request_response, RequestLine};
+use crate::metrics::{metrics, ViewerStats};
+use crate::protocol::h264::{Nalu, SpsInfo};
+use crate::rate_limit::viewer_max_kbps;
+use crate::recording::{self, RecordingFormat};
+use crate::rtmp_server::{drop_publisher, eventbus_map, is_listening, key_frame_tracker_map, publisher_conn_map, video_header_map};
+use crate::thumbnail;
+use crate::util::{bind_tcp, server_name, spawn_and_log_error};
+use crate::vod;
+use crate::whep;
+use crate::ws_common::json_escape;
+
+/// 管理接口，提供`/healthz`、`/readyz`、`/metrics`、`/api/stats`、`/api/thumbnail/<stream>.jpg`、`POST /api/streams/<stream>/drop`、
+/// `POST /api/streams/<stream>/recording/start|stop`、`/vod/<stream>/<file>.mp4|ts`和`POST /whep/<stream>`
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = bind_tcp(addr)?;
+    let addr = format!("http://{}", listener.local_addr()?);
+    log::info!("HTTP-API Server is listening to {}", addr);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        spawn_and_log_error(accept(stream));
+    }
+    Ok(())
+}
+
+async fn accept(mut stream: TcpStream) -> anyhow::Result<()> {
+    log::debug!("[HTTP-API] new connection from {}", stream.peer_addr()?);
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await?;
+    let req = String::from_utf8_lossy(&buffer[..n]);
+    let (method, uri) = match RequestLine::parse(&req) {
+        Some(line) => (line.method, line.uri),
+        None => {
+            stream.write_all(bad_request_response().as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(());
+        }
+    };
+    // 去掉query部分
+    let path = uri.split('?').next().unwrap_or_default();
+    if !cors::is_allowed(cors::request_origin(&req)) {
+        stream.write_all(cors::forbidden_response().as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+    if path.starts_with("/whep/") {
+        return whep::serve(&mut stream, method, path, &buffer[..n]).await;
+    }
+    if method == "OPTIONS" {
+        stream.write_all(cors::preflight_response(&req, "GET, POST, OPTIONS").as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+    if path.starts_with("/vod/") {
+        return vod::serve(&mut stream, uri, &req).await;
+    }
+
+    let (status, content_type, body) = match path {
+        // 探针请求频繁，不遍历流，不访问共享的map
+        "/healthz" => ("200 OK", "text/plain", b"ok".to_vec()),
+        "/readyz" if is_listening() => ("200 OK", "text/plain", b"ready".to_vec()),
+        "/readyz" => ("503 Service Unavailable", "text/plain", b"rtmp listener not bound".to_vec()),
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics().render().into_bytes()),
+        "/api/stats" => ("200 OK", "application/json", stats().into_bytes()),
+        _ => {
+            if let Some(stream_name) = path.strip_prefix("/api/thumbnail/").and_then(|x| x.strip_suffix(".jpg")) {
+                thumbnail(stream_name).await
+            } else if let Some(stream_name) = path.strip_prefix("/api/streams/").and_then(|x| x.strip_suffix("/drop")) {
+                drop_stream(method, stream_name)
+            } else if let Some(stream_name) = path.strip_prefix("/api/streams/").and_then(|x| x.strip_suffix("/recording/start")) {
+                start_recording(method, stream_name, uri, &stream.peer_addr()?.to_string())
+            } else if let Some(stream_name) = path.strip_prefix("/api/streams/").and_then(|x| x.strip_suffix("/recording/stop")) {
+                stop_recording(method, stream_name)
+            } else {
+                ("404 Not Found", "text/plain", vec![])
+            }
+        }
+    };
+    let mut response = format!("HTTP/1.1 {}\r\n\
+    Server: {}\r\n\
+    Content-Type: {}\r\n\
+    Connection: close\r\n\
+    Content-Length: {}\r\n\
+    \r\n", status, server_name(), content_type, body.len()).into_bytes();
+    response.extend_from_slice(&body);
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// 最近关键帧的JPEG，解码在线程池中执行，不影响直播流
+async fn thumbnail(stream_name: &str) -> (&'static str, &'static str, Vec<u8>) {
+    if !thumbnail::SUPPORTED {
+        return ("501 Not Implemented", "text/plain", b"thumbnail requires the openh264 feature".to_vec());
+    }
+    let key_frame = match thumbnail::latest_key_frame(stream_name) {
+        Some(key_frame) => key_frame,
+        None => return ("404 Not Found", "text/plain", vec![]),
+    };
+    match smol::unblock(move || thumbnail::encode_jpeg(&key_frame)).await {
+        Ok(jpeg) => ("200 OK", "image/jpeg", jpeg),
+        Err(e) => {
+            log::warn!("[HTTP-API] thumbnail error, stream_name={}, {:?}", stream_name, e);
+            ("500 Internal Server Error", "text/plain", vec![])
+        }
+    }
+}
+
+/// 断开推流者，播放者随之结束
+fn drop_stream(method: &str, stream_name: &str) -> (&'static str, &'static str, Vec<u8>) {
+    if method != "POST" {
+        return ("405 Method Not Allowed", "text/plain", vec![]);
+    }
+    if !drop_publisher(stream_name) {
+        return ("404 Not Found", "text/plain", vec![]);
+    }
+    let body = format!(r#"{{"stream":"{}","dropped":true}}"#, json_escape(stream_name));
+    ("200 OK", "application/json", body.into_bytes())
+}
+
+/// 开始录制，`?format=flv`、`?format=mp4`或者`?format=ts`，默认为mp4，文件路径见`recording::default_path`
+fn start_recording(method: &str, stream_name: &str, uri: &str, peer_addr: &str) -> (&'static str, &'static str, Vec<u8>) {
+    if method != "POST" {
+        return ("405 Method Not Allowed", "text/plain", vec![]);
+    }
+    let format = uri
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|x| x.strip_prefix("format=")))
+        .unwrap_or("mp4");
+    let format = match format.parse::<RecordingFormat>() {
+        Ok(format) => format,
+        Err(e) => return ("400 Bad Request", "text/plain", e.to_string().into_bytes()),
+    };
+    if !eventbus_map().contains_key(stream_name) {
+        return ("404 Not Found", "text/plain", vec![]);
+    }
+    let path = recording::default_path(stream_name, format);
+    if let Err(e) = recording::start(stream_name, format, path.clone(), peer_addr.to_string()) {
+        return ("409 Conflict", "text/plain", e.to_string().into_bytes());
+    }
+    let body = format!(r#"{{"stream":"{}","recording":true,"path":"{}"}}"#, json_escape(stream_name), json_escape(&path));
+    ("200 OK", "application/json", body.into_bytes())
+}
+
+/// 停止录制，已经收到的帧写完之后关闭文件
+fn stop_recording(method: &str, stream_name: &str) -> (&'static str, &'static str, Vec<u8>) {
+    if method != "POST" {
+        return ("405 Method Not Allowed", "text/plain", vec![]);
+    }
+    if !recording::stop(stream_name) {
+        return ("404 Not Found", "text/plain", vec![]);
+    }
+    let body = format!(r#"{{"stream":"{}","recording":false}}"#, json_escape(stream_name));
+    ("200 OK", "application/json", body.into_bytes())
+}
+
+/// 每个流的推流连接id、观看人数、视频参数和关键帧间隔
+///
+/// 还没有收到video header时`video`为null，还没有关键帧时`last_key_frame_ms`为null
+///
+/// 不是RTMP推流时`publisher_conn_id`为null，`viewer_stats`中的`id`是播放者的连接id
+fn stats() -> String {
+    let streams = eventbus_map()
+        .iter()
+        .map(|entry| {
+            let stream_name = entry.key();
+            let video = video_header_map()
+                .get(stream_name)
+                .and_then(|header| Nalu::from_rtmp_message(header.value()).iter().find_map(Nalu::sps_info))
+                .map(|x| sps_info_to_json(&x))
+                .unwrap_or_else(|| "null".to_string());
+            let (last_key_frame_ms, key_frame_overdue) = match key_frame_tracker_map().get(stream_name) {
+                Some(tracker) if tracker.has_key_frame() => {
+                    (tracker.elapsed().as_millis().to_string(), tracker.is_overdue())
+                }
+                Some(tracker) => ("null".to_string(), tracker.is_overdue()),
+                None => ("null".to_string(), false),
+            };
+            let viewer_stats = metrics()
+                .viewers(stream_name)
+                .iter()
+                .map(|x| viewer_stats_to_json(x))
+                .collect::<Vec<_>>();
+            let recording = recording::recording(stream_name)
+                .map(|(format, path)| format!(r#"{{"format":"{}","path":"{}"}}"#, format.extension(), json_escape(&path)))
+                .unwrap_or_else(|| "null".to_string());
+            let publisher_conn_id = publisher_conn_map()
+                .get(stream_name)
+                .map(|x| x.value().to_string())
+                .unwrap_or_else(|| "null".to_string());
+            format!(
+                r#"{{"stream":"{}","publisher_conn_id":{},"viewers":{},"video":{},"last_key_frame_ms":{},"key_frame_overdue":{},"viewer_max_kbps":{},"viewer_stats":[{}],"recording":{},"timestamp_corrected":{}}}"#,
+                json_escape(stream_name),
+                publisher_conn_id,
+                entry.value().receiver_count(),
+                video,
+                last_key_frame_ms,
+                key_frame_overdue,
+                viewer_max_kbps().map(|x| x.to_string()).unwrap_or_else(|| "null".to_string()),
+                viewer_stats.join(","),
+                recording,
+                metrics().timestamp_corrected(stream_name)
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", streams.join(","))
+}
+
+/// `queued`为还没有发送的消息数，持续增长说明播放者跟不上
+fn viewer_stats_to_json(viewer: &ViewerStats) -> String {
+    format!(
+        r#"{{"id":{},"peer":"{}","output":"{}","queued":{},"dropped":{},"bytes_sent":{},"join_ts":{}}}"#,
+        viewer.id,
+        json_escape(&viewer.peer_addr),
+        viewer.output,
+        viewer.queued.load(),
+        viewer.dropped.load(),
+        viewer.bytes_sent.load(),
+        viewer.join_ts
+    )
+}
+
+fn sps_info_to_json(sps_info: &SpsInfo) -> String {
+    format!(
+        r#"{{"profile":"{}","profile_idc":{},"level":"{}.{}","level_idc":{},"width":{},"height":{},"chroma_format":"{}"}}"#,
+        sps_info.profile_name(),
+        sps_info.profile,
+        sps_info.level / 10,
+        sps_info.level % 10,
+        sps_info.level,
+        sps_info.width,
+        sps_info.height,
+        sps_info.chroma_format_name()
+    )
+}
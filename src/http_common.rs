@@ -0,0 +1,88 @@
+//! HTTP-FLV、HTTP API和播放页面共用的HTTP请求解析，只读取请求头，不支持keep-alive
+
+use crate::util::server_name;
+
+/// 请求行，例如`GET /live/test?only=video HTTP/1.1`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestLine<'a> {
+    pub method: &'a str,
+    /// 包含query的请求路径
+    pub uri: &'a str,
+}
+
+impl<'a> RequestLine<'a> {
+    /// 解析请求的第一行，格式不对时返回None，调用者回复400
+    pub fn parse(req: &'a str) -> Option<Self> {
+        let mut parts = req.lines().next()?.split_whitespace();
+        let (method, uri, version) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some()
+            || !method.bytes().all(|x| x.is_ascii_uppercase())
+            || !uri.starts_with('/')
+            || !version.starts_with("HTTP/1.")
+        {
+            return None;
+        }
+        Some(Self { method, uri })
+    }
+
+    /// 去掉query之后的路径
+    pub fn path(&self) -> &'a str {
+        self.uri.split('?').next().unwrap_or_default()
+    }
+
+    pub fn query(&self) -> Option<&'a str> {
+        self.uri.split_once('?').map(|x| x.1)
+    }
+}
+
+/// 大小写不敏感地读取请求头
+pub fn header<'a>(req: &'a str, name: &str) -> Option<&'a str> {
+    req.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// 请求行格式不对时的响应
+pub fn bad_request_response() -> String {
+    empty_response("400 Bad Request", "")
+}
+
+/// 没有响应体的响应，`headers`包括每行结尾的换行
+pub fn empty_response(status: &str, headers: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nServer: {}\r\n{}Connection: close\r\nContent-Length: 0\r\n\r\n",
+        status,
+        server_name(),
+        headers
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_line_and_headers() {
+        let req = "GET /live/test?only=video HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-\r\n\r\nrange: body";
+        let line = RequestLine::parse(req).unwrap();
+        assert_eq!(line.method, "GET");
+        assert_eq!(line.path(), "/live/test");
+        assert_eq!(line.query(), Some("only=video"));
+        assert_eq!(header(req, "range"), Some("bytes=0-"));
+        assert_eq!(header(req, "origin"), None);
+
+        assert_eq!(RequestLine::parse("OPTIONS /live/test HTTP/1.1\r\n").unwrap().method, "OPTIONS");
+        assert_eq!(RequestLine::parse("GET /a HTTP/1.0").unwrap().query(), None);
+        assert!(RequestLine::parse("").is_none());
+        assert!(RequestLine::parse("\r\n").is_none());
+        assert!(RequestLine::parse("GET\r\n").is_none());
+        assert!(RequestLine::parse("GET live/test HTTP/1.1\r\n").is_none());
+        assert!(RequestLine::parse("get /live/test HTTP/1.1\r\n").is_none());
+        assert!(RequestLine::parse("GET /live/test\r\n").is_none());
+        assert!(RequestLine::parse("GET /live test HTTP/1.1\r\n").is_none());
+        assert!(RequestLine::parse("\u{16}\u{3}\u{1} /").is_none());
+    }
+}
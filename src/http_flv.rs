@@ -1,17 +1,22 @@
-use crate::util::spawn_and_log_error;
-use smol::io::{AsyncReadExt, AsyncWriteExt};
-use smol::net::{TcpListener, TcpStream};
+use crate::util::{bind_tcp, display_addr, next_conn_id, server_name, spawn_and_log_error};
+use crate::cors;
+use crate::http_common::{bad_request_response, empty_response, RequestLine};
+use crate::metrics::metrics;
+use crate::naming::{self, NamingConfig};
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::net::{SocketAddr, TcpStream};
 use smol::stream::StreamExt;
-use crate::rtmp_server::{eventbus_map, video_header_map};
-use crate::protocol::flv::{FLV_HEADER_ONLY_VIDEO_WITH_TAG0};
-use crate::protocol::flv::FlvTag;
-use chrono::Local;
+use crate::rtmp_server::{audio_header_map, meta_data_map, meta_data_message, video_header_map, wait_for_publisher, KeyFrameReceiver};
+use crate::protocol::flv::{FLV_HEADER_ONLY_AUDIO_WITH_TAG0, FLV_HEADER_ONLY_VIDEO_WITH_TAG0, FLV_HEADER_WITH_TAG0};
+use crate::protocol::flv::{FlvTag, FlvTimestamp};
 use std::convert::TryFrom;
 use crate::protocol::rtmp::ChunkMessageType;
+use crate::pacer::{PacedOutput, Pacer};
+use crate::rate_limit::{latest_from_query, FrameDecimator};
 
-pub async fn run_server(addr: String) -> anyhow::Result<()> {
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
     // Open up a TCP connection and create a URL.
-    let listener = TcpListener::bind(addr).await?;
+    let listener = bind_tcp(addr)?;
     let addr = format!("http://{}", listener.local_addr()?);
     log::info!("HTTP-FLV Server is listening to {}", addr);
 
@@ -25,53 +30,99 @@ pub async fn run_server(addr: String) -> anyhow::Result<()> {
 }
 
 // Take a TCP stream, and convert it into sequential HTTP request / response pairs.
-async fn accept(mut stream: TcpStream) -> anyhow::Result<()> {
-    log::info!("[HTTP] new connection from {}", stream.peer_addr()?);
+async fn accept(stream: TcpStream) -> anyhow::Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    serve(stream, peer_addr, naming::naming_config()).await
+}
+
+/// 处理一个播放请求，请求路径中的流名称按`naming`转换成内部名称
+async fn serve(mut stream: impl AsyncRead + AsyncWrite + Unpin, peer_addr: SocketAddr, naming: &NamingConfig) -> anyhow::Result<()> {
+    let conn_id = next_conn_id();
+    log::info!("[conn={}][HTTP] new connection from {}", conn_id, peer_addr);
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await?;
-    let req = String::from_utf8_lossy(&buffer[..]);
-    let stream_name = get_path(req.as_ref()).map(|x| x.trim_start_matches("/")).unwrap_or_default();
-    if let Some(eventbus) = eventbus_map().get(stream_name) {
-        let receiver = eventbus.register_receiver();
-
-        let header = "HTTP/1.1 200 OK\r\n\
-        Server: river\r\n\
+    let n = stream.read(&mut buffer).await?;
+    let req = String::from_utf8_lossy(&buffer[..n]);
+    let origin = cors::request_origin(&req);
+    if !cors::is_allowed(origin) {
+        log::warn!("[conn={}][HTTP] reject origin={:?}, peer={}", conn_id, origin, peer_addr);
+        stream.write_all(cors::forbidden_response().as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+    let line = match get_path(req.as_ref()) {
+        Ok(line) => line,
+        Err(response) => {
+            log::warn!("[conn={}][HTTP] bad request from {}, {:?}", conn_id, peer_addr, req.lines().next().unwrap_or_default());
+            stream.write_all(response.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(());
+        }
+    };
+    let tracks = Tracks::from_uri(line.uri);
+    let stream_name = match naming.normalize_path(line.path()) {
+        Ok(stream_name) => stream_name,
+        Err(e) => {
+            log::warn!("[conn={}][HTTP] invalid stream name from {}, {}", conn_id, peer_addr, e);
+            stream.write_all(empty_response("404 Not Found", "").as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(());
+        }
+    };
+    let stream_name = stream_name.as_str();
+    wait_for_publisher(stream_name).await;
+    // 从最近的关键帧开始发送，避免中途加入时花屏
+    let decimator = FrameDecimator::from_query(line.query());
+    let receiver = if latest_from_query(line.query()) {
+        KeyFrameReceiver::subscribe_latest(stream_name)
+    } else {
+        KeyFrameReceiver::subscribe(stream_name)
+    };
+    if let Some(mut receiver) = receiver.map(|x| x.rate_limited().decimated(decimator)) {
+        let viewer = metrics().register_viewer(conn_id, stream_name, &display_addr(peer_addr), "http-flv");
+
+        let header = format!("HTTP/1.1 200 OK\r\n\
+        Server: {}\r\n\
         Content-Type: video/x-flv\r\n\
         Connection: close\r\n\
         Transfer-Encoding: chunked\r\n\
         Cache-Control: no-cache\r\n\
-        Access-Control-Allow-Origin: *\r\n\
+        {}\
         \r\n\
-        ";
+        ", server_name(), cors::allow_origin_header(origin));
         stream.write_all(header.as_bytes()).await?;
         stream.flush().await?;
 
-        write_chunk(&mut stream, &FLV_HEADER_ONLY_VIDEO_WITH_TAG0).await?;
+        write_chunk(&mut stream, tracks.flv_header()).await?;
+
+        // 和ffmpeg一样先发送onMetaData，播放器提前拿到分辨率和帧率
+        let meta_data = meta_data_map().get(stream_name).map(|x| x.value().clone());
+        if let Some(meta_data) = meta_data {
+            let flv_tag = FlvTag::from_rtmp_message(&meta_data_message(&meta_data)?, 0)?;
+            write_chunk(&mut stream, flv_tag.as_ref()).await?;
+            write_chunk(&mut stream, &(flv_tag.as_ref().len() as u32).to_be_bytes()).await?;
+        }
 
-        // 发送sps/pps帧
-        if let Some(msg) = video_header_map().get(stream_name) {
-            let flv_tag = FlvTag::try_from(msg.value().clone())?;
+        // 发送sps/pps帧和AAC sequence header
+        let video_header = video_header_map().get(stream_name).map(|x| x.value().clone()).filter(|_| tracks.video());
+        let audio_header = audio_header_map().get(stream_name).map(|x| x.value().clone()).filter(|_| tracks.audio());
+        for msg in video_header.into_iter().chain(audio_header) {
+            let flv_tag = FlvTag::try_from(msg)?;
             write_chunk(&mut stream, flv_tag.as_ref()).await?;
             write_chunk(&mut stream, &(flv_tag.as_ref().len() as u32).to_be_bytes()).await?;
-        };
-        // 发送audio帧
-        // if let Some(msg) = audio_header_map().get(stream_name) {
-        //     let flv_tag = FlvTag::try_from(msg.value().clone())?;
-        //     write_chunk(&mut stream, flv_tag.as_ref()).await?;
-        //     write_chunk(&mut stream, &(flv_tag.as_ref().len() as u32).to_be_bytes()).await?;
-        // };
-
-        let ctx_begin_timestamp = Local::now().timestamp_millis();
-        while let Ok(mut msg) = receiver.recv().await {
-            if ChunkMessageType::VideoMessage == msg.header.message_type {
-                msg.header.timestamp = (Local::now().timestamp_millis() - ctx_begin_timestamp) as u32;
-                let flv_tag = FlvTag::try_from(msg)?;
+        }
+
+        let mut flv_timestamp = FlvTimestamp::for_stream(stream_name);
+        let mut pacer = Pacer::for_output(PacedOutput::HttpFlv);
+        while let Some(msg) = receiver.recv().await {
+            if tracks.accept(&msg.header.message_type) {
+                pacer.wait(msg.header.timestamp).await;
+                let timestamp = flv_timestamp.rebase(msg.header.timestamp);
+                let flv_tag = FlvTag::from_rtmp_message(&msg, timestamp)?;
                 write_chunk(&mut stream, flv_tag.as_ref()).await?;
                 write_chunk(&mut stream, &(flv_tag.as_ref().len() as u32).to_be_bytes()).await?;
-                if receiver.len() > 2 {
-                    log::warn!("receiver.len={}, stream_name={}", receiver.len(), stream_name);
-                }
+                viewer.bytes_sent.fetch_add(flv_tag.as_ref().len() as u64 + 4);
             }
+            receiver.update_stats(&viewer);
         }
         write_chunk(&mut stream, b"").await?;
     } else {
@@ -82,18 +133,136 @@ async fn accept(mut stream: TcpStream) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_path(req: &str) -> Option<&str> {
-    let first_line = req.lines().next().unwrap_or_default();
-    if first_line.starts_with("GET") {
-        return first_line.split_whitespace().skip(1).next();
+/// 输出的音视频，`?only=video`或者`?only=audio`，默认都输出
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tracks {
+    Both,
+    Video,
+    Audio,
+}
+
+impl Tracks {
+    fn from_uri(uri: &str) -> Self {
+        let only = uri
+            .split_once('?')
+            .and_then(|(_, query)| query.split('&').find_map(|x| x.strip_prefix("only=")));
+        match only {
+            Some("video") => Tracks::Video,
+            Some("audio") => Tracks::Audio,
+            _ => Tracks::Both,
+        }
+    }
+
+    fn video(self) -> bool {
+        self != Tracks::Audio
+    }
+
+    fn audio(self) -> bool {
+        self != Tracks::Video
+    }
+
+    fn flv_header(self) -> &'static [u8] {
+        match self {
+            Tracks::Both => &FLV_HEADER_WITH_TAG0,
+            Tracks::Video => &FLV_HEADER_ONLY_VIDEO_WITH_TAG0,
+            Tracks::Audio => &FLV_HEADER_ONLY_AUDIO_WITH_TAG0,
+        }
+    }
+
+    fn accept(self, message_type: &ChunkMessageType) -> bool {
+        match message_type {
+            ChunkMessageType::VideoMessage => self.video(),
+            ChunkMessageType::AudioMessage => self.audio(),
+            _ => false,
+        }
     }
-    None
 }
 
-async fn write_chunk(stream: &mut TcpStream, bytes: &[u8]) -> anyhow::Result<()> {
+/// 解析`GET`请求的请求行，其他情况返回需要回复的响应：请求行不对或者没有流名称时400，`OPTIONS`回复跨域预检，其他方法405
+fn get_path(req: &str) -> Result<RequestLine<'_>, String> {
+    let line = RequestLine::parse(req).ok_or_else(bad_request_response)?;
+    match line.method {
+        "GET" if line.path().trim_start_matches('/').is_empty() => Err(bad_request_response()),
+        "GET" => Ok(line),
+        "OPTIONS" => Err(cors::preflight_response(req, "GET, OPTIONS")),
+        _ => Err(empty_response("405 Method Not Allowed", "Allow: GET, OPTIONS\r\n")),
+    }
+}
+
+async fn write_chunk(stream: &mut (impl AsyncWrite + Unpin), bytes: &[u8]) -> anyhow::Result<()> {
     stream.write_all(format!("{:X}\r\n", bytes.len()).as_bytes()).await?;
     stream.write_all(bytes).await?;
     stream.write_all(b"\r\n").await?;
     stream.flush().await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_tracks_by_query() {
+        assert_eq!(Tracks::from_uri("/live/test"), Tracks::Both);
+        assert_eq!(Tracks::from_uri("/live/test?only=video"), Tracks::Video);
+        assert_eq!(Tracks::from_uri("/live/test?token=x&only=audio"), Tracks::Audio);
+        assert_eq!(Tracks::from_uri("/live/test?only=other"), Tracks::Both);
+
+        assert_eq!(Tracks::Both.flv_header()[4], 0x05);
+        assert_eq!(Tracks::Video.flv_header()[4], 0x04);
+        assert_eq!(Tracks::Audio.flv_header()[4], 0x01);
+        assert!(Tracks::Video.accept(&ChunkMessageType::VideoMessage));
+        assert!(!Tracks::Video.accept(&ChunkMessageType::AudioMessage));
+        assert!(Tracks::Audio.accept(&ChunkMessageType::AudioMessage));
+        assert!(!Tracks::Both.accept(&ChunkMessageType::AMF0DataMessage));
+    }
+
+    #[test]
+    fn get_path_rejects_bad_requests() {
+        assert_eq!(get_path("GET /live/test?only=video HTTP/1.1\r\n\r\n"), Ok(RequestLine { method: "GET", uri: "/live/test?only=video" }));
+        assert_eq!(get_path("").unwrap_err(), bad_request_response());
+        assert_eq!(get_path("GET HTTP/1.1\r\n").unwrap_err(), bad_request_response());
+        assert_eq!(get_path("GET /?only=video HTTP/1.1\r\n").unwrap_err(), bad_request_response());
+        assert!(get_path("POST /live/test HTTP/1.1\r\n").unwrap_err().starts_with("HTTP/1.1 405"));
+
+        let preflight = get_path("OPTIONS /live/test HTTP/1.1\r\nOrigin: https://example.com\r\nAccess-Control-Request-Headers: range\r\n\r\n").unwrap_err();
+        assert!(preflight.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Methods: GET, OPTIONS\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Headers: range\r\n"));
+    }
+
+    #[test]
+    fn play_aliased_stream() {
+        use crate::protocol::rtmp::RtmpMetaData;
+        use crate::protocol::transport::duplex;
+        use crate::publisher::StreamPublisher;
+
+        smol::block_on(async {
+            let publisher = StreamPublisher::create("internal/test_flv_alias").unwrap();
+            publisher.set_metadata(RtmpMetaData::default());
+            publisher.push_message(ChunkMessageType::VideoMessage, 0, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]).await;
+
+            let naming = NamingConfig {
+                aliases: vec!["live/flv_alias=internal/test_flv_alias".parse().unwrap()],
+                ..Default::default()
+            };
+            let (mut client, server) = duplex();
+            let peer_addr = "127.0.0.1:1935".parse().unwrap();
+            let server_task = smol::spawn(async move { serve(server, peer_addr, &naming).await });
+            client.write_all(b"GET /live/flv_alias?token=secret HTTP/1.1\r\n\r\n").await.unwrap();
+
+            // 公开的名称按别名找到内部的流
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !received.windows(3).any(|x| x == b"FLV") {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before flv header");
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            drop(publisher);
+            client.read_to_end(&mut received).await.unwrap();
+            assert!(server_task.await.is_ok());
+        });
+    }
+}
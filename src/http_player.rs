@@ -1,39 +1,118 @@
 use smol::io::{AsyncWriteExt, AsyncReadExt};
-use smol::net::{TcpListener, TcpStream};
+use smol::net::{SocketAddr, TcpStream};
 use smol::stream::StreamExt;
+use std::sync::Arc;
 
-use crate::util::spawn_and_log_error;
+use crate::cors;
+use crate::http_common::{bad_request_response, RequestLine};
+use crate::util::{bind_tcp, spawn_and_log_error};
 
-pub async fn run_server(addr: String, player_html: String) -> anyhow::Result<()> {
+/// 页面中被替换成`ctx`的占位符
+const INJECTED_CONTEXT: &str = "{/*$INJECTED_CONTEXT*/}";
+
+/// 默认页面，其他路径都返回这个页面，路径即流名称
+const PLAYER_HTML: &[u8] = include_bytes!("../static/player.html");
+
+/// 内置的静态文件，打包进二进制后不需要额外的static目录
+const ASSETS: &[(&str, &[u8])] = &[
+    ("/static/common.js", include_bytes!("../static/common.js")),
+    ("/static/fmp4.html", include_bytes!("../static/fmp4.html")),
+    ("/static/flv.html", include_bytes!("../static/flv.html")),
+];
+
+/// 替换过`ctx`的路由表
+struct Routes {
+    player: Vec<u8>,
+    assets: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl Routes {
+    fn new(context: &str) -> Self {
+        Self {
+            player: inject(PLAYER_HTML, context),
+            assets: ASSETS.iter().map(|(path, bytes)| (*path, inject(bytes, context))).collect(),
+        }
+    }
+
+    fn get(&self, path: &str) -> (&str, &[u8]) {
+        match self.assets.iter().find(|(x, _)| *x == path) {
+            Some((path, bytes)) => (content_type(path), bytes),
+            None => (content_type("player.html"), &self.player),
+        }
+    }
+}
+
+/// 只替换html中的占位符
+fn inject(bytes: &[u8], context: &str) -> Vec<u8> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if text.contains(INJECTED_CONTEXT) => text.replace(INJECTED_CONTEXT, context).into_bytes(),
+        _ => bytes.to_vec(),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next() {
+        Some("html") => "text/html;charset=UTF-8",
+        Some("js") => "application/javascript;charset=UTF-8",
+        Some("css") => "text/css;charset=UTF-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `context`是注入页面的JSON，例如`{"port":18000,"ports":{"ws_h264":18000},"default":"ws_h264"}`
+pub async fn run_server(addr: SocketAddr, context: String) -> anyhow::Result<()> {
     // Open up a TCP connection and create a URL.
-    let listener = TcpListener::bind(addr).await?;
+    let listener = bind_tcp(addr)?;
     let addr = format!("http://{}", listener.local_addr()?);
     log::info!("HTTP-Player Server is listening to {}", addr);
 
+    let routes = Arc::new(Routes::new(&context));
     // For each incoming TCP connection, spawn a task and call `accept`.
     let mut incoming = listener.incoming();
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
-        spawn_and_log_error(accept(stream, player_html.clone()));
+        spawn_and_log_error(accept(stream, routes.clone()));
     }
     Ok(())
 }
 
-async fn accept(mut stream: TcpStream, player_html: String) -> anyhow::Result<()> {
+async fn accept(mut stream: TcpStream, routes: Arc<Routes>) -> anyhow::Result<()> {
     log::info!("[HTTP] new connection from {}", stream.peer_addr()?);
 
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await?;
+    let n = stream.read(&mut buffer).await?;
+    let req = String::from_utf8_lossy(&buffer[..n]);
+    let line = match RequestLine::parse(&req) {
+        Some(line) => line,
+        None => {
+            stream.write_all(bad_request_response().as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(());
+        }
+    };
+    let path = line.path();
+    let origin = cors::request_origin(&req);
+    if !cors::is_allowed(origin) {
+        stream.write_all(cors::forbidden_response().as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+    if line.method == "OPTIONS" {
+        stream.write_all(cors::preflight_response(&req, "GET, OPTIONS").as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+    let (content_type, body) = routes.get(path);
 
-    let header = format!("HTTP/1.1 200 OK\r\n\
-    Content-Type: text/html;charset=UTF-8\r\n\
+    let mut response = format!("HTTP/1.1 200 OK\r\n\
+    Content-Type: {}\r\n\
     Connection: close\r\n\
     Content-Length: {}\r\n\
     Cache-Control: no-cache\r\n\
-    Access-Control-Allow-Origin: *\r\n\
-    \r\n\
-    {}", player_html.len(), player_html);
-    stream.write_all(header.as_bytes()).await?;
+    {}\
+    \r\n", content_type, body.len(), cors::allow_origin_header(origin)).into_bytes();
+    response.extend_from_slice(body);
+    stream.write_all(&response).await?;
     stream.flush().await?;
     Ok(())
 }
@@ -0,0 +1,269 @@
+//! 没有推流者时的占位画面，`--idle-asset`，用于信息屏等需要一直有画面的场景
+//!
+//! HTTP-FLV和WebSocket播放者连接一个不存在的流时，循环播放一段预先编码的H.264或者FLV，
+//! RTMP推流者开始推流时沿用占位画面的eventbus，播放者不断开，直接切换到直播画面
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use smol::Timer;
+use std::convert::TryFrom;
+
+use crate::protocol::flv::{read_header, read_tag};
+use crate::protocol::h264::{split_annexb, Nalu};
+use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage, RtmpMetaData};
+use crate::publisher::StreamPublisher;
+use crate::rtmp_server::{
+    audio_header_map, eventbus_map, gop_cache_map, key_frame_tracker_map, last_timestamp_map, meta_data_map, video_header_map,
+};
+
+/// `.h264`文件的帧率，FLV最后一帧的时长
+const FRAME_INTERVAL_MS: u32 = 40;
+/// 所有播放者离开之后停止循环的时间
+const IDLE_GRACE: Duration = Duration::from_secs(5);
+
+/// 循环播放的一帧，时间戳从0开始
+enum IdleFrame {
+    /// Annex B格式的访问单元
+    H264 { data: Vec<u8>, key_frame: bool },
+    /// FLV中的音视频tag，包括sequence header
+    Tag(RtmpMessage),
+}
+
+/// 启动时加载到内存的占位画面
+pub struct IdleAsset {
+    meta_data: RtmpMetaData,
+    frames: Vec<(u32, IdleFrame)>,
+    /// 一次循环的时长，下一次循环的时间戳从这里继续
+    duration: u32,
+}
+
+impl IdleAsset {
+    /// `.flv`按FLV读取，其他文件按Annex B格式的H.264读取，需要包含关键帧
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).map_err(|e| anyhow::anyhow!("failed to read idle asset {}, {}", path.display(), e))?;
+        let asset = match path.extension().and_then(|x| x.to_str()) {
+            Some("flv") => Self::from_flv(&data)?,
+            _ => Self::from_h264(&data),
+        };
+        if !asset.frames.iter().any(|(_, frame)| frame.is_key_frame()) {
+            return Err(anyhow::anyhow!("idle asset {} has no keyframe", path.display()));
+        }
+        log::info!("[Idle] load {}, frames={}, duration={}ms", path.display(), asset.frames.len(), asset.duration);
+        Ok(asset)
+    }
+
+    /// 按VCL NALU切分访问单元，SPS、PPS等放在之后的帧中
+    fn from_h264(data: &[u8]) -> Self {
+        let mut frames = vec![];
+        let mut access_unit = vec![];
+        for nalu in split_annexb(data) {
+            access_unit.extend_from_slice(&[0, 0, 0, 1]);
+            access_unit.extend_from_slice(nalu);
+            let unit_type = nalu.first().map(|x| x & 0x1F).unwrap_or_default();
+            if unit_type == 1 || unit_type == Nalu::UNIT_TYPE_IDR {
+                let timestamp = frames.len() as u32 * FRAME_INTERVAL_MS;
+                let key_frame = split_annexb(&access_unit).iter().any(|x| x[0] & 0x1F == Nalu::UNIT_TYPE_IDR);
+                frames.push((timestamp, IdleFrame::H264 { data: std::mem::take(&mut access_unit), key_frame }));
+            }
+        }
+        let meta_data = RtmpMetaData {
+            video_codec_id: "7".to_string(),
+            frame_rate: (1000 / FRAME_INTERVAL_MS) as f64,
+            ..Default::default()
+        };
+        let duration = frames.len() as u32 * FRAME_INTERVAL_MS;
+        Self { meta_data, frames, duration }
+    }
+
+    /// 音视频tag和onMetaData，时间戳减去第一个tag的时间戳
+    fn from_flv(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = smol::io::Cursor::new(data);
+        let mut meta_data = RtmpMetaData::default();
+        let mut messages = vec![];
+        smol::block_on(async {
+            read_header(&mut reader).await?;
+            while let Some(msg) = read_tag(&mut reader).await? {
+                if msg.header.message_type != ChunkMessageType::AMF0DataMessage {
+                    messages.push(msg);
+                    continue;
+                }
+                let values = msg.try_read_body_to_amf0().unwrap_or_default();
+                if let (Some("onMetaData"), Some(value)) = (values.first().and_then(|x| x.try_as_str()), values.get(1)) {
+                    meta_data = RtmpMetaData::try_from(value)?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        let first = messages.first().map(|x| x.header.timestamp).unwrap_or_default();
+        let frames: Vec<_> = messages.into_iter().map(|x| (x.header.timestamp.saturating_sub(first), IdleFrame::Tag(x))).collect();
+        let duration = frames.last().map(|x| x.0 + FRAME_INTERVAL_MS).unwrap_or_default();
+        Ok(Self { meta_data, frames, duration })
+    }
+}
+
+impl IdleFrame {
+    fn is_key_frame(&self) -> bool {
+        match self {
+            IdleFrame::H264 { key_frame, .. } => *key_frame,
+            IdleFrame::Tag(msg) => msg.is_video_key_frame() && !msg.is_sequence_header(),
+        }
+    }
+}
+
+static IDLE_ASSET: OnceCell<IdleAsset> = OnceCell::new();
+
+/// 启动时设置占位画面，只能设置一次
+pub fn init_idle_asset(asset: IdleAsset) {
+    if IDLE_ASSET.set(asset).is_err() {
+        log::warn!("idle asset has been initialized");
+    }
+}
+
+/// 正在播放占位画面的流
+fn idle_map() -> &'static DashMap<String, Arc<StreamPublisher>> {
+    static INSTANCE: OnceCell<DashMap<String, Arc<StreamPublisher>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 流是否正在播放占位画面，占位画面不触发自动录制
+pub fn is_idle(stream_name: &str) -> bool {
+    idle_map().contains_key(stream_name)
+}
+
+/// 播放者连接不存在的流时调用，配置了占位画面时开始循环播放，返回流是否存在
+pub fn start(stream_name: &str) -> bool {
+    match IDLE_ASSET.get() {
+        Some(asset) => start_asset(stream_name, asset),
+        None => false,
+    }
+}
+
+fn start_asset(stream_name: &str, asset: &'static IdleAsset) -> bool {
+    let entry = match idle_map().entry(stream_name.to_string()) {
+        Entry::Occupied(_) => return true,
+        Entry::Vacant(entry) => entry,
+    };
+    let publisher = match StreamPublisher::register(stream_name) {
+        Ok(publisher) => Arc::new(publisher),
+        // 推流者已经开始推流
+        Err(_) => return eventbus_map().contains_key(stream_name),
+    };
+    publisher.set_metadata(asset.meta_data.clone());
+    entry.insert(publisher.clone());
+    log::info!("[Idle] start, stream_name={}", stream_name);
+
+    smol::spawn(async move {
+        smol::future::or(play(&publisher, asset), publisher.wait_idle(IDLE_GRACE)).await;
+        idle_map().remove_if(publisher.stream_name(), |_, x| Arc::ptr_eq(x, &publisher));
+        log::info!("[Idle] stop, stream_name={}, handed_over={}", publisher.stream_name(), publisher.is_handed_over());
+    })
+    .detach();
+    true
+}
+
+/// 按时间戳循环推送，交给推流者之后停止
+async fn play(publisher: &StreamPublisher, asset: &IdleAsset) {
+    let begin = Instant::now();
+    let mut base = 0u32;
+    loop {
+        for (timestamp, frame) in &asset.frames {
+            let timestamp = base.wrapping_add(*timestamp);
+            Timer::at(begin + Duration::from_millis(timestamp as u64)).await;
+            if publisher.is_handed_over() {
+                return;
+            }
+            match frame {
+                IdleFrame::H264 { data, key_frame } => publisher.push_video(data, timestamp, *key_frame).await,
+                // sequence header只在第一次循环发送
+                IdleFrame::Tag(msg) if msg.is_sequence_header() && base > 0 => {}
+                IdleFrame::Tag(msg) => publisher.push_message(msg.header.message_type, timestamp, msg.body.clone()).await,
+            }
+        }
+        base = base.wrapping_add(asset.duration.max(FRAME_INTERVAL_MS));
+    }
+}
+
+/// RTMP推流者开始推流时调用，正在播放占位画面时停止并交出eventbus，返回是否交出
+///
+/// 清除占位画面的sequence header和GOP缓存，之后加入的播放者只会收到直播画面
+pub(crate) fn take_over(stream_name: &str) -> bool {
+    let publisher = match idle_map().remove(stream_name) {
+        Some((_, publisher)) => publisher,
+        None => return false,
+    };
+    publisher.hand_over();
+    video_header_map().remove(stream_name);
+    audio_header_map().remove(stream_name);
+    meta_data_map().remove(stream_name);
+    gop_cache_map().remove(stream_name);
+    key_frame_tracker_map().remove(stream_name);
+    last_timestamp_map().remove(stream_name);
+    eventbus_map().contains_key(stream_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SPS、PPS和IDR，之后两个P帧
+    const CLIP: [u8; 39] = [
+        0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80, 0, 0, 0, 1, 0x65, 0x88, 0x84, 0, 0, 0, 1,
+        0x41, 0x9A, 0x02, 0x04, 0, 0, 0, 1, 0x41, 0x9A, 0x02, 0x08,
+    ];
+
+    #[test]
+    fn split_h264_into_access_units() {
+        let asset = IdleAsset::from_h264(&CLIP);
+        let frames: Vec<_> = asset
+            .frames
+            .iter()
+            .map(|(timestamp, frame)| match frame {
+                IdleFrame::H264 { data, key_frame } => (*timestamp, split_annexb(data).len(), *key_frame),
+                IdleFrame::Tag(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(frames, vec![(0, 3, true), (40, 1, false), (80, 1, false)]);
+        assert_eq!(asset.duration, 120);
+        assert_eq!(asset.meta_data.frame_rate, 25.0);
+    }
+
+    #[test]
+    fn loop_until_publisher_takes_over() {
+        let stream_name = "live/test_idle_asset";
+        let asset: &'static IdleAsset = Box::leak(Box::new(IdleAsset::from_h264(&CLIP)));
+        smol::block_on(async {
+            assert!(start_asset(stream_name, asset));
+            assert!(is_idle(stream_name));
+            // 已经在播放时不重复创建
+            assert!(start_asset(stream_name, asset));
+
+            let rx = crate::rtmp_server::subscribe(stream_name).unwrap();
+            let mut timestamps = vec![];
+            while timestamps.len() < 4 {
+                let msg = rx.recv().await.unwrap();
+                if msg.header.message_type == ChunkMessageType::VideoMessage && !msg.is_sequence_header() {
+                    timestamps.push(msg.header.timestamp);
+                }
+            }
+            // 订阅之前可能已经推送了第一帧，连续4帧一定跨过一次循环，第二次循环接着第一次的时间戳
+            assert!(timestamps.windows(2).all(|x| x[1] == x[0] + 40), "{:?}", timestamps);
+
+            assert!(take_over(stream_name));
+            assert!(!is_idle(stream_name));
+            assert!(eventbus_map().contains_key(stream_name));
+            assert!(!video_header_map().contains_key(stream_name));
+            // 交出之后不再推送，eventbus保留给新的推流者
+            Timer::after(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            Timer::after(Duration::from_millis(100)).await;
+            assert!(rx.try_recv().is_err());
+            assert!(eventbus_map().contains_key(stream_name));
+            eventbus_map().remove(stream_name);
+        });
+    }
+}
@@ -1,11 +1,36 @@
+//! 流媒体服务器，RTMP推流，RTMP/HTTP-FLV/WebSocket/RTSP/WHEP播放
+//!
+//! 异步运行时只使用[smol]，不依赖async-std或tokio。各个服务的入口（例如[`rtmp_server::accept_loop`]）
+//! 是普通的future，可以用任意执行器驱动，但连接和转发任务通过`smol::spawn`运行在smol的全局执行器上，
+//! 它有自己的线程，不需要调用者提供执行器
 #[macro_use]
 extern crate num_derive;
 
+pub mod access_log;
+pub mod config;
+pub mod cors;
 mod eventbus;
+pub mod http_api;
+mod http_common;
 pub mod http_flv;
 pub mod http_player;
+pub mod idle;
+pub mod metrics;
+pub mod naming;
+pub mod pacer;
 pub mod protocol;
+pub mod rate_limit;
+pub mod publisher;
+pub mod recording;
+pub mod rtmp_push;
 pub mod rtmp_server;
+pub mod rtsp_server;
+pub mod thumbnail;
+pub mod transcode;
 pub mod util;
+mod vod;
+pub mod whep;
 pub mod ws_h264;
-pub mod ws_fmp4;
\ No newline at end of file
+pub mod ws_fmp4;
+pub mod ws_server;
+mod ws_common;
\ No newline at end of file
@@ -1,46 +1,249 @@
 use clap::crate_version;
-use clap::Clap;
-use river::{ws_h264, ws_fmp4, util, http_flv, http_player};
-use river::rtmp_server::accept_loop;
+use clap::{Clap, IntoApp};
+use river::{access_log, config, cors, recording, ws_h264, ws_fmp4, ws_server, util, http_api, http_flv, http_player, idle, rtsp_server};
+use river::rtmp_server::{self, accept_loop, init_key_frame_warn_interval, init_monotonic_timestamps, init_publisher_wait_timeout};
 use river::util::spawn_and_log_error;
+use river::protocol::flv::{timestamp_mode_map, TimestampMode};
+use river::protocol::fmp4::{init_recording_config, RecordingConfig};
+use river::protocol::rtmp::RtmpConfig;
+use river::recording::RecordingFormat;
+use river::rtmp_push::{init_push_rules, PushRule};
+use river::transcode::{self, TranscodeRule};
+use river::pacer::{init_paced_outputs, PacedOutput};
+use river::rate_limit::init_viewer_max_kbps;
+use river::naming::{init_naming_config, parse_allowlist, NamingConfig, StreamAlias};
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 
 #[derive(Clap, Debug)]
 #[clap(version = crate_version ! (), author = "Ninthakeey <ninthakeey@hotmail.com>")]
 struct Opts {
+    #[clap(long, about = "read options from a TOML file, keys are the long option names, options given on the command line override it")]
+    config: Option<String>,
+    #[clap(long, default_value = "0.0.0.0", parse(try_from_str = parse_host), about = "default host for all listeners, `::` accepts both IPv6 and IPv4")]
+    bind: IpAddr,
+    #[clap(long, default_value = "0", about = "serves /metrics and /api/*, disabled if port is 0")]
+    http_api_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    http_api_bind: Option<IpAddr>,
     #[clap(long, default_value = "0", about = "disabled if port is 0")]
     http_flv_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    http_flv_bind: Option<IpAddr>,
     #[clap(long, default_value = "18000", about = "disabled if port is 0")]
     http_player_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    http_player_bind: Option<IpAddr>,
     #[clap(long, default_value = "18001", about = "disabled if port is 0")]
     ws_h264_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    ws_h264_bind: Option<IpAddr>,
     #[clap(long, default_value = "0", about = "disabled if port is 0")]
     ws_fmp4_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    ws_fmp4_bind: Option<IpAddr>,
+    #[clap(long, default_value = "0", about = "group fMP4 frames into one fragment of this many milliseconds, a keyframe starts a new fragment, 0 sends one fragment per frame")]
+    fmp4_fragment_ms: u32,
+    #[clap(long, default_value = "0", about = "serves /ws/<stream> and keyframe-only /preview/<stream>, format negotiated by Sec-WebSocket-Protocol, disabled if port is 0")]
+    ws_port: u16,
+    #[clap(long, default_value = "1000", about = "minimum interval between two keyframes sent to a /preview/<stream> viewer")]
+    preview_interval_ms: u32,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    ws_bind: Option<IpAddr>,
+    #[clap(long, default_value = "0", about = "disabled if port is 0")]
+    rtsp_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    rtsp_bind: Option<IpAddr>,
     #[clap(long, default_value = "1935")]
     rtmp_port: u16,
+    #[clap(long, parse(try_from_str = parse_host), about = "overrides --bind")]
+    rtmp_bind: Option<IpAddr>,
+    #[clap(long, about = "listen for RTMP on this unix domain socket instead of TCP, e.g. behind a proxy that terminates TLS")]
+    rtmp_uds: Option<PathBuf>,
+    #[clap(long, default_value = "4096", about = "outgoing RTMP chunk size, larger chunks reduce overhead for high bitrates")]
+    rtmp_chunk_size: u32,
+    #[clap(long, default_value = "1048576", about = "bytes received before an RTMP Acknowledgement is sent")]
+    rtmp_ack_window_size: u32,
+    #[clap(long, default_value = "1048576", about = "window size advertised in RTMP SetPeerBandwidth")]
+    rtmp_peer_bandwidth: u32,
+    #[clap(long, default_value = "16777216", about = "disconnect an RTMP peer that announces a message longer than this many bytes")]
+    max_message_bytes: u32,
+    #[clap(long, default_value = "30", about = "disconnect a publisher that sends nothing for this many seconds, 0 to disable")]
+    publish_timeout_secs: u64,
+    #[clap(long, default_value = "0", about = "cap the bandwidth of each HTTP-FLV, WebSocket and RTMP viewer, frames are dropped until the next keyframe when it falls behind, 0 to disable")]
+    viewer_max_kbps: u32,
+    #[clap(long, default_value = "0", about = "keep viewers of a disconnected RTMP publisher for this many seconds so that a publisher reconnecting with the same name takes them over, 0 to disable")]
+    takeover_secs: u64,
+    #[clap(long, default_value = "0", about = "HTTP-FLV and WebSocket viewers of a stream that is not live wait this many seconds for its publisher and first keyframe, 0 to disable")]
+    wait_publisher_secs: u64,
+    #[clap(long, about = "H.264 Annex B or FLV file looped to HTTP-FLV and WebSocket viewers of a stream that is not live, replaced by the stream once published over RTMP")]
+    idle_asset: Option<PathBuf>,
+    #[clap(long, default_value = "10", about = "warn when a publisher sends no keyframe for this many seconds, 0 to disable")]
+    keyframe_warn_secs: u64,
+    #[clap(long, about = "clamp a publisher's audio or video timestamp that goes backwards to the previous one plus 1ms, counted in stats and metrics")]
+    monotonic_timestamps: bool,
+    #[clap(long, about = "stream name whose FLV output uses wall clock timestamps, repeatable")]
+    wall_clock_timestamp: Vec<String>,
+    #[clap(long, about = "record each published stream from its first keyframe, otherwise recordings are only started over the HTTP API")]
+    record: bool,
+    #[clap(long, default_value = "mp4", about = "format of the recording started by --record, flv, mp4 or ts")]
+    record_format: RecordingFormat,
+    #[clap(long, about = "write recordings as non-fragmented MP4 with a seekable index when the stream ends")]
+    finalize_recording: bool,
+    #[clap(long, about = "log filter such as `debug` or `info,river::rtmp_server=warn`, RUST_LOG is used if absent [default: info]")]
+    log_level: Option<String>,
+    #[clap(long, about = "print the hex dump of RTMP packets, also enabled at trace level")]
+    dump_packets: bool,
+    #[clap(long, about = "sent as the HTTP and RTSP `Server` header, the onMetaData `Server` field and the RTMP `fmsVer`, defaults to river, RIVER and FMS/3,0,1,123")]
+    server_name: Option<String>,
+    #[clap(long, about = "append a JSON line when each publish or play session starts and ends, written to the log if absent")]
+    access_log: Option<String>,
+    #[clap(long, about = "origin allowed to play over HTTP and WebSocket, others get 403, repeatable, any origin if absent")]
+    allow_origin: Vec<String>,
+    #[clap(long, about = "app accepted in RTMP connect, others are rejected, repeatable, any app if absent")]
+    app: Vec<String>,
+    #[clap(long, about = "map a published or played `app/stream` to an internal name, `<from>=<to>`, repeatable")]
+    alias: Vec<StreamAlias>,
+    #[clap(long, about = "regex that stream names must fully match after aliasing, others are rejected")]
+    stream_name_allow: Option<String>,
+    #[clap(long, about = "forward a published stream to an upstream server, `<stream>=<rtmp url>`, repeatable")]
+    push: Vec<PushRule>,
+    #[clap(long, about = "deliver frames at the media clock instead of bursting the backlog, one of rtmp, http-flv, ws-h264, ws-fmp4, repeatable")]
+    pace: Vec<PacedOutput>,
+    #[clap(long, about = "pipe a published stream as FLV through a command and publish its FLV stdout as another stream, `<stream>=<output>:<command>`, repeatable, requires the transcode feature")]
+    transcode: Vec<TranscodeRule>,
 }
 
+impl Opts {
+    /// 单个服务的监听地址，优先使用该服务自己的host
+    fn socket_addr(&self, host: Option<IpAddr>, port: u16) -> SocketAddr {
+        SocketAddr::new(host.unwrap_or(self.bind), port)
+    }
 
-fn main() -> anyhow::Result<()> {
-    util::init_logger();
+    /// 注入播放页面的JSON，包含所有开启的输出端口和默认格式
+    ///
+    /// `port`是`ws_h264_port`，兼容旧页面
+    fn player_context(&self) -> String {
+        let outputs = [
+            ("ws_h264", self.ws_h264_port),
+            ("ws_fmp4", self.ws_fmp4_port),
+            ("http_flv", self.http_flv_port),
+        ];
+        let enabled = outputs.iter().filter(|(_, port)| *port > 0).collect::<Vec<_>>();
+        let ports = enabled
+            .iter()
+            .map(|(name, port)| format!(r#""{}":{}"#, name, port))
+            .collect::<Vec<_>>()
+            .join(",");
+        let default = enabled
+            .first()
+            .map(|(name, _)| format!(r#""{}""#, name))
+            .unwrap_or_else(|| "null".to_string());
+        format!(r#"{{"port":{},"ports":{{{}}},"default":{}}}"#, self.ws_h264_port, ports, default)
+    }
+}
+
+/// 解析命令行参数，指定了`--config`时合并配置文件
+fn parse_opts() -> anyhow::Result<Opts> {
+    let cli_args: Vec<OsString> = std::env::args_os().collect();
+    let opts = Opts::parse_from(&cli_args);
+    let path = match &opts.config {
+        Some(path) => path,
+        None => return Ok(opts),
+    };
+    let file_args = config::load(path, &Opts::into_app())?;
+    let args = config::merge_args(Opts::into_app(), cli_args, file_args)?;
+    Ok(Opts::parse_from(args))
+}
+
+/// 支持`::`和`[::]`两种IPv6写法
+fn parse_host(s: &str) -> Result<IpAddr, std::net::AddrParseError> {
+    s.trim_start_matches('[').trim_end_matches(']').parse()
+}
 
-    let opts: Opts = Opts::parse();
+
+fn main() -> anyhow::Result<()> {
+    let opts = parse_opts()?;
+    util::init_logger(opts.log_level.as_deref());
+    util::set_dump_packets(opts.dump_packets);
+    if let Some(name) = &opts.server_name {
+        util::init_server_name(name.clone());
+    }
     log::info!("{:?}", &opts);
+    if let Some(path) = &opts.access_log {
+        access_log::init_access_log(path)?;
+    }
+
+    for stream_name in &opts.wall_clock_timestamp {
+        timestamp_mode_map().insert(stream_name.clone(), TimestampMode::WallClock);
+    }
 
-    let player_html = include_str!("../static/player.html");
-    let player_html = player_html.replace("{/*$INJECTED_CONTEXT*/}", &format!("{{port: {}}}", opts.ws_h264_port));
+    init_naming_config(NamingConfig {
+        aliases: opts.alias.clone(),
+        allowlist: opts.stream_name_allow.as_deref().map(parse_allowlist).transpose()?,
+        apps: opts.app.clone(),
+    });
+    cors::init_allowed_origins(opts.allow_origin.clone());
+    init_push_rules(opts.push.clone());
+    if !opts.transcode.is_empty() && !transcode::SUPPORTED {
+        return Err(anyhow::anyhow!("--transcode requires river built with `--features transcode`"));
+    }
+    transcode::init_transcode_rules(opts.transcode.clone());
+    init_paced_outputs(opts.pace.clone());
+    init_viewer_max_kbps(opts.viewer_max_kbps);
+    ws_fmp4::init_fragment_ms(opts.fmp4_fragment_ms);
+    ws_fmp4::init_preview_interval_ms(opts.preview_interval_ms);
+    init_publisher_wait_timeout(Some(Duration::from_secs(opts.wait_publisher_secs)).filter(|x| !x.is_zero()));
+    init_monotonic_timestamps(opts.monotonic_timestamps);
+    if let Some(path) = &opts.idle_asset {
+        idle::init_idle_asset(idle::IdleAsset::load(path)?);
+    }
+    init_key_frame_warn_interval(Some(Duration::from_secs(opts.keyframe_warn_secs)).filter(|x| !x.is_zero()));
+
+    init_recording_config(RecordingConfig {
+        finalize: opts.finalize_recording,
+    });
+    if opts.record {
+        recording::init_auto_format(opts.record_format);
+    }
 
     if opts.http_player_port > 0 {
-        spawn_and_log_error(http_player::run_server(format!("0.0.0.0:{}", opts.http_player_port), player_html));
+        spawn_and_log_error(http_player::run_server(opts.socket_addr(opts.http_player_bind, opts.http_player_port), opts.player_context()));
+    }
+    if opts.http_api_port > 0 {
+        spawn_and_log_error(http_api::run_server(opts.socket_addr(opts.http_api_bind, opts.http_api_port)));
     }
     if opts.http_flv_port > 0 {
-        spawn_and_log_error(http_flv::run_server(format!("0.0.0.0:{}", opts.http_flv_port)));
+        spawn_and_log_error(http_flv::run_server(opts.socket_addr(opts.http_flv_bind, opts.http_flv_port)));
     }
     if opts.ws_h264_port > 0 {
-        spawn_and_log_error(ws_h264::run_server(format!("0.0.0.0:{}", opts.ws_h264_port)));
+        spawn_and_log_error(ws_h264::run_server(opts.socket_addr(opts.ws_h264_bind, opts.ws_h264_port)));
     }
     if opts.ws_fmp4_port > 0 {
-        spawn_and_log_error(ws_fmp4::run_server(format!("0.0.0.0:{}", opts.ws_fmp4_port)));
+        spawn_and_log_error(ws_fmp4::run_server(opts.socket_addr(opts.ws_fmp4_bind, opts.ws_fmp4_port)));
+    }
+    if opts.ws_port > 0 {
+        spawn_and_log_error(ws_server::run_server(opts.socket_addr(opts.ws_bind, opts.ws_port)));
+    }
+    if opts.rtsp_port > 0 {
+        spawn_and_log_error(rtsp_server::run_server(opts.socket_addr(opts.rtsp_bind, opts.rtsp_port)));
+    }
+    let rtmp_config = RtmpConfig {
+        out_chunk_size: opts.rtmp_chunk_size,
+        ack_window_size: opts.rtmp_ack_window_size,
+        peer_bandwidth: opts.rtmp_peer_bandwidth,
+        publish_timeout: Some(Duration::from_secs(opts.publish_timeout_secs)).filter(|x| !x.is_zero()),
+        takeover_grace: Some(Duration::from_secs(opts.takeover_secs)).filter(|x| !x.is_zero()),
+        max_message_bytes: opts.max_message_bytes,
+    };
+    match &opts.rtmp_uds {
+        #[cfg(unix)]
+        Some(path) => smol::block_on(rtmp_server::accept_loop_unix(path, rtmp_config)),
+        #[cfg(not(unix))]
+        Some(_) => Err(anyhow::anyhow!("--rtmp-uds is only supported on unix")),
+        None => smol::block_on(accept_loop(opts.socket_addr(opts.rtmp_bind, opts.rtmp_port), rtmp_config)),
     }
-    smol::block_on(accept_loop(&format!("0.0.0.0:{}", opts.rtmp_port)))
 }
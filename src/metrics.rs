@@ -0,0 +1,251 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+
+use crate::access_log::AccessSession;
+use crate::rtmp_server::eventbus_map;
+
+/// 握手耗时直方图的桶上限，单位秒
+const HANDSHAKE_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub fn metrics() -> &'static Metrics {
+    static INSTANCE: OnceCell<Metrics> = OnceCell::new();
+    INSTANCE.get_or_init(Metrics::default)
+}
+
+/// 服务运行指标，按Prometheus文本格式输出
+#[derive(Default)]
+pub struct Metrics {
+    bytes_received: DashMap<String, AtomicCell<u64>>,
+    publish_errors: AtomicCell<u64>,
+    handshake_buckets: [AtomicCell<u64>; HANDSHAKE_BUCKETS.len()],
+    /// 握手耗时总和，单位微秒
+    handshake_sum_micros: AtomicCell<u64>,
+    handshake_count: AtomicCell<u64>,
+    recording_dropped: DashMap<String, AtomicCell<u64>>,
+    recording_stopped: DashMap<String, AtomicCell<u64>>,
+    timestamp_corrected: DashMap<String, AtomicCell<u64>>,
+    viewers: DashMap<u64, Arc<ViewerStats>>,
+}
+
+/// 单个播放者的发送情况，用来找出跟不上的播放者
+pub struct ViewerStats {
+    /// 连接id，和日志中的`[conn=<id>]`相同
+    pub id: u64,
+    pub stream_name: String,
+    pub peer_addr: String,
+    /// 输出格式，例如`http-flv`
+    pub output: &'static str,
+    /// 还没有发送的消息数
+    pub queued: AtomicCell<u64>,
+    /// 等待关键帧时跳过的消息数
+    pub dropped: AtomicCell<u64>,
+    pub bytes_sent: AtomicCell<u64>,
+    /// 加入的时刻，毫秒时间戳
+    pub join_ts: i64,
+}
+
+/// 播放者断开时drop，移除统计，同时记录访问日志
+pub struct ViewerGuard(Arc<ViewerStats>, AccessSession);
+
+impl ViewerGuard {
+    pub fn stats(&self) -> Arc<ViewerStats> {
+        self.0.clone()
+    }
+}
+
+impl std::ops::Deref for ViewerGuard {
+    type Target = ViewerStats;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        metrics().viewers.remove(&self.0.id);
+        self.1.bytes.store(self.0.bytes_sent.load());
+    }
+}
+
+impl Metrics {
+    /// 累加推流端发送的字节数
+    pub fn add_bytes_received(&self, stream_name: &str, bytes_num: u64) {
+        if let Some(counter) = self.bytes_received.get(stream_name) {
+            counter.fetch_add(bytes_num);
+            return;
+        }
+        self.bytes_received
+            .entry(stream_name.to_string())
+            .or_default()
+            .fetch_add(bytes_num);
+    }
+
+    /// 累加录制队列满时丢弃的帧数
+    pub fn add_recording_dropped(&self, stream_name: &str, frames: u64) {
+        add_by_stream(&self.recording_dropped, stream_name, frames);
+    }
+
+    /// 录制因为写入错误提前结束
+    pub fn inc_recording_stopped(&self, stream_name: &str) {
+        add_by_stream(&self.recording_stopped, stream_name, 1);
+    }
+
+    /// 推流端的时间戳倒退时被修正，返回这个流累计修正的次数
+    pub fn inc_timestamp_corrected(&self, stream_name: &str) -> u64 {
+        add_by_stream(&self.timestamp_corrected, stream_name, 1);
+        self.timestamp_corrected(stream_name)
+    }
+
+    /// 流累计修正时间戳的次数
+    pub fn timestamp_corrected(&self, stream_name: &str) -> u64 {
+        self.timestamp_corrected.get(stream_name).map(|x| x.load()).unwrap_or(0)
+    }
+
+    /// 开始统计一个播放者，`conn_id`由`next_conn_id`分配
+    pub fn register_viewer(&self, conn_id: u64, stream_name: &str, peer_addr: &str, output: &'static str) -> ViewerGuard {
+        let access = AccessSession::start("play", output, conn_id, stream_name, peer_addr);
+        self.track_viewer(conn_id, stream_name, peer_addr, output, access)
+    }
+
+    /// 和`register_viewer`相同，使用已经开始的访问日志，例如RTMP在play命令时已经开始记录
+    pub fn track_viewer(&self, conn_id: u64, stream_name: &str, peer_addr: &str, output: &'static str, access: AccessSession) -> ViewerGuard {
+        let id = conn_id;
+        let stats = Arc::new(ViewerStats {
+            id,
+            stream_name: stream_name.to_string(),
+            peer_addr: peer_addr.to_string(),
+            output,
+            queued: Default::default(),
+            dropped: Default::default(),
+            bytes_sent: Default::default(),
+            join_ts: chrono::Local::now().timestamp_millis(),
+        });
+        self.viewers.insert(id, stats.clone());
+        ViewerGuard(stats, access)
+    }
+
+    /// 一个流的所有播放者，按加入顺序排列
+    pub fn viewers(&self, stream_name: &str) -> Vec<Arc<ViewerStats>> {
+        let mut viewers: Vec<_> = self
+            .viewers
+            .iter()
+            .filter(|x| x.stream_name == stream_name)
+            .map(|x| x.value().clone())
+            .collect();
+        viewers.sort_by_key(|x| x.id);
+        viewers
+    }
+
+    /// 推流连接异常断开
+    pub fn inc_publish_errors(&self) {
+        self.publish_errors.fetch_add(1);
+    }
+
+    /// 记录一次握手耗时
+    pub fn observe_handshake(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, bucket) in HANDSHAKE_BUCKETS.iter().zip(self.handshake_buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1);
+            }
+        }
+        self.handshake_sum_micros.fetch_add(elapsed.as_micros() as u64);
+        self.handshake_count.fetch_add(1);
+    }
+
+    /// 输出Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        writeln!(text, "# HELP river_streams_live Number of streams being published.").ok();
+        writeln!(text, "# TYPE river_streams_live gauge").ok();
+        writeln!(text, "river_streams_live {}", eventbus_map().len()).ok();
+
+        writeln!(text, "# HELP river_viewers Number of subscribers per stream, recordings included.").ok();
+        writeln!(text, "# TYPE river_viewers gauge").ok();
+        for entry in eventbus_map().iter() {
+            writeln!(
+                text,
+                "river_viewers{{stream=\"{}\"}} {}",
+                escape_label(entry.key()),
+                entry.value().receiver_count()
+            )
+                .ok();
+        }
+
+        writeln!(text, "# HELP river_bytes_received_total Bytes received from publishers.").ok();
+        writeln!(text, "# TYPE river_bytes_received_total counter").ok();
+        for entry in self.bytes_received.iter() {
+            writeln!(
+                text,
+                "river_bytes_received_total{{stream=\"{}\"}} {}",
+                escape_label(entry.key()),
+                entry.value().load()
+            )
+                .ok();
+        }
+
+        writeln!(text, "# HELP river_publish_errors_total Publisher connections closed by an error.").ok();
+        writeln!(text, "# TYPE river_publish_errors_total counter").ok();
+        writeln!(text, "river_publish_errors_total {}", self.publish_errors.load()).ok();
+
+        writeln!(text, "# HELP river_recording_dropped_frames_total Frames dropped because the recording queue is full.").ok();
+        writeln!(text, "# TYPE river_recording_dropped_frames_total counter").ok();
+        render_by_stream(&mut text, "river_recording_dropped_frames_total", &self.recording_dropped);
+
+        writeln!(text, "# HELP river_recording_errors_total Recordings stopped by a write error such as a full disk.").ok();
+        writeln!(text, "# TYPE river_recording_errors_total counter").ok();
+        render_by_stream(&mut text, "river_recording_errors_total", &self.recording_stopped);
+
+        writeln!(text, "# HELP river_timestamp_corrected_total Publisher timestamps that went backwards and were clamped by --monotonic-timestamps.").ok();
+        writeln!(text, "# TYPE river_timestamp_corrected_total counter").ok();
+        render_by_stream(&mut text, "river_timestamp_corrected_total", &self.timestamp_corrected);
+
+        writeln!(text, "# HELP river_handshake_duration_seconds RTMP handshake duration.").ok();
+        writeln!(text, "# TYPE river_handshake_duration_seconds histogram").ok();
+        for (bound, bucket) in HANDSHAKE_BUCKETS.iter().zip(self.handshake_buckets.iter()) {
+            writeln!(
+                text,
+                "river_handshake_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load()
+            )
+                .ok();
+        }
+        let count = self.handshake_count.load();
+        writeln!(text, "river_handshake_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).ok();
+        let sum = self.handshake_sum_micros.load() as f64 / 1_000_000.0;
+        writeln!(text, "river_handshake_duration_seconds_sum {}", sum).ok();
+        writeln!(text, "river_handshake_duration_seconds_count {}", count).ok();
+
+        text
+    }
+}
+
+fn add_by_stream(map: &DashMap<String, AtomicCell<u64>>, stream_name: &str, value: u64) {
+    if let Some(counter) = map.get(stream_name) {
+        counter.fetch_add(value);
+        return;
+    }
+    map.entry(stream_name.to_string()).or_default().fetch_add(value);
+}
+
+fn render_by_stream(text: &mut String, name: &str, map: &DashMap<String, AtomicCell<u64>>) {
+    for entry in map.iter() {
+        writeln!(text, "{}{{stream=\"{}\"}} {}", name, escape_label(entry.key()), entry.value().load()).ok();
+    }
+}
+
+/// 标签值中的`\`、`"`和换行需要转义
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
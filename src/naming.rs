@@ -0,0 +1,129 @@
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+use regex::Regex;
+
+/// 流名称别名，`--alias <from>=<to>`，两边都是`app/stream`
+#[derive(Debug, Clone)]
+pub struct StreamAlias {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for StreamAlias {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expect <from>=<to>, got {}", s))?;
+        Ok(Self {
+            from: from.trim_matches('/').to_string(),
+            to: to.trim_matches('/').to_string(),
+        })
+    }
+}
+
+/// 流名称的改写规则
+#[derive(Debug, Default)]
+pub struct NamingConfig {
+    pub aliases: Vec<StreamAlias>,
+    /// 改写之后的名称必须完整匹配，None表示不限制
+    pub allowlist: Option<Regex>,
+    /// 允许connect的app，为空时不限制
+    pub apps: Vec<String>,
+}
+
+static NAMING_CONFIG: OnceCell<NamingConfig> = OnceCell::new();
+
+/// 启动时设置别名和白名单，只能设置一次
+pub fn init_naming_config(config: NamingConfig) {
+    if NAMING_CONFIG.set(config).is_err() {
+        log::warn!("naming config has been initialized");
+    }
+}
+
+/// 启动时设置的配置，没有设置时不改写
+pub fn naming_config() -> &'static NamingConfig {
+    NAMING_CONFIG.get_or_init(Default::default)
+}
+
+/// publish/play命令中的流名称去掉query部分，例如`cam1?token=xxx`
+pub fn strip_query(stream: &str) -> &str {
+    stream.split('?').next().unwrap_or_default()
+}
+
+impl NamingConfig {
+    /// 把`app/stream`转换成内部名称，应用别名之后按白名单校验，不合法时返回Error
+    pub fn normalize(&self, stream_name: &str) -> anyhow::Result<String> {
+        let stream_name = self
+            .aliases
+            .iter()
+            .find(|x| x.from == stream_name)
+            .map(|x| x.to.as_str())
+            .unwrap_or(stream_name);
+        if stream_name.is_empty() {
+            return Err(anyhow::anyhow!("empty stream name"));
+        }
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.is_match(stream_name) {
+                return Err(anyhow::anyhow!("stream name not allowed: {}", stream_name));
+            }
+        }
+        Ok(stream_name.to_string())
+    }
+
+    /// 播放请求路径中的流名称，去掉query和两端的`/`之后转换成内部名称
+    pub fn normalize_path(&self, path: &str) -> anyhow::Result<String> {
+        self.normalize(strip_query(path).trim_matches('/'))
+    }
+
+    pub fn is_app_allowed(&self, app: &str) -> bool {
+        self.apps.is_empty() || self.apps.iter().any(|x| x.trim_matches('/') == app)
+    }
+}
+
+/// connect命令中的app是否允许连接
+pub fn is_app_allowed(app: &str) -> bool {
+    naming_config().is_app_allowed(app)
+}
+
+/// 使用启动时的配置转换流名称
+pub fn normalize(stream_name: &str) -> anyhow::Result<String> {
+    naming_config().normalize(stream_name)
+}
+
+/// HTTP-FLV、WebSocket、RTSP、WHEP播放时使用，和RTMP播放一样按别名查找
+pub fn normalize_path(path: &str) -> anyhow::Result<String> {
+    naming_config().normalize_path(path)
+}
+
+/// 白名单需要匹配整个名称
+pub fn parse_allowlist(s: &str) -> anyhow::Result<Regex> {
+    Ok(Regex::new(&format!("^(?:{})$", s))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_and_allowlist() {
+        let config = NamingConfig {
+            aliases: vec!["live/cam1=internal/camera_1".parse().unwrap()],
+            allowlist: Some(parse_allowlist(r"(live|internal)/\w+").unwrap()),
+            apps: vec!["live".to_owned()],
+        };
+        assert_eq!(strip_query("cam1?token=secret"), "cam1");
+        assert_eq!(config.normalize("live/cam1").unwrap(), "internal/camera_1");
+        assert_eq!(config.normalize("live/cam2").unwrap(), "live/cam2");
+        assert!(config.normalize("other/cam2").is_err());
+        // 白名单匹配整个名称
+        assert!(config.normalize("live/cam2/../x").is_err());
+        assert_eq!(config.normalize_path("/live/cam1/?token=secret").unwrap(), "internal/camera_1");
+
+        assert!(config.is_app_allowed("live"));
+        assert!(!config.is_app_allowed("other"));
+        assert!(NamingConfig::default().is_app_allowed("other"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use smol::Timer;
+
+/// 可以开启节奏控制的输出
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacedOutput {
+    Rtmp,
+    HttpFlv,
+    /// `h264-mix`和`json-meta`
+    WsH264,
+    WsFmp4,
+}
+
+impl FromStr for PacedOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rtmp" => Ok(PacedOutput::Rtmp),
+            "http-flv" => Ok(PacedOutput::HttpFlv),
+            "ws-h264" => Ok(PacedOutput::WsH264),
+            "ws-fmp4" => Ok(PacedOutput::WsFmp4),
+            _ => Err(anyhow::anyhow!("unknown output {}, expect rtmp, http-flv, ws-h264 or ws-fmp4", s)),
+        }
+    }
+}
+
+static PACED_OUTPUTS: OnceCell<Vec<PacedOutput>> = OnceCell::new();
+
+/// 启动时设置需要节奏控制的输出，只能设置一次
+pub fn init_paced_outputs(outputs: Vec<PacedOutput>) {
+    if PACED_OUTPUTS.set(outputs).is_err() {
+        log::warn!("paced outputs has been initialized");
+    }
+}
+
+/// 按媒体时间戳控制发送节奏，避免把堆积的帧一次性发给客户端
+///
+/// 没有开启的输出不会等待
+pub struct Pacer {
+    enabled: bool,
+    /// 对齐的媒体时间戳和对应的时刻
+    anchor: Option<(u32, Instant)>,
+}
+
+impl Pacer {
+    /// 落后超过这个时间时不再追赶，从当前帧重新对齐
+    const CATCH_UP_CAP: Duration = Duration::from_millis(500);
+    /// 单次等待的上限，时间戳跳变时重新对齐
+    const MAX_WAIT: Duration = Duration::from_secs(1);
+
+    pub fn for_output(output: PacedOutput) -> Self {
+        Self {
+            enabled: PACED_OUTPUTS.get().map(|x| x.contains(&output)).unwrap_or(false),
+            anchor: None,
+        }
+    }
+
+    /// 等待到时间戳对应的发送时刻，单位毫秒
+    pub async fn wait(&mut self, timestamp: u32) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let (anchor_timestamp, anchor_instant) = *self.anchor.get_or_insert((timestamp, now));
+        // 时间戳回退时重新对齐
+        if timestamp < anchor_timestamp {
+            self.anchor = Some((timestamp, now));
+            return;
+        }
+
+        let due = anchor_instant + Duration::from_millis((timestamp - anchor_timestamp) as u64);
+        if due > now {
+            let delay = due - now;
+            if delay > Pacer::MAX_WAIT {
+                self.anchor = Some((timestamp, now));
+                return;
+            }
+            Timer::after(delay).await;
+        } else if now - due > Pacer::CATCH_UP_CAP {
+            self.anchor = Some((timestamp, now));
+        }
+    }
+}
@@ -1,11 +1,69 @@
+use std::fmt;
+
 use crate::protocol::rtmp::{RtmpMessage, ChunkMessageType};
 
+/// FLV音频tag第一个字节的高4位，即audioCodecId
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// 2为MP3，14为8KHz的MP3，帧自带头部，可以原样转发
+    Mp3,
+    Aac,
+    Speex,
+    Other(u8),
+}
+
+impl AudioCodec {
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            2 | 14 => AudioCodec::Mp3,
+            10 => AudioCodec::Aac,
+            11 => AudioCodec::Speex,
+            x => AudioCodec::Other(x),
+        }
+    }
+
+    pub fn from_rtmp_message(msg: &RtmpMessage) -> Option<Self> {
+        if msg.header.message_type != ChunkMessageType::AudioMessage || msg.body.is_empty() {
+            return None;
+        }
+        Some(Self::from_id(msg.body[0] >> 4))
+    }
+
+    /// onMetaData中的audiocodecid，可能是数字，也可能是`mp4a`、`.mp3`之类的字符串
+    pub fn from_meta_data(audio_codec_id: &str) -> Option<Self> {
+        let audio_codec_id = audio_codec_id.trim().trim_start_matches('.');
+        match audio_codec_id.to_ascii_lowercase().as_str() {
+            "" => None,
+            "mp4a" | "aac" => Some(AudioCodec::Aac),
+            "mp3" => Some(AudioCodec::Mp3),
+            "speex" => Some(AudioCodec::Speex),
+            x => x.parse::<u8>().ok().map(Self::from_id),
+        }
+    }
+
+    /// 可以转换成ADTS，或者原样输出给ws-h264播放者
+    pub fn is_supported(&self) -> bool {
+        matches!(self, AudioCodec::Aac | AudioCodec::Mp3)
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioCodec::Mp3 => write!(f, "MP3"),
+            AudioCodec::Aac => write!(f, "AAC"),
+            AudioCodec::Speex => write!(f, "Speex"),
+            AudioCodec::Other(x) => write!(f, "audioCodecId={}", x),
+        }
+    }
+}
+
 /// # AAC rtmp头部信息封装
 /// AAC音频文件的每一帧都由一个ADTS头和AAC ES(AAC音频数据)组成。
 ///
 /// 以下是AAC音频数据格式
 /// 第一个byte包含音频的编码参数：
-/// ```
+/// ```text
 /// 1-4bit: audioCodeId
 /// 5-6bit: 采样率 00 5.5KHZ, 01 11KHZ, 10 22KHZ, 11 44KHZ
 /// 7 bit: 采样长度 0 8bit, 1 16bit
@@ -14,30 +72,33 @@ use crate::protocol::rtmp::{RtmpMessage, ChunkMessageType};
 /// ## 第一帧 AAC sequence header
 /// 第一帧共4个byte：
 /// 1. 第1个byte ： audioCodeId=10，如果是44KHZ、16bit、双声道，
-/// 第一个byte是0xAF。如果实际采样率不是5.5KHZ、11KHZ、22KHZ、44KHZ，
-/// 就选一个接近的。
+///    第一个byte是0xAF。如果实际采样率不是5.5KHZ、11KHZ、22KHZ、44KHZ，
+///    就选一个接近的。
 /// 2. 第2个byte ： 0x00 表示是sequence header
 /// 3. 第3-4个byte ： 0x14,0x10
 ///
 /// 其他帧 AAC raw data
 /// 1) 第1个byte ： audioCodeId=10，如果是44KHZ、16bit、双声道，
-/// 第一个byte是0xAF。如果实际采样率不是5.5KHZ、11KHZ、22KHZ、44KHZ，
-/// 就选一个接近的。
+///    第一个byte是0xAF。如果实际采样率不是5.5KHZ、11KHZ、22KHZ、44KHZ，
+///    就选一个接近的。
 /// 2) 第2个byte ： 0x01 表示是raw data
 /// 3) 第3byte开始 ： 去掉前7个byte的AAC头之后的AAC数据。
 pub struct AAC {
     inner: Vec<u8>,
+    /// 同一个流的AAC sequence header中的解码参数
+    config: Option<AudioSpecificConfig>,
 }
 
 #[allow(unused)]
 impl AAC {
     pub fn from_rtmp_message(msg: &RtmpMessage, header: &RtmpMessage) -> Option<Self> {
-        if msg.header.message_type != ChunkMessageType::AudioMessage {
+        if AudioCodec::from_rtmp_message(msg) != Some(AudioCodec::Aac) || msg.body.len() < 2 {
             return None;
         }
 
         Some(Self {
             inner: msg.body.to_owned(),
+            config: AudioSpecificConfig::from_rtmp_message(header),
         })
     }
 
@@ -49,13 +110,16 @@ impl AAC {
         self.inner[1] != 0x00
     }
 
-    /// raw_data -> ADTS
+    /// raw_data -> ADTS，使用sequence header中的采样率和声道数，解析失败时使用默认值
     pub fn to_adts(&self) -> Option<ADTS> {
-        if self.is_raw_data() {
-            Some(ADTS::with_data(self.inner[2..].to_vec()))
-        } else {
-            None
+        if !self.is_raw_data() {
+            return None;
         }
+        let data = self.inner[2..].to_vec();
+        Some(match &self.config {
+            Some(config) => ADTS::with_config(data, config),
+            None => ADTS::with_data(data),
+        })
     }
 }
 
@@ -65,6 +129,7 @@ impl AsRef<[u8]> for AAC {
     }
 }
 
+#[derive(Clone)]
 pub struct ADTS {
     // 1 bit; 0: MPEG-4, 1: MPEG-2
     pub id: bool,
@@ -121,6 +186,16 @@ impl ADTS {
         }
     }
 
+    /// 使用AAC sequence header中的profile、采样率和声道数
+    pub fn with_config(data: Vec<u8>, config: &AudioSpecificConfig) -> Self {
+        Self {
+            profile: config.object_type.saturating_sub(1) & 0x03,
+            sampling_frequency_index: config.sampling_frequency_index,
+            channel_configuration: config.channel_configuration,
+            ..Self::with_data(data)
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = vec![0u8; 7];
         data[0] = (ADTS::SYNC_WORD >> 4) as u8;
@@ -146,4 +221,75 @@ impl ADTS {
 
 fn bool2u8(v: bool) -> u8 {
     if v { 0x01 } else { 0x00 }
-}
\ No newline at end of file
+}
+/// # AudioSpecificConfig
+///
+/// AAC sequence header（`0xAF 0x00`之后的字节）中携带的解码参数
+/// ```text
+/// 5 bits: audioObjectType
+/// 4 bits: samplingFrequencyIndex
+/// 4 bits: channelConfiguration
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSpecificConfig {
+    pub object_type: u8,
+    pub sampling_frequency_index: u8,
+    pub channel_configuration: u8,
+}
+
+impl AudioSpecificConfig {
+    /// 采样率索引对应的采样率
+    pub const SAMPLING_FREQUENCIES: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            object_type: bytes[0] >> 3,
+            sampling_frequency_index: (bytes[0] & 0x07) << 1 | bytes[1] >> 7,
+            channel_configuration: (bytes[1] >> 3) & 0x0F,
+        })
+    }
+
+    /// 从ADTS头部解析，返回配置和ADTS头部长度
+    pub fn from_adts_header(bytes: &[u8]) -> Option<(Self, usize)> {
+        if bytes.len() < ADTS::HEADER_LEN as usize || bytes[0] != 0xFF || bytes[1] & 0xF0 != 0xF0 {
+            return None;
+        }
+        let protection_absent = bytes[1] & 0x01 == 1;
+        let config = Self {
+            object_type: (bytes[2] >> 6) + 1,
+            sampling_frequency_index: (bytes[2] >> 2) & 0x0F,
+            channel_configuration: (bytes[2] & 0x01) << 2 | bytes[3] >> 6,
+        };
+        // 有CRC校验时头部多2个字节
+        let header_len = if protection_absent { 7 } else { 9 };
+        Some((config, header_len))
+    }
+
+    /// 2字节的AudioSpecificConfig
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [
+            self.object_type << 3 | self.sampling_frequency_index >> 1,
+            (self.sampling_frequency_index & 0x01) << 7 | self.channel_configuration << 3,
+        ]
+    }
+
+    /// 从缓存的AAC sequence header消息中解析
+    pub fn from_rtmp_message(header: &RtmpMessage) -> Option<Self> {
+        if header.body.len() < 2 || header.body[1] != 0x00 {
+            return None;
+        }
+        Self::from_bytes(&header.body[2..])
+    }
+
+    pub fn sampling_frequency(&self) -> u32 {
+        Self::SAMPLING_FREQUENCIES
+            .get(self.sampling_frequency_index as usize)
+            .copied()
+            .unwrap_or(44100)
+    }
+}
@@ -1,14 +1,15 @@
 use byteorder::{BigEndian, ByteOrder};
 
 use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage};
-use crate::util::spawn_and_log_error;
 use smol::channel::Receiver;
+use smol::io::{AsyncRead, AsyncReadExt};
 use std::convert::TryFrom;
+use std::sync::Arc;
 
-use crate::rtmp_server::eventbus_map;
+use crate::recording::RecordingFile;
 use chrono::Local;
-use smol::io::AsyncWriteExt;
-use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
 
 pub const FLV_HEADER_WITH_TAG0: [u8; 13] = [
     0x46, 0x4c, 0x56, // signature
@@ -21,7 +22,15 @@ pub const FLV_HEADER_WITH_TAG0: [u8; 13] = [
 pub const FLV_HEADER_ONLY_VIDEO_WITH_TAG0: [u8; 13] = [
     0x46, 0x4c, 0x56, // signature
     0x01, // version
-    0x01, // video flag
+    0x04, // video flag
+    0x00, 0x00, 0x00, 0x09, // header length
+    0x00, 0x00, 0x00, 0x00, // tag0 length
+];
+
+pub const FLV_HEADER_ONLY_AUDIO_WITH_TAG0: [u8; 13] = [
+    0x46, 0x4c, 0x56, // signature
+    0x01, // version
+    0x01, // audio flag
     0x00, 0x00, 0x00, 0x09, // header length
     0x00, 0x00, 0x00, 0x00, // tag0 length
 ];
@@ -54,16 +63,17 @@ impl FlvTag {
     }
 }
 
-impl TryFrom<RtmpMessage> for FlvTag {
-    type Error = anyhow::Error;
-
+impl FlvTag {
+    /// 使用指定的时间戳生成FLV tag，不需要修改共享的RtmpMessage
+    ///
     /// 当RtmpMessage类型不是音频或视频的时候，会返回Error
-    fn try_from(mut msg: RtmpMessage) -> Result<Self, Self::Error> {
-        let mut raw_data = vec![];
+    pub fn from_rtmp_message(msg: &RtmpMessage, timestamp: u32) -> anyhow::Result<Self> {
+        let mut raw_data = Vec::with_capacity(11 + msg.body.len());
         // data type
         match msg.header.message_type {
             ChunkMessageType::AudioMessage => raw_data.push(0x08),
             ChunkMessageType::VideoMessage => raw_data.push(0x09),
+            ChunkMessageType::AMF0DataMessage => raw_data.push(0x12),
             _ => Err(anyhow::anyhow!(
                 "[FlvTag] invalid message type, {:?}",
                 msg.header.message_type
@@ -72,70 +82,186 @@ impl TryFrom<RtmpMessage> for FlvTag {
         // data size
         raw_data.extend_from_slice(&(msg.body.len() as u32).to_be_bytes()[1..4]);
         // timestamp
-        raw_data.extend_from_slice(&(msg.header.timestamp & 0xFFFFFF).to_be_bytes()[1..4]);
+        raw_data.extend_from_slice(&(timestamp & 0xFFFFFF).to_be_bytes()[1..4]);
         // timestamp extended
-        raw_data.push((msg.header.timestamp >> 24) as u8);
+        raw_data.push((timestamp >> 24) as u8);
         // stream id
         raw_data.extend_from_slice(&0u32.to_be_bytes()[1..4]);
         // body
-        raw_data.append(&mut msg.body);
+        raw_data.extend_from_slice(&msg.body);
 
         Ok(FlvTag { raw_data })
     }
 }
 
+impl TryFrom<RtmpMessage> for FlvTag {
+    type Error = anyhow::Error;
+
+    /// 当RtmpMessage类型不是音频或视频的时候，会返回Error
+    fn try_from(msg: RtmpMessage) -> Result<Self, Self::Error> {
+        FlvTag::from_rtmp_message(&msg, msg.header.timestamp)
+    }
+}
+
 impl AsRef<[u8]> for FlvTag {
     fn as_ref(&self) -> &[u8] {
         self.raw_data.as_ref()
     }
 }
 
-/// 后台保存FLV文件
-#[allow(unused)]
-pub fn save_flv_background(stream_name: &str, peer_addr: String) {
-    if let Some(eventbus) = eventbus_map().get(stream_name) {
-        let flv_rx = eventbus.register_receiver();
-        spawn_and_log_error(handle_flv_rx(flv_rx, stream_name.to_owned(), peer_addr));
+/// FLV tag时间戳的来源
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampMode {
+    /// 使用推送者的RTMP时间戳，第一帧从0开始
+    Publisher,
+    /// 使用服务器收到消息的时间，用于时间戳异常的推送者
+    WallClock,
+}
+
+/// 按流名称指定时间戳来源，未指定的流使用`TimestampMode::Publisher`
+pub fn timestamp_mode_map() -> &'static DashMap<String, TimestampMode> {
+    static INSTANCE: OnceCell<DashMap<String, TimestampMode>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 为每一个输出重新计算FLV时间戳
+pub struct FlvTimestamp {
+    mode: TimestampMode,
+    first_timestamp: Option<u32>,
+    begin_timestamp: i64,
+}
+
+impl FlvTimestamp {
+    pub fn new(mode: TimestampMode) -> Self {
+        Self {
+            mode,
+            first_timestamp: None,
+            begin_timestamp: Local::now().timestamp_millis(),
+        }
+    }
+
+    /// 使用该流配置的时间戳来源
+    pub fn for_stream(stream_name: &str) -> Self {
+        let mode = timestamp_mode_map()
+            .get(stream_name)
+            .map(|x| *x.value())
+            .unwrap_or(TimestampMode::Publisher);
+        Self::new(mode)
+    }
+
+    pub fn rebase(&mut self, timestamp: u32) -> u32 {
+        match self.mode {
+            TimestampMode::Publisher => {
+                let first = *self.first_timestamp.get_or_insert(timestamp);
+                timestamp.saturating_sub(first)
+            }
+            TimestampMode::WallClock => {
+                (Local::now().timestamp_millis() - self.begin_timestamp) as u32
+            }
+        }
     }
 }
 
-/// Rtmp流输出到FLV文件
-async fn handle_flv_rx(
-    flv_rx: Receiver<RtmpMessage>,
-    stream_name: String,
-    peer_addr: String,
-) -> anyhow::Result<()> {
-    let tmp_dir = "tmp";
-    if smol::fs::read_dir(tmp_dir).await.is_err() {
-        smol::fs::create_dir_all(tmp_dir).await?;
+/// FLV tag和之后的PreviousTagSize
+pub(crate) fn encode_tag(msg: &RtmpMessage, timestamp: u32) -> anyhow::Result<Vec<u8>> {
+    let flv_tag = FlvTag::from_rtmp_message(msg, timestamp)?;
+    let mut bytes = flv_tag.as_ref().to_vec();
+    bytes.extend_from_slice(&(flv_tag.as_ref().len() as u32).to_be_bytes());
+    Ok(bytes)
+}
+
+/// 读取FLV header和第一个PreviousTagSize
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<()> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header).await?;
+    if &header[..3] != b"FLV" {
+        return Err(anyhow::anyhow!("[FLV] invalid signature, {:?}", &header[..3]));
     }
+    // DataOffset之后是第一个PreviousTagSize
+    let data_offset = BigEndian::read_u32(&header[5..9]) as usize;
+    let mut skip = vec![0; data_offset.saturating_sub(header.len()) + 4];
+    reader.read_exact(&mut skip).await?;
+    Ok(())
+}
 
-    let mut file = smol::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open("tmp/output.flv")
-        .await?;
+/// 读取下一个音频、视频或者script tag，转换成msid为1的RtmpMessage，其他类型的tag被跳过，流正常结束时返回None
+pub async fn read_tag<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<RtmpMessage>> {
+    loop {
+        let mut header = [0; 11];
+        match reader.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let data_size = BigEndian::read_u24(&header[1..4]) as usize;
+        let timestamp = BigEndian::read_u24(&header[4..7]) | (header[7] as u32) << 24;
+        // 包括PreviousTagSize
+        let mut body = vec![0; data_size + 4];
+        reader.read_exact(&mut body).await?;
+        body.truncate(data_size);
+        let message_type = match header[0] & 0x1F {
+            0x08 => ChunkMessageType::AudioMessage,
+            0x09 => ChunkMessageType::VideoMessage,
+            0x12 => ChunkMessageType::AMF0DataMessage,
+            tag_type => {
+                log::debug!("[FLV] skip tag, type={}, size={}", tag_type, data_size);
+                continue;
+            }
+        };
+        return Ok(Some(RtmpMessage::new(message_type, 1, timestamp, body)));
+    }
+}
+
+/// Rtmp流输出到FLV文件，由`recording::start`调用
+pub(crate) async fn write_flv(
+    flv_rx: Receiver<Arc<RtmpMessage>>,
+    path: &str,
+    stream_name: &str,
+    peer_addr: &str,
+) -> anyhow::Result<()> {
+    let mut file = RecordingFile::create(path).await?;
 
     // write header
     file.write_all(&FLV_HEADER_WITH_TAG0).await?;
 
-    let ctx_begin_timestamp = Local::now().timestamp_millis();
-    let mut last_flush_time = Instant::now();
-    let min_flush_duration = Duration::from_secs(2);
-    while let Ok(mut msg) = flv_rx.recv().await {
-        msg.header.timestamp = (Local::now().timestamp_millis() - ctx_begin_timestamp) as u32;
-        let flv_tag = FlvTag::try_from(msg)?;
-        file.write_all(flv_tag.as_ref()).await?;
-        file.write_all(&(flv_tag.as_ref().len() as u32).to_be_bytes())
-            .await?;
-
-        if last_flush_time.elapsed() > min_flush_duration {
-            last_flush_time = Instant::now();
-            file.flush().await?
-        }
+    let mut flv_timestamp = FlvTimestamp::for_stream(stream_name);
+    while let Ok(msg) = flv_rx.recv().await {
+        let timestamp = flv_timestamp.rebase(msg.header.timestamp);
+        file.write_all(&encode_tag(&msg, timestamp)?).await?;
     }
 
-    log::warn!("[peer={}][handle_flv_rx] closed, stream_name={}", peer_addr, stream_name);
+    log::warn!("[peer={}][write_flv] closed, stream_name={}", peer_addr, stream_name);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_tags_written_by_encode_tag() {
+        smol::block_on(async {
+            let video = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]);
+            let audio = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 0, vec![0xAF, 0x01, 0x21]);
+            let mut flv = FLV_HEADER_WITH_TAG0.to_vec();
+            flv.extend_from_slice(&encode_tag(&video, 40).unwrap());
+            // 未知类型的tag被跳过
+            flv.extend_from_slice(&[0x0F, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0, 0, 0, 12]);
+            flv.extend_from_slice(&encode_tag(&audio, 0x01000010).unwrap());
+
+            let mut reader = &flv[..];
+            read_header(&mut reader).await.unwrap();
+            let msg = read_tag(&mut reader).await.unwrap().unwrap();
+            assert_eq!(msg.header.message_type, ChunkMessageType::VideoMessage);
+            assert_eq!((msg.header.timestamp, msg.header.msid), (40, 1));
+            assert_eq!(msg.body, video.body);
+            let msg = read_tag(&mut reader).await.unwrap().unwrap();
+            assert_eq!(msg.header.message_type, ChunkMessageType::AudioMessage);
+            assert_eq!(msg.header.timestamp, 0x01000010);
+            assert_eq!(msg.body, audio.body);
+            assert!(read_tag(&mut reader).await.unwrap().is_none());
+
+            assert!(read_header(&mut &b"RIFF\0\0\0\0\0\0\0\0\0"[..]).await.is_err());
+        });
+    }
+}
@@ -1,10 +1,15 @@
-use std::{u32, vec};
-use crate::rtmp_server::{eventbus_map, meta_data_map, video_header_map};
-use crate::util::spawn_and_log_error;
+use crate::rtmp_server::{meta_data_map, video_header_map};
 use smol::channel::Receiver;
-use crate::protocol::rtmp::RtmpMessage;
-use crate::protocol::h264::Nalu;
-use smol::io::AsyncWriteExt;
+use crate::protocol::rtmp::{RtmpMessage, RtmpMetaData};
+use crate::protocol::aac::AudioSpecificConfig;
+use crate::protocol::h264::{remove_emulation_prevention, Nalu};
+use crate::recording::{self, RecordingFile};
+use std::sync::Arc;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use once_cell::sync::OnceCell;
+use crate::protocol::rtmp::ChunkMessageType;
+use byteorder::{BigEndian, ByteOrder};
 
 /// fps = timescale / duration
 #[derive(Clone)]
@@ -15,14 +20,54 @@ pub struct Track {
     pub width: u16,
     pub height: u16,
     pub volume: u16,
-    pub dts: u32,
+    /// 平均码率，单位bps，0表示未知，用于btrt
+    pub bitrate: u32,
     pub pps_list: Vec<Vec<u8>>,
     pub sps_list: Vec<Vec<u8>>,
 }
 
 impl Track {
-    pub const DEFAULT_TIMESCALE: u32 = 1000_000;
+    pub const DEFAULT_TIMESCALE: u32 = 1_000_000;
     pub const DEFAULT_ID: u32 = 1;
+    /// onMetaData中没有帧率时使用
+    pub const DEFAULT_FRAME_RATE: f64 = 25.0;
+
+    /// 根据onMetaData和AVC sequence header生成视频轨道，文件和WebSocket输出共用
+    ///
+    /// timescale固定为`DEFAULT_TIMESCALE`，每帧时长由帧率计算，和onMetaData中的duration无关，直播流也可以播放
+    pub fn from_metadata(meta_data: &RtmpMetaData, video_header: &RtmpMessage) -> Self {
+        let mut sps_list = vec![];
+        let mut pps_list = vec![];
+        let pioneer_nalus = Nalu::from_rtmp_message(video_header);
+        let (width, height) = video_dimensions(meta_data, &pioneer_nalus);
+        for nalu in &pioneer_nalus {
+            // avcC中的SPS/PPS不带起始码
+            let bytes = nalu.payload().to_vec();
+            match nalu.get_nal_unit_type() {
+                Nalu::UNIT_TYPE_SPS => sps_list.push(bytes),
+                Nalu::UNIT_TYPE_PPS => pps_list.push(bytes),
+                _ => {}
+            }
+        }
+
+        let frame_rate = Some(meta_data.frame_rate)
+            .filter(|x| x.is_finite() && *x > 0.0)
+            .unwrap_or(Self::DEFAULT_FRAME_RATE);
+        Self {
+            duration: (Self::DEFAULT_TIMESCALE as f64 / frame_rate).round() as u32,
+            timescale: Self::DEFAULT_TIMESCALE,
+            width,
+            height,
+            // onMetaData中videodatarate的单位是kbps
+            bitrate: Some(meta_data.video_data_rate)
+                .filter(|x| x.is_finite() && *x > 0.0)
+                .map(|x| (x * 1000.0).min(u32::MAX as f64) as u32)
+                .unwrap_or(0),
+            sps_list,
+            pps_list,
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for Track {
@@ -34,7 +79,7 @@ impl Default for Track {
             width: 0,
             height: 0,
             volume: 0,
-            dts: 0,
+            bitrate: 0,
             pps_list: vec![],
             sps_list: vec![],
         }
@@ -82,36 +127,65 @@ pub struct Flags {
 
 impl Flags {
     pub fn as_byte(&self) -> u8 {
-        self.depands_on << 4 | self.is_depended_on << 2 | self.has_redundancy as u8
+        self.depands_on << 4 | self.is_depended_on << 2 | self.has_redundancy
     }
 
-    /// in trun box
+    /// in trun box，ISO/IEC 14496-12的sample_flags：
+    /// reserved(4) is_leading(2) depends_on(2) is_depended_on(2) has_redundancy(2) padding(3) is_non_sync(1) degradation_priority(16)
     pub fn as_four_byte(&self) -> [u8; 4] {
         [
             self.is_leading << 2 | self.depands_on,
-            self.is_depended_on << 6 | self.has_redundancy << 6 | self.padding_value << 1 | self.is_non_sync,
+            self.is_depended_on << 6 | self.has_redundancy << 4 | self.padding_value << 1 | self.is_non_sync,
             (self.degrad_prio >> 8) as u8,
             self.degrad_prio as u8,
         ]
     }
 }
 
+/// 还没有输出的帧，时长在下一帧到达或者输出时才能确定
+struct PendingSample {
+    /// 解码时间，单位为timescale
+    dts: u64,
+    sample: Sample,
+    data: Vec<u8>,
+}
+
 pub struct Fmp4Encoder {
     track: Track,
     sn: u32,
+    /// 分片的目标时长，0表示每帧一个分片
+    fragment_ms: u32,
+    /// 按解码时间排序
+    pending: Vec<PendingSample>,
+    /// 已经输出的采样的结束时间，下一个分片的解码时间不早于它
+    next_dts: u64,
+    /// 最近的RTMP时间戳和它展开成64位之后的毫秒数，处理32位回绕和乱序
+    last_timestamp: Option<(u32, i64)>,
+    /// 第一帧的毫秒数对应的解码时间
+    timeline_origin: Option<(i64, u64)>,
 }
 
 impl Fmp4Encoder {
     pub fn new(track: Track) -> Self {
+        Self::with_fragment_ms(track, 0)
+    }
+
+    /// 积累`fragment_ms`毫秒的帧之后才输出一个包含多个采样的分片，减少消息数量
+    pub fn with_fragment_ms(track: Track, fragment_ms: u32) -> Self {
         Self {
             track,
             sn: 0,
+            fragment_ms,
+            pending: vec![],
+            next_dts: 0,
+            last_timestamp: None,
+            timeline_origin: None,
         }
     }
 
     pub fn init_segment(&self) -> Vec<u8> {
         let mut ftyp = ftyp();
-        let mut movie = moov(&vec![self.track.clone()], Track::DEFAULT_TIMESCALE, self.track.timescale);
+        let mut movie = moov(std::slice::from_ref(&self.track), Track::DEFAULT_TIMESCALE, self.track.timescale);
         let total_len = ftyp.len() + movie.len();
 
         let mut buffer = Vec::with_capacity(total_len);
@@ -121,25 +195,204 @@ impl Fmp4Encoder {
         buffer
     }
 
+    /// 视频参数变化时切换轨道，先输出缓存的帧，再输出新的init segment
+    ///
+    /// 解码时间和分片序号继续递增，MSE可以在同一个SourceBuffer中继续播放
+    pub fn reset_track(&mut self, track: Track) -> Vec<Vec<u8>> {
+        let mut segments: Vec<Vec<u8>> = self.flush().into_iter().collect();
+        self.track = track;
+        segments.push(self.init_segment());
+        segments
+    }
+
+    /// 立即输出缓存的帧和当前帧，当前帧的时长为轨道的`duration`，不使用时间戳
     pub fn wrap_frame(&mut self, data: &[u8], key_frame: bool) -> Vec<u8> {
-        let sample = Sample::new(
-            data.len() as u32,
-            self.track.duration,
-            0,
-            key_frame,
-        );
+        let mut bytes = self.flush().unwrap_or_default();
+        let sample = Sample::new(data.len() as u32, 0, 0, key_frame);
+        self.pending.push(PendingSample { dts: self.next_dts, sample, data: data.to_vec() });
+        let end = self.next_dts + self.track.duration as u64;
+        bytes.extend(self.flush_until(end).unwrap_or_default());
+        bytes
+    }
 
-        let mut buffer = moof(self.sn, self.track.dts, &self.track, &vec![sample]);
-        buffer.append(&mut mdat(data));
+    /// 缓存一帧，返回已经完成的分片
+    ///
+    /// `timestamp`是RTMP时间戳，`composition_time`是PTS和DTS的差值，单位都是毫秒。
+    /// 每帧的时长是到下一帧的解码时间差，所以一帧要等下一帧到达之后才会输出。
+    /// 关键帧总是在分片的开头，缓存的时长达到`fragment_ms`时输出分片
+    pub fn push_frame(&mut self, data: &[u8], timestamp: u32, composition_time: u32, key_frame: bool) -> Vec<Vec<u8>> {
+        let dts = self.decode_time(timestamp);
+        let mut fragments = vec![];
+        if let Some(first) = self.pending.first() {
+            let pending_ms = (dts.saturating_sub(first.dts) * 1000).checked_div(self.track.timescale as u64).unwrap_or(u64::MAX);
+            if key_frame || pending_ms >= self.fragment_ms as u64 {
+                fragments.extend(self.flush_until(dts));
+            }
+        }
+        let cts = composition_time as u64 * self.track.timescale as u64 / 1000;
+        let sample = Sample::new(data.len() as u32, 0, cts as u32, key_frame);
+        // 时间戳乱序时按解码时间插入
+        let index = self.pending.partition_point(|x| x.dts <= dts);
+        self.pending.insert(index, PendingSample { dts, sample, data: data.to_vec() });
+        fragments
+    }
 
-        self.track.dts += self.track.duration;
+    /// 把缓存的帧输出成一个分片，最后一帧的时长为轨道的`duration`，没有缓存时返回None
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        let end = self.pending.last()?.dts + self.track.duration as u64;
+        self.flush_until(end)
+    }
+
+    /// 输出缓存的帧，`end`是最后一帧的结束时间
+    fn flush_until(&mut self, end: u64) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let base_media_decode_time = pending[0].dts;
+        let mut samples = Vec::with_capacity(pending.len());
+        let mut data = vec![];
+        for (i, x) in pending.iter().enumerate() {
+            let next_dts = pending.get(i + 1).map(|x| x.dts).unwrap_or(end).max(x.dts);
+            samples.push(Sample { duration: (next_dts - x.dts) as u32, ..x.sample.clone() });
+            data.extend_from_slice(&x.data);
+        }
+        let mut buffer = moof(self.sn, base_media_decode_time, &self.track, &samples);
+        buffer.append(&mut mdat(&data));
+
+        self.next_dts = end.max(pending[pending.len() - 1].dts);
         self.sn += 1;
 
+        Some(buffer)
+    }
+
+    /// RTMP时间戳转换成解码时间，第一帧接在已经输出的采样之后，早于已经输出的采样的帧使用`next_dts`
+    fn decode_time(&mut self, timestamp: u32) -> u64 {
+        let ms = match self.last_timestamp {
+            // 差值按有符号数处理，32位回绕之后继续递增，乱序的帧得到较小的值
+            Some((last, last_ms)) => last_ms + timestamp.wrapping_sub(last) as i32 as i64,
+            None => timestamp as i64,
+        };
+        if self.last_timestamp.map(|(_, last_ms)| ms >= last_ms).unwrap_or(true) {
+            self.last_timestamp = Some((timestamp, ms));
+        }
+        let (origin_ms, origin_dts) = *self.timeline_origin.get_or_insert((ms, self.next_dts));
+        let elapsed = (ms - origin_ms).max(0) as u64 * self.track.timescale as u64 / 1000;
+        (origin_dts + elapsed).max(self.next_dts)
+    }
+}
+
+/// 非分片MP4，用于录制
+///
+/// 采样数据直接写在mdat中，结束时再把带有完整采样表的moov追加到文件末尾，
+/// 所以只有正常结束的录制才能播放
+pub struct Mp4Writer {
+    track: Track,
+    table: SampleTable,
+    /// 每个采样的解码时间
+    dts_list: Vec<u64>,
+    mdat_len: u64,
+}
+
+impl Mp4Writer {
+    /// 90kHz，u32的时长可以表示13个小时以上
+    pub const TIMESCALE: u32 = 90_000;
+    /// moov和tkhd使用的时间单位（毫秒）
+    const MOVIE_TIMESCALE: u32 = 1000;
+    /// mdat使用64位长度，头部固定16字节
+    const MDAT_HEADER_LEN: u64 = 16;
+
+    pub fn new(track: Track) -> Self {
+        // 每帧时长换算到90kHz
+        let duration = track.duration as u64 * Self::TIMESCALE as u64 / track.timescale.max(1) as u64;
+        Self {
+            track: Track {
+                duration: u32::try_from(duration).unwrap_or(u32::MAX),
+                timescale: Self::TIMESCALE,
+                ..track
+            },
+            table: Default::default(),
+            dts_list: vec![],
+            mdat_len: 0,
+        }
+    }
+
+    /// 文件头，ftyp + mdat头部，mdat长度在finalize时回填
+    pub fn header(&mut self) -> Vec<u8> {
+        let mut buffer = ftyp();
+        self.table.chunk_offset = (buffer.len() as u64 + Self::MDAT_HEADER_LEN) as u32;
+        buffer.extend_from_slice(&1u32.to_be_bytes());
+        buffer.extend_from_slice(b"mdat");
+        buffer.extend_from_slice(&Self::MDAT_HEADER_LEN.to_be_bytes());
         buffer
     }
+
+    /// 记录一个已经写入mdat的采样
+    ///
+    /// `timestamp`和`composition_time`单位为毫秒
+    pub fn push_sample(&mut self, size: u32, timestamp: u32, composition_time: u32, key_frame: bool) {
+        let to_timescale = |ms: u32| ms as u64 * Self::TIMESCALE as u64 / 1000;
+        self.dts_list.push(to_timescale(timestamp));
+        self.table.sizes.push(size);
+        self.table.composition_offsets.push(to_timescale(composition_time) as u32);
+        if key_frame {
+            self.table.sync_samples.push(self.table.sizes.len() as u32);
+        }
+        self.mdat_len += size as u64;
+    }
+
+    /// 回填mdat长度的位置和内容
+    pub fn mdat_size_patch(&self) -> (u64, [u8; 8]) {
+        let position = self.table.chunk_offset as u64 - 8;
+        (position, (self.mdat_len + Self::MDAT_HEADER_LEN).to_be_bytes())
+    }
+
+    /// 生成带有采样表的moov，需要追加到文件末尾
+    pub fn finalize(&mut self) -> Vec<u8> {
+        // 采样时长取相邻两帧的时间差，最后一帧使用帧率计算的时长
+        let default_duration = self.track.duration;
+        self.table.durations = self
+            .dts_list
+            .windows(2)
+            .map(|x| u32::try_from(x[1].saturating_sub(x[0])).unwrap_or(default_duration))
+            .collect();
+        if !self.dts_list.is_empty() {
+            self.table.durations.push(default_duration);
+        }
+
+        let media_duration = self.table.total_duration();
+        let movie_duration = media_duration * Self::MOVIE_TIMESCALE as u64 / self.track.timescale as u64;
+        let movie_duration = u32::try_from(movie_duration).unwrap_or(u32::MAX);
+
+        let mvhd = mvhd(Self::MOVIE_TIMESCALE, movie_duration);
+        let trak = trak(&self.track, movie_duration, &self.table);
+        mp4_box(b"moov", vec![&mvhd, &trak])
+    }
+}
+
+/// 非分片MP4的采样表，分片MP4中为空
+#[derive(Default)]
+struct SampleTable {
+    sizes: Vec<u32>,
+    durations: Vec<u32>,
+    composition_offsets: Vec<u32>,
+    /// 关键帧的序号，从1开始
+    sync_samples: Vec<u32>,
+    /// 所有采样连续存放在一个chunk中
+    chunk_offset: u32,
+}
+
+impl SampleTable {
+    fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    fn total_duration(&self) -> u64 {
+        self.durations.iter().map(|x| *x as u64).sum()
+    }
 }
 
-fn moof(sn: u32, base_media_decode_time: u32, track: &Track, samples: &[Sample]) -> Vec<u8> {
+fn moof(sn: u32, base_media_decode_time: u64, track: &Track, samples: &[Sample]) -> Vec<u8> {
     mp4_box(b"moof", vec![&mfhd(sn), &traf(track, base_media_decode_time, samples)])
 }
 
@@ -173,7 +426,7 @@ fn mfhd(sn: u32) -> Vec<u8> {
     mp4_box(b"mfhd", vec![&bytes])
 }
 
-fn traf(track: &Track, base_media_decode_time: u32, samples: &[Sample]) -> Vec<u8> {
+fn traf(track: &Track, base_media_decode_time: u64, samples: &[Sample]) -> Vec<u8> {
     let sample_dependency_table = sdtp(samples);
     let id = track.id;
 
@@ -189,21 +442,16 @@ fn traf(track: &Track, base_media_decode_time: u32, samples: &[Sample]) -> Vec<u
         mp4_box(b"tfhd", vec![&bytes])
     };
 
+    // version 1，64位的baseMediaDecodeTime，timescale为1MHz时32位只能表示71分钟
     let tfdt = {
-        let bytes: [u8; 8] = [
-            0x00, // version 0
-            0x00, 0x00, 0x00, // flags
-            (base_media_decode_time >> 24) as u8,
-            (base_media_decode_time >> 16) as u8,
-            (base_media_decode_time >> 8) as u8,
-            (base_media_decode_time as u8), // baseMediaDecodeTime
-        ];
+        let mut bytes = vec![0x01, 0x00, 0x00, 0x00];
+        bytes.extend_from_slice(&base_media_decode_time.to_be_bytes());
         mp4_box(b"tfdt", vec![&bytes])
     };
 
     let trun = trun(track, sample_dependency_table.len() as u32 +
         16 + // tfhd
-        16 + // tfdt
+        20 + // tfdt
         8 +  // traf header
         16 + // mfhd
         8 +  // moof header
@@ -292,11 +540,11 @@ fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
     mp4_box(b"mvhd", vec![&bytes])
 }
 
-fn trak(track: &Track) -> Vec<u8> {
-    mp4_box(b"trak", vec![&tkhd(&track), &mdia(&track)])
+fn trak(track: &Track, duration: u32, table: &SampleTable) -> Vec<u8> {
+    mp4_box(b"trak", vec![&tkhd(track, duration), &mdia(track, table)])
 }
 
-fn tkhd(track: &Track) -> Vec<u8> {
+fn tkhd(track: &Track, duration: u32) -> Vec<u8> {
     let bytes = vec![
         0x00, // version 0
         0x00, 0x00, 0x07, // flags
@@ -307,15 +555,15 @@ fn tkhd(track: &Track) -> Vec<u8> {
         (track.id >> 8) as u8,
         track.id as u8, // track_ID
         0x00, 0x00, 0x00, 0x00, // reserved
-        (track.duration >> 24) as u8,
-        (track.duration >> 16) as u8,
-        (track.duration >> 8) as u8,
-        track.duration as u8, // duration
+        (duration >> 24) as u8,
+        (duration >> 16) as u8,
+        (duration >> 8) as u8,
+        duration as u8, // duration
         0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00, // reserved
         0x00, 0x00, // layer
         0x00, 0x00, // alternate_group
-        (track.volume >> 0) as u8, (((track.volume % 1) * 10) >> 0) as u8, // track volume
+        track.volume as u8, 0x00, // track volume
         0x00, 0x00, // reserved
         0x00, 0x01, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00,
@@ -336,11 +584,16 @@ fn tkhd(track: &Track) -> Vec<u8> {
     mp4_box(b"tkhd", vec![&bytes])
 }
 
-fn mdia(track: &Track) -> Vec<u8> {
-    mp4_box(b"mdia", vec![&mdhd(track.timescale, track.duration), &hdlr(), &minf(track)])
+fn mdia(track: &Track, table: &SampleTable) -> Vec<u8> {
+    let duration = if table.is_empty() {
+        track.duration
+    } else {
+        u32::try_from(table.total_duration()).unwrap_or(u32::MAX)
+    };
+    mp4_box(b"mdia", vec![&mdhd(track.timescale, duration), &hdlr(), &minf(track, table)])
 }
 
-fn minf(track: &Track) -> Vec<u8> {
+fn minf(track: &Track, table: &SampleTable) -> Vec<u8> {
     const VMHD: [u8; 12] = [
         0x00, // version
         0x00, 0x00, 0x01, // flags
@@ -359,7 +612,7 @@ fn minf(track: &Track) -> Vec<u8> {
         0x00, 0x00, 0x01, // entry_flags
     ];
     let dinf = mp4_box(b"dinf", vec![&mp4_box(b"dref", vec![&DREF])]);
-    mp4_box(b"minf", vec![&mp4_box(b"vmhd", vec![&VMHD]), &dinf, &stbl(&track)])
+    mp4_box(b"minf", vec![&mp4_box(b"vmhd", vec![&VMHD]), &dinf, &stbl(track, table)])
 }
 
 fn mdhd(timescale: u32, duration: u32) -> Vec<u8> {
@@ -398,28 +651,104 @@ fn hdlr() -> Vec<u8> {
     mp4_box(b"hdlr", vec![&VIDEO_HDLR])
 }
 
-fn stbl(track: &Track) -> Vec<u8> {
-    const STCO: [u8; 8] = [
-        0x00, // version
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x00, // entry_count
-    ];
-    const STTS: [u8; 8] = STCO;
-    const STSC: [u8; 8] = STCO;
-    const STSZ: [u8; 12] = [
-        0x00, // version
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x00, // sample_size
-        0x00, 0x00, 0x00, 0x00, // sample_count
-    ];
+fn stbl(track: &Track, table: &SampleTable) -> Vec<u8> {
+    let stsd = stsd(track);
+    let stts = stts(table);
+    let stsc = stsc(table);
+    let stsz = stsz(table);
+    let stco = stco(table);
+    let mut payloads: Vec<&[u8]> = vec![&stsd, &stts, &stsc, &stsz, &stco];
+
+    // 分片MP4没有采样，不需要stss和ctts
+    let stss = stss(table);
+    let ctts = ctts(table);
+    if !table.is_empty() {
+        payloads.push(&stss);
+        if table.composition_offsets.iter().any(|x| *x != 0) {
+            payloads.push(&ctts);
+        }
+    }
+    mp4_box(b"stbl", payloads)
+}
+
+/// 采样表的full box头部（version 0, flags 0）
+const FULL_BOX_HEADER: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+
+/// decoding time to sample，相同时长的连续采样合并成一项
+fn stts(table: &SampleTable) -> Vec<u8> {
+    let mut entries: Vec<(u32, u32)> = vec![];
+    for duration in &table.durations {
+        match entries.last_mut() {
+            Some((count, last)) if last == duration => *count += 1,
+            _ => entries.push((1, *duration)),
+        }
+    }
 
-    mp4_box(b"stbl", vec![
-        &stsd(track),
-        &mp4_box(b"stts", vec![&STTS]),
-        &mp4_box(b"stsc", vec![&STSC]),
-        &mp4_box(b"stsz", vec![&STSZ]),
-        &mp4_box(b"stco", vec![&STCO])
-    ])
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    buffer.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for (count, duration) in entries {
+        buffer.extend_from_slice(&count.to_be_bytes());
+        buffer.extend_from_slice(&duration.to_be_bytes());
+    }
+    mp4_box(b"stts", vec![&buffer])
+}
+
+/// sample to chunk，所有采样都在同一个chunk中
+fn stsc(table: &SampleTable) -> Vec<u8> {
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    if table.is_empty() {
+        buffer.extend_from_slice(&0u32.to_be_bytes()); // entry_count
+    } else {
+        buffer.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        buffer.extend_from_slice(&1u32.to_be_bytes()); // first_chunk
+        buffer.extend_from_slice(&(table.sizes.len() as u32).to_be_bytes()); // samples_per_chunk
+        buffer.extend_from_slice(&1u32.to_be_bytes()); // sample_description_index
+    }
+    mp4_box(b"stsc", vec![&buffer])
+}
+
+/// sample size
+fn stsz(table: &SampleTable) -> Vec<u8> {
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    buffer.extend_from_slice(&0u32.to_be_bytes()); // sample_size
+    buffer.extend_from_slice(&(table.sizes.len() as u32).to_be_bytes());
+    for size in &table.sizes {
+        buffer.extend_from_slice(&size.to_be_bytes());
+    }
+    mp4_box(b"stsz", vec![&buffer])
+}
+
+/// chunk offset
+fn stco(table: &SampleTable) -> Vec<u8> {
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    if table.is_empty() {
+        buffer.extend_from_slice(&0u32.to_be_bytes()); // entry_count
+    } else {
+        buffer.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        buffer.extend_from_slice(&table.chunk_offset.to_be_bytes());
+    }
+    mp4_box(b"stco", vec![&buffer])
+}
+
+/// sync sample，关键帧列表
+fn stss(table: &SampleTable) -> Vec<u8> {
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    buffer.extend_from_slice(&(table.sync_samples.len() as u32).to_be_bytes());
+    for index in &table.sync_samples {
+        buffer.extend_from_slice(&index.to_be_bytes());
+    }
+    mp4_box(b"stss", vec![&buffer])
+}
+
+/// composition time to sample
+fn ctts(table: &SampleTable) -> Vec<u8> {
+    let mut buffer = FULL_BOX_HEADER.to_vec();
+    buffer.extend_from_slice(&(table.composition_offsets.len() as u32).to_be_bytes());
+    for offset in &table.composition_offsets {
+        buffer.extend_from_slice(&1u32.to_be_bytes());
+        buffer.extend_from_slice(&offset.to_be_bytes());
+    }
+    mp4_box(b"ctts", vec![&buffer])
 }
 
 fn stsd(track: &Track) -> Vec<u8> {
@@ -438,19 +767,19 @@ fn avc1(track: &Track) -> Vec<u8> {
     for item in &track.sps_list {
         let length = item.len() as u16;
         sps.extend_from_slice(&length.to_be_bytes());
-        sps.extend_from_slice(&item);
+        sps.extend_from_slice(item);
     }
 
     for item in &track.pps_list {
         let length = item.len() as u16;
         pps.extend_from_slice(&length.to_be_bytes());
-        pps.extend_from_slice(&item);
+        pps.extend_from_slice(item);
     }
 
     let width = track.width;
     let height = track.height;
 
-    let bytes = vec![
+    let mut bytes = vec![
         0x00, 0x00, 0x00, // reserved
         0x00, 0x00, 0x00, // reserved
         0x00, 0x01, // data_reference_index
@@ -467,29 +796,50 @@ fn avc1(track: &Track) -> Vec<u8> {
         0x00, 0x48, 0x00, 0x00, // vertresolution
         0x00, 0x00, 0x00, 0x00, // reserved
         0x00, 0x01, // frame_count
-        0x12,
-        0x62, 0x69, 0x6E, 0x65, // binelpro.ru
-        0x6C, 0x70, 0x72, 0x6F,
-        0x2E, 0x72, 0x75, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, // compressorname
-        0x00, 0x18,   // depth = 24
-        0xFF, 0xFF
     ];
+    bytes.extend_from_slice(&compressor_name(COMPRESSOR_NAME));
+    bytes.extend_from_slice(&[
+        0x00, 0x18,   // depth = 24
+        0xFF, 0xFF,   // pre_defined = -1
+    ]);
+
+    let avcc = avcc(track, &sps, &pps);
+    let btrt = btrt(track.bitrate);
+    let mut payloads: Vec<&[u8]> = vec![&bytes, &avcc];
+    if let Some(btrt) = &btrt {
+        payloads.push(btrt);
+    }
+    mp4_box(b"avc1", payloads)
+}
 
-    mp4_box(b"avc1", vec![&bytes, &avcc(track, &sps, &pps), &btrt()])
+/// avc1中的编码器名称
+const COMPRESSOR_NAME: &str = "AVC Coding";
+
+/// 32字节，1字节长度 + 名称，不足的部分补0，超过31字节时截断
+fn compressor_name(name: &str) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    let len = name.len().min(31);
+    bytes[0] = len as u8;
+    bytes[1..=len].copy_from_slice(&name.as_bytes()[..len]);
+    bytes
 }
 
 /// AVCConfigurationBox
+///
+/// `sps`和`pps`为带2字节长度前缀的NALU列表，profile/compat/level从第一个SPS去掉防竞争字节后读取
 fn avcc(track: &Track, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let (profile, compat, level) = track
+        .sps_list
+        .first()
+        .map(|x| remove_emulation_prevention(x))
+        .filter(|x| x.len() >= 4)
+        .map(|x| (x[1], x[2], x[3]))
+        .unwrap_or_default();
     let mut bytes = vec![
         0x01, // version
-        sps[3], // profile
-        sps[4], // profile compat
-        sps[5], // level
+        profile, // profile
+        compat, // profile compat
+        level, // level
         0xFC | 3, // lengthSizeMinusOne, hard-coded to 4 bytes
         0xE0 | track.sps_list.len() as u8, // 3bit reserved (111) + numOfSequenceParameterSets
     ];
@@ -500,18 +850,84 @@ fn avcc(track: &Track, sps: &[u8], pps: &[u8]) -> Vec<u8> {
     mp4_box(b"avcC", vec![&bytes])
 }
 
-fn btrt() -> Vec<u8> {
-    const BTRT: [u8; 12] = [
-        0x00, 0x1c, 0x9c, 0x80, // bufferSizeDB
-        0x00, 0x2d, 0xc6, 0xc0, // maxBitrate
-        0x00, 0x2d, 0xc6, 0xc0
+/// BitRateBox，码率未知时不输出，按1秒的数据量估计解码缓冲区
+fn btrt(bitrate: u32) -> Option<Vec<u8>> {
+    if bitrate == 0 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(12);
+    bytes.extend_from_slice(&(bitrate / 8).to_be_bytes()); // bufferSizeDB
+    bytes.extend_from_slice(&bitrate.to_be_bytes()); // maxBitrate
+    bytes.extend_from_slice(&bitrate.to_be_bytes()); // avgBitrate
+    Some(mp4_box(b"btrt", vec![&bytes]))
+}
+
+/// AAC的sample entry，`asc`是AAC sequence header中`0xAF 0x00`之后的AudioSpecificConfig
+///
+/// 声道数和采样率从`asc`中读取，`asc`原样写入esds的DecoderSpecificInfo。目前fMP4只有视频轨道，加入音频轨道时使用
+pub fn mp4a(asc: &[u8]) -> Vec<u8> {
+    let config = AudioSpecificConfig::from_bytes(asc);
+    let channel_count = config.map(|x| x.channel_configuration as u16).filter(|x| *x > 0).unwrap_or(2);
+    let sample_rate = config.map(|x| x.sampling_frequency()).unwrap_or(44100);
+    let mut bytes = vec![
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reserved
+        0x00, 0x01, // data_reference_index
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, // reserved
+    ];
+    bytes.extend_from_slice(&channel_count.to_be_bytes());
+    bytes.extend_from_slice(&[
+        0x00, 0x10, // samplesize = 16
+        0x00, 0x00, // pre_defined
+        0x00, 0x00, // reserved
+    ]);
+    // 16.16定点数，超过65535Hz时只能写0
+    let sample_rate = if sample_rate > 0xFFFF { 0 } else { sample_rate << 16 };
+    bytes.extend_from_slice(&sample_rate.to_be_bytes());
+
+    mp4_box(b"mp4a", vec![&bytes, &esds(asc)])
+}
+
+/// ElementaryStreamDescriptorBox，MPEG-4 Audio的DecoderSpecificInfo就是AudioSpecificConfig
+fn esds(asc: &[u8]) -> Vec<u8> {
+    let decoder_specific_info = descriptor(0x05, asc);
+    let mut decoder_config = vec![
+        0x40, // objectTypeIndication = MPEG-4 Audio
+        0x15, // streamType = AudioStream(5) << 2 | upStream(0) << 1 | reserved(1)
+        0x00, 0x00, 0x00, // bufferSizeDB
+        0x00, 0x00, 0x00, 0x00, // maxBitrate
+        0x00, 0x00, 0x00, 0x00, // avgBitrate
+    ];
+    decoder_config.extend_from_slice(&decoder_specific_info);
+    let mut es = vec![
+        0x00, 0x00, // ES_ID
+        0x00, // flags
+    ];
+    es.extend_from_slice(&descriptor(0x04, &decoder_config));
+    // SLConfigDescriptor，predefined = 2
+    es.extend_from_slice(&descriptor(0x06, &[0x02]));
+
+    let version_and_flags = [0x00, 0x00, 0x00, 0x00];
+    mp4_box(b"esds", vec![&version_and_flags, &descriptor(0x03, &es)])
+}
+
+/// MPEG-4描述符，长度使用4字节的可扩展格式，和ffmpeg一致
+fn descriptor(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len() as u32;
+    let mut bytes = vec![
+        tag,
+        0x80 | (len >> 21 & 0x7F) as u8,
+        0x80 | (len >> 14 & 0x7F) as u8,
+        0x80 | (len >> 7 & 0x7F) as u8,
+        (len & 0x7F) as u8,
     ];
-    mp4_box(b"btrt", vec![&BTRT])
+    bytes.extend_from_slice(payload);
+    bytes
 }
 
 /// movie extend
 fn mvex(tracks: &[Track]) -> Vec<u8> {
-    let boxes = tracks.into_iter().map(|t| trex(t)).collect::<Vec<Vec<u8>>>();
+    let boxes = tracks.iter().map(trex).collect::<Vec<Vec<u8>>>();
     mp4_box(b"mvex", boxes.iter().map(AsRef::as_ref).collect())
 }
 
@@ -533,9 +949,10 @@ fn trex(track: &Track) -> Vec<u8> {
 
 /// movie box
 fn moov(tracks: &[Track], duration: u32, timescale: u32) -> Vec<u8> {
-    let boxes = tracks.iter().map(|t| trak(t)).collect::<Vec<Vec<u8>>>();
+    let empty_table = SampleTable::default();
+    let boxes = tracks.iter().map(|x| trak(x, x.duration, &empty_table)).collect::<Vec<Vec<u8>>>();
     let mvhd = mvhd(timescale, duration);
-    let mvex = mvex(&tracks);
+    let mvex = mvex(tracks);
 
     let mut payloads: Vec<&[u8]> = vec![];
     payloads.push(&mvhd);
@@ -545,89 +962,467 @@ fn moov(tracks: &[Track], duration: u32, timescale: u32) -> Vec<u8> {
     mp4_box(b"moov", payloads)
 }
 
-/// 后台保存FLV文件
-#[allow(unused)]
-pub fn save_fmp4_background(stream_name: &str, peer_addr: String) {
-    if let Some(eventbus) = eventbus_map().get(stream_name) {
-        log::warn!("[peer={}] save_fmp4_background, stream_name={}", peer_addr, stream_name);
-        let rx = eventbus.register_receiver();
-        spawn_and_log_error(handle_fmp4_rx(rx, stream_name.to_owned(), peer_addr));
+/// 视频宽高，onMetaData中没有宽高时从SPS解析
+pub fn video_dimensions(meta_data: &RtmpMetaData, nalus: &[Nalu]) -> (u16, u16) {
+    if meta_data.width > 0.0 && meta_data.height > 0.0 {
+        return (meta_data.width as _, meta_data.height as _);
+    }
+    match nalus.iter().find_map(Nalu::sps_dimensions) {
+        Some((width, height)) => {
+            log::info!("width/height not found in meta_data, use SPS instead, {}x{}", width, height);
+            (width as _, height as _)
+        }
+        None => {
+            log::warn!("width/height not found in meta_data or SPS");
+            (0, 0)
+        }
     }
 }
 
-/// Rtmp流输出到mp4文件
-async fn handle_fmp4_rx(
-    rx: Receiver<RtmpMessage>,
-    stream_name: String,
-    peer_addr: String,
+/// Rtmp流输出到mp4文件，由`recording::start`调用
+///
+/// 推流者中途发送了不同的AVC sequence header（例如分辨率变化）时，当前文件结束，
+/// 之后的内容写入新的文件，见`recording::part_path`
+pub(crate) async fn write_fmp4(
+    rx: Receiver<Arc<RtmpMessage>>,
+    path: &str,
+    stream_name: &str,
+    peer_addr: &str,
 ) -> anyhow::Result<()> {
-    let tmp_dir = "tmp";
-    if smol::fs::read_dir(tmp_dir).await.is_err() {
-        smol::fs::create_dir_all(tmp_dir).await?;
-    }
-
-    let mut file = smol::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open("tmp/output.mp4")
-        .await?;
-
-    let meta_data = meta_data_map()
-        .get(&stream_name)
+    let mut video_header = video_header_map()
+        .get(stream_name)
         .map(|it| it.value().clone())
         .ok_or_else(|| anyhow::anyhow!(format!("not found meta_data, stream={}", stream_name)))?;
 
-    let video_header = video_header_map()
-        .get(&stream_name)
-        .map(|it| it.value().clone())
-        .ok_or_else(|| anyhow::anyhow!(format!("not found meta_data, stream={}", stream_name)))?;
-
-    let mut sps_list = vec![];
-    let mut pps_list = vec![];
-    let pioneer_nalus = Nalu::from_rtmp_message(&video_header);
-    for nalu in pioneer_nalus {
-        let bytes = (&nalu.to_avcc_format()[4..]).to_vec();
-        match nalu.get_nal_unit_type() {
-            Nalu::UNIT_TYPE_SPS => sps_list.push(bytes),
-            Nalu::UNIT_TYPE_PPS => pps_list.push(bytes),
-            _ => {}
+    let mut part = 0;
+    loop {
+        let part_path = recording::part_path(path, part);
+        let file = RecordingFile::create(&part_path).await?;
+
+        let meta_data = meta_data_map()
+            .get(stream_name)
+            .map(|it| it.value().clone())
+            .ok_or_else(|| anyhow::anyhow!(format!("not found meta_data, stream={}", stream_name)))?;
+        let track = Track::from_metadata(&meta_data, &video_header);
+        log::info!("[peer={}], sps={:?}, pps={:?}", peer_addr, track.sps_list, track.pps_list);
+        let next_header = if recording_config().finalize {
+            write_finalized_mp4(&rx, file, track, &video_header).await?
+        } else {
+            write_fragmented_mp4(&rx, file, track, &video_header).await?
+        };
+        match next_header {
+            Some(header) => {
+                part += 1;
+                log::warn!(
+                    "[peer={}][write_fmp4] video header changed, continue in {}, stream_name={}",
+                    peer_addr,
+                    recording::part_path(path, part),
+                    stream_name
+                );
+                video_header = header;
+            }
+            None => break,
         }
     }
 
-    log::info!("[peer={}], sps={:?}, pps={:?}", peer_addr, sps_list, pps_list);
-    let mut fmp4_encoder = Fmp4Encoder::new(Track {
-        duration: (Track::DEFAULT_TIMESCALE as f64 / meta_data.frame_rate) as _,
-        timescale: Track::DEFAULT_TIMESCALE,
-        width: meta_data.width as _,
-        height: meta_data.height as _,
-        sps_list,
-        pps_list,
-        ..Default::default()
-    });
+    log::warn!("[peer={}][write_fmp4] closed, stream_name={}", peer_addr, stream_name);
+    Ok(())
+}
+
+/// 和当前不同的AVC sequence header，之前的轨道参数不能再解码之后的帧
+pub(crate) fn is_changed_video_header(msg: &RtmpMessage, current: &RtmpMessage) -> bool {
+    msg.header.message_type == ChunkMessageType::VideoMessage && msg.is_sequence_header() && msg.body != current.body
+}
+
+/// 录制分片MP4，返回新的video header表示需要切换到新的文件，None表示录制结束
+async fn write_fragmented_mp4(
+    rx: &Receiver<Arc<RtmpMessage>>,
+    mut file: RecordingFile,
+    track: Track,
+    video_header: &RtmpMessage,
+) -> anyhow::Result<Option<RtmpMessage>> {
+    let mut fmp4_encoder = Fmp4Encoder::new(track);
 
     // send video header
     let header = fmp4_encoder.init_segment();
     file.write_all(&header).await?;
 
-    let mut found_key_frame = false;
+    let mut next_header = None;
     while let Ok(msg) = rx.recv().await {
-        let nalus = Nalu::from_rtmp_message(&msg);
-        for nalu in nalus {
-            if !found_key_frame {
-                if nalu.is_key_frame {
-                    found_key_frame = true;
-                } else {
-                    continue;
-                }
-            }
-
-            let bytes = fmp4_encoder.wrap_frame(&nalu.to_avcc_format(), nalu.is_key_frame);
+        if is_changed_video_header(&msg, video_header) {
+            next_header = Some(msg.as_ref().clone());
+            break;
+        }
+        // sps/pps已经写在init segment中
+        if msg.is_sequence_header() {
+            continue;
+        }
+        let data: Vec<u8> = msg.nalus().iter().flat_map(|x| x.to_avcc_format()).collect();
+        if data.is_empty() {
+            continue;
+        }
+        for bytes in fmp4_encoder.push_frame(&data, msg.header.timestamp, msg.composition_time(), msg.is_video_key_frame()) {
             file.write_all(&bytes).await?;
         }
-        file.flush().await?
     }
+    // 最后一帧没有下一帧，时长按帧率估算
+    if let Some(bytes) = fmp4_encoder.flush() {
+        file.write_all(&bytes).await?;
+    }
+    Ok(next_header)
+}
 
-    log::warn!("[peer={}][handle_fmp4_rx] closed, stream_name={}", peer_addr, stream_name);
-    Ok(())
+/// 录制非分片MP4，结束时写入moov并回填mdat长度，返回值和`write_fragmented_mp4`相同
+async fn write_finalized_mp4(
+    rx: &Receiver<Arc<RtmpMessage>>,
+    mut file: RecordingFile,
+    track: Track,
+    video_header: &RtmpMessage,
+) -> anyhow::Result<Option<RtmpMessage>> {
+    let mut writer = Mp4Writer::new(track);
+    file.write_all(&writer.header()).await?;
+
+    let mut next_header = None;
+    while let Ok(msg) = rx.recv().await {
+        if is_changed_video_header(&msg, video_header) {
+            next_header = Some(msg.as_ref().clone());
+            break;
+        }
+        // 只记录AVC NALU，body[5..]已经是avcc格式
+        if msg.header.message_type != ChunkMessageType::VideoMessage || msg.body.len() <= 5 || msg.body[1] != 1 {
+            continue;
+        }
+        let key_frame = msg.body[0] >> 4 == 1;
+
+        let data = &msg.body[5..];
+        file.write_all(data).await?;
+        writer.push_sample(data.len() as u32, msg.header.timestamp, msg.composition_time(), key_frame);
+    }
+
+    file.write_all(&writer.finalize()).await?;
+    let (position, mdat_size) = writer.mdat_size_patch();
+    file.seek(SeekFrom::Start(position)).await?;
+    file.write_all(&mdat_size).await?;
+    Ok(next_header)
+}
+
+/// 录制相关的配置
+#[derive(Debug, Clone, Default)]
+pub struct RecordingConfig {
+    /// 录制结束时整理成带有完整采样表的非分片MP4
+    pub finalize: bool,
+}
+
+static RECORDING_CONFIG: OnceCell<RecordingConfig> = OnceCell::new();
+
+/// 启动时设置录制配置，只能设置一次
+pub fn init_recording_config(config: RecordingConfig) {
+    if RECORDING_CONFIG.set(config).is_err() {
+        log::warn!("recording config has been initialized");
+    }
+}
+
+pub fn recording_config() -> &'static RecordingConfig {
+    RECORDING_CONFIG.get_or_init(Default::default)
+}
+
+/// 按路径查找子box，返回子box的内容（不含box头部），VOD按时间定位时解析录制文件
+fn find_box<'a>(mut data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (box_type, rest) = path.split_first()?;
+    while data.len() >= 8 {
+        let size = BigEndian::read_u32(&data[..4]) as usize;
+        if size < 8 || size > data.len() {
+            return None;
+        }
+        if &data[4..8] == *box_type {
+            let payload = &data[8..size];
+            return if rest.is_empty() { Some(payload) } else { find_box(payload, rest) };
+        }
+        data = &data[size..];
+    }
+    None
+}
+
+/// moov中视频轨道mdhd的timescale
+pub(crate) fn media_timescale(moov: &[u8]) -> Option<u32> {
+    let mdhd = find_box(moov, &[b"trak", b"mdia", b"mdhd"])?;
+    // version 1的creation_time和modification_time为64位
+    let offset = if *mdhd.first()? == 1 { 20 } else { 12 };
+    mdhd.get(offset..offset + 4).map(BigEndian::read_u32)
+}
+
+/// moof中第一个采样的解码时间（单位为timescale）和是否是关键帧
+pub(crate) fn fragment_start(moof: &[u8]) -> Option<(u64, bool)> {
+    let tfdt = find_box(moof, &[b"traf", b"tfdt"])?;
+    let dts = match *tfdt.first()? {
+        1 => BigEndian::read_u64(tfdt.get(4..12)?),
+        _ => BigEndian::read_u32(tfdt.get(4..8)?) as u64,
+    };
+    let trun = find_box(moof, &[b"traf", b"trun"])?;
+    let flags = BigEndian::read_u24(trun.get(1..4)?);
+    // version/flags和sample_count之后依次为可选的data_offset、first_sample_flags，然后是第一个采样
+    let mut offset = 8 + if flags & 0x01 != 0 { 4 } else { 0 };
+    if flags & 0x04 == 0 {
+        offset += if flags & 0x100 != 0 { 4 } else { 0 } + if flags & 0x200 != 0 { 4 } else { 0 };
+        if flags & 0x400 == 0 {
+            // 没有采样标志，无法判断，按关键帧处理
+            return Some((dts, true));
+        }
+    }
+    // sample_flags中的sample_is_non_sync_sample
+    let is_non_sync = trun.get(offset + 1)? & 0x01 != 0;
+    Some((dts, !is_non_sync))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// x264 1280x720 High@3.1，SPS中包含防竞争字节
+    const SPS: [u8; 26] = [
+        0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00,
+        0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+    ];
+    const PPS: [u8; 6] = [0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+
+    #[test]
+    fn avcc_matches_ffmpeg() {
+        let track = Track {
+            sps_list: vec![SPS.to_vec()],
+            pps_list: vec![PPS.to_vec()],
+            ..Default::default()
+        };
+        let mut sps = (SPS.len() as u16).to_be_bytes().to_vec();
+        sps.extend_from_slice(&SPS);
+        let mut pps = (PPS.len() as u16).to_be_bytes().to_vec();
+        pps.extend_from_slice(&PPS);
+
+        // ffmpeg -i in.flv -c copy out.mp4 生成的avcC
+        let mut expected = vec![0x00, 0x00, 0x00, 0x33, b'a', b'v', b'c', b'C'];
+        expected.extend_from_slice(&[0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x1A]);
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[0x01, 0x00, 0x06]);
+        expected.extend_from_slice(&PPS);
+
+        assert_eq!(avcc(&track, &sps, &pps), expected);
+    }
+
+    #[test]
+    fn avc1_box_layout() {
+        let track = Track {
+            width: 1280,
+            height: 720,
+            sps_list: vec![SPS.to_vec()],
+            pps_list: vec![PPS.to_vec()],
+            ..Default::default()
+        };
+        let avcc_len = 8 + 6 + 2 + SPS.len() + 1 + 2 + PPS.len();
+        // VisualSampleEntry固定为78字节
+        let bytes = avc1(&track);
+        assert_eq!(bytes.len(), 8 + 78 + avcc_len);
+        assert_eq!(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize, bytes.len());
+        assert_eq!(&bytes[4..8], b"avc1");
+        let name = &bytes[8 + 42..8 + 74];
+        assert_eq!(name[0] as usize, COMPRESSOR_NAME.len());
+        assert_eq!(&name[1..=COMPRESSOR_NAME.len()], COMPRESSOR_NAME.as_bytes());
+        assert!(name[COMPRESSOR_NAME.len() + 1..].iter().all(|x| *x == 0));
+        assert_eq!(&bytes[8 + 74..8 + 78], &[0x00, 0x18, 0xFF, 0xFF]);
+
+        // 2500kbps
+        let bytes = avc1(&Track { bitrate: 2_500_000, ..track });
+        assert_eq!(bytes.len(), 8 + 78 + avcc_len + 20);
+        let btrt = &bytes[bytes.len() - 20..];
+        assert_eq!(&btrt[4..8], b"btrt");
+        assert_eq!(&btrt[8..12], &312_500u32.to_be_bytes());
+        assert_eq!(&btrt[12..16], &2_500_000u32.to_be_bytes());
+        assert_eq!(&btrt[16..20], &2_500_000u32.to_be_bytes());
+    }
+
+    #[test]
+    fn mp4a_carries_audio_specific_config() {
+        // AAC-LC 48kHz 双声道
+        let asc = [0x11, 0x90];
+        let bytes = mp4a(&asc);
+        assert_eq!(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize, bytes.len());
+        assert_eq!(&bytes[4..8], b"mp4a");
+        assert_eq!(&bytes[8 + 16..8 + 18], &[0x00, 0x02]); // channelcount
+        assert_eq!(&bytes[8 + 24..8 + 28], &[0xBB, 0x80, 0x00, 0x00]); // 48000 << 16
+
+        let esds = &bytes[8 + 28..];
+        assert_eq!(u32::from_be_bytes([esds[0], esds[1], esds[2], esds[3]]) as usize, esds.len());
+        assert_eq!(&esds[4..8], b"esds");
+        // ES_Descriptor(3 + DecoderConfigDescriptor(13 + DecoderSpecificInfo) + SLConfigDescriptor)
+        assert_eq!(esds.len(), 12 + 5 + 3 + 5 + 13 + 5 + asc.len() + 5 + 1);
+        let dsi = &esds[12 + 5 + 3 + 5 + 13..];
+        assert_eq!(&dsi[..5], &[0x05, 0x80, 0x80, 0x80, 0x02]);
+        assert_eq!(&dsi[5..7], &asc);
+        assert_eq!(&esds[esds.len() - 6..], &[0x06, 0x80, 0x80, 0x80, 0x01, 0x02]);
+        assert_eq!(esds[12 + 5 + 3 + 5], 0x40);
+    }
+
+    #[test]
+    fn avcc_profile_skips_emulation_prevention() {
+        // profile_idc=0x00, constraint=0x00之后紧跟防竞争字节
+        let sps = vec![0x67, 0x00, 0x00, 0x03, 0x01, 0xAC];
+        let track = Track {
+            sps_list: vec![sps],
+            ..Default::default()
+        };
+        let bytes = avcc(&track, &[], &[]);
+        assert_eq!(&bytes[8..12], &[0x01, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn fragment_groups_frames_until_duration_or_key_frame() {
+        // 25fps，每帧40ms
+        let track = Track { duration: 1000, timescale: 25000, ..Default::default() };
+        let mut encoder = Fmp4Encoder::with_fragment_ms(track, 200);
+
+        assert!(encoder.push_frame(&[0x65; 10], 0, 0, true).is_empty());
+        for i in 1..5 {
+            assert!(encoder.push_frame(&[0x41; 10], i * 40, 0, false).is_empty());
+        }
+        // 第6帧到达时缓存达到200ms
+        let fragments = encoder.push_frame(&[0x41; 10], 200, 0, false);
+        assert_eq!(fragments.len(), 1);
+
+        // trun中有5个采样，data_offset指向mdat的数据
+        let fragment = &fragments[0];
+        let moof_len = u32::from_be_bytes([fragment[0], fragment[1], fragment[2], fragment[3]]) as usize;
+        let trun = fragment.windows(4).position(|x| x == b"trun").unwrap();
+        assert_eq!(&fragment[trun + 8..trun + 12], &[0, 0, 0, 5]);
+        assert_eq!(&fragment[trun + 12..trun + 16], &((moof_len + 8) as u32).to_be_bytes());
+        assert_eq!(fragment.len(), moof_len + 8 + 50);
+
+        // 关键帧之前的帧单独输出
+        encoder.push_frame(&[0x41; 10], 240, 0, false);
+        assert_eq!(encoder.push_frame(&[0x65; 10], 280, 0, true).len(), 1);
+        assert!(encoder.flush().is_some());
+        assert!(encoder.flush().is_none());
+    }
+
+    /// 分片中每个采样的(duration, cts)和tfdt
+    fn parse_fragment(fragment: &[u8]) -> (u64, Vec<(u32, u32)>) {
+        let tfdt = fragment.windows(4).position(|x| x == b"tfdt").unwrap();
+        assert_eq!(fragment[tfdt + 4], 1);
+        let base_media_decode_time = u64::from_be_bytes(fragment[tfdt + 8..tfdt + 16].try_into().unwrap());
+        let trun = fragment.windows(4).position(|x| x == b"trun").unwrap();
+        let count = u32::from_be_bytes(fragment[trun + 8..trun + 12].try_into().unwrap()) as usize;
+        let samples = (0..count)
+            .map(|i| {
+                let entry = &fragment[trun + 16 + i * 16..];
+                (u32::from_be_bytes(entry[..4].try_into().unwrap()), u32::from_be_bytes(entry[12..16].try_into().unwrap()))
+            })
+            .collect();
+        (base_media_decode_time, samples)
+    }
+
+    #[test]
+    fn sample_durations_follow_timestamps() {
+        // 帧率按25fps估算，实际时间戳间隔不均匀
+        let track = Track { duration: 40, timescale: 1000, ..Default::default() };
+        let mut encoder = Fmp4Encoder::with_fragment_ms(track, 1000);
+        // 推流中途加入，第一帧的解码时间为0
+        encoder.push_frame(&[0x65; 10], 5000, 80, true);
+        encoder.push_frame(&[0x41; 10], 5033, 0, false);
+        // 乱序的帧按时间戳插入
+        encoder.push_frame(&[0x41; 10], 5100, 0, false);
+        encoder.push_frame(&[0x41; 10], 5066, 40, false);
+        let fragments = encoder.push_frame(&[0x65; 10], 5150, 0, true);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(parse_fragment(&fragments[0]), (0, vec![(33, 80), (33, 0), (34, 40), (50, 0)]));
+
+        // 最后一帧没有下一帧，时长使用轨道的duration，之后的分片接着它
+        let (tfdt, samples) = parse_fragment(&encoder.flush().unwrap());
+        assert_eq!((tfdt, samples), (150, vec![(40, 0)]));
+        // 早于已输出采样的帧不会回退
+        encoder.push_frame(&[0x41; 10], 5100, 0, false);
+        assert_eq!(parse_fragment(&encoder.flush().unwrap()).0, 190);
+    }
+
+    #[test]
+    fn finalized_mp4_last_sample_uses_frame_rate() {
+        // 30fps的轨道，最后一帧的时长按帧率计算，不是固定的25fps
+        let track = Track { duration: 33_333, ..Default::default() };
+        let mut writer = Mp4Writer::new(track);
+        writer.header();
+        writer.push_sample(10, 1000, 0, true);
+        writer.push_sample(10, 1040, 0, false);
+        writer.finalize();
+        assert_eq!(writer.table.durations, vec![3600, 2999]);
+    }
+
+    #[test]
+    fn decode_time_continues_after_timestamp_wraparound() {
+        let track = Track { duration: 40, timescale: 1000, ..Default::default() };
+        let mut encoder = Fmp4Encoder::new(track);
+        encoder.push_frame(&[0x65; 10], u32::MAX - 19, 0, true);
+        let fragments = encoder.push_frame(&[0x41; 10], 20, 0, false);
+        assert_eq!(parse_fragment(&fragments[0]), (0, vec![(40, 0)]));
+        assert_eq!(parse_fragment(&encoder.flush().unwrap()).0, 40);
+    }
+
+    #[test]
+    fn track_from_live_metadata() {
+        let mut video_header = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x00, 0, 0, 0, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1]);
+        video_header.body.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+        video_header.body.extend_from_slice(&SPS);
+        video_header.body.extend_from_slice(&[0x01, 0x00, PPS.len() as u8]);
+        video_header.body.extend_from_slice(&PPS);
+
+        // 直播流的duration为0，也没有帧率
+        let track = Track::from_metadata(&RtmpMetaData::default(), &video_header);
+        assert_eq!(track.timescale, Track::DEFAULT_TIMESCALE);
+        assert_eq!(track.duration, 40_000);
+        assert_eq!((track.width, track.height), (1280, 720));
+        assert_eq!(track.sps_list, vec![SPS.to_vec()]);
+        assert_eq!(track.pps_list, vec![PPS.to_vec()]);
+
+        let meta_data = RtmpMetaData { frame_rate: 30.0, ..Default::default() };
+        assert_eq!(Track::from_metadata(&meta_data, &video_header).duration, 33_333);
+    }
+
+    #[test]
+    fn reset_track_keeps_timeline() {
+        let track = Track { duration: 1000, timescale: 25000, width: 1280, height: 720, ..Default::default() };
+        let mut encoder = Fmp4Encoder::with_fragment_ms(track.clone(), 200);
+        encoder.push_frame(&[0x65; 10], 0, 0, true);
+        encoder.push_frame(&[0x41; 10], 40, 0, false);
+
+        // 缓存的帧先输出，之后是新的init segment，解码时间继续递增
+        let segments = encoder.reset_track(Track { width: 640, height: 360, ..track });
+        assert_eq!(segments.len(), 2);
+        assert_eq!(&segments[0][4..8], b"moof");
+        assert_eq!(&segments[1][4..8], b"ftyp");
+        assert_eq!(encoder.next_dts, 2000);
+        assert_eq!((encoder.track.width, encoder.track.height), (640, 360));
+
+        let header = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x00, 0, 0, 0, 0x01]);
+        let changed = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x00, 0, 0, 0, 0x02]);
+        let frame = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0, 0x02]);
+        assert!(!is_changed_video_header(&header, &header));
+        assert!(is_changed_video_header(&changed, &header));
+        assert!(!is_changed_video_header(&frame, &header));
+        assert_eq!(recording::part_path("tmp/live/test.mp4", 0), "tmp/live/test.mp4");
+        assert_eq!(recording::part_path("tmp/live/test.mp4", 2), "tmp/live/test.2.mp4");
+    }
+
+    #[test]
+    fn sample_flags_bit_layout() {
+        // 和ffmpeg、mp4box输出的trun一致：关键帧0x02000000，非关键帧0x01010000
+        assert_eq!(Sample::new(0, 0, 0, true).flags.as_four_byte(), [0x02, 0x00, 0x00, 0x00]);
+        assert_eq!(Sample::new(0, 0, 0, false).flags.as_four_byte(), [0x01, 0x01, 0x00, 0x00]);
+
+        let flags = Flags {
+            is_leading: 3,
+            is_depended_on: 2,
+            has_redundancy: 1,
+            depands_on: 1,
+            padding_value: 5,
+            is_non_sync: 1,
+            degrad_prio: 0x1234,
+        };
+        assert_eq!(u32::from_be_bytes(flags.as_four_byte()), 3 << 26 | 1 << 24 | 2 << 22 | 1 << 20 | 5 << 17 | 1 << 16 | 0x1234);
+    }
 }
@@ -0,0 +1,64 @@
+use crate::protocol::aac::{AudioCodec, AAC, ADTS};
+use crate::protocol::h264::Nalu;
+use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage};
+
+/// 推流时解析一次的音视频帧，随消息一起分发给所有播放者，避免每个播放者重复解析
+#[derive(Clone, Default)]
+pub struct ParsedFrame {
+    pub nalus: Vec<Nalu>,
+    pub key_frame: bool,
+    /// AAC帧封装成的ADTS，其他格式或者还没有收到AAC sequence header时为空
+    pub adts: Vec<ADTS>,
+}
+
+impl ParsedFrame {
+    /// `audio_header`是同一个流的AAC sequence header
+    pub fn parse(msg: &RtmpMessage, audio_header: Option<&RtmpMessage>) -> Self {
+        match msg.header.message_type {
+            ChunkMessageType::VideoMessage => Self {
+                nalus: Nalu::from_rtmp_message(msg),
+                key_frame: msg.is_video_key_frame(),
+                adts: vec![],
+            },
+            ChunkMessageType::AudioMessage => Self {
+                adts: audio_header
+                    .filter(|_| AudioCodec::from_rtmp_message(msg) == Some(AudioCodec::Aac))
+                    .and_then(|header| AAC::from_rtmp_message(msg, header))
+                    .and_then(|x| x.to_adts())
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            _ => Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_once_and_share() {
+        let body = vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88];
+        let mut msg = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 40, body);
+        assert!(msg.parsed_frame().is_none());
+        msg.attach_parsed_frame(None);
+        let frame = msg.parsed_frame().unwrap();
+        assert!(frame.key_frame);
+        assert_eq!(frame.nalus.len(), 1);
+        assert_eq!(msg.nalus()[0].as_ref(), &[0, 0, 0, 1, 0x65, 0x88]);
+        // 复制消息时共享解析结果
+        let copied = msg.clone();
+        assert!(std::ptr::eq(copied.parsed_frame().unwrap(), frame));
+
+        // AAC LC 44.1kHz 双声道
+        let header = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 0, vec![0xAF, 0x00, 0x12, 0x10]);
+        let mut audio = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 40, vec![0xAF, 0x01, 0x21, 0x10]);
+        audio.attach_parsed_frame(Some(&header));
+        let adts = &audio.parsed_frame().unwrap().adts;
+        assert_eq!(adts.len(), 1);
+        assert_eq!(adts[0].to_bytes().len(), 7 + 2);
+        assert!(ParsedFrame::parse(&audio, None).adts.is_empty());
+    }
+}
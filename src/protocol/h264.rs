@@ -1,14 +1,17 @@
 use byteorder::{BigEndian, ByteOrder};
 
 use crate::protocol::rtmp::{RtmpContext, RtmpMessage, ChunkMessageType};
+use std::sync::Arc;
 
-/// H264编码数据存储或传输的基本单元
+/// H264编码数据存储或传输的基本单元，复制时共享数据
+#[derive(Clone)]
 pub struct Nalu {
-    inner: Vec<u8>,
+    inner: Arc<[u8]>,
     pub is_key_frame: bool,
 }
 
 impl Nalu {
+    pub const UNIT_TYPE_IDR: u8 = 5;
     pub const UNIT_TYPE_SPS: u8 = 7;
     pub const UNIT_TYPE_PPS: u8 = 8;
 
@@ -20,6 +23,10 @@ impl Nalu {
 
         let bytes = &msg.body;
         let mut nalus = vec![];
+        // end of sequence只有frame type和packet type，也可能更短
+        if bytes.len() < 5 {
+            return nalus;
+        }
 
         let frame_type = bytes[0];
         let is_key_frame = frame_type == 0x17;
@@ -27,65 +34,81 @@ impl Nalu {
         let acv_packet_type = bytes[read_index];
         read_index += 4;
 
-        // AVCDecoderConfigurationRecord（AVC sequence header）
+        // AVCDecoderConfigurationRecord（AVC sequence header），长度不够时丢弃剩余部分
         if acv_packet_type == 0 {
             read_index += 5;
-            let sps_num = &bytes[read_index] & 0x1F;
-            read_index += 1;
-            for _ in 0..sps_num as usize {
-                let data_len = BigEndian::read_u16(&bytes[read_index..]);
-                read_index += 2;
-                let data = &bytes[read_index..(read_index + data_len as usize)];
-                read_index += data_len as usize;
+            for _ in 0..2 {
+                let num = match bytes.get(read_index) {
+                    Some(x) => x & 0x1F,
+                    None => break,
+                };
+                read_index += 1;
+                for _ in 0..num as usize {
+                    let data_len = match bytes.get(read_index..read_index + 2) {
+                        Some(x) => BigEndian::read_u16(x) as usize,
+                        None => return nalus,
+                    };
+                    read_index += 2;
+                    let data = match bytes.get(read_index..read_index + data_len) {
+                        Some(x) => x,
+                        None => return nalus,
+                    };
+                    read_index += data_len;
 
-                let mut nalu_bytes: Vec<u8> = vec![0x00, 0x00, 0x00, 0x01];
-                nalu_bytes.extend_from_slice(data);
-                nalus.push(Self { inner: nalu_bytes, is_key_frame });
-            }
-            let num_of_pps = &bytes[read_index] & 0x1F;
-            read_index += 1;
-            for _ in 0..num_of_pps as usize {
-                let data_len = BigEndian::read_u16(&bytes[read_index..]);
-                read_index += 2;
-                let data = &bytes[read_index..(read_index + data_len as usize)];
-                read_index += data_len as usize;
-
-                let mut nalu_bytes: Vec<u8> = vec![0x00, 0x00, 0x00, 0x01];
-                nalu_bytes.extend_from_slice(data);
-                nalus.push(Self { inner: nalu_bytes, is_key_frame });
+                    let mut nalu_bytes: Vec<u8> = vec![0x00, 0x00, 0x00, 0x01];
+                    nalu_bytes.extend_from_slice(data);
+                    nalus.push(Self { inner: nalu_bytes.into(), is_key_frame });
+                }
             }
         }
         // One or more NALUs (Full frames are required)
         else if acv_packet_type == 1 {
             loop {
-                if read_index >= bytes.len() {
+                if read_index + 4 > bytes.len() {
                     break;
                 }
                 let data_len = BigEndian::read_u32(&bytes[read_index..]);
                 read_index += 4;
+                if read_index + data_len as usize > bytes.len() {
+                    log::warn!("nalu length out of range, data_len={}, remain={}", data_len, bytes.len() - read_index);
+                    break;
+                }
                 let data = &bytes[read_index..(read_index + data_len as usize)];
                 read_index += data_len as usize;
 
                 let mut nalu_bytes: Vec<u8> = vec![0x00, 0x00, 0x00, 0x01];
                 nalu_bytes.extend_from_slice(data);
-                nalus.push(Self { inner: nalu_bytes, is_key_frame });
+                nalus.push(Self { inner: nalu_bytes.into(), is_key_frame });
             }
+        }
+        // AVC end of sequence，没有NALU
+        else if acv_packet_type == 2 {
         } else {
             log::warn!("unknown acv packet type");
         };
         nalus
     }
 
+    /// 去掉起始码之后的NAL数据，从NAL header开始
+    pub fn payload(&self) -> &[u8] {
+        &self.inner[start_code_len(&self.inner)..]
+    }
+
+    /// NAL header，包括forbidden_zero_bit、nal_ref_idc和nal_unit_type，没有数据时为0
+    pub fn nal_header(&self) -> u8 {
+        self.payload().first().copied().unwrap_or_default()
+    }
+
     /// 帧优先级
     #[allow(unused)]
     pub fn get_nal_ref_idc(&self) -> u8 {
-        self.inner[0] >> 5
+        (self.nal_header() >> 5) & 0x03
     }
 
     /// 帧类型
     #[allow(unused)]
     pub fn get_nal_unit_type(&self) -> u8 {
-        self.inner[4] & 0x1F
+        self.nal_header() & 0x1F
     }
 
     #[allow(unused)]
@@ -117,24 +140,36 @@ impl Nalu {
         format!("{}::{}", priority, t)
     }
 
-    pub fn to_avcc_format(&self) -> Vec<u8> {
-        let origin = self.as_ref();
-        let mut bytes = vec![0x00, 0x00, 0x00, 0x00];
-        for i in 4..origin.len() {
-            // remove prevention byte
-            // if origin[i - 2] == 0 && origin[i - 1] == 0 && origin[i] == 3 {
-            //    if i < origin.len() && [0u8, 1, 2, 3].contains(&origin[i + 1]) {
-            //        continue;
-            //    }
-            // }
-
-            bytes.push(origin[i]);
+    /// 从SPS中解析视频宽高，不是SPS时返回None
+    pub fn sps_dimensions(&self) -> Option<(u32, u32)> {
+        self.sps_info().map(|x| (x.width, x.height))
+    }
+
+    /// 解析SPS中的profile、level和宽高，不是SPS时返回None
+    pub fn sps_info(&self) -> Option<SpsInfo> {
+        if self.get_nal_unit_type() != Self::UNIT_TYPE_SPS {
+            return None;
         }
-        let len = (bytes.len() - 4) as u32;
-        bytes[0] = (len >> 24) as u8;
-        bytes[1] = (len >> 16) as u8;
-        bytes[2] = (len >> 8) as u8;
-        bytes[3] = len as u8;
+        parse_sps(self.payload())
+    }
+
+    /// Annex B格式的数据转换成Nalu，3字节和4字节的起始码都统一成4字节
+    pub fn from_annexb(data: &[u8], is_key_frame: bool) -> Vec<Nalu> {
+        split_annexb(data)
+            .into_iter()
+            .map(|x| {
+                let mut nalu_bytes: Vec<u8> = vec![0x00, 0x00, 0x00, 0x01];
+                nalu_bytes.extend_from_slice(x);
+                Self { inner: nalu_bytes.into(), is_key_frame }
+            })
+            .collect()
+    }
+
+    /// 去掉起始码，前面加上4字节长度
+    pub fn to_avcc_format(&self) -> Vec<u8> {
+        let payload = self.payload();
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
         bytes
     }
 }
@@ -145,6 +180,285 @@ impl AsRef<[u8]> for Nalu {
     }
 }
 
+/// 开头的起始码长度，没有起始码时为0
+fn start_code_len(bytes: &[u8]) -> usize {
+    if bytes.starts_with(&[0, 0, 0, 1]) {
+        4
+    } else if bytes.starts_with(&[0, 0, 1]) {
+        3
+    } else {
+        0
+    }
+}
+
+/// 按起始码（`00 00 01`或`00 00 00 01`）切分Annex B格式的数据，返回不含起始码的NALU
+///
+/// NALU不会以`00`结尾，末尾的`00`是下一个4字节起始码的一部分或者trailing_zero_8bits，一起去掉
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = vec![];
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                nalus.push(trim_trailing_zeros(&data[s..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        nalus.push(trim_trailing_zeros(&data[s..]));
+    }
+    nalus.retain(|x| !x.is_empty());
+    nalus
+}
+
+fn trim_trailing_zeros(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|x| *x != 0).map(|x| x + 1).unwrap_or(0);
+    &bytes[..end]
+}
+
+/// Annex B格式转换成AVCC格式，每个NALU前面加上4字节长度
+pub fn annexb_to_avcc(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 4);
+    for nalu in split_annexb(data) {
+        bytes.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(nalu);
+    }
+    bytes
+}
+
+/// 去除防竞争字节，`00 00 03`中的`03`
+pub fn remove_emulation_prevention(bytes: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(bytes.len());
+    let mut zero_count = 0;
+    for &byte in bytes {
+        if zero_count >= 2 && byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+        zero_count = if byte == 0 { zero_count + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// 按位读取RBSP，支持指数哥伦布编码
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 0x01;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn read_bits(&mut self, n: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    /// ue(v)
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    /// se(v)
+    fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        if value % 2 == 0 {
+            Some((-value / 2) as i32)
+        } else {
+            Some(((value + 1) / 2) as i32)
+        }
+    }
+}
+
+/// SPS中用于排查兼容性问题的参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpsInfo {
+    /// profile_idc
+    pub profile: u8,
+    /// level_idc，等于level乘以10
+    pub level: u8,
+    pub width: u32,
+    pub height: u32,
+    /// chroma_format_idc，0: 4:0:0, 1: 4:2:0, 2: 4:2:2, 3: 4:4:4
+    pub chroma_format: u8,
+}
+
+impl SpsInfo {
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile {
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            44 => "CAVLC 4:4:4 Intra",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn chroma_format_name(&self) -> &'static str {
+        match self.chroma_format {
+            0 => "4:0:0",
+            1 => "4:2:0",
+            2 => "4:2:2",
+            3 => "4:4:4",
+            _ => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for SpsInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}({})@{}.{} {}x{} {}",
+            self.profile_name(),
+            self.profile,
+            self.level / 10,
+            self.level % 10,
+            self.width,
+            self.height,
+            self.chroma_format_name()
+        )
+    }
+}
+
+/// 解析SPS中的`pic_width_in_mbs`、`pic_height_in_map_units`和`frame_crop`，计算视频宽高
+///
+/// `sps`为不含起始码的NAL，第一个字节是NAL header
+pub fn parse_sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    parse_sps(sps).map(|x| (x.width, x.height))
+}
+
+/// 解析SPS，`sps`为不含起始码的NAL，第一个字节是NAL header
+pub fn parse_sps(sps: &[u8]) -> Option<SpsInfo> {
+    let rbsp = remove_emulation_prevention(sps);
+    let mut reader = BitReader::new(rbsp.get(1..)?);
+
+    let profile_idc = reader.read_bits(8)?;
+    reader.read_bits(8)?; // constraint_set_flags
+    let level_idc = reader.read_bits(8)?;
+    reader.read_ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = reader.read_bit()? == 1;
+        }
+        reader.read_ue()?; // bit_depth_luma_minus8
+        reader.read_ue()?; // bit_depth_chroma_minus8
+        reader.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+        // seq_scaling_matrix_present_flag
+        if reader.read_bit()? == 1 {
+            let count = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..count {
+                // seq_scaling_list_present_flag
+                if reader.read_bit()? == 1 {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?; // log2_max_frame_num_minus4
+    let pic_order_cnt_type = reader.read_ue()?;
+    if pic_order_cnt_type == 0 {
+        reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+    } else if pic_order_cnt_type == 1 {
+        reader.read_bit()?; // delta_pic_order_always_zero_flag
+        reader.read_se()?; // offset_for_non_ref_pic
+        reader.read_se()?; // offset_for_top_to_bottom_field
+        let num_ref_frames_in_pic_order_cnt_cycle = reader.read_ue()?;
+        for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+            reader.read_se()?; // offset_for_ref_frame
+        }
+    }
+    reader.read_ue()?; // max_num_ref_frames
+    reader.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+
+    let pic_width_in_mbs_minus1 = reader.read_ue()?;
+    let pic_height_in_map_units_minus1 = reader.read_ue()?;
+    let frame_mbs_only_flag = reader.read_bit()?;
+    if frame_mbs_only_flag == 0 {
+        reader.read_bit()?; // mb_adaptive_frame_field_flag
+    }
+    reader.read_bit()?; // direct_8x8_inference_flag
+
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    // frame_cropping_flag
+    if reader.read_bit()? == 1 {
+        crop_left = reader.read_ue()?;
+        crop_right = reader.read_ue()?;
+        crop_top = reader.read_ue()?;
+        crop_bottom = reader.read_ue()?;
+    }
+
+    // 裁剪单位由色度采样格式决定
+    let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+    let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+        0 => (1, 2 - frame_mbs_only_flag),
+        1 => (2, 2 * (2 - frame_mbs_only_flag)),
+        2 => (2, 2 - frame_mbs_only_flag),
+        _ => (1, 2 - frame_mbs_only_flag),
+    };
+
+    let width = (pic_width_in_mbs_minus1 + 1) * 16;
+    let height = (2 - frame_mbs_only_flag) * (pic_height_in_map_units_minus1 + 1) * 16;
+    let width = width.checked_sub(crop_unit_x * (crop_left + crop_right))?;
+    let height = height.checked_sub(crop_unit_y * (crop_top + crop_bottom))?;
+    Some(SpsInfo {
+        profile: profile_idc as u8,
+        level: level_idc as u8,
+        width,
+        height,
+        chroma_format: chroma_format_idc as u8,
+    })
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
 /// # VideoTagHeader
 ///
 /// ## Frame Type
@@ -272,3 +586,55 @@ pub fn handle_video_data(bytes: &[u8], ctx: &RtmpContext) {
 
     fn handle_nalu(nalu_bytes: Vec<u8>) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sps_info() {
+        // x264 1280x720 High@3.1
+        let sps = [
+            0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00,
+            0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+        ];
+        let info = parse_sps(&sps).unwrap();
+        assert_eq!(
+            info,
+            SpsInfo { profile: 100, level: 31, width: 1280, height: 720, chroma_format: 1 }
+        );
+        assert_eq!(info.to_string(), "High(100)@3.1 1280x720 4:2:0");
+    }
+
+    #[test]
+    fn mixed_start_codes_to_avcc() {
+        // 4字节起始码的SPS、3字节起始码的PPS、带trailing_zero_8bits的IDR
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 0, 0, 1, 0x65, 0x88, 0x84, 0, 0, 3, 0x01,
+        ];
+        assert_eq!(split_annexb(&data), vec![&[0x67, 0x42][..], &[0x68, 0xCE], &[0x65, 0x88, 0x84, 0, 0, 3, 0x01]]);
+        assert_eq!(
+            annexb_to_avcc(&data),
+            vec![0, 0, 0, 2, 0x67, 0x42, 0, 0, 0, 2, 0x68, 0xCE, 0, 0, 0, 7, 0x65, 0x88, 0x84, 0, 0, 3, 0x01]
+        );
+
+        let nalus = Nalu::from_annexb(&data, true);
+        let types = nalus.iter().map(|x| x.get_nal_unit_type()).collect::<Vec<_>>();
+        assert_eq!(types, vec![7, 8, 5]);
+        assert_eq!(nalus[1].to_avcc_format(), vec![0, 0, 0, 2, 0x68, 0xCE]);
+        assert_eq!(nalus[1].payload(), &[0x68, 0xCE]);
+        assert_eq!(nalus[2].nal_header(), 0x65);
+        assert_eq!(nalus[2].get_nal_ref_idc(), 3);
+    }
+
+    #[test]
+    fn truncated_sequence_header() {
+        let msg = RtmpMessage::new(
+            ChunkMessageType::VideoMessage,
+            1,
+            0,
+            vec![0x17, 0x00, 0, 0, 0, 0x01, 0x42, 0xC0, 0x1F, 0xFF, 0xE1, 0x00, 0x20, 0x67],
+        );
+        assert!(Nalu::from_rtmp_message(&msg).is_empty());
+    }
+}
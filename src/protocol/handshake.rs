@@ -0,0 +1,147 @@
+//! Flash Player使用的complex握手，C1/S1中带有HMAC-SHA256摘要
+//!
+//! C1/S1的格式为 time(4) + version(4) + 764字节的key块和764字节的digest块，
+//! 两个块的顺序由schema决定，digest块中32字节摘要的位置由块开头4个字节计算。
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::util::gen_random_bytes;
+
+const PACKET_LENGTH: usize = 1536;
+const DIGEST_LENGTH: usize = 32;
+
+/// "Genuine Adobe Flash Player 001" + 32字节常量，C1摘要只使用前30字节
+const GENUINE_FP_KEY: [u8; 62] = [
+    0x47, 0x65, 0x6E, 0x75, 0x69, 0x6E, 0x65, 0x20, 0x41, 0x64, 0x6F, 0x62, 0x65, 0x20, 0x46, 0x6C,
+    0x61, 0x73, 0x68, 0x20, 0x50, 0x6C, 0x61, 0x79, 0x65, 0x72, 0x20, 0x30, 0x30, 0x31, 0xF0, 0xEE,
+    0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1, 0x02, 0x9E, 0x7E, 0x57, 0x6E, 0xEC,
+    0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36, 0xCF, 0xEB, 0x31, 0xAE,
+];
+
+/// "Genuine Adobe Flash Media Server 001" + 32字节常量，S1摘要只使用前36字节
+const GENUINE_FMS_KEY: [u8; 68] = [
+    0x47, 0x65, 0x6E, 0x75, 0x69, 0x6E, 0x65, 0x20, 0x41, 0x64, 0x6F, 0x62, 0x65, 0x20, 0x46, 0x6C,
+    0x61, 0x73, 0x68, 0x20, 0x4D, 0x65, 0x64, 0x69, 0x61, 0x20, 0x53, 0x65, 0x72, 0x76, 0x65, 0x72,
+    0x20, 0x30, 0x30, 0x31, 0xF0, 0xEE, 0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1,
+    0x02, 0x9E, 0x7E, 0x57, 0x6E, 0xEC, 0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36,
+    0xCF, 0xEB, 0x31, 0xAE,
+];
+
+/// S1中的版本号，客户端只检查是否为0
+const SERVER_VERSION: [u8; 4] = [0x04, 0x05, 0x00, 0x01];
+
+/// digest块在C1中的位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestSchema {
+    /// digest块在前，从第8个字节开始
+    DigestFirst,
+    /// key块在前，digest块从第772个字节开始
+    KeyFirst,
+}
+
+impl DigestSchema {
+    /// 摘要在握手包中的偏移
+    fn digest_offset(self, packet: &[u8]) -> usize {
+        let base = match self {
+            DigestSchema::DigestFirst => 8,
+            DigestSchema::KeyFirst => 772,
+        };
+        let sum: usize = packet[base..base + 4].iter().map(|x| *x as usize).sum();
+        sum % 728 + base + 4
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_LENGTH] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// 除去摘要之外的所有字节的HMAC
+fn packet_digest(packet: &[u8], offset: usize, key: &[u8]) -> [u8; DIGEST_LENGTH] {
+    hmac_sha256(key, &[&packet[..offset], &packet[offset + DIGEST_LENGTH..]])
+}
+
+/// 校验C1的摘要，返回使用的schema和摘要，不是complex握手时返回None
+pub fn validate_c1(c1: &[u8]) -> Option<(DigestSchema, [u8; DIGEST_LENGTH])> {
+    if c1.len() != PACKET_LENGTH || c1[4..8] == [0; 4] {
+        return None;
+    }
+    [DigestSchema::KeyFirst, DigestSchema::DigestFirst].iter().find_map(|schema| {
+        let offset = schema.digest_offset(c1);
+        let expected = packet_digest(c1, offset, &GENUINE_FP_KEY[..30]);
+        if c1[offset..offset + DIGEST_LENGTH] == expected {
+            Some((*schema, expected))
+        } else {
+            None
+        }
+    })
+}
+
+/// 生成带摘要的S1，使用和C1相同的schema
+pub fn create_s1(time: u32, schema: DigestSchema) -> Vec<u8> {
+    let mut s1 = gen_random_bytes(PACKET_LENGTH as u32);
+    s1[0..4].copy_from_slice(&time.to_be_bytes());
+    s1[4..8].copy_from_slice(&SERVER_VERSION);
+    let offset = schema.digest_offset(&s1);
+    let digest = packet_digest(&s1, offset, &GENUINE_FMS_KEY[..36]);
+    s1[offset..offset + DIGEST_LENGTH].copy_from_slice(&digest);
+    s1
+}
+
+/// 生成S2，最后32字节是以C1摘要派生出的key计算的摘要
+pub fn create_s2(c1_digest: &[u8; DIGEST_LENGTH]) -> Vec<u8> {
+    let mut s2 = gen_random_bytes(PACKET_LENGTH as u32);
+    let key = hmac_sha256(&GENUINE_FMS_KEY, &[c1_digest]);
+    let digest = hmac_sha256(&key, &[&s2[..PACKET_LENGTH - DIGEST_LENGTH]]);
+    s2[PACKET_LENGTH - DIGEST_LENGTH..].copy_from_slice(&digest);
+    s2
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 按librtmp客户端的方式生成C1，digest块在前
+    pub(crate) fn client_c1() -> Vec<u8> {
+        let mut c1 = gen_random_bytes(PACKET_LENGTH as u32);
+        c1[0..4].copy_from_slice(&[0; 4]);
+        c1[4..8].copy_from_slice(&[0x09, 0x00, 0x7C, 0x02]);
+        let offset = c1[8..12].iter().map(|x| *x as usize).sum::<usize>() % 728 + 12;
+        let digest = hmac_sha256(&GENUINE_FP_KEY[..30], &[&c1[..offset], &c1[offset + 32..]]);
+        c1[offset..offset + 32].copy_from_slice(&digest);
+        c1
+    }
+
+    #[test]
+    fn complex_handshake_verified_by_client() {
+        let c1 = client_c1();
+        let (schema, c1_digest) = validate_c1(&c1).unwrap();
+        assert_eq!(schema, DigestSchema::DigestFirst);
+
+        // 客户端用FMS key的前36字节校验S1
+        let s1 = create_s1(0, schema);
+        let offset = s1[8..12].iter().map(|x| *x as usize).sum::<usize>() % 728 + 12;
+        let expected = hmac_sha256(&GENUINE_FMS_KEY[..36], &[&s1[..offset], &s1[offset + 32..]]);
+        assert_eq!(s1[offset..offset + 32], expected);
+
+        // 客户端用完整的FMS key和自己的C1摘要校验S2
+        let s2 = create_s2(&c1_digest);
+        let key = hmac_sha256(&GENUINE_FMS_KEY, &[&c1_digest]);
+        assert_eq!(s2[1504..], hmac_sha256(&key, &[&s2[..1504]]));
+    }
+
+    #[test]
+    fn simple_handshake_is_not_complex() {
+        let mut c1 = gen_random_bytes(PACKET_LENGTH as u32);
+        c1[4..8].copy_from_slice(&[0; 4]);
+        assert!(validate_c1(&c1).is_none());
+
+        // 版本号不为0但是摘要不对
+        c1[4..8].copy_from_slice(&[0x09, 0x00, 0x7C, 0x02]);
+        assert!(validate_c1(&c1).is_none());
+    }
+}
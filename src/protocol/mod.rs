@@ -3,3 +3,8 @@ pub mod rtmp;
 pub mod h264;
 pub mod aac;
 pub mod fmp4;
+pub mod frame;
+pub mod handshake;
+pub mod rtp;
+pub mod ts;
+pub mod transport;
@@ -1,16 +1,28 @@
 use std::fmt::{Debug, Formatter};
 
-use amf::amf0;
 use amf::amf0::Value;
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use chrono::Local;
 use num::FromPrimitive;
 use smol::io::{AsyncReadExt, AsyncWriteExt};
-use smol::net::TcpStream;
+use smol::Timer;
 
-use crate::rtmp_server::eventbus_map;
-use crate::util::bytes_hex_format;
+use crate::access_log::AccessSession;
+use crate::protocol::aac::AudioCodec;
+use crate::protocol::frame::ParsedFrame;
+use crate::protocol::h264::Nalu;
+use crate::protocol::transport::Transport;
+use crate::rtmp_server::{
+    audio_header_map, drop_signal_map, eventbus_map, gop_cache_map, key_frame_tracker_map, last_timestamp_map, meta_data_map, publisher_conn_map,
+    video_header_map,
+    wait_for_takeover,
+};
+use crate::util::{bytes_hex_format, next_conn_id};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Handshake0 {
@@ -93,69 +105,333 @@ impl Handshake2 {
 
 #[derive(Debug)]
 pub struct RtmpContext {
-    pub stream: TcpStream,
+    pub stream: Box<dyn Transport>,
+    /// 连接id，日志中用`[conn=<id>]`区分同一个地址的多次连接
+    pub conn_id: u64,
     pub ctx_begin_timestamp: i64,
-    pub last_timestamp: u32,
-    pub last_timestamp_delta: u32,
-    pub last_message_length: u32,
-    pub last_message_type_id: u8,
-    pub last_message_stream_id: u32,
+    /// 对端发送的chunk大小，由对端的SetChunkSize设置
     pub chunk_size: u32,
-    pub remain_message_length: u32,
+    /// 发送给对端的chunk大小，在connect应答中通告
+    pub out_chunk_size: u32,
+    /// 每个chunk stream的上一个消息头和正在组装的消息，key为csid
+    pub chunk_streams: HashMap<u32, ChunkStreamState>,
     pub recv_bytes_num: u32,
+    /// 上一次发送Acknowledgement时的recv_bytes_num
+    pub last_ack_bytes_num: u32,
+    /// 发送给对端的WindowAcknowledgementSize，对端收到这么多字节之后需要应答
+    pub ack_window_size: u32,
+    /// SetPeerBandwidth中通告的窗口大小，对端发送多少字节之后需要等待应答
+    pub peer_bandwidth: u32,
+    /// 收到多少字节之后需要发送一次Acknowledgement，由对端的WindowAcknowledgementSize设置
+    pub peer_ack_window_size: u32,
+    /// 对端SetPeerBandwidth限制的发送窗口
+    pub peer_bandwidth_limit: Option<(u32, BandwidthLimitType)>,
     pub peer_addr: String,
+    /// connect命令中的app
+    pub app: String,
+    /// 流的唯一标识，格式为`app/stream`
     pub stream_name: String,
     pub is_publisher: bool,
+    pub play_args: PlayArgs,
+    /// connect命令中客户端要求的AMF版本，0: AMF0, 3: AMF3
+    pub object_encoding: f64,
+    /// 推流者超过这个时间没有发送数据时断开连接，None表示不限制
+    pub publish_timeout: Option<Duration>,
+    /// 管理接口要求断开推流者，在读取下一个消息之后检查
+    pub drop_signal: Arc<AtomicBool>,
+    /// 推流者断开之后保留播放者的时间，期间同名的推流者可以接管，None表示立即结束
+    pub takeover_grace: Option<Duration>,
+    /// 对端发送的单个消息的最大长度，超过时按协议错误断开连接
+    pub max_message_bytes: u32,
+    /// 推流者最近一个音频消息的格式，变化时检查是否支持
+    pub audio_codec: Option<AudioCodec>,
+    /// 推流或者播放开始后的访问日志会话，结束时drop
+    pub access: Option<AccessSession>,
+    /// 分片body的读取缓冲区，所有分片复用
+    read_buf: ReadBuffer,
+    /// peek时已经从连接读取但还没有消费的数据，读取时先从这里取
+    read_ahead: ReadBuffer,
+}
+
+/// Debug时只显示长度，避免日志中输出整个缓冲区
+#[derive(Default)]
+struct ReadBuffer(Vec<u8>);
+
+impl Debug for ReadBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReadBuffer(len={})", self.0.len())
+    }
+}
+
+/// 单个chunk stream的状态，fmt=1/2/3的分片省略的字段沿用同一个csid上一个消息头
+#[derive(Debug, Default)]
+pub struct ChunkStreamState {
+    pub timestamp: u32,
+    pub timestamp_delta: u32,
+    pub message_length: u32,
+    pub message_type_id: u8,
+    pub message_stream_id: u32,
+    /// 当前消息还没有读取的长度，0表示下一个分片开始新消息
+    pub remain_message_length: u32,
+    /// 当前消息使用了扩展时间戳，后续的type3分片也带有4字节的扩展时间戳
+    extended_timestamp: bool,
+    /// 已经读取的分片
+    partial: Option<RtmpMessage>,
+}
+
+/// SetPeerBandwidth的限制类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthLimitType {
+    /// 按新的窗口限制
+    Hard,
+    /// 新的窗口比当前的小时生效
+    Soft,
+    /// 之前是Hard时按Hard处理，否则忽略
+    Dynamic,
+}
+
+impl BandwidthLimitType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => BandwidthLimitType::Hard,
+            1 => BandwidthLimitType::Soft,
+            _ => BandwidthLimitType::Dynamic,
+        }
+    }
+}
+
+/// RTMP连接的可配置参数
+#[derive(Debug, Clone)]
+pub struct RtmpConfig {
+    pub out_chunk_size: u32,
+    pub ack_window_size: u32,
+    pub peer_bandwidth: u32,
+    /// 推流者的读超时，None表示不限制
+    pub publish_timeout: Option<Duration>,
+    /// 推流者断开之后等待接管的时间，None表示不等待
+    pub takeover_grace: Option<Duration>,
+    /// 接收的单个消息的最大长度，避免对端声明一个很大的长度占用内存
+    pub max_message_bytes: u32,
+}
+
+impl RtmpConfig {
+    /// chunk大小的最大值，最高位必须为0
+    pub const MAX_CHUNK_SIZE: u32 = 0x7FFFFFFF;
+}
+
+impl Default for RtmpConfig {
+    fn default() -> Self {
+        Self {
+            out_chunk_size: 4096,
+            ack_window_size: RtmpContext::DEFAULT_ACK_WINDOW_SIZE,
+            peer_bandwidth: RtmpContext::DEFAULT_ACK_WINDOW_SIZE,
+            publish_timeout: None,
+            takeover_grace: None,
+            max_message_bytes: 16 * 1024 * 1024,
+        }
+    }
 }
 
 impl RtmpContext {
-    pub fn new(stream: TcpStream) -> Self {
-        let peer_addr = stream
-            .peer_addr()
-            .map(|a| a.to_string())
-            .unwrap_or_default();
+    /// 在connect应答中向对端通告的窗口大小
+    pub const DEFAULT_ACK_WINDOW_SIZE: u32 = 0x100000;
+
+    pub fn new(stream: impl Transport) -> Self {
+        Self::with_config(stream, &RtmpConfig::default())
+    }
+
+    pub fn with_config(stream: impl Transport, config: &RtmpConfig) -> Self {
+        let peer_addr = stream.display_peer();
         RtmpContext {
-            stream,
+            stream: Box::new(stream),
+            conn_id: next_conn_id(),
             ctx_begin_timestamp: Local::now().timestamp_millis(),
-            last_timestamp_delta: 0,
-            last_timestamp: 0,
-            last_message_length: 0,
-            last_message_type_id: 0,
-            last_message_stream_id: 0,
             chunk_size: 128,
-            remain_message_length: 0,
+            out_chunk_size: config.out_chunk_size.clamp(1, RtmpConfig::MAX_CHUNK_SIZE),
+            chunk_streams: HashMap::new(),
             recv_bytes_num: 0,
+            last_ack_bytes_num: 0,
+            ack_window_size: config.ack_window_size,
+            peer_bandwidth: config.peer_bandwidth,
+            // 对端通告之前和发送给对端的窗口相同
+            peer_ack_window_size: config.ack_window_size,
+            peer_bandwidth_limit: None,
             peer_addr,
+            app: Default::default(),
             stream_name: Default::default(),
             is_publisher: false,
+            play_args: Default::default(),
+            object_encoding: 0.0,
+            publish_timeout: config.publish_timeout,
+            drop_signal: Default::default(),
+            takeover_grace: config.takeover_grace,
+            max_message_bytes: config.max_message_bytes,
+            audio_codec: None,
+            access: None,
+            read_buf: Default::default(),
+            read_ahead: Default::default(),
         }
     }
 
     pub async fn read_exact_from_peer(&mut self, bytes_num: u32) -> anyhow::Result<Vec<u8>> {
         let mut data = vec![0u8; bytes_num as usize];
-        AsyncReadExt::read_exact(&mut self.stream, &mut data).await?;
+        self.read_into_from_peer(&mut data).await?;
         Ok(data)
     }
 
-    /// Receives data without removing it from the queue.
+    /// 读满调用者的缓冲区，推流者在`publish_timeout`内没有数据时返回Error，按断开连接处理
+    pub async fn read_into_from_peer(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let buffered = self.read_ahead.0.len().min(data.len());
+        if buffered > 0 {
+            data[..buffered].copy_from_slice(&self.read_ahead.0[..buffered]);
+            self.read_ahead.0.drain(..buffered);
+        }
+        let data = &mut data[buffered..];
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self.publish_timeout.filter(|_| self.is_publisher) {
+            Some(timeout) => {
+                let (conn_id, peer_addr) = (self.conn_id, &self.peer_addr);
+                let stream = &mut self.stream;
+                let read = async { AsyncReadExt::read_exact(stream, data).await.map_err(anyhow::Error::from) };
+                let timer = async {
+                    Timer::after(timeout).await;
+                    log::warn!("[conn={}][peer={}] publisher idle for {:?}, disconnect", conn_id, peer_addr, timeout);
+                    Err(anyhow::anyhow!("publisher idle for {:?}", timeout))
+                };
+                smol::future::or(read, timer).await?;
+            }
+            None => AsyncReadExt::read_exact(&mut self.stream, data).await?,
+        }
+        Ok(())
+    }
+
+    /// 读取`bytes_num`个字节但不消费，之后的读取仍然从这些字节开始
+    ///
+    /// 数据分多次到达时会等到读满，不会像`TcpStream::peek`那样只返回已经到达的部分
     pub async fn peek_exact_from_peer(&mut self, bytes_num: u32) -> anyhow::Result<Vec<u8>> {
-        let mut data = vec![0u8; bytes_num as usize];
-        self.stream.peek(&mut data).await?;
-        Ok(data)
+        let bytes_num = bytes_num as usize;
+        if self.read_ahead.0.len() < bytes_num {
+            let mut more = vec![0u8; bytes_num - self.read_ahead.0.len()];
+            AsyncReadExt::read_exact(&mut self.stream, &mut more).await?;
+            self.read_ahead.0.extend_from_slice(&more);
+        }
+        Ok(self.read_ahead.0[..bytes_num].to_vec())
     }
 
     pub async fn write_to_peer(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
         self.stream.write_all(bytes).await?;
+        if let Some(access) = &self.access {
+            access.bytes.fetch_add(bytes.len() as u64);
+        }
         Ok(())
     }
 }
 
-impl Drop for RtmpContext {
-    fn drop(&mut self) {
+impl RtmpContext {
+    /// 由app和publish/play命令中的流名称组成`app/stream`，不同app下的同名流互不影响
+    pub fn stream_key(&self, stream: &str) -> String {
+        if self.app.is_empty() {
+            stream.to_string()
+        } else {
+            format!("{}/{}", self.app, stream)
+        }
+    }
+
+    /// 丢弃chunk stream上未读完的消息，返回是否有消息被丢弃
+    ///
+    /// 收到AbortMessage时调用，之后这个csid上的分片按新消息处理
+    pub fn abort_chunk_stream(&mut self, csid: u32) -> bool {
+        match self.chunk_streams.get_mut(&csid) {
+            Some(state) if state.remain_message_length > 0 => {
+                state.remain_message_length = 0;
+                state.partial = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 自上次应答之后收到的字节数是否已经超过窗口大小
+    pub fn should_send_ack(&self) -> bool {
+        self.recv_bytes_num.wrapping_sub(self.last_ack_bytes_num) >= self.peer_ack_window_size
+    }
+
+    /// 需要应答时返回Acknowledgement消息，并记录应答位置
+    pub fn take_acknowledgement(&mut self) -> Option<RtmpMessage> {
+        if !self.should_send_ack() {
+            return None;
+        }
+        self.last_ack_bytes_num = self.recv_bytes_num;
+        Some(RtmpMessage::new(ChunkMessageType::Acknowledgement, 0, 0, self.recv_bytes_num.to_be_bytes().to_vec()))
+    }
+
+    /// 处理对端的WindowAcknowledgementSize和SetPeerBandwidth，返回需要回复给对端的消息
+    ///
+    /// SetPeerBandwidth的窗口和上次发送给对端的WindowAcknowledgementSize不同时，需要回复新的WindowAcknowledgementSize
+    pub fn handle_window_message(&mut self, message: &RtmpMessage) -> Option<RtmpMessage> {
+        let body = &message.body;
+        match message.header.message_type {
+            ChunkMessageType::WindowAcknowledgementSize if body.len() >= 4 => {
+                let size = BigEndian::read_u32(body);
+                log::info!("[conn={}][peer={}] window acknowledgement size={}", self.conn_id, self.peer_addr, size);
+                if size > 0 {
+                    self.peer_ack_window_size = size;
+                }
+                None
+            }
+            ChunkMessageType::SetPeerBandwidth if body.len() >= 5 => {
+                let size = BigEndian::read_u32(body);
+                let limit_type = BandwidthLimitType::from_u8(body[4]);
+                log::info!("[conn={}][peer={}] set peer bandwidth={}, limit_type={:?}", self.conn_id, self.peer_addr, size, limit_type);
+                let (window, limit_type) = match (limit_type, self.peer_bandwidth_limit) {
+                    (BandwidthLimitType::Soft, Some((current, current_type))) if current < size => (current, current_type),
+                    // Dynamic只有在之前是Hard时生效，按Hard处理
+                    (BandwidthLimitType::Dynamic, Some((_, BandwidthLimitType::Hard))) => (size, BandwidthLimitType::Hard),
+                    (BandwidthLimitType::Dynamic, _) => return None,
+                    (limit_type, _) => (size, limit_type),
+                };
+                self.peer_bandwidth_limit = Some((window, limit_type));
+                if window == self.ack_window_size {
+                    return None;
+                }
+                self.ack_window_size = window;
+                Some(RtmpMessage::new(ChunkMessageType::WindowAcknowledgementSize, 0, 0, window.to_be_bytes().to_vec()))
+            }
+            _ => None,
+        }
+    }
+
+    /// 推送者停止推流，移除eventbus和缓存的header、onMetaData，避免重新推流时播放者拿到旧数据
+    ///
+    /// 开启接管时保留eventbus，播放者继续等待新的推流者
+    pub fn unpublish(&mut self) {
         if self.is_publisher {
-            eventbus_map().remove(&self.stream_name);
+            self.is_publisher = false;
+            self.access = None;
+            let drop_signal = &self.drop_signal;
+            // 已经被新的推流者接管时不能移除新推流者的数据
+            if drop_signal_map().remove_if(&self.stream_name, |_, x| Arc::ptr_eq(x, drop_signal)).is_none() {
+                log::info!("[conn={}][peer={}][RtmpContext] stream taken over, stream_name={}", self.conn_id, self.peer_addr, self.stream_name);
+                return;
+            }
+            match self.takeover_grace.filter(|_| !drop_signal.load(Ordering::Relaxed)) {
+                Some(grace) => wait_for_takeover(&self.stream_name, drop_signal.clone(), grace),
+                None => {
+                    eventbus_map().remove(&self.stream_name);
+                }
+            }
+            publisher_conn_map().remove(&self.stream_name);
+            video_header_map().remove(&self.stream_name);
+            audio_header_map().remove(&self.stream_name);
+            meta_data_map().remove(&self.stream_name);
+            gop_cache_map().remove(&self.stream_name);
+            key_frame_tracker_map().remove(&self.stream_name);
+            last_timestamp_map().remove(&self.stream_name);
             log::warn!(
-                "[{}][RtmpContext] remove eventbus, stream_name={}",
+                "[conn={}][peer={}][RtmpContext] unpublish, stream_name={}",
+                self.conn_id,
                 self.peer_addr,
                 self.stream_name
             );
@@ -163,10 +439,72 @@ impl Drop for RtmpContext {
     }
 }
 
+impl Drop for RtmpContext {
+    fn drop(&mut self) {
+        self.unpublish();
+    }
+}
+
+/// 从tcUrl中解析app，`rtmp://host[:port]/app`
+pub fn parse_app_from_tc_url(tc_url: &str) -> Option<String> {
+    let without_scheme = tc_url.split("://").nth(1)?;
+    let app = &without_scheme[without_scheme.find('/')? + 1..];
+    let app = app.split('?').next().unwrap_or_default().trim_matches('/');
+    if app.is_empty() {
+        None
+    } else {
+        Some(app.to_string())
+    }
+}
+
+/// play命令的参数
+///
+/// `play(streamName, start, duration, reset)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayArgs {
+    /// -2: 优先直播，-1: 只播放直播，>=0: 从指定秒数开始播放录像
+    pub start: f64,
+    /// -1: 播放到结束，0: 只播放一帧，>0: 播放的秒数
+    pub duration: f64,
+    /// 是否清空之前的播放列表
+    pub reset: bool,
+}
+
+impl Default for PlayArgs {
+    fn default() -> Self {
+        Self {
+            start: -2.0,
+            duration: -1.0,
+            reset: true,
+        }
+    }
+}
+
+impl PlayArgs {
+    /// 从play命令的AMF值中解析，缺省的参数使用默认值
+    pub fn from_amf0(values: &[Value]) -> Self {
+        let default = PlayArgs::default();
+        Self {
+            start: values.get(4).and_then(|x| x.try_as_f64()).unwrap_or(default.start),
+            duration: values.get(5).and_then(|x| x.try_as_f64()).unwrap_or(default.duration),
+            reset: match values.get(6) {
+                Some(Value::Boolean(x)) => *x,
+                Some(Value::Number(x)) => *x != 0.0,
+                _ => default.reset,
+            },
+        }
+    }
+
+    /// 只支持直播，start >= 0 表示请求录像中的指定位置
+    pub fn is_seek(&self) -> bool {
+        self.start >= 0.0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RtmpMessageHeader {
     /// chunk stream id
-    pub csid: u8,
+    pub csid: u32,
     pub timestamp: u32,
     pub message_length: u32,
     pub message_type_id: u8,
@@ -178,10 +516,27 @@ pub struct RtmpMessageHeader {
 }
 
 impl RtmpMessageHeader {
+    /// 按照csid的大小生成1～3字节的basic header
+    ///
+    /// - 2～63: 1字节，低6位为csid
+    /// - 64～319: 2字节，低6位为0，第2字节为csid - 64
+    /// - 320～65599: 3字节，低6位为1，后两字节为csid - 64（小端）
+    pub fn basic_header(fmt: u8, csid: u32) -> Vec<u8> {
+        let fmt = fmt << 6;
+        match csid {
+            0..=63 => vec![fmt | csid as u8],
+            64..=319 => vec![fmt, (csid - 64) as u8],
+            _ => {
+                let id = csid - 64;
+                vec![fmt | 1, id as u8, (id >> 8) as u8]
+            }
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let enable_extend_timestamp_field = self.timestamp >= 0xFFFFFF;
 
-        let mut rs = vec![self.csid];
+        let mut rs = RtmpMessageHeader::basic_header(0, self.csid);
         if enable_extend_timestamp_field {
             rs.write_u24::<BigEndian>(0xFFFFFF).unwrap();
         } else {
@@ -202,131 +557,292 @@ pub struct RtmpMessage {
     pub header: RtmpMessageHeader,
     pub body: Vec<u8>,
     pub chunk_count: u32,
+    /// 推流时解析的音视频帧，复制消息时共享
+    parsed: Option<Arc<ParsedFrame>>,
 }
 
 impl RtmpMessage {
-    /// 读取完整消息
-    pub async fn read_from(ctx: &mut RtmpContext) -> anyhow::Result<Self> {
-        let mut chunk = RtmpMessage::read_chunk_from(ctx).await?;
-        while ctx.remain_message_length > 0 {
-            let mut remain_chunk = RtmpMessage::read_chunk_from(ctx).await?;
-            chunk.body.append(&mut remain_chunk.body);
-            chunk.chunk_count += 1;
+    /// 读取消息时按消息头中的长度预先分配的上限，避免只发送消息头就占用大量内存
+    const MAX_PREALLOCATE_LEN: u32 = 64 * 1024;
+
+    /// 构造一个待发送的消息，chunk stream id按消息类型选择
+    pub fn new(message_type: ChunkMessageType, msid: u32, timestamp: u32, body: Vec<u8>) -> Self {
+        let csid = match message_type {
+            ChunkMessageType::SetChunkSize
+            | ChunkMessageType::AbortMessage
+            | ChunkMessageType::Acknowledgement
+            | ChunkMessageType::UserControlMessage
+            | ChunkMessageType::WindowAcknowledgementSize
+            | ChunkMessageType::SetPeerBandwidth => 2,
+            ChunkMessageType::AudioMessage => 4,
+            ChunkMessageType::VideoMessage => 6,
+            ChunkMessageType::AMF0DataMessage | ChunkMessageType::AMF3DataMessage => 5,
+            _ => 3,
+        };
+        Self {
+            header: RtmpMessageHeader {
+                csid,
+                timestamp,
+                message_length: body.len() as u32,
+                message_type_id: message_type as u8,
+                message_type,
+                msid,
+            },
+            body,
+            chunk_count: 1,
+            parsed: None,
         }
+    }
+
+    /// 解析音视频帧并附加到消息上，推流时调用一次，`audio_header`是流的AAC sequence header
+    pub fn attach_parsed_frame(&mut self, audio_header: Option<&RtmpMessage>) {
+        self.parsed = Some(Arc::new(ParsedFrame::parse(self, audio_header)));
+    }
 
-        Ok(chunk)
+    pub fn parsed_frame(&self) -> Option<&ParsedFrame> {
+        self.parsed.as_deref()
     }
 
-    /// 读取一个消息分片
-    async fn read_chunk_from(ctx: &mut RtmpContext) -> anyhow::Result<Self> {
-        let one = ctx.read_exact_from_peer(1).await?[0];
+    /// 视频消息中的NALU，优先使用推流时的解析结果
+    pub fn nalus(&self) -> Vec<Nalu> {
+        match &self.parsed {
+            Some(parsed) => parsed.nalus.clone(),
+            None => Nalu::from_rtmp_message(self),
+        }
+    }
+
+    /// 读取完整消息，不同chunk stream的分片可以交错
+    pub async fn read_from(ctx: &mut RtmpContext) -> anyhow::Result<Self> {
+        loop {
+            let header = RtmpMessage::read_chunk_from(ctx).await?;
+            let state = ctx.chunk_streams.entry(header.csid).or_default();
+            // 分片的body在ctx.read_buf中，直接追加到消息上
+            let message = match state.partial.take() {
+                Some(mut message) => {
+                    message.body.extend_from_slice(&ctx.read_buf.0);
+                    message.chunk_count += 1;
+                    message
+                }
+                None => {
+                    let capacity = header.message_length.min(Self::MAX_PREALLOCATE_LEN) as usize;
+                    let mut body = Vec::with_capacity(capacity.max(ctx.read_buf.0.len()));
+                    body.extend_from_slice(&ctx.read_buf.0);
+                    RtmpMessage { header, body, chunk_count: 1, parsed: None }
+                }
+            };
+            if state.remain_message_length == 0 {
+                return Ok(message);
+            }
+            state.partial = Some(message);
+        }
+    }
+
+    /// 读取一个消息分片，返回消息头，分片的body读取到`ctx.read_buf`中
+    ///
+    /// 消息头使用栈上的数组读取，不分配内存
+    async fn read_chunk_from(ctx: &mut RtmpContext) -> anyhow::Result<RtmpMessageHeader> {
+        let mut h = [0u8; 11];
+        ctx.read_into_from_peer(&mut h[..1]).await?;
+        let one = h[0];
         let fmt = one >> 6;
-        let csid = one << 2 >> 2;
-        let (timestamp, message_length, message_type_id, message_stream_id) = match fmt {
+        let csid = match one & 0x3F {
+            // 2字节basic header
+            0 => {
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(1);
+                ctx.read_into_from_peer(&mut h[..1]).await?;
+                h[0] as u32 + 64
+            }
+            // 3字节basic header
+            1 => {
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(2);
+                ctx.read_into_from_peer(&mut h[..2]).await?;
+                h[0] as u32 + h[1] as u32 * 256 + 64
+            }
+            x => x as u32,
+        };
+        // 新的消息头会结束这个csid上未读完的消息
+        if fmt < 3 && ctx.abort_chunk_stream(csid) {
+            log::warn!("[conn={}][peer={}] discard incomplete message, csid={}", ctx.conn_id, ctx.peer_addr, csid);
+        }
+        // 先取出这个csid的状态，读取消息头时需要借用ctx
+        let mut state = ctx.chunk_streams.remove(&csid).unwrap_or_default();
+        let remain_message_length = state.remain_message_length;
+        match fmt {
             0 => {
-                let h = ctx.read_exact_from_peer(11).await?;
-                // print_hex(&h);
+                ctx.read_into_from_peer(&mut h).await?;
                 // 时间差值置零
-                ctx.last_timestamp_delta = 0;
-                ctx.last_timestamp = BigEndian::read_u24(&h[0..3]);
-
-                ctx.last_message_length = BigEndian::read_u24(&h[3..6]);
-                ctx.remain_message_length = 0;
-                ctx.last_message_type_id = h[6];
-                ctx.last_message_stream_id = BigEndian::read_u32(&h[7..11]);
-                ctx.recv_bytes_num += 12;
-                if ctx.last_timestamp >= 0xFFFFFF {
-                    let extend = ctx.read_exact_from_peer(4).await?;
-                    ctx.last_timestamp = BigEndian::read_u32(&extend[0..4]);
-                    ctx.recv_bytes_num += 4;
+                state.timestamp_delta = 0;
+                state.timestamp = BigEndian::read_u24(&h[0..3]);
+                state.message_length = BigEndian::read_u24(&h[3..6]);
+                state.message_type_id = h[6];
+                state.message_stream_id = BigEndian::read_u32(&h[7..11]);
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(12);
+                state.extended_timestamp = state.timestamp >= 0xFFFFFF;
+                if state.extended_timestamp {
+                    ctx.read_into_from_peer(&mut h[..4]).await?;
+                    state.timestamp = BigEndian::read_u32(&h[0..4]);
+                    ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(4);
                 }
-
-                (
-                    ctx.last_timestamp,
-                    ctx.last_message_length,
-                    ctx.last_message_type_id,
-                    ctx.last_message_stream_id,
-                )
             }
             1 => {
-                let h = ctx.read_exact_from_peer(7).await?;
-                // bytes_hex_format(&h);
+                ctx.read_into_from_peer(&mut h[..7]).await?;
                 let timestamp_delta = BigEndian::read_u24(&h[0..3]);
-                ctx.last_message_length = BigEndian::read_u24(&h[3..6]);
-                ctx.remain_message_length = 0;
-                ctx.last_message_type_id = h[6];
-                ctx.last_timestamp += timestamp_delta;
-                ctx.recv_bytes_num += 8;
-
-                (
-                    ctx.last_timestamp,
-                    ctx.last_message_length,
-                    ctx.last_message_type_id,
-                    ctx.last_message_stream_id,
-                )
+                state.message_length = BigEndian::read_u24(&h[3..6]);
+                state.message_type_id = h[6];
+                state.timestamp_delta = timestamp_delta;
+                state.timestamp = state.timestamp.wrapping_add(timestamp_delta);
+                state.extended_timestamp = false;
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(8);
             }
             2 => {
-                let h = ctx.read_exact_from_peer(3).await?;
+                ctx.read_into_from_peer(&mut h[..3]).await?;
                 let timestamp_delta = BigEndian::read_u24(&h[0..3]);
-                ctx.last_timestamp_delta = timestamp_delta;
-                ctx.last_timestamp += timestamp_delta;
-                ctx.recv_bytes_num += 4;
-
-                (
-                    ctx.last_timestamp,
-                    ctx.last_message_length,
-                    ctx.last_message_type_id,
-                    ctx.last_message_stream_id,
-                )
+                state.timestamp_delta = timestamp_delta;
+                state.timestamp = state.timestamp.wrapping_add(timestamp_delta);
+                state.extended_timestamp = false;
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(4);
             }
             3 => {
-                ctx.last_timestamp += ctx.last_timestamp_delta;
-                (
-                    ctx.last_timestamp,
-                    ctx.last_message_length,
-                    ctx.last_message_type_id,
-                    ctx.last_message_stream_id,
-                )
+                // 同一个消息的后续分片沿用消息的时间戳，开始新消息时才加上时间差
+                if remain_message_length == 0 {
+                    state.timestamp = state.timestamp.wrapping_add(state.timestamp_delta);
+                }
+                ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(1);
+                // 规范要求后续分片重复扩展时间戳，但有些推流端省略了，和消息的时间戳相同时才跳过
+                if remain_message_length > 0 && state.extended_timestamp {
+                    let expected = state.timestamp.to_be_bytes();
+                    if ctx.peek_exact_from_peer(4).await? == expected {
+                        ctx.read_into_from_peer(&mut h[..4]).await?;
+                        ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(4);
+                    }
+                }
             }
             _ => unreachable!(),
         };
+        if remain_message_length == 0 && state.message_length > ctx.max_message_bytes {
+            log::warn!(
+                "[conn={}][peer={}] protocol error, message length {} exceeds {}, csid={}",
+                ctx.conn_id, ctx.peer_addr, state.message_length, ctx.max_message_bytes, csid
+            );
+            anyhow::bail!("message length {} exceeds {}", state.message_length, ctx.max_message_bytes);
+        }
+        let timestamp = state.timestamp;
+        let message_length = state.message_length;
+        let message_type_id = state.message_type_id;
+        let message_stream_id = state.message_stream_id;
 
         // 当前分片的body长度
         let read_num = {
-            let remain_length = if ctx.remain_message_length > 0 {
-                ctx.remain_message_length
+            let remain_length = if remain_message_length > 0 {
+                remain_message_length
             } else {
                 message_length
             };
 
             if remain_length > ctx.chunk_size {
-                ctx.remain_message_length = remain_length - ctx.chunk_size;
+                state.remain_message_length = remain_length - ctx.chunk_size;
                 ctx.chunk_size
             } else {
-                ctx.remain_message_length = 0;
+                state.remain_message_length = 0;
                 remain_length
             }
         };
-        let message_data = ctx.read_exact_from_peer(read_num).await?;
-        ctx.recv_bytes_num += read_num;
+        ctx.chunk_streams.insert(csid, state);
+        // 复用读取缓冲区，容量达到chunk大小之后不再分配
+        let mut buf = std::mem::take(&mut ctx.read_buf.0);
+        buf.resize(read_num as usize, 0);
+        let result = ctx.read_into_from_peer(&mut buf).await;
+        ctx.read_buf.0 = buf;
+        result?;
+        ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(read_num);
 
         let message_type = FromPrimitive::from_u8(message_type_id).ok_or(anyhow::anyhow!(
             format!("invalid message type: {}", message_type_id)
         ))?;
-        Ok(RtmpMessage {
-            header: RtmpMessageHeader {
-                csid,
-                msid: message_stream_id,
-                message_length,
-                timestamp,
-                message_type_id,
-                message_type,
-            },
-            body: message_data,
-            chunk_count: 1,
+        Ok(RtmpMessageHeader {
+            csid,
+            msid: message_stream_id,
+            message_length,
+            timestamp,
+            message_type_id,
+            message_type,
         })
     }
 
+    /// 视频消息中的composition time，单位毫秒，即PTS和DTS的差值，负值和其他消息按0处理
+    pub fn composition_time(&self) -> u32 {
+        if self.header.message_type != ChunkMessageType::VideoMessage {
+            return 0;
+        }
+        self.body.get(2..5).map(BigEndian::read_i24).unwrap_or(0).max(0) as u32
+    }
+
+    /// 视频关键帧，不包括AVC sequence header
+    pub fn is_video_key_frame(&self) -> bool {
+        self.header.message_type == ChunkMessageType::VideoMessage
+            && self.body.len() >= 2
+            && self.body[0] >> 4 == 1
+            && self.body[1] == 0x01
+    }
+
+    /// AVC/AAC sequence header
+    pub fn is_sequence_header(&self) -> bool {
+        matches!(self.header.message_type, ChunkMessageType::VideoMessage | ChunkMessageType::AudioMessage)
+            && self.body.len() >= 2
+            && self.body[1] == 0x00
+            // 只有AAC的音频有sequence header，MP3等格式的第2个字节是音频数据
+            && (self.header.message_type == ChunkMessageType::VideoMessage || self.body[0] >> 4 == 10)
+    }
+
+    /// AVC end of sequence，推流端停止推流时发送
+    pub fn is_video_end_of_sequence(&self) -> bool {
+        self.header.message_type == ChunkMessageType::VideoMessage && self.body.len() >= 2 && self.body[1] == 0x02
+    }
+
+    /// 拆分AggregateMessage
+    ///
+    /// 每个子消息为FLV tag格式：类型(1) + 长度(3) + 时间戳(3) + 扩展时间戳(1) + stream id(3) + 数据 + back pointer(4)，
+    /// 子消息的时间戳相对于第一个子消息，换算到聚合消息的时间戳上。格式不完整时丢弃剩余部分
+    pub fn split_aggregate(&self) -> Vec<RtmpMessage> {
+        const TAG_HEADER_LEN: usize = 11;
+        const BACK_POINTER_LEN: usize = 4;
+
+        let bytes = &self.body;
+        let mut messages = vec![];
+        let mut first_timestamp = None;
+        let mut read_index = 0;
+        while read_index + TAG_HEADER_LEN <= bytes.len() {
+            let message_type_id = bytes[read_index];
+            let data_len = BigEndian::read_u24(&bytes[read_index + 1..]) as usize;
+            let timestamp = BigEndian::read_u24(&bytes[read_index + 4..]) | (bytes[read_index + 7] as u32) << 24;
+            read_index += TAG_HEADER_LEN;
+            if read_index + data_len > bytes.len() {
+                log::warn!("aggregate sub message out of range, data_len={}, remain={}", data_len, bytes.len() - read_index);
+                break;
+            }
+            let body = bytes[read_index..read_index + data_len].to_vec();
+            read_index += data_len + BACK_POINTER_LEN;
+
+            let message_type = match ChunkMessageType::from_u8(message_type_id) {
+                Some(message_type) => message_type,
+                None => {
+                    log::warn!("unknown aggregate sub message type {}", message_type_id);
+                    continue;
+                }
+            };
+            let first = *first_timestamp.get_or_insert(timestamp);
+            let mut message = RtmpMessage::new(
+                message_type,
+                self.header.msid,
+                self.header.timestamp.wrapping_add(timestamp.wrapping_sub(first)),
+                body,
+            );
+            message.header.csid = self.header.csid;
+            messages.push(message);
+        }
+        messages
+    }
+
     pub fn message_type_desc(&self) -> String {
         match self.header.message_type_id {
             1 => "ProtocolControlMessages::SetChunkSize",
@@ -350,37 +866,51 @@ impl RtmpMessage {
     }
 
     /// 把body数据解析成amf0格式
+    ///
+    /// AMF3的命令和数据消息（type 15/17）以一个格式选择字节0x00开头，
+    /// 后面是AMF0编码的值，其中的AMF3数据通过AVM+标记（0x11）切换
     pub fn try_read_body_to_amf0(&self) -> Option<Vec<Value>> {
         match self.header.message_type_id {
-            18 | 19 | 20 => read_all_amf_value(&self.body),
+            18..=20 => read_all_amf_value(&self.body),
+            15 | 17 => match self.body.split_first() {
+                Some((0x00, rest)) => read_all_amf_value(rest),
+                _ => read_all_amf_value(&self.body),
+            },
             _ => None,
         }
     }
 
     /// 把一个长message分离成多个chunk，第一个chunk的type=0，后续的type=3
+    ///
+    /// 时间戳需要扩展时，每个type3分片的basic header之后也带有4字节的扩展时间戳
     pub fn split_chunks_bytes(&self, chunk_size: u32) -> Vec<Vec<u8>> {
-        let chunk_size = chunk_size as usize;
-        let mut rs = vec![];
+        Self::split_body_into_chunks(&self.header, &self.body, chunk_size)
+    }
 
-        let mut remain = self.body.clone();
-        while remain.len() > chunk_size {
-            let right = remain.split_off(chunk_size);
-            rs.push(remain);
-            remain = right;
-        }
-        rs.push(remain);
+    /// 使用指定的头部分离chunk，多个播放者共享同一个body时只需要复制头部
+    pub fn split_body_into_chunks(
+        header: &RtmpMessageHeader,
+        body: &[u8],
+        chunk_size: u32,
+    ) -> Vec<Vec<u8>> {
+        let chunk_size = chunk_size.max(1) as usize;
+        let mut parts = body.chunks(chunk_size);
 
         // 添加type0头部
-        for item in self.header.to_bytes().iter().rev() {
-            (&mut rs[0]).insert(0, item.clone());
-        }
+        let mut first = header.to_bytes();
+        first.extend_from_slice(parts.next().unwrap_or_default());
+        let mut rs = vec![first];
 
         // 添加type3头部
-        if rs.len() > 1 {
-            let type3_fmt = 0xC0 | self.header.csid;
-            for item in &mut rs[1..] {
-                item.insert(0, type3_fmt);
-            }
+        let mut type3_header = RtmpMessageHeader::basic_header(3, header.csid);
+        if header.timestamp >= 0xFFFFFF {
+            type3_header.extend_from_slice(&header.timestamp.to_be_bytes());
+        }
+        for part in parts {
+            let mut chunk = Vec::with_capacity(type3_header.len() + part.len());
+            chunk.extend_from_slice(&type3_header);
+            chunk.extend_from_slice(part);
+            rs.push(chunk);
         }
 
         rs
@@ -430,6 +960,8 @@ pub struct RtmpMetaData {
     pub frame_rate: f64,
     pub duration: f64,
     pub begin_time: i64,
+    /// 推流者发送的原始onMetaData，转发给播放者时保留全部字段和顺序
+    pub raw_value: Option<Value>,
 }
 
 impl TryFrom<&amf::amf0::Value> for RtmpMetaData {
@@ -462,8 +994,13 @@ impl TryFrom<&amf::amf0::Value> for RtmpMetaData {
                             item.value.try_as_f64().unwrap_or_default();
                     }
                     "audiocodecid" => {
-                        meta_data.audio_codec_id =
-                            item.value.try_as_str().unwrap_or_default().to_owned();
+                        // 可能是数字10，也可能是字符串mp4a
+                        meta_data.audio_codec_id = item
+                            .value
+                            .try_as_str()
+                            .map(str::to_owned)
+                            .or_else(|| item.value.try_as_f64().map(|x| x.to_string()))
+                            .unwrap_or_default();
                     }
                     "audiodatarate" => {
                         meta_data.audio_data_rate =
@@ -473,6 +1010,7 @@ impl TryFrom<&amf::amf0::Value> for RtmpMetaData {
                 }
             }
             meta_data.begin_time = Local::now().timestamp_millis();
+            meta_data.raw_value = Some(value.clone());
             Ok(meta_data)
         } else {
             Err(anyhow::anyhow!("value is not Value::EcmaArray"))?
@@ -480,56 +1018,491 @@ impl TryFrom<&amf::amf0::Value> for RtmpMetaData {
     }
 }
 
-/// 计算一个AMF值的字节长度
-pub fn calc_amf_byte_len(v: &amf0::Value) -> usize {
-    match v {
-        Value::Number(_) => 9,
-        Value::Boolean(_) => 2,
-        Value::String(s) => (s.len() + 3),
-        Value::Object { entries, .. } => {
-            // marker and tail
-            let mut len = 4;
-            for en in entries {
-                len += en.key.len() + 2;
-                len += calc_amf_byte_len(&en.value);
+/// AMF值嵌套的最大深度，防止构造的深层对象导致栈溢出
+const AMF_MAX_DEPTH: usize = 64;
+
+/// 根据字节数组计算第一个AMF0值的字节长度，声明的长度超出数组范围或者格式不支持时返回None
+///
+/// AMF3的值（AVM+标记）无法预先计算长度，返回剩余全部字节
+pub fn calc_amf_byte_len(bytes: &[u8]) -> Option<usize> {
+    amf_value_len(bytes, 0)
+}
+
+fn amf_value_len(bytes: &[u8], depth: usize) -> Option<usize> {
+    if depth > AMF_MAX_DEPTH {
+        return None;
+    }
+    let len = match *bytes.first()? {
+        0x00 => 9,
+        0x01 => 2,
+        0x02 => 3 + BigEndian::read_u16(bytes.get(1..3)?) as usize,
+        0x03 => 1 + amf_pairs_len(bytes.get(1..)?, depth)?,
+        0x05 | 0x06 => 1,
+        0x07 => 3,
+        0x08 => 5 + amf_pairs_len(bytes.get(5..)?, depth)?,
+        0x0A => {
+            let count = BigEndian::read_u32(bytes.get(1..5)?);
+            let mut len = 5;
+            for _ in 0..count {
+                len += amf_value_len(bytes.get(len..)?, depth + 1)?;
             }
             len
         }
-        Value::Null => 1,
-        Value::Undefined => 1,
-        Value::EcmaArray { entries } => {
-            // marker and tail
-            let mut len = 8;
-            for en in entries {
-                len += en.key.len() + 2;
-                len += calc_amf_byte_len(&en.value);
-            }
-            len
+        0x0B => 11,
+        0x0C | 0x0F => 5 + BigEndian::read_u32(bytes.get(1..5)?) as usize,
+        0x10 => {
+            let name_len = 3 + BigEndian::read_u16(bytes.get(1..3)?) as usize;
+            name_len + amf_pairs_len(bytes.get(name_len..)?, depth)?
         }
-        Value::Array { entries: _ } => unimplemented!(),
-        Value::Date { unix_time: _ } => unimplemented!(),
-        Value::XmlDocument(_) => unimplemented!(),
-        Value::AvmPlus(_) => unimplemented!(),
+        0x11 => bytes.len(),
+        _ => return None,
+    };
+    if len > bytes.len() {
+        return None;
     }
+    Some(len)
 }
 
-/// 从字节数组中读取全部的AMF值
+/// 对象的键值对，以空键和0x09结束
+fn amf_pairs_len(bytes: &[u8], depth: usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let key_len = BigEndian::read_u16(bytes.get(len..len + 2)?) as usize;
+        len += 2 + key_len;
+        if key_len == 0 && *bytes.get(len)? == 0x09 {
+            return Some(len + 1);
+        }
+        len += amf_value_len(bytes.get(len..)?, depth + 1)?;
+    }
+}
+
+/// 从字节数组中读取全部的AMF值，数据被截断或者格式错误时返回None
 pub fn read_all_amf_value(bytes: &[u8]) -> Option<Vec<Value>> {
-    let mut read_num = 0;
+    let mut reader = bytes;
     let mut list = Vec::new();
 
-    loop {
-        if let Ok(v) = amf::amf0::Value::read_from(&mut &bytes[read_num..]) {
-            let len = calc_amf_byte_len(&v);
-            read_num += len;
-            list.push(v);
+    // 先按声明的长度检查边界，解码器只会看到完整的值
+    while !reader.is_empty() {
+        let (mut value_bytes, rest) = reader.split_at(calc_amf_byte_len(reader)?);
+        match amf::amf0::Value::read_from(&mut value_bytes) {
+            Ok(v) => list.push(v),
+            Err(_) => return None,
+        }
+        // AMF3的值长度未知，从解码结束的位置继续
+        reader = if rest.is_empty() { value_bytes } else { rest };
+    }
+    if list.is_empty() {
+        return None;
+    }
+    Some(list)
+}
 
-            if read_num >= bytes.len() {
-                break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::net::{TcpListener, TcpStream};
+    use crate::protocol::transport::duplex;
+
+    const CHUNK_SIZE: u32 = 128;
+
+    /// 通过本地TCP连接把分片写给RtmpContext，再读回完整消息
+    fn round_trip(message: &RtmpMessage, chunk_size: u32) -> RtmpMessage {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = chunk_size;
+            for chunk in message.split_chunks_bytes(chunk_size) {
+                client.write_all(&chunk).await.unwrap();
             }
-        } else {
-            return None;
+            RtmpMessage::read_from(&mut ctx).await.unwrap()
+        })
+    }
+
+    fn video_message(timestamp: u32, body_len: usize) -> RtmpMessage {
+        let body = (0..body_len).map(|x| x as u8).collect();
+        RtmpMessage::new(ChunkMessageType::VideoMessage, 1, timestamp, body)
+    }
+
+    #[test]
+    fn split_and_reassemble_multiple_chunks() {
+        let message = video_message(1000, CHUNK_SIZE as usize * 3 + 50);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[0].len(), 12 + CHUNK_SIZE as usize);
+        assert_eq!(chunks[3], [&[0xC6][..], &message.body[CHUNK_SIZE as usize * 3..]].concat());
+
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert_eq!(received.body, message.body);
+        assert_eq!(received.header.timestamp, 1000);
+        assert_eq!(received.header.csid, 6);
+        assert_eq!(received.header.msid, 1);
+        assert_eq!(received.header.message_type, ChunkMessageType::VideoMessage);
+        assert_eq!(received.chunk_count, 4);
+    }
+
+    #[test]
+    fn body_equal_to_chunk_size() {
+        let message = video_message(0, CHUNK_SIZE as usize);
+        assert_eq!(message.split_chunks_bytes(CHUNK_SIZE).len(), 1);
+        assert_eq!(round_trip(&message, CHUNK_SIZE).body, message.body);
+    }
+
+    #[test]
+    fn body_one_byte_over_chunk_size() {
+        let message = video_message(0, CHUNK_SIZE as usize + 1);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].len(), 2);
+
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert_eq!(received.body, message.body);
+        assert_eq!(received.chunk_count, 2);
+    }
+
+    #[test]
+    fn empty_body() {
+        let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, vec![]);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        assert_eq!(chunks, vec![message.header.to_bytes()]);
+
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert!(received.body.is_empty());
+        assert_eq!(received.header.message_type, ChunkMessageType::AMF0CommandMessage);
+    }
+
+    #[test]
+    fn extended_timestamp() {
+        let message = video_message(0x01234567, CHUNK_SIZE as usize * 2);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        // 3字节时间戳全1，完整时间戳放在消息头之后
+        assert_eq!(&chunks[0][1..4], &[0xFF, 0xFF, 0xFF]);
+        assert_eq!(&chunks[0][12..16], &0x01234567u32.to_be_bytes());
+
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert_eq!(received.header.timestamp, 0x01234567);
+        assert_eq!(received.body, message.body);
+    }
+
+    #[test]
+    fn extended_timestamp_on_continuation_chunks() {
+        let timestamp = 0x7FFF_FFF0;
+        let mut body = vec![0x17, 0x01, 0, 0, 0];
+        body.extend((0..CHUNK_SIZE as usize * 3).map(|x| x as u8));
+        let message = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, timestamp, body);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        assert_eq!(chunks.len(), 4);
+        for chunk in &chunks[1..] {
+            assert_eq!(chunk[0], 0xC6);
+            assert_eq!(&chunk[1..5], &timestamp.to_be_bytes());
         }
+        assert_eq!(chunks[3].len(), 1 + 4 + 5);
+
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert!(received.is_video_key_frame());
+        assert_eq!(received.header.timestamp, timestamp);
+        assert_eq!(received.body, message.body);
+        assert_eq!(received.chunk_count, 4);
+    }
+
+    #[test]
+    fn reject_oversized_message() {
+        let config = RtmpConfig { max_message_bytes: 1024, ..Default::default() };
+        // 只发送声明了16MB长度的消息头，不发送body
+        let mut header = video_message(0, 0).header;
+        header.message_length = 0xFFFFFF;
+        let result = smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::with_config(server, &config);
+            client.write_all(&header.to_bytes()).await.unwrap();
+            RtmpMessage::read_from(&mut ctx).await
+        });
+        assert!(result.unwrap_err().to_string().contains("exceeds 1024"));
+
+        let message = video_message(0, 1024);
+        let received = smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::with_config(server, &config);
+            client.write_all(&message.split_chunks_bytes(128).concat()).await.unwrap();
+            RtmpMessage::read_from(&mut ctx).await.unwrap()
+        });
+        assert_eq!(received.body, message.body);
+    }
+
+    #[test]
+    fn continuation_without_extended_timestamp() {
+        // 省略了type3分片的扩展时间戳，body的开头和时间戳不同
+        let message = video_message(0x01234567, CHUNK_SIZE as usize * 2);
+        let chunks = message.split_chunks_bytes(CHUNK_SIZE);
+        let mut bytes = chunks[0].clone();
+        bytes.push(chunks[1][0]);
+        bytes.extend_from_slice(&chunks[1][5..]);
+        let received = smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = CHUNK_SIZE;
+            client.write_all(&bytes).await.unwrap();
+            RtmpMessage::read_from(&mut ctx).await.unwrap()
+        });
+        assert_eq!(received.body, message.body);
+    }
+
+    #[test]
+    fn large_csid_basic_header() {
+        let mut message = video_message(0, 10);
+        message.header.csid = 400;
+        let received = round_trip(&message, CHUNK_SIZE);
+        assert_eq!(received.header.csid, 400);
+        assert_eq!(received.body, message.body);
+    }
+
+    #[test]
+    fn abort_discards_partial_message() {
+        let aborted = video_message(1000, CHUNK_SIZE as usize * 2);
+        let mut bytes = aborted.split_chunks_bytes(CHUNK_SIZE)[0].clone();
+        let abort = RtmpMessage::new(ChunkMessageType::AbortMessage, 0, 0, 6u32.to_be_bytes().to_vec());
+        bytes.extend_from_slice(&abort.split_chunks_bytes(CHUNK_SIZE).concat());
+        // 中止之后同一个csid上开始新消息
+        let next = video_message(1040, CHUNK_SIZE as usize + 10);
+        bytes.extend_from_slice(&next.split_chunks_bytes(CHUNK_SIZE).concat());
+
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = CHUNK_SIZE;
+            client.write_all(&bytes).await.unwrap();
+
+            let received = RtmpMessage::read_from(&mut ctx).await.unwrap();
+            assert_eq!(received.header.message_type, ChunkMessageType::AbortMessage);
+            assert!(ctx.abort_chunk_stream(6));
+
+            let received = RtmpMessage::read_from(&mut ctx).await.unwrap();
+            assert_eq!(received.body, next.body);
+            assert_eq!(received.chunk_count, 2);
+        });
+    }
+
+    #[test]
+    fn interleaved_chunk_streams() {
+        // 音频和视频在不同的csid上交错发送，fmt=2/3沿用各自csid上一个消息头
+        let video = video_message(1000, CHUNK_SIZE as usize + 20);
+        let audio = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 1010, vec![0xAF; CHUNK_SIZE as usize + 10]);
+        let video_chunks = video.split_chunks_bytes(CHUNK_SIZE);
+        let audio_chunks = audio.split_chunks_bytes(CHUNK_SIZE);
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&video_chunks[0]);
+        bytes.extend_from_slice(&audio_chunks[0]);
+        bytes.extend_from_slice(&video_chunks[1]);
+        bytes.extend_from_slice(&audio_chunks[1]);
+        // 下一个视频消息只有fmt=2的时间差，下一个音频消息整个使用fmt=3
+        bytes.extend_from_slice(&[0x86, 0x00, 0x00, 40]);
+        bytes.extend_from_slice(&video.body[..CHUNK_SIZE as usize]);
+        bytes.push(0xC4);
+        bytes.extend_from_slice(&audio.body[..CHUNK_SIZE as usize]);
+        bytes.push(0xC6);
+        bytes.extend_from_slice(&video.body[CHUNK_SIZE as usize..]);
+        bytes.push(0xC4);
+        bytes.extend_from_slice(&audio.body[CHUNK_SIZE as usize..]);
+
+        let received = smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = CHUNK_SIZE;
+            client.write_all(&bytes).await.unwrap();
+            let mut received = vec![];
+            for _ in 0..4 {
+                received.push(RtmpMessage::read_from(&mut ctx).await.unwrap());
+            }
+            received
+        });
+
+        let expected = [(&video, 1000), (&audio, 1010), (&video, 1040), (&audio, 1010)];
+        for (message, (expected, timestamp)) in received.iter().zip(expected.iter()) {
+            assert_eq!(message.header.message_type, expected.header.message_type);
+            assert_eq!(message.header.timestamp, *timestamp);
+            assert_eq!(message.body, expected.body);
+        }
+    }
+
+    #[test]
+    fn type3_chunks_continue_and_start_messages() {
+        const MESSAGE_LEN: usize = CHUNK_SIZE as usize * 2 + 44;
+        let first = video_message(1000, MESSAGE_LEN);
+        let mut bytes = first.split_chunks_bytes(CHUNK_SIZE).concat();
+
+        // 第二个消息使用fmt=2，时间差40，后续分片为fmt=3
+        let second = video_message(1040, MESSAGE_LEN);
+        bytes.extend_from_slice(&[0x86, 0x00, 0x00, 40]);
+        for (index, part) in second.body.chunks(CHUNK_SIZE as usize).enumerate() {
+            if index > 0 {
+                bytes.push(0xC6);
+            }
+            bytes.extend_from_slice(part);
+        }
+
+        // 第三个消息整个使用fmt=3，沿用长度、类型和时间差
+        let third = video_message(1080, MESSAGE_LEN);
+        for part in third.body.chunks(CHUNK_SIZE as usize) {
+            bytes.push(0xC6);
+            bytes.extend_from_slice(part);
+        }
+
+        let received = smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = CHUNK_SIZE;
+            client.write_all(&bytes).await.unwrap();
+            let mut received = vec![];
+            for _ in 0..3 {
+                received.push(RtmpMessage::read_from(&mut ctx).await.unwrap());
+            }
+            assert!(ctx.chunk_streams.values().all(|x| x.remain_message_length == 0));
+            received
+        });
+
+        for (message, expected) in received.iter().zip([&first, &second, &third].iter()) {
+            assert_eq!(message.header.timestamp, expected.header.timestamp);
+            assert_eq!(message.header.message_length, MESSAGE_LEN as u32);
+            assert_eq!(message.chunk_count, 3);
+            assert_eq!(message.body, expected.body);
+        }
+    }
+
+    #[test]
+    fn split_aggregate_message() {
+        fn sub_message(message_type_id: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+            let mut bytes = vec![message_type_id];
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+            bytes.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+            bytes.push((timestamp >> 24) as u8);
+            bytes.extend_from_slice(&[0, 0, 0]);
+            bytes.extend_from_slice(data);
+            bytes.extend_from_slice(&(11 + data.len() as u32).to_be_bytes());
+            bytes
+        }
+
+        let mut body = sub_message(9, 500, &[0x17, 0x01, 0, 0, 0]);
+        body.extend(sub_message(8, 523, &[0xAF, 0x01, 0x21]));
+        body.extend(sub_message(9, 540, &[0x27, 0x01, 0, 0, 0]));
+        let aggregate = RtmpMessage::new(ChunkMessageType::AggregateMessage, 1, 2000, body);
+
+        let messages = aggregate.split_aggregate();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].header.message_type, ChunkMessageType::VideoMessage);
+        assert_eq!(messages[1].header.message_type, ChunkMessageType::AudioMessage);
+        assert_eq!(messages[1].body, [0xAF, 0x01, 0x21]);
+        let timestamps: Vec<u32> = messages.iter().map(|x| x.header.timestamp).collect();
+        assert_eq!(timestamps, [2000, 2023, 2040]);
+        assert!(messages.iter().all(|x| x.header.msid == 1));
+
+        // 截断的子消息被丢弃
+        let mut truncated = aggregate.clone();
+        truncated.body.truncate(truncated.body.len() - 6);
+        assert_eq!(truncated.split_aggregate().len(), 2);
+    }
+
+    #[test]
+    fn app_from_tc_url() {
+        assert_eq!(parse_app_from_tc_url("rtmp://localhost:1935/live"), Some("live".to_string()));
+        assert_eq!(parse_app_from_tc_url("rtmp://localhost/live/?token=1"), Some("live".to_string()));
+        assert_eq!(parse_app_from_tc_url("rtmp://localhost/"), None);
+        assert_eq!(parse_app_from_tc_url("localhost/live"), None);
+    }
+
+    #[test]
+    fn truncated_amf_command() {
+        let mut body = vec![];
+        for value in [Value::String("publish".to_owned()), Value::Number(5.0), Value::Null] {
+            value.write_to(&mut body).unwrap();
+        }
+        let command = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, body.clone());
+        assert_eq!(command.try_read_body_to_amf0().unwrap().len(), 3);
+
+        // 字符串声明的长度超出消息
+        let mut truncated = command.clone();
+        truncated.body.truncate(5);
+        assert!(truncated.try_read_body_to_amf0().is_none());
+
+        // long string声明4GB长度
+        let mut huge = body;
+        huge.extend_from_slice(&[0x0C, 0xFF, 0xFF, 0xFF, 0xFF, b'a']);
+        assert!(read_all_amf_value(&huge).is_none());
+
+        // 对象缺少结束标记
+        assert!(read_all_amf_value(&[0x03, 0x00, 0x01, b'a', 0x05]).is_none());
+        assert_eq!(calc_amf_byte_len(&[0x03, 0x00, 0x01, b'a', 0x05, 0x00, 0x00, 0x09]), Some(8));
+    }
+
+    /// 序列号按RTMP规范在4GiB之后回绕，回绕之后仍然按窗口回复Acknowledgement
+    #[test]
+    fn recv_bytes_num_wraps_around() {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let mut ctx = RtmpContext::new(server);
+            ctx.chunk_size = CHUNK_SIZE;
+            ctx.peer_ack_window_size = 100;
+            ctx.recv_bytes_num = u32::MAX - 10;
+            ctx.last_ack_bytes_num = u32::MAX - 10;
+
+            let message = video_message(0, 200);
+            for chunk in message.split_chunks_bytes(CHUNK_SIZE) {
+                client.write_all(&chunk).await.unwrap();
+            }
+            RtmpMessage::read_from(&mut ctx).await.unwrap();
+            // 12字节头 + 128字节 + 1字节头 + 72字节
+            assert_eq!(ctx.recv_bytes_num, 213 - 11);
+            assert_eq!(ctx.take_acknowledgement().unwrap().body, 202u32.to_be_bytes());
+        })
+    }
+
+    #[test]
+    fn window_messages_drive_acknowledgement() {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let mut ctx = RtmpContext::new(server);
+
+            let window_ack = RtmpMessage::new(ChunkMessageType::WindowAcknowledgementSize, 0, 0, 1000u32.to_be_bytes().to_vec());
+            assert!(ctx.handle_window_message(&window_ack).is_none());
+            ctx.recv_bytes_num = 999;
+            assert!(ctx.take_acknowledgement().is_none());
+            ctx.recv_bytes_num = 1000;
+            let ack = ctx.take_acknowledgement().unwrap();
+            assert_eq!(ack.header.message_type, ChunkMessageType::Acknowledgement);
+            assert_eq!(ack.body, 1000u32.to_be_bytes());
+            assert!(ctx.take_acknowledgement().is_none());
+
+            // Hard设置窗口，和通告的不同时回复WindowAcknowledgementSize
+            let set_peer_bandwidth = |size: u32, limit_type: u8| {
+                let mut body = size.to_be_bytes().to_vec();
+                body.push(limit_type);
+                RtmpMessage::new(ChunkMessageType::SetPeerBandwidth, 0, 0, body)
+            };
+            let reply = ctx.handle_window_message(&set_peer_bandwidth(5000, 0)).unwrap();
+            assert_eq!(reply.header.message_type, ChunkMessageType::WindowAcknowledgementSize);
+            assert_eq!(reply.body, 5000u32.to_be_bytes());
+            assert!(ctx.handle_window_message(&set_peer_bandwidth(5000, 0)).is_none());
+            // Soft只能缩小窗口，Dynamic在之前是Hard时按Hard处理
+            assert!(ctx.handle_window_message(&set_peer_bandwidth(8000, 1)).is_none());
+            assert_eq!(ctx.peer_bandwidth_limit, Some((5000, BandwidthLimitType::Hard)));
+            assert!(ctx.handle_window_message(&set_peer_bandwidth(3000, 1)).is_some());
+            assert_eq!(ctx.peer_bandwidth_limit, Some((3000, BandwidthLimitType::Soft)));
+            assert!(ctx.handle_window_message(&set_peer_bandwidth(9000, 2)).is_none());
+            assert_eq!(ctx.ack_window_size, 3000);
+        });
     }
-    Some(list)
 }
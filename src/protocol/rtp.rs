@@ -0,0 +1,169 @@
+/// RTP打包，RTSP输出使用
+///
+/// H264按照RFC 6184打包，超过MTU的NALU使用FU-A分片；
+/// AAC按照RFC 3640的AAC-hbr模式打包。
+pub struct RtpPacketizer {
+    payload_type: u8,
+    ssrc: u32,
+    sequence_number: u16,
+}
+
+impl RtpPacketizer {
+    pub const HEADER_LEN: usize = 12;
+    /// 单个RTP包的最大负载长度
+    pub const MAX_PAYLOAD_LEN: usize = 1400;
+    const FU_A: u8 = 28;
+
+    pub fn new(payload_type: u8, ssrc: u32) -> Self {
+        Self {
+            payload_type,
+            ssrc,
+            sequence_number: 0,
+        }
+    }
+
+    /// 把一个NALU（不含起始码）打包成一个或多个RTP包
+    ///
+    /// `marker`表示该NALU是否为一个访问单元的最后一个NALU
+    pub fn pack_h264(&mut self, nalu: &[u8], timestamp: u32, marker: bool) -> Vec<Vec<u8>> {
+        if nalu.is_empty() {
+            return vec![];
+        }
+        if nalu.len() <= Self::MAX_PAYLOAD_LEN {
+            return vec![self.packet(timestamp, marker, &[nalu])];
+        }
+
+        let nal_header = nalu[0];
+        let fu_indicator = (nal_header & 0xE0) | Self::FU_A;
+        let nal_type = nal_header & 0x1F;
+        let fragments = nalu[1..].chunks(Self::MAX_PAYLOAD_LEN - 2).collect::<Vec<&[u8]>>();
+        let last_index = fragments.len() - 1;
+
+        fragments
+            .into_iter()
+            .enumerate()
+            .map(|(i, fragment)| {
+                let mut fu_header = nal_type;
+                if i == 0 {
+                    fu_header |= 0x80;
+                }
+                if i == last_index {
+                    fu_header |= 0x40;
+                }
+                let last = i == last_index;
+                self.packet(timestamp, marker && last, &[&[fu_indicator, fu_header], fragment])
+            })
+            .collect()
+    }
+
+    /// 把一个AAC原始帧打包成RTP包，每个包携带一个AU
+    pub fn pack_aac(&mut self, frame: &[u8], timestamp: u32) -> Vec<u8> {
+        // AU-headers-length固定16bit，AU-header为13bit长度 + 3bit索引
+        let au_size = (frame.len() as u16) << 3;
+        let au_header = [0x00, 0x10, (au_size >> 8) as u8, au_size as u8];
+        self.packet(timestamp, true, &[&au_header, frame])
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payloads: &[&[u8]]) -> Vec<u8> {
+        let payload_len: usize = payloads.iter().map(|x| x.len()).sum();
+        let mut buffer = Vec::with_capacity(Self::HEADER_LEN + payload_len);
+        buffer.push(0x80); // version 2
+        buffer.push(if marker { 0x80 } else { 0x00 } | self.payload_type);
+        buffer.extend_from_slice(&self.sequence_number.to_be_bytes());
+        buffer.extend_from_slice(&timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.ssrc.to_be_bytes());
+        for p in payloads {
+            buffer.extend_from_slice(p);
+        }
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        buffer
+    }
+}
+
+/// RTSP interleaved帧：`$` + channel + 2字节长度 + RTP包
+pub fn interleaved_frame(channel: u8, packet: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(packet.len() + 4);
+    buffer.push(b'$');
+    buffer.push(channel);
+    buffer.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(packet);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD_TYPE: u8 = 96;
+
+    fn idr_nalu(len: usize) -> Vec<u8> {
+        let mut nalu = vec![0x65];
+        nalu.extend((1..len).map(|x| x as u8));
+        nalu
+    }
+
+    fn is_marker(packet: &[u8]) -> bool {
+        packet[1] & 0x80 != 0
+    }
+
+    #[test]
+    fn single_nalu_packet_up_to_max_payload() {
+        let mut packetizer = RtpPacketizer::new(PAYLOAD_TYPE, 0x1234_5678);
+        let nalu = idr_nalu(RtpPacketizer::MAX_PAYLOAD_LEN);
+        let packets = packetizer.pack_h264(&nalu, 9000, true);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][..12], [0x80, 0x80 | PAYLOAD_TYPE, 0, 0, 0, 0, 0x23, 0x28, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(packets[0][12..], nalu[..]);
+        assert!(packetizer.pack_h264(&[], 9000, true).is_empty());
+    }
+
+    #[test]
+    fn split_large_nalu_into_fu_a() {
+        let mut packetizer = RtpPacketizer::new(PAYLOAD_TYPE, 1);
+        // 去掉NALU头之后刚好多出一个字节，分成两片
+        let nalu = idr_nalu(RtpPacketizer::MAX_PAYLOAD_LEN + 1);
+        let packets = packetizer.pack_h264(&nalu, 0, true);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].len(), RtpPacketizer::HEADER_LEN + RtpPacketizer::MAX_PAYLOAD_LEN);
+        assert_eq!(packets[1].len(), RtpPacketizer::HEADER_LEN + 2 + 2);
+
+        // FU indicator保留NRI，类型为28；FU header第一片有S位，最后一片有E位
+        assert_eq!(packets[0][12..14], [0x7C, 0x80 | 0x05]);
+        assert_eq!(packets[1][12..14], [0x7C, 0x40 | 0x05]);
+        // 只有最后一片带marker，序号连续
+        assert!(!is_marker(&packets[0]));
+        assert!(is_marker(&packets[1]));
+        assert_eq!(packets[0][2..4], [0, 0]);
+        assert_eq!(packets[1][2..4], [0, 1]);
+
+        // 去掉FU头之后拼回原来的NALU
+        let mut reassembled = vec![(packets[0][12] & 0xE0) | (packets[0][13] & 0x1F)];
+        for packet in &packets {
+            reassembled.extend_from_slice(&packet[14..]);
+        }
+        assert_eq!(reassembled, nalu);
+
+        // 不是访问单元的最后一个NALU时所有分片都不带marker
+        let packets = packetizer.pack_h264(&idr_nalu(RtpPacketizer::MAX_PAYLOAD_LEN * 3), 0, false);
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|x| !is_marker(x)));
+        assert_eq!(packets.iter().filter(|x| x[13] & 0xC0 != 0).count(), 2);
+    }
+
+    #[test]
+    fn aac_hbr_au_header() {
+        let mut packetizer = RtpPacketizer::new(97, 1);
+        let frame = vec![0x21; 371];
+        let packet = packetizer.pack_aac(&frame, 1024);
+        assert!(is_marker(&packet));
+        assert_eq!(packet[4..8], 1024u32.to_be_bytes());
+        // AU-headers-length为16bit，AU-size为371 << 3，AU-index为0
+        assert_eq!(packet[12..16], [0x00, 0x10, 0x0B, 0x98]);
+        assert_eq!(packet[16..], frame[..]);
+    }
+
+    #[test]
+    fn interleaved_frame_header() {
+        assert_eq!(interleaved_frame(2, &[1, 2, 3]), [b'$', 2, 0, 3, 1, 2, 3]);
+    }
+}
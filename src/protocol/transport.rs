@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use smol::io::{AsyncRead, AsyncWrite};
+use smol::net::{SocketAddr, TcpStream};
+
+use crate::util::display_addr;
+
+/// RTMP连接的底层传输，TCP连接、Unix domain socket或者测试用的内存管道
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug + 'static {
+    /// 对端地址，内存管道和Unix domain socket没有地址时返回None
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// 日志中显示的对端，没有地址时为空
+    fn display_peer(&self) -> String {
+        self.peer_addr().map(display_addr).unwrap_or_default()
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for smol::net::unix::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// 客户端一般不绑定路径，显示为监听的路径，例如`unix:/run/river.sock`，匿名socket显示为`unix`
+    fn display_peer(&self) -> String {
+        match smol::net::unix::UnixStream::local_addr(self).ok().as_ref().and_then(|x| x.as_pathname()) {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "unix".to_string(),
+        }
+    }
+}
+
+/// 单向的内存缓冲区
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    /// 写入端已经关闭，读完之后返回EOF
+    closed: bool,
+    reader: Option<Waker>,
+}
+
+impl Pipe {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.buf.is_empty() {
+            if self.closed {
+                return Poll::Ready(Ok(0));
+            }
+            self.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(self.buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.buf.iter()) {
+            *dst = *src;
+        }
+        self.buf.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// 内存中的双向管道的一端，由[`duplex`]创建，可以代替TCP连接用脚本化的字节测试握手和消息处理
+///
+/// 写入不会阻塞，drop时对端读完剩余数据后返回EOF
+#[derive(Debug)]
+pub struct MemoryStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// 创建一对互相连接的内存管道
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let a: Arc<Mutex<Pipe>> = Default::default();
+    let b: Arc<Mutex<Pipe>> = Default::default();
+    (
+        MemoryStream { read: a.clone(), write: b.clone() },
+        MemoryStream { read: b, write: a },
+    )
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.read.lock().unwrap().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        pipe.buf.extend(buf);
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close();
+        self.read.lock().unwrap().close();
+    }
+}
+
+impl Transport for MemoryStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn duplex_read_and_eof() {
+        smol::block_on(async {
+            let (mut a, mut b) = duplex();
+            let reader = smol::spawn(async move {
+                let mut data = vec![];
+                b.read_to_end(&mut data).await.unwrap();
+                data
+            });
+            a.write_all(b"hello").await.unwrap();
+            drop(a);
+            assert_eq!(reader.await, b"hello");
+        });
+    }
+}
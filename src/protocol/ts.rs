@@ -0,0 +1,374 @@
+//! # MPEG-TS
+//!
+//! H.264 + AAC复用成188字节的TS包，每个关键帧之前重复PAT/PMT，
+//! 文件在任意位置截断时，之前的内容仍然可以播放，适合长时间录制
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use smol::channel::Receiver;
+
+use crate::protocol::aac::{AudioCodec, AudioSpecificConfig, ADTS};
+use crate::protocol::flv::FlvTimestamp;
+use crate::protocol::h264::Nalu;
+use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage};
+use crate::recording::RecordingFile;
+use crate::rtmp_server::{audio_header_map, video_header_map};
+
+pub const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_AAC: u8 = 0x0F;
+/// 时间戳单位为90kHz
+const TIMESCALE_PER_MS: u64 = 90;
+/// H.264 access unit delimiter，部分播放器依赖它区分帧
+const ACCESS_UNIT_DELIMITER: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xF0];
+
+pub struct TsMuxer {
+    has_audio: bool,
+    /// 每个PID的continuity_counter
+    continuity: HashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    pub fn new(has_audio: bool) -> Self {
+        Self {
+            has_audio,
+            continuity: HashMap::new(),
+        }
+    }
+
+    /// PAT和PMT，各占一个TS包
+    pub fn tables(&mut self) -> Vec<u8> {
+        // program 1 -> PMT
+        let mut pat = vec![0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01];
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+
+        let mut pmt = vec![0x00, 0x01, 0xC1, 0x00, 0x00];
+        // PCR_PID，program_info_length=0
+        pmt.extend_from_slice(&(0xE000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&[0xF0, 0x00]);
+        let mut streams = vec![(STREAM_TYPE_H264, VIDEO_PID)];
+        if self.has_audio {
+            streams.push((STREAM_TYPE_AAC, AUDIO_PID));
+        }
+        for (stream_type, pid) in streams {
+            pmt.push(stream_type);
+            pmt.extend_from_slice(&(0xE000 | pid).to_be_bytes());
+            pmt.extend_from_slice(&[0xF0, 0x00]);
+        }
+
+        let mut bytes = self.section_packet(PAT_PID, 0x00, &pat);
+        bytes.extend(self.section_packet(PMT_PID, 0x02, &pmt));
+        bytes
+    }
+
+    /// 一帧Annex B格式的视频，关键帧之前加上PAT/PMT，时间戳单位毫秒
+    pub fn video(&mut self, annexb: &[u8], dts: u32, pts: u32, is_key_frame: bool) -> Vec<u8> {
+        let mut payload = ACCESS_UNIT_DELIMITER.to_vec();
+        payload.extend_from_slice(annexb);
+        let pes = pes_packet(0xE0, &payload, pts, Some(dts).filter(|x| *x != pts));
+
+        let mut bytes = if is_key_frame { self.tables() } else { vec![] };
+        bytes.extend(self.packetize(VIDEO_PID, &pes, Some(dts as u64 * TIMESCALE_PER_MS), is_key_frame));
+        bytes
+    }
+
+    /// 一帧ADTS格式的音频，没有音频轨道时返回空
+    pub fn audio(&mut self, adts: &[u8], pts: u32) -> Vec<u8> {
+        if !self.has_audio {
+            return vec![];
+        }
+        let pes = pes_packet(0xC0, adts, pts, None);
+        self.packetize(AUDIO_PID, &pes, None, false)
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let counter = self.continuity.entry(pid).or_insert(0);
+        let current = *counter;
+        *counter = (current + 1) & 0x0F;
+        current
+    }
+
+    /// PSI表放在一个TS包中，剩余部分填充0xFF
+    fn section_packet(&mut self, pid: u16, table_id: u8, body: &[u8]) -> Vec<u8> {
+        // section_length包括body和4字节CRC
+        let section_length = (body.len() + 4) as u16;
+        let mut section = vec![table_id];
+        section.extend_from_slice(&(0xB000 | section_length).to_be_bytes());
+        section.extend_from_slice(body);
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let mut packet = Vec::with_capacity(PACKET_LEN);
+        packet.extend_from_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | self.next_continuity(pid)]);
+        // pointer_field
+        packet.push(0x00);
+        packet.extend_from_slice(&section);
+        packet.resize(PACKET_LEN, 0xFF);
+        packet
+    }
+
+    /// PES切分成TS包，第一个包可以带PCR，最后一个包用adaptation field填充
+    fn packetize(&mut self, pid: u16, pes: &[u8], pcr: Option<u64>, random_access: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((pes.len() / 184 + 2) * PACKET_LEN);
+        let mut offset = 0;
+        while offset < pes.len() {
+            let is_first = offset == 0;
+            // adaptation field中长度字节之后的内容，None表示没有adaptation field
+            let mut adaptation: Option<Vec<u8>> = None;
+            if is_first && (pcr.is_some() || random_access) {
+                let mut field = vec![if random_access { 0x40 } else { 0x00 } | if pcr.is_some() { 0x10 } else { 0x00 }];
+                if let Some(pcr) = pcr {
+                    field.extend_from_slice(&pcr_bytes(pcr));
+                }
+                adaptation = Some(field);
+            }
+            let header_len = 4 + adaptation.as_ref().map(|x| x.len() + 1).unwrap_or(0);
+            let remain = pes.len() - offset;
+            let mut payload_len = PACKET_LEN - header_len;
+            if remain < payload_len {
+                let stuffing = payload_len - remain;
+                match &mut adaptation {
+                    Some(field) => field.resize(field.len() + stuffing, 0xFF),
+                    // 只有长度字节，长度为0
+                    None if stuffing == 1 => adaptation = Some(vec![]),
+                    None => {
+                        let mut field = vec![0x00];
+                        field.resize(stuffing - 1, 0xFF);
+                        adaptation = Some(field);
+                    }
+                }
+                payload_len = remain;
+            }
+
+            let adaptation_control = if adaptation.is_some() { 0x30 } else { 0x10 };
+            let start_indicator = if is_first { 0x40 } else { 0x00 };
+            bytes.extend_from_slice(&[
+                SYNC_BYTE,
+                start_indicator | (pid >> 8) as u8,
+                pid as u8,
+                adaptation_control | self.next_continuity(pid),
+            ]);
+            if let Some(field) = adaptation {
+                bytes.push(field.len() as u8);
+                bytes.extend_from_slice(&field);
+            }
+            bytes.extend_from_slice(&pes[offset..offset + payload_len]);
+            offset += payload_len;
+        }
+        bytes
+    }
+}
+
+/// PES头部，`dts`和`pts`相同时只写`pts`
+fn pes_packet(stream_id: u8, payload: &[u8], pts: u32, dts: Option<u32>) -> Vec<u8> {
+    let header_data_len = if dts.is_some() { 10 } else { 5 };
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+    // 视频帧可能超过65535字节，长度写0表示不限制
+    let pes_len = 3 + header_data_len + payload.len();
+    let pes_len = if stream_id == 0xE0 || pes_len > 0xFFFF { 0 } else { pes_len as u16 };
+    pes.extend_from_slice(&pes_len.to_be_bytes());
+    pes.push(0x80);
+    pes.push(if dts.is_some() { 0xC0 } else { 0x80 });
+    pes.push(header_data_len as u8);
+    let pts = pts as u64 * TIMESCALE_PER_MS;
+    match dts {
+        Some(dts) => {
+            pes.extend_from_slice(&timestamp_bytes(0x03, pts));
+            pes.extend_from_slice(&timestamp_bytes(0x01, dts as u64 * TIMESCALE_PER_MS));
+        }
+        None => pes.extend_from_slice(&timestamp_bytes(0x02, pts)),
+    }
+    pes.extend_from_slice(payload);
+    pes
+}
+
+/// 33位的PTS/DTS，中间插入marker bit
+fn timestamp_bytes(prefix: u8, timestamp: u64) -> [u8; 5] {
+    [
+        prefix << 4 | ((timestamp >> 29) & 0x0E) as u8 | 0x01,
+        (timestamp >> 22) as u8,
+        ((timestamp >> 14) & 0xFE) as u8 | 0x01,
+        (timestamp >> 7) as u8,
+        ((timestamp << 1) & 0xFE) as u8 | 0x01,
+    ]
+}
+
+/// 33位的program_clock_reference_base，extension为0
+fn pcr_bytes(base: u64) -> [u8; 6] {
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base & 0x01) as u8) << 7 | 0x7E,
+        0x00,
+    ]
+}
+
+/// CRC-32/MPEG-2，PSI表的校验
+fn crc32_mpeg2(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// AVC sequence header中的SPS和PPS，Annex B格式，关键帧之前重复写入
+fn parameter_sets(header: &RtmpMessage) -> Vec<u8> {
+    Nalu::from_rtmp_message(header).iter().flat_map(|x| x.as_ref().to_vec()).collect()
+}
+
+/// Rtmp流输出到TS文件，由`recording::start`调用
+///
+/// 从第一个关键帧开始写入，每次写入都直接落盘，进程被杀掉时已经写入的部分仍然可以播放
+pub(crate) async fn write_ts(
+    rx: Receiver<Arc<RtmpMessage>>,
+    path: &str,
+    stream_name: &str,
+    peer_addr: &str,
+) -> anyhow::Result<()> {
+    let mut file = RecordingFile::create(path).await?;
+
+    let mut sps_pps = video_header_map().get(stream_name).map(|x| parameter_sets(x.value())).unwrap_or_default();
+    let mut audio_config = audio_header_map()
+        .get(stream_name)
+        .and_then(|x| AudioSpecificConfig::from_rtmp_message(x.value()));
+    // 第一个关键帧时创建，此时已经知道是否有AAC音频
+    let mut muxer: Option<TsMuxer> = None;
+    let mut ts_timestamp = FlvTimestamp::for_stream(stream_name);
+    while let Ok(msg) = rx.recv().await {
+        if msg.is_sequence_header() {
+            match msg.header.message_type {
+                ChunkMessageType::VideoMessage => sps_pps = parameter_sets(&msg),
+                _ => audio_config = AudioSpecificConfig::from_rtmp_message(&msg),
+            }
+            continue;
+        }
+        let bytes = match msg.header.message_type {
+            ChunkMessageType::VideoMessage => {
+                let is_key_frame = msg.is_video_key_frame();
+                if muxer.is_none() && is_key_frame {
+                    muxer = Some(TsMuxer::new(audio_config.is_some()));
+                }
+                let nalus = msg.nalus();
+                let muxer = match &mut muxer {
+                    Some(muxer) if !nalus.is_empty() => muxer,
+                    _ => continue,
+                };
+                let mut frame = if is_key_frame { sps_pps.clone() } else { vec![] };
+                for nalu in &nalus {
+                    frame.extend_from_slice(nalu.as_ref());
+                }
+                let dts = ts_timestamp.rebase(msg.header.timestamp);
+                muxer.video(&frame, dts, dts.wrapping_add(msg.composition_time()), is_key_frame)
+            }
+            ChunkMessageType::AudioMessage => {
+                let (muxer, config) = match (&mut muxer, audio_config) {
+                    (Some(muxer), Some(config)) => (muxer, config),
+                    _ => continue,
+                };
+                if AudioCodec::from_rtmp_message(&msg) != Some(AudioCodec::Aac) || msg.body.len() <= 2 {
+                    continue;
+                }
+                let adts = ADTS::with_config(msg.body[2..].to_vec(), &config);
+                muxer.audio(&adts.to_bytes(), ts_timestamp.rebase(msg.header.timestamp))
+            }
+            _ => continue,
+        };
+        file.write_all(&bytes).await?;
+    }
+
+    log::warn!("[peer={}][write_ts] closed, stream_name={}", peer_addr, stream_name);
+    Ok(())
+}
+
+/// 是否是PAT，每个关键帧之前重复，VOD从这里开始发送
+pub(crate) fn is_pat(packet: &[u8]) -> bool {
+    packet.len() >= 4 && packet[0] == SYNC_BYTE && packet_pid(packet) == PAT_PID
+}
+
+/// 视频关键帧第一个TS包中的PCR，单位90kHz，其他包返回None
+pub(crate) fn key_frame_pcr(packet: &[u8]) -> Option<u64> {
+    // payload_unit_start_indicator，有adaptation field，adaptation field中有random_access_indicator和PCR
+    if packet.len() < 12
+        || packet[0] != SYNC_BYTE
+        || packet_pid(packet) != VIDEO_PID
+        || packet[1] & 0x40 == 0
+        || packet[3] & 0x20 == 0
+        || packet[4] < 7
+        || packet[5] & 0x50 != 0x50
+    {
+        return None;
+    }
+    let pcr = &packet[6..11];
+    Some((pcr[0] as u64) << 25 | (pcr[1] as u64) << 17 | (pcr[2] as u64) << 9 | (pcr[3] as u64) << 1 | (pcr[4] as u64) >> 7)
+}
+
+fn packet_pid(packet: &[u8]) -> u16 {
+    u16::from_be_bytes([packet[1], packet[2]]) & 0x1FFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder};
+
+    fn pid(packet: &[u8]) -> u16 {
+        BigEndian::read_u16(&packet[1..3]) & 0x1FFF
+    }
+
+    #[test]
+    fn mux_key_frame_into_packets() {
+        let mut muxer = TsMuxer::new(true);
+        let mut frame = vec![0x00, 0x00, 0x00, 0x01, 0x65];
+        frame.resize(1000, 0xAB);
+        let bytes = muxer.video(&frame, 40, 80, true);
+        assert_eq!(bytes.len() % PACKET_LEN, 0);
+        let packets: Vec<&[u8]> = bytes.chunks(PACKET_LEN).collect();
+        assert!(packets.iter().all(|x| x[0] == SYNC_BYTE));
+
+        // 和ffmpeg写出的PAT相同
+        assert_eq!(pid(packets[0]), PAT_PID);
+        assert_eq!(
+            packets[0][5..21],
+            [0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xF0, 0x00, 0x2A, 0xB1, 0x04, 0xB2]
+        );
+        assert_eq!(pid(packets[1]), PMT_PID);
+        assert_eq!(packets[1][17], STREAM_TYPE_H264);
+        assert_eq!(packets[1][22], STREAM_TYPE_AAC);
+
+        // 第一个视频包带有PCR和random_access_indicator，PES从adaptation field之后开始
+        let video = packets[2];
+        assert_eq!(pid(video), VIDEO_PID);
+        assert_eq!(video[1] & 0x40, 0x40);
+        assert_eq!(video[3] & 0x30, 0x30);
+        assert_eq!(video[5], 0x50);
+        let pes = &video[5 + video[4] as usize..];
+        assert_eq!(pes[..4], [0x00, 0x00, 0x01, 0xE0]);
+        assert_eq!(pes[7], 0xC0);
+        assert_eq!(pes[9..14], timestamp_bytes(0x03, 80 * 90));
+        assert_eq!(pes[14..19], timestamp_bytes(0x01, 40 * 90));
+
+        // continuity_counter按PID递增
+        let counters: Vec<u8> = packets[2..].iter().map(|x| x[3] & 0x0F).collect();
+        assert_eq!(counters, (0..counters.len() as u8).collect::<Vec<_>>());
+
+        // 非关键帧不重复PAT/PMT，音频PES带有长度
+        let bytes = muxer.video(&frame[..100], 80, 80, false);
+        assert_eq!(pid(&bytes), VIDEO_PID);
+        assert_eq!(bytes.len(), PACKET_LEN);
+        let bytes = muxer.audio(&[0xFF, 0xF1, 0x50, 0x80, 0x02, 0x1F, 0xFC, 0x21], 90);
+        assert_eq!(pid(&bytes), AUDIO_PID);
+        let pes = &bytes[5 + bytes[4] as usize..];
+        assert_eq!(BigEndian::read_u16(&pes[4..6]), 3 + 5 + 8);
+    }
+}
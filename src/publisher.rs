@@ -0,0 +1,256 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::eventbus::EventBus;
+use crate::protocol::aac::AudioSpecificConfig;
+use crate::protocol::h264::{split_annexb, Nalu};
+use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage, RtmpMetaData};
+use crate::rtmp_push::start_push;
+use crate::rtmp_server::{
+    audio_header_map, eventbus_map, gop_cache_map, key_frame_tracker_map, last_timestamp_map, meta_data_map, publish_media_message, video_header_map,
+};
+use crate::util::next_conn_id;
+use chrono::Local;
+use smol::Timer;
+
+/// 在程序内直接推流，不经过RTMP连接
+///
+/// 推送的帧会被转换成和RTMP推流相同的`RtmpMessage`，HTTP-FLV、WebSocket和录制等输出不需要区分来源。
+/// drop时停止推流。
+///
+/// ```no_run
+/// # async fn run(frames: Vec<(Vec<u8>, u32, bool)>) -> anyhow::Result<()> {
+/// use river::publisher::StreamPublisher;
+///
+/// let publisher = StreamPublisher::create("live/camera")?;
+/// for (annexb, pts, keyframe) in frames {
+///     publisher.push_video(&annexb, pts, keyframe).await;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct StreamPublisher {
+    stream_name: String,
+    /// 和RTMP连接共用id序列，日志中区分多次创建
+    conn_id: u64,
+    /// 最近一次生成sequence header使用的sps/pps
+    video_config: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
+    audio_config: Mutex<Option<AudioSpecificConfig>>,
+    /// 流已经交给RTMP推流者，drop时不再清理
+    handed_over: AtomicBool,
+}
+
+impl StreamPublisher {
+    const PEER_ADDR: &'static str = "local";
+    const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// 注册一个新的流，同名的流已经存在时返回Error
+    pub fn create(stream_name: &str) -> anyhow::Result<Self> {
+        let publisher = Self::register(stream_name)?;
+        start_push(stream_name);
+        Ok(publisher)
+    }
+
+    /// 只注册流，不按`--push`转推，`--idle-asset`的占位画面使用
+    pub(crate) fn register(stream_name: &str) -> anyhow::Result<Self> {
+        if eventbus_map().contains_key(stream_name) {
+            return Err(anyhow::anyhow!("stream already exists, stream_name={}", stream_name));
+        }
+        eventbus_map().insert(stream_name.to_string(), EventBus::with_label(stream_name.to_string()));
+        let conn_id = next_conn_id();
+        log::info!("[conn={}][StreamPublisher] create, stream_name={}", conn_id, stream_name);
+
+        Ok(Self {
+            stream_name: stream_name.to_string(),
+            conn_id,
+            video_config: Mutex::new(None),
+            audio_config: Mutex::new(None),
+            handed_over: AtomicBool::new(false),
+        })
+    }
+
+    /// 把eventbus交给新的推流者，之后drop时不再移除流，播放者不会断开
+    pub(crate) fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
+
+    pub fn stream_name(&self) -> &str {
+        &self.stream_name
+    }
+
+    /// 当前的订阅者数量，包括各种协议的播放者、录制和转推
+    pub fn subscriber_count(&self) -> usize {
+        eventbus_map().get(&self.stream_name).map(|x| x.receiver_count()).unwrap_or(0)
+    }
+
+    /// 连续`grace`时间没有订阅者时返回
+    ///
+    /// 从上游拉流再通过`StreamPublisher`发布时，可以用来在最后一个播放者离开后停止拉流，节省上游带宽
+    pub async fn wait_idle(&self, grace: Duration) {
+        let check_interval = grace.min(StreamPublisher::IDLE_CHECK_INTERVAL);
+        let mut idle_since: Option<Instant> = None;
+        loop {
+            if self.subscriber_count() > 0 {
+                idle_since = None;
+            } else {
+                let since = *idle_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= grace {
+                    log::info!("[StreamPublisher] no subscriber for {:?}, stream_name={}", grace, self.stream_name);
+                    return;
+                }
+            }
+            Timer::after(check_interval).await;
+        }
+    }
+
+    /// 设置onMetaData，宽高为0时输出会从SPS中解析
+    pub fn set_metadata(&self, mut meta_data: RtmpMetaData) {
+        meta_data.begin_time = Local::now().timestamp_millis();
+        meta_data_map().insert(self.stream_name.clone(), meta_data);
+    }
+
+    /// 推送一个H264访问单元
+    ///
+    /// `data`为Annex B格式（带起始码），`pts`单位为毫秒。
+    /// 遇到新的SPS/PPS时会先发送AVC sequence header，所以第一个关键帧需要带上SPS/PPS。
+    pub async fn push_video(&self, data: &[u8], pts: u32, keyframe: bool) {
+        let mut nalus = split_annexb(data);
+        if nalus.is_empty() && !data.is_empty() {
+            nalus.push(data);
+        }
+
+        let sps = nalus.iter().find(|x| x.len() >= 4 && x[0] & 0x1F == Nalu::UNIT_TYPE_SPS);
+        let pps = nalus.iter().find(|x| x[0] & 0x1F == Nalu::UNIT_TYPE_PPS);
+        if let (Some(sps), Some(pps)) = (sps, pps) {
+            let changed = {
+                let mut video_config = self.video_config.lock().unwrap();
+                let config = (sps.to_vec(), pps.to_vec());
+                if video_config.as_ref() != Some(&config) {
+                    *video_config = Some(config);
+                    true
+                } else {
+                    false
+                }
+            };
+            if changed {
+                self.publish(ChunkMessageType::VideoMessage, 0, avc_sequence_header(sps, pps)).await;
+            }
+        }
+
+        let mut body = vec![if keyframe { 0x17 } else { 0x27 }, 0x01, 0x00, 0x00, 0x00];
+        for nalu in nalus {
+            body.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+            body.extend_from_slice(nalu);
+        }
+        self.publish(ChunkMessageType::VideoMessage, pts, body).await;
+    }
+
+    /// 推送一个AAC帧，`pts`单位为毫秒
+    ///
+    /// `data`可以是ADTS帧，也可以是不带ADTS头部的原始帧，
+    /// 原始帧需要先调用`set_audio_config`设置AudioSpecificConfig
+    pub async fn push_audio(&self, data: &[u8], pts: u32) {
+        let raw_data = match AudioSpecificConfig::from_adts_header(data) {
+            Some((config, header_len)) if data.len() > header_len => {
+                self.set_audio_config(config).await;
+                &data[header_len..]
+            }
+            _ => data,
+        };
+        if self.audio_config.lock().unwrap().is_none() {
+            log::warn!("[StreamPublisher] audio config not found, stream_name={}", self.stream_name);
+        }
+
+        let mut body = vec![0xAF, 0x01];
+        body.extend_from_slice(raw_data);
+        self.publish(ChunkMessageType::AudioMessage, pts, body).await;
+    }
+
+    /// 设置AudioSpecificConfig，配置变化时发送AAC sequence header
+    pub async fn set_audio_config(&self, config: AudioSpecificConfig) {
+        let changed = {
+            let mut audio_config = self.audio_config.lock().unwrap();
+            let changed = *audio_config != Some(config);
+            *audio_config = Some(config);
+            changed
+        };
+        if changed {
+            let mut body = vec![0xAF, 0x00];
+            body.extend_from_slice(&config.to_bytes());
+            self.publish(ChunkMessageType::AudioMessage, 0, body).await;
+        }
+    }
+
+    /// 推送FLV tag格式的音视频数据，例如转码进程输出的FLV，sequence header和普通帧都可以直接推送
+    pub async fn push_message(&self, message_type: ChunkMessageType, timestamp: u32, body: Vec<u8>) {
+        self.publish(message_type, timestamp, body).await;
+    }
+
+    async fn publish(&self, message_type: ChunkMessageType, timestamp: u32, body: Vec<u8>) {
+        let message = RtmpMessage::new(message_type, 1, timestamp, body);
+        publish_media_message(&self.stream_name, self.conn_id, StreamPublisher::PEER_ADDR, message).await;
+    }
+}
+
+impl Drop for StreamPublisher {
+    fn drop(&mut self) {
+        if self.is_handed_over() {
+            log::info!("[StreamPublisher] drop after hand over, stream_name={}", self.stream_name);
+            return;
+        }
+        eventbus_map().remove(&self.stream_name);
+        video_header_map().remove(&self.stream_name);
+        audio_header_map().remove(&self.stream_name);
+        meta_data_map().remove(&self.stream_name);
+        gop_cache_map().remove(&self.stream_name);
+        key_frame_tracker_map().remove(&self.stream_name);
+        last_timestamp_map().remove(&self.stream_name);
+        log::info!("[StreamPublisher] drop, stream_name={}", self.stream_name);
+    }
+}
+
+/// AVCDecoderConfigurationRecord
+fn avc_sequence_header(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x17, 0x00, 0x00, 0x00, 0x00];
+    body.push(0x01); // configurationVersion
+    body.extend_from_slice(&sps[1..4]); // profile, compatibility, level
+    body.push(0xFF); // lengthSizeMinusOne = 3
+    body.push(0xE1); // numOfSequenceParameterSets = 1
+    body.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    body.extend_from_slice(sps);
+    body.push(0x01); // numOfPictureParameterSets
+    body.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    body.extend_from_slice(pps);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtmp_server::subscribe;
+
+    #[test]
+    fn publisher_idle_without_subscribers() {
+        let grace = Duration::from_millis(100);
+        smol::block_on(async {
+            let publisher = StreamPublisher::create("live/test_publisher_idle").unwrap();
+            let viewer = subscribe(publisher.stream_name()).unwrap();
+            assert_eq!(publisher.subscriber_count(), 1);
+            let idle = smol::future::or(async { publisher.wait_idle(grace).await; true }, async {
+                Timer::after(Duration::from_millis(300)).await;
+                false
+            });
+            assert!(!idle.await);
+
+            drop(viewer);
+            let begin = Instant::now();
+            publisher.wait_idle(grace).await;
+            assert!(begin.elapsed() >= grace);
+        });
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use smol::Timer;
+
+use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage};
+
+static VIEWER_MAX_KBPS: OnceCell<u32> = OnceCell::new();
+
+/// 启动时设置每个播放者的带宽上限，0表示不限制，只能设置一次
+pub fn init_viewer_max_kbps(kbps: u32) {
+    if VIEWER_MAX_KBPS.set(kbps).is_err() {
+        log::warn!("viewer max kbps has been initialized");
+    }
+}
+
+/// 每个播放者的带宽上限，单位kbps，None表示不限制
+pub fn viewer_max_kbps() -> Option<u32> {
+    VIEWER_MAX_KBPS.get().copied().filter(|x| *x > 0)
+}
+
+/// 令牌桶限速，积压过多时丢帧直到下一个关键帧
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    /// 可以为负数，表示需要等待补充的字节数
+    tokens: f64,
+    last_refill: Instant,
+    dropping: bool,
+}
+
+impl RateLimiter {
+    /// 令牌桶的容量，允许突发发送的时长
+    const BURST: Duration = Duration::from_secs(1);
+    /// 限速时积压的消息数超过这个值后开始丢帧
+    pub const MAX_BACKLOG: usize = 256;
+
+    pub fn new(kbps: u32) -> Self {
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec * RateLimiter::BURST.as_secs_f64(),
+            last_refill: Instant::now(),
+            dropping: false,
+        }
+    }
+
+    /// 按`--viewer-max-kbps`创建，没有限制时返回None
+    pub fn for_viewer() -> Option<Self> {
+        viewer_max_kbps().map(RateLimiter::new)
+    }
+
+    /// 发送消息之前调用，`backlog`是还没有读取的消息数
+    ///
+    /// 返回false表示需要丢弃这个消息，否则等待到令牌足够
+    pub async fn admit(&mut self, msg: &RtmpMessage, backlog: usize) -> bool {
+        if backlog > RateLimiter::MAX_BACKLOG && !self.dropping {
+            self.dropping = true;
+            log::warn!("[RateLimiter] backlog={}, drop frames until next key frame", backlog);
+        }
+        if self.dropping {
+            if msg.is_video_key_frame() {
+                self.dropping = false;
+            } else if !msg.is_sequence_header() {
+                return false;
+            }
+        }
+        self.consume(msg.body.len()).await;
+        true
+    }
+
+    /// 消耗令牌，不够时等待
+    pub async fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let capacity = self.bytes_per_sec * RateLimiter::BURST.as_secs_f64();
+        let refill = (now - self.last_refill).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(capacity) - bytes as f64;
+        self.last_refill = now;
+        if self.tokens < 0.0 {
+            Timer::after(Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)).await;
+        }
+    }
+}
+
+/// 请求的query中是否带有`latest=1`，读取跟不上时跳到最新的消息，不积压
+pub fn latest_from_query(query: Option<&str>) -> bool {
+    query.map(|x| x.split('&').any(|x| x == "latest=1")).unwrap_or(false)
+}
+
+/// 降低帧率，转发所有关键帧和每N个非关键帧中的一个，请求中带有`?decimate=N`时使用
+///
+/// 被丢弃的帧可能被后面的帧参考，解码会出现花屏，到下一个关键帧恢复
+#[derive(Debug, Clone, Copy)]
+pub struct FrameDecimator {
+    keep_every: u32,
+    skipped: u32,
+}
+
+impl FrameDecimator {
+    pub fn new(keep_every: u32) -> Self {
+        Self { keep_every: keep_every.max(1), skipped: 0 }
+    }
+
+    /// 从请求的query中读取`decimate=N`，N小于2时不需要丢帧，返回None
+    pub fn from_query(query: Option<&str>) -> Option<Self> {
+        query?
+            .split('&')
+            .find_map(|x| x.strip_prefix("decimate="))
+            .and_then(|x| x.parse::<u32>().ok())
+            .filter(|x| *x > 1)
+            .map(FrameDecimator::new)
+    }
+
+    /// 返回false表示丢弃这个消息，音频和sequence header总是保留
+    pub fn admit(&mut self, msg: &RtmpMessage) -> bool {
+        if msg.header.message_type != ChunkMessageType::VideoMessage || msg.is_sequence_header() {
+            return true;
+        }
+        if msg.is_video_key_frame() {
+            self.skipped = 0;
+            return true;
+        }
+        self.skipped += 1;
+        if self.skipped < self.keep_every {
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_and_drop_until_key_frame() {
+        smol::block_on(async {
+            // 80kbps即10000字节每秒，令牌桶初始是满的
+            let mut limiter = RateLimiter::new(80);
+            let begin = Instant::now();
+            limiter.consume(10_000).await;
+            assert!(begin.elapsed() < Duration::from_millis(50));
+            limiter.consume(2_000).await;
+            assert!(begin.elapsed() >= Duration::from_millis(200));
+
+            let inter = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x27, 0x01, 0, 0, 0]);
+            let key = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0]);
+            assert!(limiter.admit(&inter, 0).await);
+            assert!(!limiter.admit(&inter, RateLimiter::MAX_BACKLOG + 1).await);
+            assert!(!limiter.admit(&inter, 0).await);
+            assert!(limiter.admit(&key, 0).await);
+            assert!(limiter.admit(&inter, 0).await);
+        });
+    }
+
+    #[test]
+    fn parse_latest_from_query() {
+        assert!(latest_from_query(Some("latest=1")));
+        assert!(latest_from_query(Some("decimate=2&latest=1")));
+        assert!(!latest_from_query(Some("latest=0")));
+        assert!(!latest_from_query(None));
+    }
+
+    #[test]
+    fn decimate_inter_frames() {
+        assert!(FrameDecimator::from_query(None).is_none());
+        assert!(FrameDecimator::from_query(Some("decimate=1")).is_none());
+        assert!(FrameDecimator::from_query(Some("decimate=x")).is_none());
+        let mut decimator = FrameDecimator::from_query(Some("token=x&decimate=3")).unwrap();
+
+        let header = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x00, 0, 0, 0]);
+        let key = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0]);
+        let inter = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x27, 0x01, 0, 0, 0]);
+        let audio = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 0, vec![0xAF, 0x01]);
+        assert!(decimator.admit(&header));
+        assert!(decimator.admit(&key));
+        let admitted: Vec<bool> = (0..6).map(|_| decimator.admit(&inter)).collect();
+        assert_eq!(admitted, [false, false, true, false, false, true]);
+        assert!(decimator.admit(&audio));
+        // 关键帧重新开始计数
+        assert!(!decimator.admit(&inter));
+        assert!(decimator.admit(&key));
+        assert!(!decimator.admit(&inter));
+        assert!(!decimator.admit(&inter));
+        assert!(decimator.admit(&inter));
+    }
+}
@@ -0,0 +1,382 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Local;
+use crossbeam_utils::atomic::AtomicCell;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use smol::channel::{Receiver, Sender, TrySendError};
+use smol::Timer;
+
+use crate::metrics::metrics;
+use crate::protocol::rtmp::RtmpMessage;
+use crate::protocol::{flv, fmp4, ts};
+use crate::rtmp_server::KeyFrameReceiver;
+use crate::util::spawn_and_log_error;
+
+/// 录制文件的目录，点播也从这里读取
+pub const RECORDINGS_DIR: &str = "tmp";
+
+/// 录制队列的消息数上限，写磁盘跟不上时丢帧，不再占用更多内存
+const QUEUE_LEN: usize = 1024;
+/// 单次写入失败后的重试次数
+const WRITE_RETRIES: usize = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// 录制文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Flv,
+    Fmp4,
+    /// MPEG-TS，进程异常退出时已经写入的部分仍然可以播放
+    Ts,
+}
+
+impl RecordingFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Flv => "flv",
+            RecordingFormat::Fmp4 => "mp4",
+            RecordingFormat::Ts => "ts",
+        }
+    }
+}
+
+impl FromStr for RecordingFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flv" => Ok(RecordingFormat::Flv),
+            "mp4" | "fmp4" => Ok(RecordingFormat::Fmp4),
+            "ts" => Ok(RecordingFormat::Ts),
+            _ => Err(anyhow::anyhow!("unknown recording format `{}`, expect flv, mp4 or ts", s)),
+        }
+    }
+}
+
+/// 正在进行的录制，关闭队列即可停止
+pub struct RecordingHandle {
+    id: u64,
+    pub format: RecordingFormat,
+    pub path: String,
+    rx: Receiver<Arc<RtmpMessage>>,
+}
+
+/// 每个流最多一路录制
+fn recording_map() -> &'static DashMap<String, RecordingHandle> {
+    static INSTANCE: OnceCell<DashMap<String, RecordingHandle>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 区分同一个流先后的录制，旧的录制结束时不会删除新的录制
+static RECORDING_ID: AtomicCell<u64> = AtomicCell::new(0);
+
+static AUTO_FORMAT: OnceCell<RecordingFormat> = OnceCell::new();
+
+/// 启动时开启推流自动录制，`--record`和`--record-format`，只能设置一次
+///
+/// 没有设置时不自动录制，只能通过管理接口开始录制
+pub fn init_auto_format(format: RecordingFormat) {
+    if AUTO_FORMAT.set(format).is_err() {
+        log::warn!("recording format has been initialized");
+    }
+}
+
+/// 推流自动录制的格式，没有开启时为None
+pub fn auto_format() -> Option<RecordingFormat> {
+    AUTO_FORMAT.get().copied()
+}
+
+/// 推流之后收到第一个关键帧时调用，开启了自动录制时开始录制到默认路径
+///
+/// 已经在录制时不重复开始，例如接管推流之后
+pub fn start_auto(stream_name: &str, peer_addr: &str) {
+    let format = match auto_format() {
+        Some(format) => format,
+        None => return,
+    };
+    if recording_map().contains_key(stream_name) {
+        return;
+    }
+    if let Err(e) = start(stream_name, format, default_path(stream_name, format), peer_addr.to_string()) {
+        log::info!("[peer={}] skip recording, stream_name={}, {}", peer_addr, stream_name, e);
+    }
+}
+
+/// 默认的录制文件路径，`live/test`录制到`tmp/live/test.mp4`，不同的流写入不同的文件
+///
+/// TS用于归档，每次录制写入新的文件`tmp/live/test/<毫秒时间戳>.ts`，不会覆盖之前的录制
+pub fn default_path(stream_name: &str, format: RecordingFormat) -> String {
+    match format {
+        RecordingFormat::Ts => format!("{}/{}/{}.ts", RECORDINGS_DIR, stream_name, Local::now().timestamp_millis()),
+        _ => format!("{}/{}.{}", RECORDINGS_DIR, stream_name, format.extension()),
+    }
+}
+
+/// 同一个录制的第`part`个文件，`tmp/live/test.mp4`之后依次为`tmp/live/test.1.mp4`、`tmp/live/test.2.mp4`
+pub fn part_path(path: &str, part: u32) -> String {
+    if part == 0 {
+        return path.to_string();
+    }
+    let path = Path::new(path);
+    let stem = path.file_stem().map(|x| x.to_string_lossy()).unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, part, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, part),
+    };
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
+/// 开始录制，流不存在、已经在录制或者文件被其他录制占用时返回Error
+pub fn start(stream_name: &str, format: RecordingFormat, path: String, peer_addr: String) -> anyhow::Result<()> {
+    if recording_map().iter().any(|x| x.path == path) {
+        return Err(anyhow::anyhow!("{} is being written by another recording", path));
+    }
+    let entry = match recording_map().entry(stream_name.to_owned()) {
+        Entry::Occupied(_) => return Err(anyhow::anyhow!("stream {} is already recording", stream_name)),
+        Entry::Vacant(entry) => entry,
+    };
+    let rx = subscribe(stream_name).ok_or_else(|| anyhow::anyhow!("stream {} not found", stream_name))?;
+    let id = RECORDING_ID.fetch_add(1);
+    log::warn!("[peer={}][recording] start, stream_name={}, path={}", peer_addr, stream_name, path);
+    entry.insert(RecordingHandle {
+        id,
+        format,
+        path: path.clone(),
+        rx: rx.clone(),
+    });
+
+    let stream_name = stream_name.to_owned();
+    spawn_and_log_error(async move {
+        let result = match format {
+            RecordingFormat::Flv => flv::write_flv(rx, &path, &stream_name, &peer_addr).await,
+            RecordingFormat::Fmp4 => fmp4::write_fmp4(rx, &path, &stream_name, &peer_addr).await,
+            RecordingFormat::Ts => ts::write_ts(rx, &path, &stream_name, &peer_addr).await,
+        };
+        recording_map().remove_if(&stream_name, |_, x| x.id == id);
+        finish(&stream_name, &peer_addr, result)
+    });
+    Ok(())
+}
+
+/// 停止录制，已经收到的消息写完之后再关闭文件，没有在录制时返回false
+pub fn stop(stream_name: &str) -> bool {
+    match recording_map().remove(stream_name) {
+        Some((_, handle)) => {
+            handle.rx.close();
+            true
+        }
+        None => false,
+    }
+}
+
+/// 正在进行的录制的格式和文件路径
+pub fn recording(stream_name: &str) -> Option<(RecordingFormat, String)> {
+    recording_map().get(stream_name).map(|x| (x.format, x.path.clone()))
+}
+
+/// 订阅录制需要的消息，从最近的关键帧开始，返回有界队列的接收端
+///
+/// 队列满时丢弃之后的帧，直到下一个关键帧，保证录制文件仍然可以解码
+pub fn subscribe(stream_name: &str) -> Option<Receiver<Arc<RtmpMessage>>> {
+    let rx = KeyFrameReceiver::subscribe(stream_name)?;
+    let (tx, bounded_rx) = smol::channel::bounded(QUEUE_LEN);
+    smol::spawn(forward(rx, tx, stream_name.to_owned())).detach();
+    Some(bounded_rx)
+}
+
+async fn forward(mut rx: KeyFrameReceiver, tx: Sender<Arc<RtmpMessage>>, stream_name: String) {
+    let mut dropping = false;
+    while let Some(msg) = rx.recv().await {
+        if dropping {
+            if msg.is_video_key_frame() {
+                dropping = false;
+                log::info!("[recording] resume at key frame, stream_name={}", stream_name);
+            } else if !msg.is_sequence_header() {
+                metrics().add_recording_dropped(&stream_name, 1);
+                continue;
+            }
+        }
+        match tx.try_send(msg) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                dropping = true;
+                metrics().add_recording_dropped(&stream_name, 1);
+                log::warn!("[recording] queue is full, drop frames until next key frame, stream_name={}", stream_name);
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+}
+
+/// 录制文件，写入失败时重试，磁盘写满时直接返回错误
+///
+/// 使用同步文件在线程池中写入，出错时可以确定已经写入的字节数
+pub struct RecordingFile {
+    file: Option<std::fs::File>,
+}
+
+impl RecordingFile {
+    /// 创建或者清空文件，目录不存在时自动创建
+    pub async fn create(path: &str) -> io::Result<Self> {
+        let path = path.to_owned();
+        let file = smol::unblock(move || {
+            if let Some(parent) = Path::new(&path).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&path)
+        })
+            .await?;
+        Ok(Self { file: Some(file) })
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        let mut retries = 0;
+        while written < buf.len() {
+            let chunk = buf[written..].to_vec();
+            match self.with_file(move |file| file.write(&chunk)).await {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    written += n;
+                    retries = 0;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if is_disk_full(&e) || retries >= WRITE_RETRIES => return Err(e),
+                Err(e) => {
+                    retries += 1;
+                    log::warn!("[recording] write error, retry {}/{}, {}", retries, WRITE_RETRIES, e);
+                    Timer::after(RETRY_DELAY).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.with_file(move |file| io::Seek::seek(file, pos)).await
+    }
+
+    /// 在线程池中操作文件
+    async fn with_file<T, F>(&mut self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut std::fs::File) -> io::Result<T> + Send + 'static,
+    {
+        let mut file = self.file.take().ok_or_else(|| io::Error::other("file is busy"))?;
+        let (file, result) = smol::unblock(move || {
+            let result = f(&mut file);
+            (file, result)
+        })
+            .await;
+        self.file = Some(file);
+        result
+    }
+}
+
+fn is_disk_full(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::StorageFull
+}
+
+/// 录制任务结束，磁盘写满时只停止这一路录制，不影响推流和其他输出
+pub fn finish(stream_name: &str, peer_addr: &str, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    let e = match result {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    metrics().inc_recording_stopped(stream_name);
+    match e.downcast_ref::<io::Error>() {
+        Some(io_error) if is_disk_full(io_error) => {
+            log::error!("[peer={}][recording] disk is full, stop recording, stream_name={}", peer_addr, stream_name);
+            Ok(())
+        }
+        _ => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventbus::EventBus;
+    use crate::protocol::flv::FLV_HEADER_WITH_TAG0;
+    use crate::protocol::rtmp::{ChunkMessageType, RtmpMetaData};
+    use crate::rtmp_server::{eventbus_map, meta_data_map, video_header_map};
+
+    /// 等待录制任务写完文件
+    async fn read_when_written(path: &str, min_len: usize) -> Vec<u8> {
+        for _ in 0..100 {
+            if let Ok(bytes) = std::fs::read(path) {
+                if bytes.len() >= min_len {
+                    return bytes;
+                }
+            }
+            Timer::after(Duration::from_millis(20)).await;
+        }
+        panic!("{} is not written", path);
+    }
+
+    #[test]
+    fn record_two_streams_to_distinct_files() {
+        smol::block_on(async {
+            let (flv_stream, mp4_stream) = ("live/test_rec_flv", "live/test_rec_mp4");
+            let dir = std::env::temp_dir().join(format!("river_test_rec_{}", std::process::id()));
+            let flv_path = dir.join("a.flv").to_string_lossy().to_string();
+            let mp4_path = dir.join("b.mp4").to_string_lossy().to_string();
+
+            let mut video_header = vec![0x17, 0x00, 0, 0, 0, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F];
+            video_header.extend_from_slice(&[0x01, 0x00, 0x02, 0x68, 0xEE]);
+            let video_header = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, video_header);
+            video_header_map().insert(mp4_stream.to_string(), video_header);
+            let meta_data = RtmpMetaData { width: 1280.0, height: 720.0, ..Default::default() };
+            meta_data_map().insert(mp4_stream.to_string(), meta_data);
+            for stream_name in [flv_stream, mp4_stream] {
+                eventbus_map().insert(stream_name.to_string(), EventBus::with_label(stream_name.to_string()));
+            }
+
+            start(flv_stream, RecordingFormat::Flv, flv_path.clone(), "local".into()).unwrap();
+            start(mp4_stream, RecordingFormat::Fmp4, mp4_path.clone(), "local".into()).unwrap();
+            // 同一个流和同一个文件不能同时有两路录制
+            assert!(start(flv_stream, RecordingFormat::Flv, dir.join("c.flv").to_string_lossy().to_string(), "local".into()).is_err());
+            assert!(start("live/test_rec_other", RecordingFormat::Flv, flv_path.clone(), "local".into()).is_err());
+            assert_eq!(recording(mp4_stream), Some((RecordingFormat::Fmp4, mp4_path.clone())));
+
+            let key_frame = Arc::new(RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88]));
+            for stream_name in [flv_stream, mp4_stream] {
+                eventbus_map().get(stream_name).unwrap().publish(key_frame.clone()).await;
+            }
+            let flv = read_when_written(&flv_path, FLV_HEADER_WITH_TAG0.len() + 11).await;
+            assert_eq!(flv[..FLV_HEADER_WITH_TAG0.len()], FLV_HEADER_WITH_TAG0);
+            assert_eq!(flv[FLV_HEADER_WITH_TAG0.len()], 9);
+            let mp4 = read_when_written(&mp4_path, 16).await;
+            assert_eq!(&mp4[4..8], b"ftyp");
+
+            assert!(stop(flv_stream));
+            assert!(stop(mp4_stream));
+            assert!(!stop(mp4_stream));
+            assert_eq!(recording(mp4_stream), None);
+
+            for stream_name in [flv_stream, mp4_stream] {
+                eventbus_map().remove(stream_name);
+            }
+            std::fs::remove_dir_all(dir).ok();
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn disk_full_stops_recording_gracefully() {
+        smol::block_on(async {
+            // 写入/dev/full总是返回ENOSPC
+            let mut file = RecordingFile::create("/dev/full").await.unwrap();
+            let e = file.write_all(&[0; 16]).await.unwrap_err();
+            assert!(is_disk_full(&e));
+            assert!(finish("live/test_disk_full", "local", Err(e.into())).is_ok());
+            assert!(metrics().render().contains("river_recording_errors_total{stream=\"live/test_disk_full\"} 1"));
+        });
+    }
+}
@@ -0,0 +1,429 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use amf::amf0::Value;
+use amf::Pair;
+use byteorder::{BigEndian, ByteOrder};
+use once_cell::sync::OnceCell;
+use smol::channel::Receiver;
+use smol::net::TcpStream;
+use smol::Timer;
+
+use crate::protocol::rtmp::{ChunkMessageType, Handshake0, Handshake1, Handshake2, RtmpContext, RtmpMessage};
+use crate::rtmp_server::{audio_header_map, eventbus_map, meta_data_map, meta_data_to_amf0, video_header_map, KeyFrameReceiver};
+use crate::util::gen_random_bytes;
+
+/// 转推规则，`--push <stream>=<url>`
+#[derive(Debug, Clone)]
+pub struct PushRule {
+    /// 本地的流名称，格式为`app/stream`
+    pub stream_name: String,
+    /// 上游地址，`rtmp://host[:port]/app/stream`
+    pub url: String,
+}
+
+impl FromStr for PushRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stream_name, url) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expect <stream>=<url>, got {}", s))?;
+        PushUrl::parse(url)?;
+        Ok(Self {
+            stream_name: stream_name.trim_matches('/').to_string(),
+            url: url.to_string(),
+        })
+    }
+}
+
+static PUSH_RULES: OnceCell<Vec<PushRule>> = OnceCell::new();
+
+/// 启动时设置转推规则，只能设置一次
+pub fn init_push_rules(rules: Vec<PushRule>) {
+    if PUSH_RULES.set(rules).is_err() {
+        log::warn!("push rules has been initialized");
+    }
+}
+
+fn push_rules() -> &'static [PushRule] {
+    PUSH_RULES.get_or_init(Vec::new)
+}
+
+/// 推流开始时调用，按规则转推到上游
+pub fn start_push(stream_name: &str) {
+    for rule in push_rules().iter().filter(|x| x.stream_name == stream_name) {
+        let stream_name = stream_name.to_string();
+        let url = rule.url.clone();
+        smol::spawn(push_loop(stream_name, url)).detach();
+    }
+}
+
+/// 上游断开后按指数退避重连，本地流结束后退出
+async fn push_loop(stream_name: String, url: String) {
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    let mut backoff = MIN_BACKOFF;
+    while eventbus_map().contains_key(&stream_name) {
+        log::info!("[RtmpPush] start, stream_name={}, url={}", stream_name, url);
+        match push_once(&stream_name, &url, &mut backoff).await {
+            Ok(()) => break,
+            Err(e) => {
+                log::warn!(
+                    "[RtmpPush] upstream error, retry in {:?}, stream_name={}, url={}, {:?}",
+                    backoff,
+                    stream_name,
+                    url,
+                    e
+                );
+            }
+        }
+        Timer::after(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    log::info!("[RtmpPush] stop, stream_name={}, url={}", stream_name, url);
+}
+
+/// 完成一次连接、握手、publish并转发消息，本地流结束时返回Ok
+async fn push_once(stream_name: &str, url: &str, backoff: &mut Duration) -> anyhow::Result<()> {
+    let push_url = PushUrl::parse(url)?;
+    let stream = TcpStream::connect(&push_url.addr).await?;
+    let mut ctx = RtmpContext::new(stream.clone());
+    ctx.peer_addr = push_url.addr.clone();
+
+    client_handshake(&mut ctx).await?;
+    let stream_id = client_publish(&mut ctx, &push_url).await?;
+    log::info!("[RtmpPush] publish success, stream_name={}, url={}", stream_name, url);
+    // 成功publish之后重置退避时间
+    *backoff = Duration::from_secs(1);
+
+    // 上游发来的控制消息需要持续读取，出错或者断开时结束转推
+    // 需要回复的控制消息交给forward发送，避免两个任务同时写入打乱分片
+    let (control_tx, control_rx) = smol::channel::unbounded();
+    let mut reader = RtmpContext::new(stream);
+    reader.peer_addr = ctx.peer_addr.clone();
+    reader.chunk_size = ctx.chunk_size;
+    // 上游之后的fmt 1/2/3 chunk沿用connect等命令时的chunk stream状态
+    reader.chunk_streams = std::mem::take(&mut ctx.chunk_streams);
+    reader.recv_bytes_num = ctx.recv_bytes_num;
+    reader.last_ack_bytes_num = ctx.last_ack_bytes_num;
+    reader.ack_window_size = ctx.ack_window_size;
+    reader.peer_ack_window_size = ctx.peer_ack_window_size;
+    reader.peer_bandwidth_limit = ctx.peer_bandwidth_limit;
+    let read_upstream = async move {
+        loop {
+            let message = RtmpMessage::read_from(&mut reader).await?;
+            if let Some(ack) = reader.take_acknowledgement() {
+                control_tx.send(ack).await?;
+            }
+            match message.header.message_type {
+                ChunkMessageType::SetChunkSize if message.body.len() >= 4 => {
+                    reader.chunk_size = BigEndian::read_u32(&message.body);
+                }
+                ChunkMessageType::WindowAcknowledgementSize | ChunkMessageType::SetPeerBandwidth => {
+                    if let Some(reply) = reader.handle_window_message(&message) {
+                        control_tx.send(reply).await?;
+                    }
+                }
+                ChunkMessageType::AMF0CommandMessage => {
+                    if let Some(code) = message.try_read_body_to_amf0().as_deref().and_then(on_status_error) {
+                        return Err(anyhow::anyhow!("upstream onStatus error, code={}", code));
+                    }
+                }
+                _ => {}
+            }
+        }
+    };
+
+    smol::future::or(forward(&mut ctx, stream_name, stream_id, control_rx), read_upstream).await
+}
+
+/// 推流客户端，复用转推的握手和publish流程，用于集成测试等需要自己生成音视频的场景
+///
+/// 只发送不读取，服务器的控制消息留在TCP缓冲区，不适合长时间推流
+pub struct RtmpPublishClient {
+    ctx: RtmpContext,
+    stream_id: u32,
+}
+
+impl RtmpPublishClient {
+    /// 连接`rtmp://host[:port]/app/stream`并完成publish
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let push_url = PushUrl::parse(url)?;
+        let stream = TcpStream::connect(&push_url.addr).await?;
+        let mut ctx = RtmpContext::new(stream);
+        ctx.peer_addr = push_url.addr.clone();
+        client_handshake(&mut ctx).await?;
+        let stream_id = client_publish(&mut ctx, &push_url).await?;
+        Ok(Self { ctx, stream_id })
+    }
+
+    /// 发送一个消息，`body`为FLV tag的数据部分，onMetaData需要包含`@setDataFrame`
+    pub async fn send(&mut self, message_type: ChunkMessageType, timestamp: u32, body: Vec<u8>) -> anyhow::Result<()> {
+        send_message(&mut self.ctx, RtmpMessage::new(message_type, self.stream_id, timestamp, body)).await
+    }
+}
+
+/// 依次发送onMetaData、sequence header和GOP缓存，然后转发实时消息
+///
+/// `control_rx`是读取任务需要回复给上游的Acknowledgement等控制消息，在两个消息之间发送
+async fn forward(ctx: &mut RtmpContext, stream_name: &str, stream_id: u32, control_rx: Receiver<RtmpMessage>) -> anyhow::Result<()> {
+    let mut receiver = KeyFrameReceiver::subscribe(stream_name)
+        .ok_or_else(|| anyhow::anyhow!("not found stream {}", stream_name))?;
+
+    let meta_data = meta_data_map().get(stream_name).map(|x| x.value().clone());
+    if let Some(meta_data) = meta_data {
+        let mut body = vec![];
+        Value::String("@setDataFrame".to_string()).write_to(&mut body)?;
+        Value::String("onMetaData".to_string()).write_to(&mut body)?;
+        meta_data_to_amf0(&meta_data).write_to(&mut body)?;
+        send_message(ctx, RtmpMessage::new(ChunkMessageType::AMF0DataMessage, stream_id, 0, body)).await?;
+    }
+    let video_header = video_header_map().get(stream_name).map(|x| x.value().clone());
+    let audio_header = audio_header_map().get(stream_name).map(|x| x.value().clone());
+    for header in video_header.into_iter().chain(audio_header) {
+        send_message(ctx, RtmpMessage::new(header.header.message_type, stream_id, 0, header.body)).await?;
+    }
+
+    let mut first_timestamp = None;
+    while let Some(msg) = receiver.recv().await {
+        while let Ok(control) = control_rx.try_recv() {
+            send_message(ctx, control).await?;
+        }
+        let first = *first_timestamp.get_or_insert(msg.header.timestamp);
+        let mut header = msg.header.clone();
+        header.msid = stream_id;
+        header.timestamp = msg.header.timestamp.saturating_sub(first);
+        for chunk in RtmpMessage::split_body_into_chunks(&header, &msg.body, ctx.out_chunk_size) {
+            ctx.write_to_peer(&chunk).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 客户端握手，发送C0/C1，读取S0/S1/S2，回显S1作为C2
+async fn client_handshake(ctx: &mut RtmpContext) -> anyhow::Result<()> {
+    let c1 = Handshake1 {
+        time: 0,
+        zero: 0,
+        random_data: gen_random_bytes(1528),
+    };
+    let mut c0c1 = Handshake0::S0_V3.to_bytes();
+    c0c1.extend_from_slice(&c1.to_bytes());
+    ctx.write_to_peer(&c0c1).await?;
+
+    let s0 = ctx.read_exact_from_peer(1).await?[0];
+    if s0 != Handshake0::S0_V3.version {
+        log::warn!("[RtmpPush] S0, unexpected version={}, peer={}", s0, ctx.peer_addr);
+    }
+    let s1_vec = ctx.read_exact_from_peer(Handshake1::PACKET_LENGTH).await?;
+    let _s2_vec = ctx.read_exact_from_peer(Handshake2::PACKET_LENGTH).await?;
+
+    let c2 = Handshake2 {
+        time: BigEndian::read_u32(&s1_vec[0..4]),
+        time2: 0,
+        random_echo: s1_vec[8..].to_vec(),
+    };
+    ctx.write_to_peer(&c2.to_bytes()).await?;
+    Ok(())
+}
+
+/// 发送connect/releaseStream/FCPublish/createStream/publish，返回上游分配的stream id
+async fn client_publish(ctx: &mut RtmpContext, push_url: &PushUrl) -> anyhow::Result<u32> {
+    let set_chunk_size = RtmpMessage::new(ChunkMessageType::SetChunkSize, 0, 0, ctx.out_chunk_size.to_be_bytes().to_vec());
+    send_message(ctx, set_chunk_size).await?;
+
+    let command_object = Value::Object {
+        class_name: None,
+        entries: vec![
+            Pair { key: "app".to_owned(), value: Value::String(push_url.app.clone()) },
+            Pair { key: "type".to_owned(), value: Value::String("nonprivate".to_owned()) },
+            Pair { key: "flashVer".to_owned(), value: Value::String("FMLE/3.0 (compatible; river)".to_owned()) },
+            Pair { key: "tcUrl".to_owned(), value: Value::String(push_url.tc_url.clone()) },
+        ],
+    };
+    send_command(ctx, 0, vec![Value::String("connect".to_owned()), Value::Number(1.0), command_object]).await?;
+    wait_result(ctx, 1.0).await?;
+
+    let stream = Value::String(push_url.stream.clone());
+    send_command(ctx, 0, vec![Value::String("releaseStream".to_owned()), Value::Number(2.0), Value::Null, stream.clone()]).await?;
+    send_command(ctx, 0, vec![Value::String("FCPublish".to_owned()), Value::Number(3.0), Value::Null, stream.clone()]).await?;
+    send_command(ctx, 0, vec![Value::String("createStream".to_owned()), Value::Number(4.0), Value::Null]).await?;
+    let stream_id = wait_result(ctx, 4.0)
+        .await?
+        .get(3)
+        .and_then(|x| x.try_as_f64())
+        .ok_or_else(|| anyhow::anyhow!("createStream result without stream id"))? as u32;
+
+    send_command(
+        ctx,
+        stream_id,
+        vec![Value::String("publish".to_owned()), Value::Number(5.0), Value::Null, stream, Value::String("live".to_owned())],
+    )
+        .await?;
+    loop {
+        let values = read_command(ctx).await?;
+        if values.first().and_then(|x| x.try_as_str()) != Some("onStatus") {
+            continue;
+        }
+        if let Some(code) = on_status_error(&values) {
+            return Err(anyhow::anyhow!("publish rejected, code={}", code));
+        }
+        return Ok(stream_id);
+    }
+}
+
+/// 等待指定事务ID的`_result`，收到`_error`时返回Error
+async fn wait_result(ctx: &mut RtmpContext, transaction_id: f64) -> anyhow::Result<Vec<Value>> {
+    loop {
+        let values = read_command(ctx).await?;
+        let name = values.first().and_then(|x| x.try_as_str()).unwrap_or_default();
+        let id = values.get(1).and_then(|x| x.try_as_f64());
+        if id != Some(transaction_id) {
+            continue;
+        }
+        match name {
+            "_result" => return Ok(values),
+            "_error" => return Err(anyhow::anyhow!("command rejected, transaction_id={}, values={:?}", transaction_id, values)),
+            _ => {}
+        }
+    }
+}
+
+/// 读取下一个命令消息，期间处理SetChunkSize、WindowAcknowledgementSize和SetPeerBandwidth
+async fn read_command(ctx: &mut RtmpContext) -> anyhow::Result<Vec<Value>> {
+    loop {
+        let message = RtmpMessage::read_from(ctx).await?;
+        if let Some(ack) = ctx.take_acknowledgement() {
+            send_message(ctx, ack).await?;
+        }
+        match message.header.message_type {
+            ChunkMessageType::SetChunkSize if message.body.len() >= 4 => {
+                ctx.chunk_size = BigEndian::read_u32(&message.body);
+            }
+            ChunkMessageType::WindowAcknowledgementSize | ChunkMessageType::SetPeerBandwidth => {
+                if let Some(reply) = ctx.handle_window_message(&message) {
+                    send_message(ctx, reply).await?;
+                }
+            }
+            ChunkMessageType::AMF0CommandMessage | ChunkMessageType::AMF3CommandMessage => {
+                if let Some(values) = message.try_read_body_to_amf0() {
+                    return Ok(values);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// onStatus中level为error时返回code
+fn on_status_error(values: &[Value]) -> Option<String> {
+    if values.first().and_then(|x| x.try_as_str()) != Some("onStatus") {
+        return None;
+    }
+    let info = values.get(3).cloned()?.try_into_pairs().ok()?.collect::<Vec<(String, amf::Value)>>();
+    let get_field = |key: &str| info.iter().find(|(k, _)| k == key).and_then(|(_, v)| v.try_as_str());
+    if get_field("level") == Some("error") {
+        Some(get_field("code").unwrap_or_default().to_string())
+    } else {
+        None
+    }
+}
+
+async fn send_command(ctx: &mut RtmpContext, msid: u32, values: Vec<Value>) -> anyhow::Result<()> {
+    let mut body = vec![];
+    for value in values {
+        value.write_to(&mut body)?;
+    }
+    send_message(ctx, RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, msid, 0, body)).await
+}
+
+async fn send_message(ctx: &mut RtmpContext, message: RtmpMessage) -> anyhow::Result<()> {
+    for chunk in message.split_chunks_bytes(ctx.out_chunk_size) {
+        ctx.write_to_peer(&chunk).await?;
+    }
+    Ok(())
+}
+
+/// `rtmp://host[:port]/app/stream`，app可以包含多级路径，最后一级为流名称
+struct PushUrl {
+    addr: String,
+    app: String,
+    stream: String,
+    tc_url: String,
+}
+
+impl PushUrl {
+    const DEFAULT_PORT: u16 = 1935;
+
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("rtmp://")
+            .ok_or_else(|| anyhow::anyhow!("only rtmp:// is supported, url={}", url))?;
+        let (host, path) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("missing app, url={}", url))?;
+        let (app, stream) = path
+            .rsplit_once('/')
+            .filter(|(app, stream)| !app.is_empty() && !stream.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("expect rtmp://host/app/stream, url={}", url))?;
+        // 没有端口时使用默认端口，`[::1]`这样的IPv6地址需要判断方括号之后的部分
+        let has_port = host.rsplit_once(']').map(|(_, x)| x).unwrap_or(host).contains(':');
+        let addr = if has_port {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, PushUrl::DEFAULT_PORT)
+        };
+        Ok(Self {
+            addr,
+            app: app.to_string(),
+            stream: stream.to_string(),
+            tc_url: format!("rtmp://{}/{}", host, app),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_push_url() {
+        let url = PushUrl::parse("rtmp://example.com/live/test").unwrap();
+        assert_eq!(url.addr, "example.com:1935");
+        assert_eq!(url.app, "live");
+        assert_eq!(url.stream, "test");
+        assert_eq!(url.tc_url, "rtmp://example.com/live");
+
+        let url = PushUrl::parse("rtmp://[::1]:1936/live/test").unwrap();
+        assert_eq!(url.addr, "[::1]:1936");
+        assert_eq!(url.tc_url, "rtmp://[::1]:1936/live");
+        assert_eq!(PushUrl::parse("rtmp://[::1]/live/test").unwrap().addr, "[::1]:1935");
+
+        // 多级app，最后一级为流名称
+        let url = PushUrl::parse("rtmp://127.0.0.1:1937/app/sub/test").unwrap();
+        assert_eq!(url.addr, "127.0.0.1:1937");
+        assert_eq!(url.app, "app/sub");
+        assert_eq!(url.stream, "test");
+        assert_eq!(url.tc_url, "rtmp://127.0.0.1:1937/app/sub");
+
+        assert!(PushUrl::parse("http://example.com/live/test").is_err());
+        assert!(PushUrl::parse("rtmp://example.com").is_err());
+        assert!(PushUrl::parse("rtmp://example.com/live").is_err());
+        assert!(PushUrl::parse("rtmp://example.com/live/").is_err());
+    }
+
+    #[test]
+    fn parse_push_rule() {
+        let rule: PushRule = "/live/test/=rtmp://example.com/live/test".parse().unwrap();
+        assert_eq!(rule.stream_name, "live/test");
+        assert_eq!(rule.url, "rtmp://example.com/live/test");
+        // url中可以包含`=`
+        let rule: PushRule = "live/test=rtmp://example.com/live/test?key=abc".parse().unwrap();
+        assert_eq!(rule.url, "rtmp://example.com/live/test?key=abc");
+
+        assert!("live/test".parse::<PushRule>().is_err());
+        assert!("live/test=rtmp://example.com/live".parse::<PushRule>().is_err());
+    }
+}
@@ -4,120 +4,655 @@ use byteorder::{BigEndian, ByteOrder};
 use chrono::Local;
 use dashmap::DashMap;
 use once_cell::sync::OnceCell;
-use smol::net::{TcpListener, TcpStream};
+use smol::net::SocketAddr;
 use smol::prelude::*;
 
-use crate::eventbus::EventBus;
+use crate::access_log::AccessSession;
+use crate::eventbus::{EventBus, LatestReceiver};
+use crate::protocol::aac::AudioCodec;
+use crate::protocol::h264::Nalu;
+use crate::protocol::handshake;
+use crate::protocol::transport::Transport;
 use crate::protocol::rtmp::{
-    ChunkMessageType, Handshake0, Handshake1, Handshake2, RtmpContext, RtmpMessage, RtmpMetaData,
+    parse_app_from_tc_url, ChunkMessageType, Handshake0, Handshake1, Handshake2, PlayArgs, RtmpConfig, RtmpContext, RtmpMessage,
+    RtmpMetaData,
 };
-use crate::util::{bytes_hex_format, gen_random_bytes, print_hex, spawn_and_log_error};
+use crate::util::{bind_tcp, configured_server_name, gen_random_bytes, print_hex, spawn_and_log_error};
 use std::convert::TryFrom;
-use crate::protocol::fmp4::save_fmp4_background;
+use crate::metrics::{metrics, ViewerStats};
+use crate::idle;
+use crate::naming;
+use crate::pacer::{PacedOutput, Pacer};
+use crate::rate_limit::{FrameDecimator, RateLimiter};
+use crate::recording;
+use crate::rtmp_push::start_push;
+use crate::transcode::start_transcode;
+use smol::channel::{Receiver, Sender};
+use smol::Timer;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn eventbus_map() -> &'static DashMap<String, EventBus<RtmpMessage>> {
-    static INSTANCE: OnceCell<DashMap<String, EventBus<RtmpMessage>>> = OnceCell::new();
-    INSTANCE.get_or_init(|| DashMap::new())
+/// 推流者的消息通过`Arc`分发，所有播放者共享同一份数据
+pub fn eventbus_map() -> &'static DashMap<String, EventBus<Arc<RtmpMessage>>> {
+    static INSTANCE: OnceCell<DashMap<String, EventBus<Arc<RtmpMessage>>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
 }
 
 pub fn video_header_map() -> &'static DashMap<String, RtmpMessage> {
     static INSTANCE: OnceCell<DashMap<String, RtmpMessage>> = OnceCell::new();
-    INSTANCE.get_or_init(|| DashMap::new())
+    INSTANCE.get_or_init(DashMap::new)
 }
 
 pub fn audio_header_map() -> &'static DashMap<String, RtmpMessage> {
     static INSTANCE: OnceCell<DashMap<String, RtmpMessage>> = OnceCell::new();
-    INSTANCE.get_or_init(|| DashMap::new())
+    INSTANCE.get_or_init(DashMap::new)
 }
 
 pub fn meta_data_map() -> &'static DashMap<String, RtmpMetaData> {
     static INSTANCE: OnceCell<DashMap<String, RtmpMetaData>> = OnceCell::new();
-    INSTANCE.get_or_init(|| DashMap::new())
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 每个流从最近一个关键帧开始的音视频消息，新加入的播放者可以立即解码
+pub fn gop_cache_map() -> &'static DashMap<String, Vec<Arc<RtmpMessage>>> {
+    static INSTANCE: OnceCell<DashMap<String, Vec<Arc<RtmpMessage>>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+static PUBLISHER_WAIT_TIMEOUT: OnceCell<Option<Duration>> = OnceCell::new();
+
+/// 启动时设置播放者等待推流者的时间，None表示不等待，流不存在时直接返回404
+pub fn init_publisher_wait_timeout(timeout: Option<Duration>) {
+    if PUBLISHER_WAIT_TIMEOUT.set(timeout).is_err() {
+        log::warn!("publisher wait timeout has been initialized");
+    }
+}
+
+static MONOTONIC_TIMESTAMPS: OnceCell<bool> = OnceCell::new();
+
+/// 启动时设置是否修正推流端倒退的时间戳，`--monotonic-timestamps`
+pub fn init_monotonic_timestamps(enabled: bool) {
+    if MONOTONIC_TIMESTAMPS.set(enabled).is_err() {
+        log::warn!("monotonic timestamps has been initialized");
+    }
+}
+
+fn monotonic_timestamps() -> bool {
+    MONOTONIC_TIMESTAMPS.get().copied().unwrap_or(false)
+}
+
+/// 每个流的视频和音频上一帧的时间戳，开启`--monotonic-timestamps`时使用
+pub fn last_timestamp_map() -> &'static DashMap<String, LastTimestamp> {
+    static INSTANCE: OnceCell<DashMap<String, LastTimestamp>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 音视频分别判断，两路交错时音频比视频早几毫秒是正常的
+#[derive(Debug, Default)]
+pub struct LastTimestamp {
+    video: Option<u32>,
+    audio: Option<u32>,
+}
+
+impl LastTimestamp {
+    /// 比上一帧小时返回上一帧加1毫秒，32位回绕按前进处理
+    fn correct(&mut self, message_type: ChunkMessageType, timestamp: u32) -> Option<u32> {
+        let last = match message_type {
+            ChunkMessageType::VideoMessage => &mut self.video,
+            ChunkMessageType::AudioMessage => &mut self.audio,
+            _ => return None,
+        };
+        let corrected = last.filter(|x| (timestamp.wrapping_sub(*x) as i32) < 0).map(|x| x.wrapping_add(1));
+        *last = Some(corrected.unwrap_or(timestamp));
+        corrected
+    }
+}
+
+/// 等待流就绪的播放者，收到第一个关键帧时唤醒
+fn stream_waiters_map() -> &'static DashMap<String, Vec<Sender<()>>> {
+    static INSTANCE: OnceCell<DashMap<String, Vec<Sender<()>>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 播放者在推流者之前连接时，等待推流者开始推流并且收到关键帧，超时或者没有开启等待时返回false
+///
+/// 流已经存在时立即返回true
+pub async fn wait_for_publisher(stream_name: &str) -> bool {
+    wait_for_publisher_timeout(stream_name, PUBLISHER_WAIT_TIMEOUT.get().copied().flatten()).await
+}
+
+/// 按指定的等待时间等待推流者，None表示不等待
+async fn wait_for_publisher_timeout(stream_name: &str, timeout: Option<Duration>) -> bool {
+    if eventbus_map().contains_key(stream_name) {
+        return true;
+    }
+    // 配置了占位画面时先播放占位画面，推流者开始推流后切换
+    if idle::start(stream_name) {
+        return true;
+    }
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return false,
+    };
+
+    let (tx, rx) = smol::channel::bounded(1);
+    stream_waiters_map().entry(stream_name.to_string()).or_default().push(tx);
+    // 注册之后再检查一次，避免错过通知
+    if gop_cache_map().contains_key(stream_name) {
+        return true;
+    }
+    log::info!("wait for publisher, stream_name={}, timeout={:?}", stream_name, timeout);
+    let ready = async { rx.recv().await.is_ok() };
+    let timer = async {
+        Timer::after(timeout).await;
+        false
+    };
+    let ready = smol::future::or(ready, timer).await;
+    drop(rx);
+    // 清理超时的等待者
+    if let Some(mut waiters) = stream_waiters_map().get_mut(stream_name) {
+        waiters.retain(|x| !x.is_closed());
+    }
+    stream_waiters_map().remove_if(stream_name, |_, waiters| waiters.is_empty());
+    ready
+}
+
+fn notify_stream_waiters(stream_name: &str) {
+    if let Some((_, waiters)) = stream_waiters_map().remove(stream_name) {
+        log::info!("stream is ready, wake up {} viewers, stream_name={}", waiters.len(), stream_name);
+        for tx in waiters {
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+/// 推流连接的断开标记，和`RtmpContext::drop_signal`是同一个
+pub fn drop_signal_map() -> &'static DashMap<String, Arc<AtomicBool>> {
+    static INSTANCE: OnceCell<DashMap<String, Arc<AtomicBool>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 推流连接的id，统计接口中和日志关联
+pub fn publisher_conn_map() -> &'static DashMap<String, u64> {
+    static INSTANCE: OnceCell<DashMap<String, u64>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 强制断开RTMP推流者，eventbus随之移除，所有播放者也会结束
+///
+/// 推流者在下一个消息到达时断开，流不存在或者不是RTMP推流时返回false
+pub fn drop_publisher(stream_name: &str) -> bool {
+    match drop_signal_map().get(stream_name) {
+        Some(signal) => {
+            signal.store(true, Ordering::Relaxed);
+            log::warn!("drop publisher, stream_name={}", stream_name);
+            true
+        }
+        None => false,
+    }
+}
+
+/// 等待接管的流，value是断开的推流者的drop_signal，用来区分多次断开
+fn takeover_map() -> &'static DashMap<String, Arc<AtomicBool>> {
+    static INSTANCE: OnceCell<DashMap<String, Arc<AtomicBool>>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+/// 推流者断开之后保留eventbus，超时没有被接管时移除，播放者随之结束
+pub(crate) fn wait_for_takeover(stream_name: &str, drop_signal: Arc<AtomicBool>, grace: Duration) {
+    log::info!("wait {:?} for takeover, stream_name={}", grace, stream_name);
+    takeover_map().insert(stream_name.to_string(), drop_signal.clone());
+    let stream_name = stream_name.to_string();
+    smol::spawn(async move {
+        Timer::after(grace).await;
+        if takeover_map().remove_if(&stream_name, |_, x| Arc::ptr_eq(x, &drop_signal)).is_some() {
+            eventbus_map().remove(&stream_name);
+            log::warn!("no publisher took over, remove eventbus, stream_name={}", stream_name);
+        }
+    })
+        .detach();
+}
+
+/// 开启接管时，同名的RTMP推流者还在或者刚断开，新的推流者沿用eventbus，播放者不会断开
+///
+/// 仍然连接的旧推流者在下一个消息到达时断开
+fn take_over(stream_name: &str) -> bool {
+    let waiting = takeover_map().remove(stream_name).is_some();
+    let connected = match drop_signal_map().get(stream_name) {
+        Some(signal) => {
+            signal.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    };
+    (waiting || connected) && eventbus_map().contains_key(stream_name)
+}
+
+/// GOP缓存的消息数上限，超过后丢弃缓存，等待下一个关键帧
+const GOP_CACHE_MAX_LEN: usize = 1024;
+
+/// 每个流最近一个关键帧的时刻
+pub fn key_frame_tracker_map() -> &'static DashMap<String, KeyFrameTracker> {
+    static INSTANCE: OnceCell<DashMap<String, KeyFrameTracker>> = OnceCell::new();
+    INSTANCE.get_or_init(DashMap::new)
+}
+
+static KEY_FRAME_WARN_INTERVAL: OnceCell<Option<Duration>> = OnceCell::new();
+
+/// 启动时设置关键帧间隔的告警阈值，None表示不告警
+pub fn init_key_frame_warn_interval(interval: Option<Duration>) {
+    if KEY_FRAME_WARN_INTERVAL.set(interval).is_err() {
+        log::warn!("key frame warn interval has been initialized");
+    }
+}
+
+fn key_frame_warn_interval() -> Option<Duration> {
+    KEY_FRAME_WARN_INTERVAL.get().copied().unwrap_or(Some(Duration::from_secs(10)))
+}
+
+/// 跟踪推流端的关键帧间隔，GOP太长时新加入的播放者需要等很久才能看到画面
+pub struct KeyFrameTracker {
+    /// 最近一个关键帧的时刻，还没有关键帧时是第一个视频帧的时刻
+    last_key_frame: Instant,
+    has_key_frame: bool,
+    /// 本次超时是否已经告警过，收到关键帧后重置
+    warned: bool,
+}
+
+impl KeyFrameTracker {
+    fn new() -> Self {
+        Self { last_key_frame: Instant::now(), has_key_frame: false, warned: false }
+    }
+
+    /// 距离最近一个关键帧的时间
+    pub fn elapsed(&self) -> Duration {
+        self.last_key_frame.elapsed()
+    }
+
+    pub fn has_key_frame(&self) -> bool {
+        self.has_key_frame
+    }
+
+    /// 是否超过告警阈值没有收到关键帧
+    pub fn is_overdue(&self) -> bool {
+        key_frame_warn_interval().map(|x| self.elapsed() > x).unwrap_or(false)
+    }
+}
+
+/// RTMP播放者的输出时间戳，从第一个消息开始为0
+///
+/// 推流者重连接管或者占位画面交出时，新的推流者先发送sequence header，时间戳从它开始重新计算，
+/// 接着之前的输出时间戳继续，不会回退或者溢出
+#[derive(Debug, Default)]
+struct PlayTimestamp {
+    /// 当前推流者的第一个时间戳和当时的输出时间戳
+    base: Option<(u32, u32)>,
+    last: u32,
+}
+
+impl PlayTimestamp {
+    fn rebase(&mut self, msg: &RtmpMessage) -> u32 {
+        if msg.is_sequence_header() {
+            self.base = None;
+        }
+        let (first, offset) = *self.base.get_or_insert((msg.header.timestamp, self.last));
+        self.last = offset.wrapping_add(msg.header.timestamp.saturating_sub(first));
+        self.last
+    }
+}
+
+/// 从最近的关键帧开始接收推流消息
+///
+/// 先输出GOP缓存，缓存为空时跳过第一个关键帧之前的消息，所有播放输出共用
+pub struct KeyFrameReceiver {
+    cached: VecDeque<Arc<RtmpMessage>>,
+    /// 注册接收者之后才分发的缓存消息会在rx中再出现一次，需要去重
+    duplicated: Vec<Arc<RtmpMessage>>,
+    rx: MessageSource,
+    found_key_frame: bool,
+    /// 等待关键帧或者限速时跳过的消息数
+    dropped: u64,
+    limiter: Option<RateLimiter>,
+    decimator: Option<FrameDecimator>,
+}
+
+/// 实时消息的来源
+enum MessageSource {
+    /// 按顺序接收所有消息
+    All(Receiver<Arc<RtmpMessage>>),
+    /// 读取跟不上时只接收最近的关键帧和每种消息最新的一条
+    Latest(LatestReceiver<Arc<RtmpMessage>>),
+}
+
+impl MessageSource {
+    async fn recv(&self) -> Option<Arc<RtmpMessage>> {
+        match self {
+            MessageSource::All(rx) => rx.recv().await.ok(),
+            MessageSource::Latest(rx) => rx.recv().await,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            MessageSource::All(rx) => rx.len(),
+            MessageSource::Latest(rx) => rx.len(),
+        }
+    }
+}
+
+/// 只保留最新消息的接收者需要保留的关键帧，sequence header除外
+fn is_latest_key_frame(msg: &Arc<RtmpMessage>) -> bool {
+    msg.is_video_key_frame()
+}
+
+/// 只保留最新消息的接收者按消息类型区分轨道，sequence header单独一个轨道，不会被之后的帧覆盖
+fn latest_track(msg: &Arc<RtmpMessage>) -> u8 {
+    msg.header.message_type_id | if msg.is_sequence_header() { 0x80 } else { 0 }
+}
+
+impl KeyFrameReceiver {
+    /// 流不存在时返回None
+    pub fn subscribe(stream_name: &str) -> Option<Self> {
+        Self::register(stream_name, false)
+    }
+
+    /// 读取跟不上时跳到最近的关键帧和每种消息最新的一条，不会积压，播放请求中指定了`?latest=1`时使用
+    pub fn subscribe_latest(stream_name: &str) -> Option<Self> {
+        Self::register(stream_name, true)
+    }
+
+    fn register(stream_name: &str, latest: bool) -> Option<Self> {
+        // 持有缓存的读锁，保证注册接收者和读取缓存之间缓存不会被替换
+        let cache = gop_cache_map().get(stream_name);
+        let eventbus = eventbus_map().get(stream_name)?;
+        let rx = if latest {
+            MessageSource::Latest(eventbus.register_latest_receiver(is_latest_key_frame, latest_track))
+        } else {
+            MessageSource::All(eventbus.register_receiver())
+        };
+        let cached: Vec<Arc<RtmpMessage>> = cache.map(|x| x.value().clone()).unwrap_or_default();
+
+        Some(Self {
+            found_key_frame: !cached.is_empty(),
+            duplicated: cached.clone(),
+            cached: cached.into(),
+            rx,
+            dropped: 0,
+            limiter: None,
+            decimator: None,
+        })
+    }
+
+    /// 按`--viewer-max-kbps`限速，播放输出使用
+    pub fn rate_limited(mut self) -> Self {
+        self.limiter = RateLimiter::for_viewer();
+        self
+    }
+
+    /// 播放请求中指定了`?decimate=N`时降低帧率
+    pub fn decimated(mut self, decimator: Option<FrameDecimator>) -> Self {
+        self.decimator = decimator;
+        self
+    }
+
+    pub async fn recv(&mut self) -> Option<Arc<RtmpMessage>> {
+        while let Some(msg) = self.cached.pop_front() {
+            if self.admit_by_decimator(&msg) {
+                return Some(msg);
+            }
+        }
+        while let Some(msg) = self.rx.recv().await {
+            if !self.duplicated.is_empty() {
+                if self.duplicated.iter().any(|x| Arc::ptr_eq(x, &msg)) {
+                    continue;
+                }
+                self.duplicated.clear();
+            }
+            // 第一个关键帧之前的sequence header需要保留
+            if !self.found_key_frame && !msg.is_sequence_header() {
+                if !msg.is_video_key_frame() {
+                    self.dropped += 1;
+                    continue;
+                }
+                self.found_key_frame = true;
+            }
+            if !self.admit_by_decimator(&msg) {
+                continue;
+            }
+            if let Some(limiter) = &mut self.limiter {
+                if !limiter.admit(&msg, self.rx.len()).await {
+                    self.dropped += 1;
+                    continue;
+                }
+            }
+            return Some(msg);
+        }
+        None
+    }
+
+    fn admit_by_decimator(&mut self, msg: &RtmpMessage) -> bool {
+        self.decimator.as_mut().map(|x| x.admit(msg)).unwrap_or(true)
+    }
+
+    /// 尚未读取的实时消息数量
+    pub fn backlog(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// 更新播放者的积压和丢弃数量
+    pub fn update_stats(&self, stats: &ViewerStats) {
+        stats.queued.store(self.backlog() as u64);
+        stats.dropped.store(self.dropped);
+    }
+}
+
+/// 在程序内订阅一个流的实时消息，不需要建立网络连接，可用于嵌入和集成测试
+///
+/// 只接收订阅之后分发的消息，不包含sequence header和GOP缓存，需要从关键帧开始时使用[`KeyFrameReceiver`]。
+/// 流不存在时返回None，drop接收者即取消订阅。
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use river::publisher::StreamPublisher;
+/// use river::rtmp_server;
+///
+/// let publisher = StreamPublisher::create("live/camera")?;
+/// let rx = rtmp_server::subscribe(publisher.stream_name()).unwrap();
+/// while let Ok(msg) = rx.recv().await {
+///     println!("{:?} {} bytes", msg.header.message_type, msg.body.len());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn subscribe(stream_name: &str) -> Option<Receiver<Arc<RtmpMessage>>> {
+    Some(eventbus_map().get(stream_name)?.register_receiver())
+}
+
+/// 订阅一个流，读取跟不上时跳到最近的关键帧和每种消息最新的一条，不会积压，适合监控墙等可以丢帧的场景
+///
+/// 和[`subscribe`]一样不包含sequence header，流不存在时返回None
+pub fn subscribe_latest(stream_name: &str) -> Option<impl Stream<Item = Arc<RtmpMessage>>> {
+    let receiver = eventbus_map().get(stream_name)?.register_latest_receiver(is_latest_key_frame, latest_track);
+    Some(receiver.into_stream())
+}
+
+/// 当前正在推流的流名称，按名称排序
+pub fn list_streams() -> Vec<String> {
+    let mut streams: Vec<String> = eventbus_map().iter().map(|x| x.key().clone()).collect();
+    streams.sort();
+    streams
+}
+
+static RTMP_LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// RTMP端口是否已经监听成功，`/readyz`使用
+pub fn is_listening() -> bool {
+    RTMP_LISTENING.load(Ordering::Relaxed)
 }
 
 /// TCP 连接处理
-pub async fn accept_loop(addr: &str) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(addr.clone()).await?;
+pub async fn accept_loop(addr: SocketAddr, config: RtmpConfig) -> anyhow::Result<()> {
+    let listener = bind_tcp(addr)?;
+    RTMP_LISTENING.store(true, Ordering::Relaxed);
     log::info!("RTMP Server is listening to {}", addr);
 
     let mut incoming = listener.incoming();
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
-        log::info!("new connection: {}", stream.peer_addr()?);
-        spawn_and_log_error(connection_loop(stream));
+        spawn_and_log_error(connection_loop(stream, config.clone()));
     }
     Ok(())
 }
 
-async fn connection_loop(stream: TcpStream) -> anyhow::Result<()> {
-    let mut ctx = RtmpContext::new(stream);
+/// Unix domain socket连接处理，例如由另一个进程终结TLS之后转发RTMP
+///
+/// 路径上已经存在的socket文件是上次运行留下的，先删除再监听
+#[cfg(unix)]
+pub async fn accept_loop_unix(path: &std::path::Path, config: RtmpConfig) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).map(|x| x.file_type().is_socket()).unwrap_or(false) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = smol::net::unix::UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("failed to bind unix socket {}, {}", path.display(), e))?;
+    RTMP_LISTENING.store(true, Ordering::Relaxed);
+    log::info!("RTMP Server is listening to unix:{}", path.display());
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        spawn_and_log_error(connection_loop(stream, config.clone()));
+    }
+    Ok(())
+}
+
+async fn connection_loop(stream: impl Transport, config: RtmpConfig) -> anyhow::Result<()> {
+    let mut ctx = RtmpContext::with_config(stream, &config);
+    log::info!("[conn={}][peer={}] new connection", ctx.conn_id, ctx.peer_addr);
+
+    let handshake_begin = Instant::now();
+    if !handle_rtmp_handshake(&mut ctx).await? {
+        return Ok(());
+    }
+    metrics().observe_handshake(handshake_begin.elapsed());
 
-    handle_rtmp_handshake(&mut ctx).await?;
+    let result = message_loop(&mut ctx).await;
+    if result.is_err() && ctx.is_publisher {
+        metrics().inc_publish_errors();
+    }
+    result
+}
 
+async fn message_loop(ctx: &mut RtmpContext) -> anyhow::Result<()> {
     loop {
-        let message = RtmpMessage::read_from(&mut ctx).await?;
-        log::debug!(
-            "[peer={}] C->S, [{}] csid={}, msid={}",
+        let recv_bytes_before = ctx.recv_bytes_num;
+        let message = RtmpMessage::read_from(ctx).await?;
+        if ctx.drop_signal.load(Ordering::Relaxed) {
+            log::warn!("[conn={}][peer={}] publisher dropped, stream_name={}", ctx.conn_id, ctx.peer_addr, ctx.stream_name);
+            return Ok(());
+        }
+        if ctx.is_publisher {
+            let bytes_num = ctx.recv_bytes_num.wrapping_sub(recv_bytes_before);
+            metrics().add_bytes_received(&ctx.stream_name, bytes_num as u64);
+            if let Some(access) = &ctx.access {
+                access.bytes.fetch_add(bytes_num as u64);
+            }
+        }
+        if ctx.should_send_ack() {
+            send_acknowledgement(ctx).await?;
+        }
+        log::trace!(
+            "[conn={}][peer={}] C->S, [{}] csid={}, msid={}",
+            ctx.conn_id,
             ctx.peer_addr,
             message.message_type_desc(),
             &message.header.csid,
             &message.header.msid
         );
         match message.header.message_type {
-            ChunkMessageType::SetChunkSize => {
+            ChunkMessageType::SetChunkSize if message.body.len() >= 4 => {
                 ctx.chunk_size = BigEndian::read_u32(&message.body);
                 log::info!(
-                    "[peer={}] C->S, [{}] value={}",
+                    "[conn={}][peer={}] C->S, [{}] value={}",
+                    ctx.conn_id,
                     ctx.peer_addr,
                     message.message_type_desc(),
                     &ctx.chunk_size
                 );
             }
-            ChunkMessageType::UserControlMessage => {
+            ChunkMessageType::WindowAcknowledgementSize | ChunkMessageType::SetPeerBandwidth => {
+                if let Some(reply) = ctx.handle_window_message(&message) {
+                    for chunk in reply.split_chunks_bytes(ctx.out_chunk_size) {
+                        ctx.write_to_peer(&chunk).await?;
+                    }
+                }
+            }
+            ChunkMessageType::AbortMessage if message.body.len() >= 4 => {
+                let csid = BigEndian::read_u32(&message.body);
+                let discarded = ctx.abort_chunk_stream(csid);
+                log::info!(
+                    "[conn={}][peer={}] C->S, [{}] csid={}, discarded={}",
+                    ctx.conn_id,
+                    ctx.peer_addr,
+                    message.message_type_desc(),
+                    csid,
+                    discarded
+                );
+            }
+            ChunkMessageType::UserControlMessage if message.body.len() >= 2 => {
                 let bytes = &message.body;
                 let event_type = BigEndian::read_u16(&bytes[0..2]);
                 // set buffer length
-                if event_type == 3 {
+                if event_type == 3 && bytes.len() >= 10 {
                     // 等于 create_stream 应答中第4个字段值
                     let stream_id = BigEndian::read_u32(&bytes[2..6]);
                     let buffer_length = BigEndian::read_u32(&bytes[6..10]);
                     log::info!(
-                        "[peer={}] C->S, [{}] set buffer length={}, streamId={}",
+                        "[conn={}][peer={}] C->S, [{}] set buffer length={}, streamId={}",
+                        ctx.conn_id,
                         ctx.peer_addr,
                         message.message_type_desc(),
                         buffer_length,
                         stream_id
                     );
-                    response_play(&mut ctx, stream_id).await?;
-
-                    if let Some(el) = meta_data_map().get(&ctx.stream_name) {
-                        send_meta_data_for_play(&mut ctx, el.value()).await?;
-                    } else {
-                        log::warn!(
-                            "[peer={}] not found meta_data, stream_name={}",
-                            ctx.peer_addr,
-                            ctx.stream_name
-                        );
-                        continue;
+                    // 没有推流时告诉播放器流不存在，之后断开，否则播放器会一直等待
+                    let meta_data = meta_data_map().get(&ctx.stream_name).map(|x| x.value().clone());
+                    let meta_data = match meta_data {
+                        Some(meta_data) if eventbus_map().contains_key(&ctx.stream_name) => meta_data,
+                        _ => {
+                            log::warn!(
+                                "[conn={}][peer={}] not found meta_data, stream_name={}",
+                                ctx.conn_id,
+                                ctx.peer_addr,
+                                ctx.stream_name
+                            );
+                            let description = format!("stream {} is not found", ctx.stream_name);
+                            send_on_status(ctx, "error", "NetStream.Play.StreamNotFound", &description).await?;
+                            return Ok(());
+                        }
                     };
+                    response_play(ctx, stream_id).await?;
+                    send_meta_data_for_play(ctx, &meta_data).await?;
 
                     ctx.ctx_begin_timestamp = Local::now().timestamp_millis();
-                    let src_begin_timestamp = meta_data_map()
-                        .get(&ctx.stream_name)
-                        .map(|x| x.begin_time)
-                        .unwrap_or(ctx.ctx_begin_timestamp);
-                    let begin_time_delta = (ctx.ctx_begin_timestamp - src_begin_timestamp + 1000) as u32;
-                    log::info!("[RTMP] begin_time_delta={}", begin_time_delta);
 
                     // 发送sps/pps帧
                     if let Some(msg) = video_header_map().get(&ctx.stream_name) {
-                        let chunks = msg.split_chunks_bytes(ctx.chunk_size);
+                        let chunks = msg.split_chunks_bytes(ctx.out_chunk_size);
                         for chunk in chunks {
                             ctx.write_to_peer(&chunk).await?;
                         }
                     } else {
                         log::warn!(
-                            "[peer={}] not found video header, stream_name={}",
+                            "[conn={}][peer={}] not found video header, stream_name={}",
+                            ctx.conn_id,
                             ctx.peer_addr,
                             ctx.stream_name
                         );
@@ -125,151 +660,298 @@ async fn connection_loop(stream: TcpStream) -> anyhow::Result<()> {
 
                     // 发送 aac header
                     if let Some(msg) = audio_header_map().get(&ctx.stream_name) {
-                        let chunks = msg.split_chunks_bytes(ctx.chunk_size);
+                        let chunks = msg.split_chunks_bytes(ctx.out_chunk_size);
                         for chunk in chunks {
                             ctx.write_to_peer(&chunk).await?;
                         }
                     } else {
                         log::warn!(
-                            "[peer={}] not found audio header, stream_name={}",
+                            "[conn={}][peer={}] not found audio header, stream_name={}",
+                            ctx.conn_id,
                             ctx.peer_addr,
                             ctx.stream_name
                         );
                     };
 
-                    if let Some(eventbus) = eventbus_map().get(&ctx.stream_name) {
-                        let receiver = eventbus.register_receiver();
-                        while let Ok(mut msg) = receiver.recv().await {
-                            msg.header.timestamp = msg.header.timestamp - begin_time_delta;
-                            let chunks = msg.split_chunks_bytes(ctx.chunk_size);
+                    // 从GOP缓存开始发送，避免中途加入时花屏
+                    if let Some(mut receiver) = KeyFrameReceiver::subscribe(&ctx.stream_name).map(|x| x.rate_limited()) {
+                        // play命令时已经开始记录访问日志，断开时随统计一起结束
+                        let access = ctx.access.take().unwrap_or_else(|| {
+                            AccessSession::start("play", "rtmp", ctx.conn_id, &ctx.stream_name, &ctx.peer_addr)
+                        });
+                        let viewer = metrics().track_viewer(ctx.conn_id, &ctx.stream_name, &ctx.peer_addr, "rtmp", access);
+                        let mut pacer = Pacer::for_output(PacedOutput::Rtmp);
+                        let mut play_timestamp = PlayTimestamp::default();
+                        while let Some(msg) = receiver.recv().await {
+                            receiver.update_stats(&viewer);
+                            pacer.wait(msg.header.timestamp).await;
+                            let mut header = msg.header.clone();
+                            header.timestamp = play_timestamp.rebase(&msg);
+                            let chunks = RtmpMessage::split_body_into_chunks(&header, &msg.body, ctx.out_chunk_size);
                             for chunk in chunks {
                                 ctx.write_to_peer(&chunk).await?;
+                                viewer.bytes_sent.fetch_add(chunk.len() as u64);
                             }
                         }
+                        // 推流结束，通知播放器之后断开
+                        log::info!("[conn={}][peer={}] publisher stopped, stream_name={}", ctx.conn_id, ctx.peer_addr, ctx.stream_name);
+                        send_on_status(ctx, "status", "NetStream.Play.UnpublishNotify", "stream is unpublished").await?;
+                        send_stream_eof(ctx, stream_id).await?;
+                        return Ok(());
                     } else {
+                        // 推流者在发送meta_data之后刚好结束
                         log::error!(
-                            "[peer={}] not found eventbus, stream_name={}",
+                            "[conn={}][peer={}] not found eventbus, stream_name={}",
+                            ctx.conn_id,
                             ctx.peer_addr,
                             ctx.stream_name
                         );
+                        let description = format!("stream {} is not found", ctx.stream_name);
+                        send_on_status(ctx, "error", "NetStream.Play.StreamNotFound", &description).await?;
                         Err(anyhow::anyhow!("not found stream {}", ctx.stream_name))?;
                     }
+                } else if event_type == 6 && bytes.len() >= 6 {
+                    // PingRequest，原样带回时间戳，部分客户端收不到PingResponse会断开
+                    let timestamp = BigEndian::read_u32(&bytes[2..6]);
+                    send_ping_response(ctx, timestamp).await?;
                 } else {
                     log::info!(
-                        "[peer={}] C->S, [{}] \n{}",
+                        "[conn={}][peer={}] C->S, [{}] len={}",
+                        ctx.conn_id,
                         ctx.peer_addr,
                         message.message_type_desc(),
-                        bytes_hex_format(&message.body)
+                        message.body.len()
                     );
+                    print_hex(&message.body);
                 }
             }
-            ChunkMessageType::AMF0CommandMessage => {
-                let option = message.try_read_body_to_amf0();
-                if option.is_none() {
-                    log::error!(
-                        "[peer={}] C->S, expect AMF0 data, ctx={:#?} \n msg={:#?}",
-                        ctx.peer_addr,
-                        &ctx,
-                        &message
-                    );
-                    Err(anyhow::anyhow!("[AMF0CommandMessage] expect AMF0 data"))?
-                }
-                let values = option.unwrap();
-                let command = values[0].try_as_str().unwrap();
+            ChunkMessageType::AMF0CommandMessage | ChunkMessageType::AMF3CommandMessage => {
+                let values = match message.try_read_body_to_amf0() {
+                    Some(values) => values,
+                    None => {
+                        log::error!(
+                            "[conn={}][peer={}] C->S, expect AMF0 data, ctx={:#?} \n msg={:#?}",
+                            ctx.conn_id,
+                            ctx.peer_addr,
+                            &ctx,
+                            &message
+                        );
+                        Err(anyhow::anyhow!("[AMF0CommandMessage] expect AMF0 data"))?
+                    }
+                };
+                let command = values
+                    .first()
+                    .and_then(|x| x.try_as_str())
+                    .ok_or_else(|| anyhow::anyhow!("[AMF0CommandMessage] command name is not a string"))?;
                 for v in &values {
-                    log::info!("[peer={}] C->S, {} part: {:?}", ctx.peer_addr, command, v);
+                    log::info!("[conn={}][peer={}] C->S, {} part: {:?}", ctx.conn_id, ctx.peer_addr, command, v);
                 }
 
                 match command {
                     "connect" => {
-                        response_connect(&mut ctx).await?;
+                        let command_object = values
+                            .get(2)
+                            .cloned()
+                            .and_then(|x| x.try_into_pairs().ok())
+                            .map(|pairs| pairs.collect::<Vec<(String, amf::Value)>>())
+                            .unwrap_or_default();
+                        let get_field = |key: &str| command_object.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+
+                        ctx.object_encoding = get_field("objectEncoding")
+                            .and_then(|v| v.try_as_f64())
+                            .unwrap_or(0.0);
+                        // app字段缺失时从tcUrl中解析
+                        ctx.app = get_field("app")
+                            .and_then(|v| v.try_as_str())
+                            .map(|x| x.trim_matches('/').to_string())
+                            .filter(|x| !x.is_empty())
+                            .or_else(|| get_field("tcUrl").and_then(|v| v.try_as_str()).and_then(parse_app_from_tc_url))
+                            .unwrap_or_default();
+                        let field_str = |key: &str| get_field(key).and_then(|v| v.try_as_str()).unwrap_or_default();
+                        log::info!(
+                            "[conn={}][peer={}] app={}, tcUrl={}, flashVer={}, swfUrl={}",
+                            ctx.conn_id,
+                            ctx.peer_addr,
+                            ctx.app,
+                            field_str("tcUrl"),
+                            field_str("flashVer"),
+                            field_str("swfUrl")
+                        );
+                        if !naming::is_app_allowed(&ctx.app) {
+                            log::warn!("[conn={}][peer={}] reject connect, unknown app={}", ctx.conn_id, ctx.peer_addr, ctx.app);
+                            response_connect_rejected(ctx, transaction_id(&values)?).await?;
+                            Err(anyhow::anyhow!("unknown app {}", ctx.app))?
+                        }
+                        response_connect(ctx).await?;
                     }
                     "createStream" => {
-                        response_create_stream(&mut ctx, &values[1]).await?;
+                        response_create_stream(ctx, transaction_id(&values)?).await?;
                     }
                     "publish" => {
-                        ctx.stream_name = values[3].try_as_str().unwrap_or_default().to_string();
-                        log::info!("[peer={}] stream_name={}", ctx.peer_addr, ctx.stream_name);
+                        let stream = values.get(3).and_then(|x| x.try_as_str()).unwrap_or_default();
+                        ctx.stream_name = resolve_stream_name(ctx, stream, "NetStream.Publish.BadName").await?;
+                        log::info!("[conn={}][peer={}] stream_name={}", ctx.conn_id, ctx.peer_addr, ctx.stream_name);
 
-                        // 推送者创建eventbus
-                        eventbus_map().insert(
-                            ctx.stream_name.clone(),
-                            EventBus::with_label(ctx.stream_name.clone()),
-                        );
+                        // 推送者创建eventbus，接管时沿用原来的eventbus，新的sequence header会发送给现有的播放者
+                        if idle::take_over(&ctx.stream_name) {
+                            log::info!("[conn={}][peer={}] replace idle asset, stream_name={}", ctx.conn_id, ctx.peer_addr, ctx.stream_name);
+                        } else if ctx.takeover_grace.is_some() && take_over(&ctx.stream_name) {
+                            log::info!("[conn={}][peer={}] take over stream_name={}", ctx.conn_id, ctx.peer_addr, ctx.stream_name);
+                        } else {
+                            eventbus_map().insert(
+                                ctx.stream_name.clone(),
+                                EventBus::with_label(ctx.stream_name.clone()),
+                            );
+                        }
+                        drop_signal_map().insert(ctx.stream_name.clone(), ctx.drop_signal.clone());
+                        // 接管时新的推流者的时间戳可能从0开始
+                        last_timestamp_map().remove(&ctx.stream_name);
+                        publisher_conn_map().insert(ctx.stream_name.clone(), ctx.conn_id);
                         ctx.is_publisher = true;
-                        response_publish(&mut ctx).await?;
+                        ctx.access = Some(AccessSession::start("publish", "rtmp", ctx.conn_id, &ctx.stream_name, &ctx.peer_addr));
+                        response_publish(ctx).await?;
+                        start_push(&ctx.stream_name);
+                        start_transcode(&ctx.stream_name);
                     }
                     "play" => {
-                        ctx.stream_name = values[3].try_as_str().unwrap_or_default().to_string();
-                        log::info!("[peer={}] stream_name={}", ctx.peer_addr, ctx.stream_name);
+                        let stream = values.get(3).and_then(|x| x.try_as_str()).unwrap_or_default();
+                        ctx.stream_name = resolve_stream_name(ctx, stream, "NetStream.Play.Failed").await?;
+                        ctx.play_args = PlayArgs::from_amf0(&values);
+                        ctx.access = Some(AccessSession::start("play", "rtmp", ctx.conn_id, &ctx.stream_name, &ctx.peer_addr));
+                        log::info!(
+                            "[conn={}][peer={}] stream_name={}, play_args={:?}",
+                            ctx.conn_id,
+                            ctx.peer_addr,
+                            ctx.stream_name,
+                            ctx.play_args
+                        );
+                        if ctx.play_args.is_seek() {
+                            log::warn!(
+                                "[conn={}][peer={}] seek is not supported, start={}, play live instead, stream_name={}",
+                                ctx.conn_id,
+                                ctx.peer_addr,
+                                ctx.play_args.start,
+                                ctx.stream_name
+                            );
+                        }
+                    }
+                    "releaseStream" => {
+                        response_command_result(ctx, transaction_id(&values)?).await?;
                     }
-                    _ => (),
+                    "FCPublish" => {
+                        response_command_result(ctx, transaction_id(&values)?).await?;
+                        // Wirecast和FMLE收到onFCPublish之后才开始推流
+                        let stream_name = values.get(3).and_then(|x| x.try_as_str()).unwrap_or_default();
+                        response_on_fc_publish(ctx, stream_name).await?;
+                    }
+                    "FCUnpublish" | "deleteStream" => {
+                        ctx.unpublish();
+                    }
+                    // 部分播放器在connect之后调用，不回复时会等待超时
+                    "_checkbw" | "ping" => {
+                        response_command_result(ctx, transaction_id(&values)?).await?;
+                    }
+                    "getStreamLength" => {
+                        let stream = values.get(3).and_then(|x| x.try_as_str()).unwrap_or_default();
+                        let duration = naming::normalize(&ctx.stream_key(naming::strip_query(stream)))
+                            .ok()
+                            .and_then(|stream_name| meta_data_map().get(&stream_name).map(|x| x.duration))
+                            .unwrap_or_default();
+                        response_stream_length(ctx, transaction_id(&values)?, duration).await?;
+                    }
+                    // 事务ID为0的命令不需要应答，其他不支持的命令回复错误，避免客户端一直等待
+                    _ => match values.get(1) {
+                        Some(id) if id.try_as_f64().is_some_and(|x| x != 0.0) => {
+                            let description = format!("command {} is not supported", command);
+                            let chunk_size = ctx.out_chunk_size;
+                            send_command_error(ctx, id, "NetConnection.Call.Failed", &description, chunk_size).await?;
+                        }
+                        _ => (),
+                    },
                 }
             }
-            ChunkMessageType::AMF0DataMessage => {
-                let values = message.try_read_body_to_amf0().unwrap();
-                let command = values[0].try_as_str().unwrap();
+            ChunkMessageType::AMF0DataMessage | ChunkMessageType::AMF3DataMessage => {
+                let values = message
+                    .try_read_body_to_amf0()
+                    .ok_or_else(|| anyhow::anyhow!("[AMF0DataMessage] expect AMF0 data"))?;
+                let command = values
+                    .first()
+                    .and_then(|x| x.try_as_str())
+                    .ok_or_else(|| anyhow::anyhow!("[AMF0DataMessage] handler name is not a string"))?;
                 for v in &values {
                     if let Value::EcmaArray { entries } = v {
-                        log::info!("[peer={}] C->S, [{}] part Array: ", ctx.peer_addr, command);
+                        log::info!("[conn={}][peer={}] C->S, [{}] part Array: ", ctx.conn_id, ctx.peer_addr, command);
                         for item in entries {
                             log::info!(
-                                "[peer={}] C->S, [{}] item: {:?}",
+                                "[conn={}][peer={}] C->S, [{}] item: {:?}",
+                                ctx.conn_id,
                                 ctx.peer_addr,
                                 command,
                                 item
                             );
                         }
                     } else {
-                        log::info!("[peer={}] C->S, [{}] part: {:?}", ctx.peer_addr, command, v);
+                        log::info!("[conn={}][peer={}] C->S, [{}] part: {:?}", ctx.conn_id, ctx.peer_addr, command, v);
                     }
                 }
                 if command == "@setDataFrame" {
-                    let meta_data = RtmpMetaData::try_from(&values[2])?;
-                    meta_data_map().insert(ctx.stream_name.clone(), meta_data);
+                    let meta_data = values
+                        .get(2)
+                        .ok_or_else(|| anyhow::anyhow!("[@setDataFrame] missing meta data"))
+                        .and_then(RtmpMetaData::try_from)?;
+                    let audio_codec = AudioCodec::from_meta_data(&meta_data.audio_codec_id);
+                    warn_unsupported_audio_codec(ctx, audio_codec);
+                    let is_update = meta_data_map().insert(ctx.stream_name.clone(), meta_data.clone()).is_some();
                     log::info!(
-                        "[peer={}] C->S, cache meta_data, stream_name={}",
+                        "[conn={}][peer={}] C->S, cache meta_data, stream_name={}",
+                        ctx.conn_id,
                         ctx.peer_addr,
                         ctx.stream_name
                     );
+                    // 推流中途更新的onMetaData转发给已经在播放的RTMP播放者
+                    if ctx.is_publisher && is_update {
+                        let mut update = meta_data_message(&meta_data)?;
+                        update.header.timestamp = message.header.timestamp;
+                        publish_media_message(&ctx.stream_name, ctx.conn_id, &ctx.peer_addr, update).await;
+                    }
+                }
+                // 字幕和cue point带有时间戳，和音视频一起转发给播放者
+                if ctx.is_publisher && is_timed_metadata(command) && message.header.message_type == ChunkMessageType::AMF0DataMessage {
+                    publish_media_message(&ctx.stream_name, ctx.conn_id, &ctx.peer_addr, message).await;
                 }
             }
 
-            ChunkMessageType::VideoMessage => {
-                if message.body[0] == 0x17 && message.body[1] == 0x00 {
-                    let mut message_clone = message.clone();
-                    message_clone.header.timestamp = 0;
-                    video_header_map().insert(ctx.stream_name.clone(), message_clone);
-                    log::info!(
-                        "[peer={}] C->S, cache video header, stream_name={}",
-                        ctx.peer_addr,
-                        ctx.stream_name
-                    );
-
-                    save_fmp4_background(&ctx.stream_name, ctx.peer_addr.clone());
-                }
-                if let Some(eventbus) = eventbus_map().get(&ctx.stream_name) {
-                    eventbus.publish(message.clone()).await;
+            ChunkMessageType::VideoMessage | ChunkMessageType::AudioMessage => {
+                let audio_codec = AudioCodec::from_rtmp_message(&message);
+                if audio_codec.is_some() && audio_codec != ctx.audio_codec {
+                    ctx.audio_codec = audio_codec;
+                    warn_unsupported_audio_codec(ctx, audio_codec);
                 }
+                publish_media_message(&ctx.stream_name, ctx.conn_id, &ctx.peer_addr, message).await;
             }
-            ChunkMessageType::AudioMessage => {
-                if message.body[0] == 0xAF && message.body[1] == 0x00 {
-                    let mut message_clone = message.clone();
-                    message_clone.header.timestamp = 0;
-                    audio_header_map().insert(ctx.stream_name.clone(), message_clone);
-                    log::info!(
-                        "[peer={}] C->S, cache audio header, stream_name={}",
-                        ctx.peer_addr,
-                        ctx.stream_name
-                    );
-                }
-                if let Some(eventbus) = eventbus_map().get(&ctx.stream_name) {
-                    eventbus.publish(message.clone()).await;
+            // 部分编码器把音视频打包成聚合消息，拆分后按单独的消息处理
+            ChunkMessageType::AggregateMessage => {
+                for sub_message in message.split_aggregate() {
+                    match sub_message.header.message_type {
+                        ChunkMessageType::VideoMessage | ChunkMessageType::AudioMessage => {
+                            publish_media_message(&ctx.stream_name, ctx.conn_id, &ctx.peer_addr, sub_message).await;
+                        }
+                        _ => {
+                            log::info!(
+                                "[conn={}][peer={}] C->S, [{}] ignored in aggregate, len={}",
+                                ctx.conn_id,
+                                ctx.peer_addr,
+                                sub_message.message_type_desc(),
+                                sub_message.body.len()
+                            );
+                        }
+                    }
                 }
             }
             _ => {
                 log::info!(
-                    "[peer={}] C->S, [{}] OTHER len={}",
+                    "[conn={}][peer={}] C->S, [{}] OTHER len={}",
+                    ctx.conn_id,
                     ctx.peer_addr,
                     message.message_type_desc(),
                     message.header.message_length
@@ -279,93 +961,291 @@ async fn connection_loop(stream: TcpStream) -> anyhow::Result<()> {
     }
 }
 
-/// 处理RTMP握手流程
+/// 分发推流者的音视频消息，sequence header会被缓存给之后加入的播放者
 ///
-/// 有时候OBS在握手流程中会发送ACK报文
-async fn handle_rtmp_handshake(ctx: &mut RtmpContext) -> anyhow::Result<()> {
-    /* C0/C1 */
-    let c0 = ctx.read_exact_from_peer(1).await?[0];
-    log::info!("[peer={}] C0, version={}", ctx.peer_addr, c0);
-
-    let c1_vec = ctx.read_exact_from_peer(Handshake1::PACKET_LENGTH).await?;
-    let c1 = Handshake1 {
-        time: BigEndian::read_u32(&c1_vec[0..4]),
-        zero: BigEndian::read_u32(&c1_vec[4..8]),
-        random_data: c1_vec[8..Handshake1::PACKET_LENGTH as usize].to_vec(),
-    };
-    log::info!("[peer={}] C1，time={}, zero={}, last12=0x{:02X?}", ctx.peer_addr, c1.time, c1.zero, &c1_vec[Handshake1::PACKET_LENGTH as usize - 12..]);
-
-    /* S0/S1/S2 */
-    ctx.write_to_peer(Handshake0::S0_V3.to_bytes().as_ref())
-        .await?;
-    log::info!("[peer={}] S0, version={:?}", ctx.peer_addr, Handshake0::S0_V3);
+/// RTMP推流和`StreamPublisher`共用，保证所有输出的行为一致
+pub(crate) async fn publish_media_message(stream_name: &str, conn_id: u64, peer_addr: &str, mut message: RtmpMessage) {
+    if monotonic_timestamps() && !message.is_sequence_header() {
+        let corrected = last_timestamp_map()
+            .entry(stream_name.to_string())
+            .or_default()
+            .correct(message.header.message_type, message.header.timestamp);
+        if let Some(timestamp) = corrected {
+            let count = metrics().inc_timestamp_corrected(stream_name);
+            // 编码器持续出错时不刷屏
+            if count == 1 || count.is_multiple_of(100) {
+                log::warn!(
+                    "[conn={}][peer={}] [{}] timestamp went backwards from {} to {}, corrected {} times, stream_name={}",
+                    conn_id,
+                    peer_addr,
+                    message.message_type_desc(),
+                    timestamp.wrapping_sub(1),
+                    message.header.timestamp,
+                    count,
+                    stream_name
+                );
+            }
+            message.header.timestamp = timestamp;
+        }
+    }
+    match message.header.message_type {
+        ChunkMessageType::VideoMessage if message.body.len() >= 2 && message.body[0] == 0x17 && message.body[1] == 0x00 => {
+            let mut message_clone = message.clone();
+            message_clone.header.timestamp = 0;
+            let previous = video_header_map().insert(stream_name.to_string(), message_clone);
+            // 新的sps/pps之后旧的GOP不能再解码
+            gop_cache_map().remove(stream_name);
+            log::info!(
+                "[conn={}][peer={}] C->S, cache video header, stream_name={}",
+                conn_id,
+                peer_addr,
+                stream_name
+            );
+            let sps_info = Nalu::from_rtmp_message(&message).iter().find_map(Nalu::sps_info);
+            match &sps_info {
+                Some(sps_info) => log::info!("[conn={}][peer={}] stream_name={}, video: {}", conn_id, peer_addr, stream_name, sps_info),
+                None => log::warn!("[conn={}][peer={}] stream_name={}, SPS not found in video header", conn_id, peer_addr, stream_name),
+            }
+            // 推流中途改变了编码参数，fMP4输出会重新发送init segment，录制切换到新的文件
+            if previous.filter(|x| x.body != message.body).is_some() {
+                log::warn!("[conn={}][peer={}] video header changed, stream_name={}", conn_id, peer_addr, stream_name);
+                if let (Some(sps_info), Some(mut meta_data)) = (sps_info, meta_data_map().get_mut(stream_name)) {
+                    meta_data.width = sps_info.width as f64;
+                    meta_data.height = sps_info.height as f64;
+                }
+            }
+        }
+        ChunkMessageType::AudioMessage if message.is_sequence_header() => {
+            let mut message_clone = message.clone();
+            message_clone.header.timestamp = 0;
+            audio_header_map().insert(stream_name.to_string(), message_clone);
+            log::info!(
+                "[conn={}][peer={}] C->S, cache audio header, stream_name={}",
+                conn_id,
+                peer_addr,
+                stream_name
+            );
+        }
+        ChunkMessageType::VideoMessage if message.is_video_end_of_sequence() => {
+            // 推流端停止时发送，原样转发给播放者作为结束标记
+            gop_cache_map().remove(stream_name);
+            log::info!(
+                "[conn={}][peer={}] C->S, video end of sequence, stream_name={}",
+                conn_id,
+                peer_addr,
+                stream_name
+            );
+        }
+        _ => {}
+    }
+    let first_key_frame = message.header.message_type == ChunkMessageType::VideoMessage
+        && !message.is_sequence_header()
+        && update_key_frame_tracker(stream_name, conn_id, peer_addr, &message);
+    let mut message = message;
+    // 音视频只在这里解析一次，播放输出直接使用解析结果
+    if matches!(message.header.message_type, ChunkMessageType::VideoMessage | ChunkMessageType::AudioMessage) && !message.is_sequence_header() {
+        let audio_header = audio_header_map().get(stream_name);
+        message.attach_parsed_frame(audio_header.as_deref());
+    }
+    let message = Arc::new(message);
+    update_gop_cache(stream_name, &message);
+    // 推流之后的第一个关键帧，sequence header已经缓存，在发布这一帧之前订阅，录制从这一帧开始
+    if first_key_frame && !idle::is_idle(stream_name) {
+        recording::start_auto(stream_name, peer_addr);
+    }
+    if let Some(eventbus) = eventbus_map().get(stream_name) {
+        eventbus.publish(message).await;
+    }
+}
 
+/// 需要转发给播放者的数据消息
+pub fn is_timed_metadata(command: &str) -> bool {
+    command == "onTextData" || command == "onCuePoint"
+}
 
-    let s1 = Handshake1 {
-        time: (Local::now().timestamp_millis() - ctx.ctx_begin_timestamp) as u32,
-        zero: 0,
-        random_data: {
-            let mut random_bytes = gen_random_bytes(1528);
-            random_bytes[0] = 0x0; // 首字符置0
-            random_bytes
-        },
+/// 收到关键帧时更新时刻，超过阈值没有关键帧时告警一次
+///
+/// 返回是否是这次推流的第一个关键帧
+fn update_key_frame_tracker(stream_name: &str, conn_id: u64, peer_addr: &str, message: &RtmpMessage) -> bool {
+    let mut tracker = key_frame_tracker_map()
+        .entry(stream_name.to_string())
+        .or_insert_with(KeyFrameTracker::new);
+    if message.is_video_key_frame() {
+        let first = !tracker.has_key_frame;
+        if tracker.warned {
+            log::info!(
+                "[conn={}][peer={}] key frame received after {}ms, stream_name={}",
+                conn_id,
+                peer_addr,
+                tracker.elapsed().as_millis(),
+                stream_name
+            );
+        }
+        tracker.last_key_frame = Instant::now();
+        tracker.has_key_frame = true;
+        tracker.warned = false;
+        return first;
+    }
+    if !tracker.warned && tracker.is_overdue() {
+        tracker.warned = true;
+        log::warn!(
+            "[conn={}][peer={}] no key frame for {}ms, check the GOP size of the encoder, stream_name={}",
+            conn_id,
+            peer_addr,
+            tracker.elapsed().as_millis(),
+            stream_name
+        );
+    }
+    false
+}
+
+/// 遇到关键帧时重置GOP缓存，之后的音视频消息追加到缓存
+fn update_gop_cache(stream_name: &str, message: &Arc<RtmpMessage>) {
+    // 只缓存音视频，新加入的播放者不需要之前的字幕
+    if !matches!(message.header.message_type, ChunkMessageType::VideoMessage | ChunkMessageType::AudioMessage) {
+        return;
+    }
+    if message.is_video_key_frame() {
+        gop_cache_map().insert(stream_name.to_string(), vec![message.clone()]);
+        notify_stream_waiters(stream_name);
+        return;
+    }
+    // sequence header单独缓存
+    if message.body.len() < 2 || message.body[1] != 0x01 {
+        return;
+    }
+    let overflow = match gop_cache_map().get_mut(stream_name) {
+        Some(mut cache) if cache.len() < GOP_CACHE_MAX_LEN => {
+            cache.push(message.clone());
+            false
+        }
+        Some(_) => true,
+        None => false,
     };
-    ctx.write_to_peer(s1.to_bytes().as_ref()).await?;
-    log::info!("[peer={}] S1", ctx.peer_addr);
+    if overflow {
+        gop_cache_map().remove(stream_name);
+        log::warn!("GOP cache overflow, len={}, stream_name={}", GOP_CACHE_MAX_LEN, stream_name);
+    }
+}
 
-    let s2 = Handshake2 {
-        time: c1.time,
-        time2: 0,
-        random_echo: c1.random_data,
+/// 处理RTMP握手流程
+///
+/// 有时候OBS在握手流程中会发送ACK报文
+///
+/// 返回false表示对端不是RTMP客户端，需要关闭连接
+async fn handle_rtmp_handshake(ctx: &mut RtmpContext) -> anyhow::Result<bool> {
+    /* C0/C1 */
+    let c0 = ctx.read_exact_from_peer(1).await?[0];
+    log::info!("[conn={}][peer={}] C0, version={}", ctx.conn_id, ctx.peer_addr, c0);
+    // 版本号只能是1~31，HTTP请求或者扫描器的首字节都不在这个范围内
+    if !(1..=31).contains(&c0) {
+        log::warn!(
+            "[conn={}][peer={}] C0, invalid version=0x{:02X}, not a RTMP client, close connection",
+            ctx.conn_id,
+            ctx.peer_addr,
+            c0
+        );
+        return Ok(false);
+    }
+
+    let c1_vec = ctx.read_exact_from_peer(Handshake1::PACKET_LENGTH).await?;
+    let c1 = Handshake1 {
+        time: BigEndian::read_u32(&c1_vec[0..4]),
+        zero: BigEndian::read_u32(&c1_vec[4..8]),
+        random_data: c1_vec[8..Handshake1::PACKET_LENGTH as usize].to_vec(),
+    };
+    log::info!("[conn={}][peer={}] C1，time={}, zero={}, last12=0x{:02X?}", ctx.conn_id, ctx.peer_addr, c1.time, c1.zero, &c1_vec[Handshake1::PACKET_LENGTH as usize - 12..]);
+
+    // 版本号不为0并且摘要正确时使用complex握手，否则回显C1
+    let complex = handshake::validate_c1(&c1_vec);
+
+    /* S0/S1/S2 */
+    ctx.write_to_peer(Handshake0::S0_V3.to_bytes().as_ref())
+        .await?;
+    log::info!("[conn={}][peer={}] S0, version={:?}", ctx.conn_id, ctx.peer_addr, Handshake0::S0_V3);
+
+    let time = (Local::now().timestamp_millis() - ctx.ctx_begin_timestamp) as u32;
+    let (s1_bytes, s2_bytes) = match complex {
+        Some((schema, c1_digest)) => {
+            log::info!("[conn={}][peer={}] complex handshake, schema={:?}", ctx.conn_id, ctx.peer_addr, schema);
+            (handshake::create_s1(time, schema), handshake::create_s2(&c1_digest))
+        }
+        None => {
+            let s1 = Handshake1 {
+                time,
+                zero: 0,
+                random_data: {
+                    let mut random_bytes = gen_random_bytes(1528);
+                    random_bytes[0] = 0x0; // 首字符置0
+                    random_bytes
+                },
+            };
+            let s2 = Handshake2 {
+                time: c1.time,
+                time2: 0,
+                random_echo: c1.random_data,
+            };
+            (s1.to_bytes(), s2.to_bytes())
+        }
     };
-    ctx.write_to_peer(s2.to_bytes().as_ref()).await?;
-    log::info!("[peer={}] S2", ctx.peer_addr);
+    ctx.write_to_peer(&s1_bytes).await?;
+    log::info!("[conn={}][peer={}] S1", ctx.conn_id, ctx.peer_addr);
+    ctx.write_to_peer(&s2_bytes).await?;
+    log::info!("[conn={}][peer={}] S2", ctx.conn_id, ctx.peer_addr);
 
     let peek_len = 12;
     let peek_vec = ctx.peek_exact_from_peer(peek_len).await?;
-    if peek_vec != &s1.to_bytes()[0..peek_len as usize] {
-        log::info!("[peer={}] ACK in handshake, peek=0x{:02X?}, s1_part=0x{:02X?}", ctx.peer_addr, peek_vec, &s1.to_bytes()[0..peek_len as usize]);
+    // simple握手的C2是S1的回显，complex握手的C2是随机数据，只能按Acknowledgement的消息头判断
+    let is_ack = match complex {
+        Some(_) => peek_vec[0] == 0x02 && peek_vec[4..8] == [0x00, 0x00, 0x04, 0x03],
+        None => peek_vec != s1_bytes[0..peek_len as usize],
+    };
+    if is_ack {
+        log::info!("[conn={}][peer={}] ACK in handshake, peek=0x{:02X?}, s1_part=0x{:02X?}", ctx.conn_id, ctx.peer_addr, peek_vec, &s1_bytes[0..peek_len as usize]);
         let _ = RtmpMessage::read_from(ctx).await?;
     }
     /* C2*/
     let c2_vec = ctx.read_exact_from_peer(Handshake2::PACKET_LENGTH).await?;
-    let c2 = Handshake2 {
-        time: BigEndian::read_u32(&c2_vec[0..4]),
-        time2: BigEndian::read_u32(&c2_vec[4..8]),
-        random_echo: c2_vec[8..Handshake2::PACKET_LENGTH as usize].to_vec(),
-    };
-    log::info!("[peer={}] C2, time=0x{:02X?}, time2=0x{:02X?}", ctx.peer_addr, &c2_vec[0..4], &c2_vec[4..8]);
-    assert_eq!(s1.random_data, c2.random_echo);
+    log::info!("[conn={}][peer={}] C2, time=0x{:02X?}, time2=0x{:02X?}", ctx.conn_id, ctx.peer_addr, &c2_vec[0..4], &c2_vec[4..8]);
+    // 部分客户端回显的数据并不完全一致，这里只记录不中断连接
+    if complex.is_none() && s1_bytes[8..] != c2_vec[8..] {
+        log::warn!("[conn={}][peer={}] C2, random echo mismatch with S1", ctx.conn_id, ctx.peer_addr);
+    }
 
-    ctx.recv_bytes_num += 1 + Handshake1::PACKET_LENGTH + Handshake2::PACKET_LENGTH;
+    ctx.recv_bytes_num = ctx.recv_bytes_num.wrapping_add(1 + Handshake1::PACKET_LENGTH + Handshake2::PACKET_LENGTH);
+    Ok(true)
+}
+
+/// 发送Acknowledgement，内容为目前为止收到的字节数
+async fn send_acknowledgement(ctx: &mut RtmpContext) -> anyhow::Result<()> {
+    let ack = RtmpMessage::new(ChunkMessageType::Acknowledgement, 0, 0, ctx.recv_bytes_num.to_be_bytes().to_vec());
+    write_message(ctx, &ack).await?;
+    ctx.last_ack_bytes_num = ctx.recv_bytes_num;
+    log::debug!("[conn={}][peer={}] S->C, acknowledgement, sequence={}", ctx.conn_id, ctx.peer_addr, ctx.recv_bytes_num);
     Ok(())
 }
 
 async fn response_connect(ctx: &mut RtmpContext) -> anyhow::Result<()> {
     {
-        let ack_window_size = [
-            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x10, 0x00,
+        let mut ack_window_size = vec![
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00,
         ];
+        ack_window_size.extend_from_slice(&ctx.ack_window_size.to_be_bytes());
         ctx.write_to_peer(ack_window_size.as_ref()).await?;
-        log::info!("[peer={}] S->C, ack_window_size_packet:", ctx.peer_addr);
+        log::info!("[conn={}][peer={}] S->C, ack_window_size_packet:", ctx.conn_id, ctx.peer_addr);
         print_hex(ack_window_size.to_vec().as_ref());
     }
 
-    if ctx.chunk_size == 128 {
-        ctx.chunk_size = 4096;
-    }
-
     {
         let mut set_peer_bandwidth = vec![
             0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00,
         ];
-        set_peer_bandwidth.append(&mut ctx.chunk_size.to_be_bytes().to_vec());
+        set_peer_bandwidth.extend_from_slice(&ctx.peer_bandwidth.to_be_bytes());
         // 0-Hard, 1-Soft, 2-Dynamic
         set_peer_bandwidth.push(0x01);
 
         ctx.write_to_peer(&set_peer_bandwidth).await?;
-        log::info!("[peer={}] S->C, set_peer_bandwidth:", ctx.peer_addr);
+        log::info!("[conn={}][peer={}] S->C, set_peer_bandwidth:", ctx.conn_id, ctx.peer_addr);
         print_hex(set_peer_bandwidth.to_vec().as_ref());
     }
 
@@ -373,86 +1253,110 @@ async fn response_connect(ctx: &mut RtmpContext) -> anyhow::Result<()> {
         let mut set_chunk_size = vec![
             0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00,
         ];
-        set_chunk_size.append(&mut ctx.chunk_size.to_be_bytes().to_vec());
+        set_chunk_size.extend_from_slice(&ctx.out_chunk_size.to_be_bytes());
         ctx.write_to_peer(set_chunk_size.as_ref()).await?;
-        log::info!("[peer={}] S->C, set_chunk_size:", ctx.peer_addr);
+        log::info!("[conn={}][peer={}] S->C, set_chunk_size:", ctx.conn_id, ctx.peer_addr);
         print_hex(set_chunk_size.to_vec().as_ref());
     }
 
     {
-        let mut response_result: Vec<u8> = vec![
-            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x14, 0x00, 0x00, 0x00, 0x00,
-        ];
-        amf::amf0::Value::String("_result".to_string()).write_to(&mut response_result)?;
-        amf::amf0::Value::Number(1.0).write_to(&mut response_result)?;
-        amf::amf0::Value::Object {
-            class_name: None,
-            entries: vec![
-                Pair {
-                    key: "fmsVer".to_owned(),
-                    value: amf::amf0::Value::String("FMS/3,0,1,123".to_owned()),
-                },
-                Pair {
-                    key: "capabilities".to_owned(),
-                    value: amf::amf0::Value::Number(31.0),
-                },
-            ],
-        }
-            .write_to(&mut response_result)?;
-        amf::amf0::Value::Object {
-            class_name: None,
-            entries: vec![
-                Pair {
-                    key: "level".to_owned(),
-                    value: amf::amf0::Value::String("status".to_owned()),
-                },
-                Pair {
-                    key: "code".to_owned(),
-                    value: amf::amf0::Value::String("NetConnection.Connect.Success".to_owned()),
-                },
-                Pair {
-                    key: "description".to_owned(),
-                    value: amf::amf0::Value::String("Connection succeeded.".to_owned()),
-                },
-                Pair {
-                    key: "objectEncoding".to_owned(),
-                    value: amf::amf0::Value::Number(0.0),
-                },
-            ],
-        }
-            .write_to(&mut response_result)?;
-        response_result[6] = (response_result.len() - 12) as u8;
-        ctx.write_to_peer(response_result.as_ref()).await?;
-        log::info!("[peer={}] S->C, response_result:", ctx.peer_addr);
-        print_hex(response_result.as_ref());
+        let fms_ver = configured_server_name().unwrap_or("FMS/3,0,1,123");
+        // SetChunkSize已经生效，fmsVer较长或者chunk size较小时会超过一个chunk
+        let message = connect_result_message(fms_ver, ctx.object_encoding)?;
+        write_message(ctx, &message).await?;
+        log::info!("[conn={}][peer={}] S->C, response_result:", ctx.conn_id, ctx.peer_addr);
+        print_hex(&message.body);
     }
 
     Ok(())
 }
 
+/// connect命令的_result，`--server-name`作为fmsVer，长度不限
+fn connect_result_message(fms_ver: &str, object_encoding: f64) -> anyhow::Result<RtmpMessage> {
+    let mut response_result: Vec<u8> = vec![];
+    amf::amf0::Value::String("_result".to_string()).write_to(&mut response_result)?;
+    amf::amf0::Value::Number(1.0).write_to(&mut response_result)?;
+    amf::amf0::Value::Object {
+        class_name: None,
+        entries: vec![
+            Pair {
+                key: "fmsVer".to_owned(),
+                value: amf::amf0::Value::String(fms_ver.to_owned()),
+            },
+            Pair {
+                key: "capabilities".to_owned(),
+                value: amf::amf0::Value::Number(31.0),
+            },
+        ],
+    }
+        .write_to(&mut response_result)?;
+    amf::amf0::Value::Object {
+        class_name: None,
+        entries: vec![
+            Pair {
+                key: "level".to_owned(),
+                value: amf::amf0::Value::String("status".to_owned()),
+            },
+            Pair {
+                key: "code".to_owned(),
+                value: amf::amf0::Value::String("NetConnection.Connect.Success".to_owned()),
+            },
+            Pair {
+                key: "description".to_owned(),
+                value: amf::amf0::Value::String("Connection succeeded.".to_owned()),
+            },
+            Pair {
+                key: "objectEncoding".to_owned(),
+                value: amf::amf0::Value::Number(object_encoding),
+            },
+        ],
+    }
+        .write_to(&mut response_result)?;
+    Ok(RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, response_result))
+}
+
 async fn response_create_stream(
     ctx: &mut RtmpContext,
     prev_command_number: &amf::amf0::Value,
 ) -> anyhow::Result<()> {
-    let mut response_result: Vec<u8> = vec![
-        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x14, 0x00, 0x00, 0x00, 0x00,
-    ];
+    let mut response_result: Vec<u8> = vec![];
     amf::amf0::Value::String("_result".to_string()).write_to(&mut response_result)?;
     prev_command_number.write_to(&mut response_result)?;
     amf::amf0::Value::Null.write_to(&mut response_result)?;
     amf::amf0::Value::Number(9.0).write_to(&mut response_result)?;
-    response_result[6] = (response_result.len() - 12) as u8;
-    ctx.write_to_peer(response_result.as_ref()).await?;
-    log::info!("[peer={}] S->C, response_result:", ctx.peer_addr);
-    print_hex(response_result.as_ref());
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, response_result);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, response_result:", ctx.conn_id, ctx.peer_addr);
+    print_hex(&message.body);
+
+    Ok(())
+}
+
+/// 命令的第二个值是transaction id，回复时原样带回
+fn transaction_id(values: &[Value]) -> anyhow::Result<&Value> {
+    values.get(1).ok_or_else(|| anyhow::anyhow!("missing transaction id"))
+}
+
+/// 通用的命令应答，`_result` + 事务ID + null + undefined
+async fn response_command_result(
+    ctx: &mut RtmpContext,
+    prev_command_number: &amf::amf0::Value,
+) -> anyhow::Result<()> {
+    let mut response_result: Vec<u8> = vec![];
+    amf::amf0::Value::String("_result".to_string()).write_to(&mut response_result)?;
+    prev_command_number.write_to(&mut response_result)?;
+    amf::amf0::Value::Null.write_to(&mut response_result)?;
+    amf::amf0::Value::Undefined.write_to(&mut response_result)?;
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, response_result);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, response_result:", ctx.conn_id, ctx.peer_addr);
+    print_hex(&message.body);
 
     Ok(())
 }
 
 async fn response_publish(ctx: &mut RtmpContext) -> anyhow::Result<()> {
-    let mut response_result: Vec<u8> = vec![
-        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x14, 0x00, 0x00, 0x00, 0x01,
-    ];
+    let mut response_result: Vec<u8> = vec![];
     amf::amf0::Value::String("onStatus".to_string()).write_to(&mut response_result)?;
     amf::amf0::Value::Number(1.0).write_to(&mut response_result)?;
     amf::amf0::Value::Null.write_to(&mut response_result)?;
@@ -474,31 +1378,128 @@ async fn response_publish(ctx: &mut RtmpContext) -> anyhow::Result<()> {
         ],
     }
         .write_to(&mut response_result)?;
-    response_result[6] = (response_result.len() - 12) as u8;
-    ctx.write_to_peer(response_result.as_ref()).await?;
-    log::info!("[peer={}] S->C, Start publishing:", ctx.peer_addr);
-    print_hex(response_result.as_ref());
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 1, 0, response_result);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, Start publishing:", ctx.conn_id, ctx.peer_addr);
+    print_hex(&message.body);
+
+    Ok(())
+}
+
+/// `getStreamLength`的应答，直播流的时长为0
+async fn response_stream_length(ctx: &mut RtmpContext, transaction_id: &Value, duration: f64) -> anyhow::Result<()> {
+    let mut body = vec![];
+    amf::amf0::Value::String("_result".to_string()).write_to(&mut body)?;
+    transaction_id.write_to(&mut body)?;
+    amf::amf0::Value::Null.write_to(&mut body)?;
+    amf::amf0::Value::Number(duration).write_to(&mut body)?;
+
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, body);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, stream length={}", ctx.conn_id, ctx.peer_addr, duration);
+    Ok(())
+}
+
+/// connect被拒绝时回复`_error`，之后关闭连接
+async fn response_connect_rejected(ctx: &mut RtmpContext, transaction_id: &Value) -> anyhow::Result<()> {
+    let description = format!("Application {} is not allowed.", ctx.app);
+    // 还没有发送SetChunkSize，使用默认的128字节
+    send_command_error(ctx, transaction_id, "NetConnection.Connect.Rejected", &description, 128).await?;
+    log::info!("[conn={}][peer={}] S->C, connect rejected, app={}", ctx.conn_id, ctx.peer_addr, ctx.app);
+    Ok(())
+}
+
+/// 命令失败时回复`_error`，带回命令的事务ID
+async fn send_command_error(
+    ctx: &mut RtmpContext,
+    transaction_id: &Value,
+    code: &str,
+    description: &str,
+    chunk_size: u32,
+) -> anyhow::Result<()> {
+    let mut body = vec![];
+    amf::amf0::Value::String("_error".to_string()).write_to(&mut body)?;
+    transaction_id.write_to(&mut body)?;
+    amf::amf0::Value::Null.write_to(&mut body)?;
+    amf::amf0::Value::Object {
+        class_name: None,
+        entries: vec![
+            Pair {
+                key: "level".to_owned(),
+                value: amf::amf0::Value::String("error".to_owned()),
+            },
+            Pair {
+                key: "code".to_owned(),
+                value: amf::amf0::Value::String(code.to_owned()),
+            },
+            Pair {
+                key: "description".to_owned(),
+                value: amf::amf0::Value::String(description.to_owned()),
+            },
+        ],
+    }
+        .write_to(&mut body)?;
+
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, body);
+    for chunk in message.split_chunks_bytes(chunk_size) {
+        ctx.write_to_peer(&chunk).await?;
+    }
+    log::info!("[conn={}][peer={}] S->C, _error {}:", ctx.conn_id, ctx.peer_addr, code);
+    print_hex(&message.body);
+
+    Ok(())
+}
+
+async fn response_on_fc_publish(ctx: &mut RtmpContext, stream_name: &str) -> anyhow::Result<()> {
+    let mut body = vec![];
+    amf::amf0::Value::String("onFCPublish".to_string()).write_to(&mut body)?;
+    amf::amf0::Value::Number(0.0).write_to(&mut body)?;
+    amf::amf0::Value::Null.write_to(&mut body)?;
+    amf::amf0::Value::Object {
+        class_name: None,
+        entries: vec![
+            Pair {
+                key: "level".to_owned(),
+                value: amf::amf0::Value::String("status".to_owned()),
+            },
+            Pair {
+                key: "code".to_owned(),
+                value: amf::amf0::Value::String("NetStream.Publish.Start".to_owned()),
+            },
+            Pair {
+                key: "description".to_owned(),
+                value: amf::amf0::Value::String(format!("FCPublish to stream {}.", stream_name)),
+            },
+        ],
+    }
+        .write_to(&mut body)?;
+
+    // 流名称较长时会超过一个chunk
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 0, 0, body);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, onFCPublish, stream_name={}", ctx.conn_id, ctx.peer_addr, stream_name);
+    print_hex(&message.body);
 
     Ok(())
 }
 
 async fn response_play(ctx: &mut RtmpContext, stream_id: u32) -> anyhow::Result<()> {
     {
-        let rs: Vec<u8> = vec![
-            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
-        ];
-        ctx.write_to_peer(rs.as_ref()).await?;
+        write_message(ctx, &user_control_message(0, stream_id)).await?;
         log::info!(
-            "[peer={}] S->C, Stream Begin, streamId={}",
+            "[conn={}][peer={}] S->C, Stream Begin, streamId={}",
+            ctx.conn_id,
             ctx.peer_addr,
             &stream_id
         );
     }
 
+    if ctx.play_args.reset {
+        send_on_status(ctx, "status", "NetStream.Play.Reset", "Playing and resetting").await?;
+    }
+
     {
-        let mut response_result: Vec<u8> = vec![
-            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x01,
-        ];
+        let mut response_result: Vec<u8> = vec![];
         amf::amf0::Value::String("onStatus".to_string()).write_to(&mut response_result)?;
         amf::amf0::Value::Number(0.0).write_to(&mut response_result)?;
         amf::amf0::Value::Null.write_to(&mut response_result)?;
@@ -520,111 +1521,1078 @@ async fn response_play(ctx: &mut RtmpContext, stream_id: u32) -> anyhow::Result<
             ],
         }
             .write_to(&mut response_result)?;
-        response_result[6] = (response_result.len() - 12) as u8;
-        ctx.write_to_peer(response_result.as_ref()).await?;
-        log::info!("[peer={}] S->C, Start play:", ctx.peer_addr);
-        print_hex(response_result.as_ref());
+        let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 1, 0, response_result);
+        write_message(ctx, &message).await?;
+        log::info!("[conn={}][peer={}] S->C, Start play:", ctx.conn_id, ctx.peer_addr);
+        print_hex(&message.body);
     }
 
     {
-        let mut response_result: Vec<u8> = vec![
-            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x01,
-        ];
+        let mut response_result: Vec<u8> = vec![];
         amf::amf0::Value::String("|RtmpSampleAccess".to_string()).write_to(&mut response_result)?;
         amf::amf0::Value::Boolean(true).write_to(&mut response_result)?;
         amf::amf0::Value::Boolean(true).write_to(&mut response_result)?;
-        response_result[6] = (response_result.len() - 12) as u8;
-        ctx.write_to_peer(response_result.as_ref()).await?;
-        log::info!("[peer={}] S->C, Start play:", ctx.peer_addr);
-        print_hex(response_result.as_ref());
+        let message = RtmpMessage::new(ChunkMessageType::AMF0DataMessage, 1, 0, response_result);
+        write_message(ctx, &message).await?;
+        log::info!("[conn={}][peer={}] S->C, Start play:", ctx.conn_id, ctx.peer_addr);
+        print_hex(&message.body);
     }
     Ok(())
 }
 
-/// # 向对端发送onMetaData数据
-///
-/// 在publish或者play之后就是开始传输媒体数据了，媒体数据分为3种，
-/// script脚本数据、video视频数据、audio音频数据。
-///
-/// 首先需要传输的是脚本数据onMetaData，也称为元数据。
+/// User Control消息Stream EOF，播放的流已经没有数据
+async fn send_stream_eof(ctx: &mut RtmpContext, stream_id: u32) -> anyhow::Result<()> {
+    write_message(ctx, &user_control_message(1, stream_id)).await?;
+    log::info!("[conn={}][peer={}] S->C, Stream EOF, streamId={}", ctx.conn_id, ctx.peer_addr, stream_id);
+    Ok(())
+}
+
+/// User Control消息，2字节事件类型 + 4字节事件数据
+fn user_control_message(event_type: u16, data: u32) -> RtmpMessage {
+    let mut body = event_type.to_be_bytes().to_vec();
+    body.extend_from_slice(&data.to_be_bytes());
+    RtmpMessage::new(ChunkMessageType::UserControlMessage, 0, 0, body)
+}
+
+/// 回复PingRequest
+async fn send_ping_response(ctx: &mut RtmpContext, timestamp: u32) -> anyhow::Result<()> {
+    write_message(ctx, &user_control_message(7, timestamp)).await?;
+    log::debug!("[conn={}][peer={}] S->C, PingResponse, timestamp={}", ctx.conn_id, ctx.peer_addr, timestamp);
+    Ok(())
+}
+
+/// 按out_chunk_size分片发送，SetChunkSize之后的消息都需要分片
+async fn write_message(ctx: &mut RtmpContext, message: &RtmpMessage) -> anyhow::Result<()> {
+    for chunk in message.split_chunks_bytes(ctx.out_chunk_size) {
+        ctx.write_to_peer(&chunk).await?;
+    }
+    Ok(())
+}
+
+/// publish/play命令中的流名称转换成内部名称，去掉query部分并应用别名
 ///
-/// onMetaData主要描述音视频的编码格式的相关参数。
-async fn send_meta_data_for_play(
+/// 名称不合法时回复onStatus错误，返回Error断开连接
+async fn resolve_stream_name(ctx: &mut RtmpContext, stream: &str, error_code: &str) -> anyhow::Result<String> {
+    let stream_key = ctx.stream_key(naming::strip_query(stream));
+    match naming::normalize(&stream_key) {
+        Ok(stream_name) => Ok(stream_name),
+        Err(e) => {
+            log::warn!("[conn={}][peer={}] reject stream_name={}, {}", ctx.conn_id, ctx.peer_addr, stream_key, e);
+            send_on_status(ctx, "error", error_code, &e.to_string()).await?;
+            Err(e)
+        }
+    }
+}
+
+/// 在msid=1上发送onStatus消息
+async fn send_on_status(
     ctx: &mut RtmpContext,
-    meta_data: &RtmpMetaData,
+    level: &str,
+    code: &str,
+    description: &str,
 ) -> anyhow::Result<()> {
-    let mut response_result: Vec<u8> = vec![
-        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x01,
-    ];
-    amf::amf0::Value::String("onMetaData".to_string()).write_to(&mut response_result)?;
+    let mut response_result: Vec<u8> = vec![];
+    amf::amf0::Value::String("onStatus".to_string()).write_to(&mut response_result)?;
+    amf::amf0::Value::Number(0.0).write_to(&mut response_result)?;
+    amf::amf0::Value::Null.write_to(&mut response_result)?;
     amf::amf0::Value::Object {
         class_name: None,
         entries: vec![
             Pair {
-                key: "Server".to_owned(),
-                value: amf::amf0::Value::String("RIVER".to_owned()),
-            },
-            Pair {
-                key: "width".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.width),
-            },
-            Pair {
-                key: "height".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.height),
-            },
-            Pair {
-                key: "displayWidth".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.width),
-            },
-            Pair {
-                key: "displayHeight".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.height),
-            },
-            Pair {
-                key: "duration".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.duration),
-            },
-            Pair {
-                key: "framerate".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.frame_rate),
-            },
-            Pair {
-                key: "fps".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.frame_rate),
-            },
-            Pair {
-                key: "videocodecid".to_owned(),
-                value: amf::amf0::Value::String(meta_data.video_codec_id.to_string()),
-            },
-            Pair {
-                key: "videodatarate".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.video_data_rate),
-            },
-            Pair {
-                key: "audiocodecid".to_owned(),
-                value: amf::amf0::Value::String(meta_data.audio_codec_id.to_string()),
-            },
-            Pair {
-                key: "audiodatarate".to_owned(),
-                value: amf::amf0::Value::Number(meta_data.audio_data_rate),
+                key: "level".to_owned(),
+                value: amf::amf0::Value::String(level.to_owned()),
             },
             Pair {
-                key: "profile".to_owned(),
-                value: amf::amf0::Value::String(Default::default()),
+                key: "code".to_owned(),
+                value: amf::amf0::Value::String(code.to_owned()),
             },
             Pair {
-                key: "level".to_owned(),
-                value: amf::amf0::Value::String(Default::default()),
+                key: "description".to_owned(),
+                value: amf::amf0::Value::String(description.to_owned()),
             },
         ],
     }
         .write_to(&mut response_result)?;
-    let be_bytes: [u8; 2] = ((response_result.len() - 12) as u16).to_be_bytes();
-    response_result[5] = be_bytes[0];
-    response_result[6] = be_bytes[1];
-    ctx.write_to_peer(response_result.as_ref()).await?;
-    log::info!("[peer={}] S->C, Start play:", ctx.peer_addr);
-    print_hex(response_result.as_ref());
+    // description中带有流名称，长度不固定，需要按chunk size分片
+    let message = RtmpMessage::new(ChunkMessageType::AMF0CommandMessage, 1, 0, response_result);
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, onStatus {}:", ctx.conn_id, ctx.peer_addr, code);
+    print_hex(&message.body);
+
+    Ok(())
+}
+
+/// MP3以外的非AAC音频只能原样转发给RTMP和HTTP-FLV播放者，ws-h264和RTSP会跳过
+fn warn_unsupported_audio_codec(ctx: &RtmpContext, audio_codec: Option<AudioCodec>) {
+    match audio_codec {
+        Some(codec) if !codec.is_supported() => log::warn!(
+            "[conn={}][peer={}] audio codec {} is only passed through to RTMP and HTTP-FLV, skipped by other outputs, stream_name={}",
+            ctx.conn_id,
+            ctx.peer_addr,
+            codec,
+            ctx.stream_name
+        ),
+        Some(codec) => log::info!("[conn={}][peer={}] stream_name={}, audio: {}", ctx.conn_id, ctx.peer_addr, ctx.stream_name, codec),
+        None => {}
+    }
+}
+
+/// # 向对端发送onMetaData数据
+///
+/// 在publish或者play之后就是开始传输媒体数据了，媒体数据分为3种，
+/// script脚本数据、video视频数据、audio音频数据。
+///
+/// 首先需要传输的是脚本数据onMetaData，也称为元数据。
+///
+/// onMetaData主要描述音视频的编码格式的相关参数。
+async fn send_meta_data_for_play(
+    ctx: &mut RtmpContext,
+    meta_data: &RtmpMetaData,
+) -> anyhow::Result<()> {
+    // 原始数据的长度不确定，需要按chunk size分片发送
+    let message = meta_data_message(meta_data)?;
+    write_message(ctx, &message).await?;
+    log::info!("[conn={}][peer={}] S->C, onMetaData:", ctx.conn_id, ctx.peer_addr);
+    print_hex(&message.body);
 
     Ok(())
 }
+
+/// onMetaData脚本数据消息，RTMP播放者和HTTP-FLV的script tag共用
+pub(crate) fn meta_data_message(meta_data: &RtmpMetaData) -> anyhow::Result<RtmpMessage> {
+    let mut body = vec![];
+    amf::amf0::Value::String("onMetaData".to_string()).write_to(&mut body)?;
+    meta_data_to_amf0(meta_data).write_to(&mut body)?;
+    Ok(RtmpMessage::new(ChunkMessageType::AMF0DataMessage, 1, 0, body))
+}
+
+/// onMetaData的AMF0值
+pub(crate) fn meta_data_to_amf0(meta_data: &RtmpMetaData) -> amf::amf0::Value {
+    // 优先转发推流者的原始数据，没有的时候再根据解析出的字段重新生成
+    match &meta_data.raw_value {
+        Some(raw_value) => raw_value.clone(),
+        None => amf::amf0::Value::Object {
+            class_name: None,
+            entries: vec![
+                Pair {
+                    key: "Server".to_owned(),
+                    value: amf::amf0::Value::String(configured_server_name().unwrap_or("RIVER").to_owned()),
+                },
+                Pair {
+                    key: "width".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.width),
+                },
+                Pair {
+                    key: "height".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.height),
+                },
+                Pair {
+                    key: "displayWidth".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.width),
+                },
+                Pair {
+                    key: "displayHeight".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.height),
+                },
+                Pair {
+                    key: "duration".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.duration),
+                },
+                Pair {
+                    key: "framerate".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.frame_rate),
+                },
+                Pair {
+                    key: "fps".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.frame_rate),
+                },
+                Pair {
+                    key: "videocodecid".to_owned(),
+                    value: amf::amf0::Value::String(meta_data.video_codec_id.to_string()),
+                },
+                Pair {
+                    key: "videodatarate".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.video_data_rate),
+                },
+                Pair {
+                    key: "audiocodecid".to_owned(),
+                    value: amf::amf0::Value::String(meta_data.audio_codec_id.to_string()),
+                },
+                Pair {
+                    key: "audiodatarate".to_owned(),
+                    value: amf::amf0::Value::Number(meta_data.audio_data_rate),
+                },
+                Pair {
+                    key: "profile".to_owned(),
+                    value: amf::amf0::Value::String(Default::default()),
+                },
+                Pair {
+                    key: "level".to_owned(),
+                    value: amf::amf0::Value::String(Default::default()),
+                },
+            ],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amf::amf0::Value;
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use smol::net::TcpListener;
+    use smol::Timer;
+    use crate::util::next_conn_id;
+    use crate::protocol::transport::{duplex, MemoryStream};
+    use smol::io::AsyncWrite;
+    use smol::Task;
+
+    async fn send(client: &mut (impl AsyncWrite + Unpin), message_type: ChunkMessageType, msid: u32, body: Vec<u8>) {
+        send_at(client, message_type, msid, 0, body).await;
+    }
+
+    async fn send_at(client: &mut (impl AsyncWrite + Unpin), message_type: ChunkMessageType, msid: u32, timestamp: u32, body: Vec<u8>) {
+        let message = RtmpMessage::new(message_type, msid, timestamp, body);
+        for chunk in message.split_chunks_bytes(128) {
+            client.write_all(&chunk).await.unwrap();
+        }
+    }
+
+    async fn send_amf0(client: &mut (impl AsyncWrite + Unpin), message_type: ChunkMessageType, msid: u32, values: Vec<Value>) {
+        let mut body = vec![];
+        for value in values {
+            value.write_to(&mut body).unwrap();
+        }
+        send(client, message_type, msid, body).await;
+    }
+
+    /// 客户端发送C0/C1，读取S0/S1/S2之后回显S1作为C2
+    async fn handshake(client: &mut MemoryStream) {
+        client.write_all(&c0c1()).await.unwrap();
+        let mut s0s1s2 = vec![0; 1 + 1536 * 2];
+        client.read_exact(&mut s0s1s2).await.unwrap();
+        client.write_all(&s0s1s2[1..1537]).await.unwrap();
+    }
+
+    /// 在内存管道上建立RTMP连接并完成握手，返回客户端和服务端的连接任务
+    async fn connect(config: RtmpConfig) -> (MemoryStream, Task<anyhow::Result<()>>) {
+        let (mut client, server) = duplex();
+        let server_task = smol::spawn(connection_loop(server, config));
+        handshake(&mut client).await;
+        (client, server_task)
+    }
+
+    /// 播放端读取下一个视频消息的时间戳，以及是否为sequence header
+    async fn read_video(ctx: &mut RtmpContext) -> (u32, bool) {
+        loop {
+            let msg = RtmpMessage::read_from(ctx).await.unwrap();
+            match msg.header.message_type {
+                ChunkMessageType::SetChunkSize => ctx.chunk_size = BigEndian::read_u32(&msg.body),
+                ChunkMessageType::VideoMessage => return (msg.header.timestamp, msg.is_sequence_header()),
+                _ => {}
+            }
+        }
+    }
+
+    /// 读取下一个onStatus消息中的信息对象
+    async fn read_on_status(ctx: &mut RtmpContext) -> Vec<(String, String)> {
+        loop {
+            let msg = RtmpMessage::read_from(ctx).await.unwrap();
+            if msg.header.message_type == ChunkMessageType::SetChunkSize {
+                ctx.chunk_size = BigEndian::read_u32(&msg.body);
+                continue;
+            }
+            let values = msg.try_read_body_to_amf0().unwrap_or_default();
+            if values.first().and_then(|x| x.try_as_str()) != Some("onStatus") {
+                continue;
+            }
+            match values.get(3) {
+                Some(Value::Object { entries, .. }) => {
+                    return entries
+                        .iter()
+                        .map(|x| (x.key.clone(), x.value.try_as_str().unwrap_or_default().to_owned()))
+                        .collect();
+                }
+                _ => panic!("onStatus without info object"),
+            }
+        }
+    }
+
+    /// C0和simple握手的C1，C1的version字段为0
+    fn c0c1() -> Vec<u8> {
+        let mut c0c1 = vec![3];
+        c0c1.extend((0..1536).map(|x| x as u8));
+        c0c1[5..9].copy_from_slice(&[0; 4]);
+        c0c1
+    }
+
+    #[test]
+    fn handshake_over_memory_pipe() {
+        smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::new(server);
+            let c0c1 = c0c1();
+            let handshake = handle_rtmp_handshake(&mut ctx);
+            let script = async {
+                client.write_all(&c0c1).await.unwrap();
+                let mut s0s1s2 = vec![0; 1 + 1536 * 2];
+                client.read_exact(&mut s0s1s2).await.unwrap();
+                assert_eq!(s0s1s2[0], 3);
+                // S2回显C1的时间和随机数据
+                assert_eq!(s0s1s2[1537..1541], c0c1[1..5]);
+                assert_eq!(s0s1s2[1545..], c0c1[9..]);
+                client.write_all(&s0s1s2[1..1537]).await.unwrap();
+            };
+            let (result, _) = smol::future::zip(handshake, script).await;
+            assert!(result.unwrap());
+            assert_eq!(ctx.recv_bytes_num, 1 + 1536 * 2);
+        });
+    }
+
+    #[test]
+    fn handshake_skips_ack_before_c2() {
+        smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::new(server);
+            let handshake = handle_rtmp_handshake(&mut ctx);
+            let script = async {
+                client.write_all(&c0c1()).await.unwrap();
+                let mut s0s1s2 = vec![0; 1 + 1536 * 2];
+                client.read_exact(&mut s0s1s2).await.unwrap();
+                let ack = RtmpMessage::new(ChunkMessageType::Acknowledgement, 0, 0, 3073u32.to_be_bytes().to_vec());
+                client.write_all(&ack.split_chunks_bytes(128).concat()).await.unwrap();
+                client.write_all(&s0s1s2[1..1537]).await.unwrap();
+                // 握手之后的第一个消息能正常读取，说明C2没有多读或者少读
+                let chunk_size = RtmpMessage::new(ChunkMessageType::SetChunkSize, 0, 0, 4096u32.to_be_bytes().to_vec());
+                client.write_all(&chunk_size.split_chunks_bytes(128).concat()).await.unwrap();
+            };
+            let (result, _) = smol::future::zip(handshake, script).await;
+            assert!(result.unwrap());
+            let msg = RtmpMessage::read_from(&mut ctx).await.unwrap();
+            assert_eq!(msg.header.message_type, ChunkMessageType::SetChunkSize);
+            assert_eq!(msg.body, 4096u32.to_be_bytes());
+        });
+    }
+
+    #[test]
+    fn handshake_waits_for_fragmented_ack() {
+        smol::block_on(async {
+            let (mut client, server) = duplex();
+            let mut ctx = RtmpContext::new(server);
+            let handshake = handle_rtmp_handshake(&mut ctx);
+            let script = async {
+                // complex握手只能按ACK的消息头判断，需要完整的12个字节
+                let mut c0c1 = vec![3];
+                c0c1.extend(crate::protocol::handshake::tests::client_c1());
+                client.write_all(&c0c1).await.unwrap();
+                let mut s0s1s2 = vec![0; 1 + 1536 * 2];
+                client.read_exact(&mut s0s1s2).await.unwrap();
+                // ACK分两次到达，第一次不够peek的长度
+                let ack = RtmpMessage::new(ChunkMessageType::Acknowledgement, 0, 0, 3073u32.to_be_bytes().to_vec());
+                let ack = ack.split_chunks_bytes(128).concat();
+                client.write_all(&ack[..5]).await.unwrap();
+                Timer::after(Duration::from_millis(20)).await;
+                client.write_all(&ack[5..]).await.unwrap();
+                client.write_all(&s0s1s2[1..1537]).await.unwrap();
+                client.write_all(&[0x42]).await.unwrap();
+            };
+            let (result, _) = smol::future::zip(handshake, script).await;
+            assert!(result.unwrap());
+            // C2之后的字节没有被peek多读或者丢弃
+            assert_eq!(ctx.read_exact_from_peer(1).await.unwrap(), [0x42]);
+        });
+    }
+
+    #[test]
+    fn handshake_rejects_invalid_version() {
+        smol::block_on(async {
+            let (mut client, server) = duplex();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            connection_loop(server, RtmpConfig::default()).await.unwrap();
+            // 没有发送S0，直接关闭连接
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        });
+    }
+
+    /// Unix domain socket上完成握手，对端显示为监听的路径
+    #[cfg(unix)]
+    #[test]
+    fn accept_unix_domain_socket() {
+        let path = std::env::temp_dir().join(format!("river_test_{}.sock", std::process::id()));
+        // 残留的socket文件被替换
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        smol::block_on(async {
+            let server_path = path.clone();
+            let _server_task = smol::spawn(async move { accept_loop_unix(&server_path, RtmpConfig::default()).await });
+            let mut client = loop {
+                match smol::net::unix::UnixStream::connect(&path).await {
+                    Ok(x) => break x,
+                    Err(_) => {
+                        Timer::after(Duration::from_millis(10)).await;
+                    }
+                }
+            };
+            client.write_all(&c0c1()).await.unwrap();
+            let mut s0s1s2 = vec![0; 1 + 1536 * 2];
+            client.read_exact(&mut s0s1s2).await.unwrap();
+            assert_eq!(s0s1s2[0], 3);
+
+            let (_client, server) = smol::net::unix::UnixStream::pair().unwrap();
+            assert_eq!(RtmpContext::new(server).peer_addr, "unix");
+        });
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn fc_publish_replies_on_fc_publish() {
+        smol::block_on(async {
+            let (mut client, _server_task) = connect(RtmpConfig::default()).await;
+
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("FCPublish".to_owned()), Value::Number(3.0), Value::Null,
+                Value::String("test_fc_publish".to_owned()),
+            ]).await;
+
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !contains(&received, b"onFCPublish") || !contains(&received, b"NetStream.Publish.Start") {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before onFCPublish");
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert!(contains(&received, b"_result"));
+        });
+    }
+
+    /// chunk size为RTMP默认的128时，connect的`_result`等应答超过一个chunk，需要分片
+    #[test]
+    fn connect_with_default_chunk_size() {
+        use crate::rtmp_push::RtmpPublishClient;
+
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("rtmp://{}/live/test_chunk_size_128", listener.local_addr().unwrap());
+            let _server_task = smol::spawn(async move {
+                let (server, _) = listener.accept().await.unwrap();
+                let config = RtmpConfig { out_chunk_size: 128, ..RtmpConfig::default() };
+                connection_loop(server, config).await
+            });
+
+            let connect = async { RtmpPublishClient::connect(&url).await.map(|_| true).unwrap() };
+            let timeout = async {
+                Timer::after(Duration::from_secs(5)).await;
+                false
+            };
+            assert!(smol::future::or(connect, timeout).await, "publish did not complete");
+        });
+    }
+
+    #[test]
+    fn long_server_name_in_connect_result() {
+        let fms_ver = "x".repeat(400);
+        let message = connect_result_message(&fms_ver, 0.0).unwrap();
+        smol::block_on(async {
+            let (client, server) = duplex();
+            let mut server_ctx = RtmpContext::new(server);
+            server_ctx.out_chunk_size = 128;
+            write_message(&mut server_ctx, &message).await.unwrap();
+            let mut client_ctx = RtmpContext::new(client);
+            let reply = RtmpMessage::read_from(&mut client_ctx).await.unwrap();
+            let values = reply.try_read_body_to_amf0().unwrap();
+            assert_eq!(values[0].try_as_str(), Some("_result"));
+            match &values[2] {
+                Value::Object { entries, .. } => assert_eq!(entries[0].value.try_as_str(), Some(fms_ver.as_str())),
+                _ => panic!("expect properties object"),
+            }
+        });
+    }
+
+    #[test]
+    fn subscribe_without_connection() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_subscribe";
+        assert!(subscribe(stream_name).is_none());
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            assert!(list_streams().iter().any(|x| x == stream_name));
+
+            let rx = subscribe(stream_name).unwrap();
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9A], 40, false).await;
+            let msg = rx.recv().await.unwrap();
+            assert_eq!(msg.header.message_type, ChunkMessageType::VideoMessage);
+            assert_eq!(msg.header.timestamp, 40);
+
+            drop(publisher);
+            assert!(!list_streams().iter().any(|x| x == stream_name));
+        });
+    }
+
+    /// 长度不足的SetChunkSize和User Control消息被忽略，连接继续处理之后的命令
+    #[test]
+    fn short_control_messages_are_ignored() {
+        smol::block_on(async {
+            let (mut client, _server_task) = connect(RtmpConfig::default()).await;
+
+            send(&mut client, ChunkMessageType::SetChunkSize, 0, vec![0x00, 0x10]).await;
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0x00]).await;
+            // set buffer length缺少buffer length
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0x00, 0x03, 0x00, 0x00, 0x00, 0x01]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("FCPublish".to_owned()), Value::Number(3.0), Value::Null,
+                Value::String("test_short_control".to_owned()),
+            ]).await;
+
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !contains(&received, b"onFCPublish") {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before onFCPublish");
+                received.extend_from_slice(&buf[..n]);
+            }
+        });
+    }
+
+    #[test]
+    fn malformed_command_closes_connection() {
+        smol::block_on(async {
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            // 命令名不是字符串，只有一个值
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![Value::Number(1.0)]).await;
+            assert!(server_task.await.is_err());
+
+            // 字符串被截断
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+            send(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![0x02, 0x00, 0x07, b'c', b'o']).await;
+            assert!(server_task.await.is_err());
+        });
+    }
+
+    #[test]
+    fn drop_publisher_ends_viewers() {
+        let stream_name = "live/test_drop";
+        assert!(!drop_publisher(stream_name));
+        smol::block_on(async {
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("publish".to_owned()), Value::Number(5.0), Value::Null,
+                Value::String(stream_name.to_owned()), Value::String("live".to_owned()),
+            ]).await;
+            for _ in 0..100 {
+                if eventbus_map().contains_key(stream_name) {
+                    break;
+                }
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            let viewer = subscribe(stream_name).unwrap();
+
+            assert!(drop_publisher(stream_name));
+            assert!(publisher_conn_map().get(stream_name).is_some_and(|x| *x > 0));
+            // 推流者在下一个消息到达时断开
+            send(&mut client, ChunkMessageType::VideoMessage, 1, vec![0x27, 0x01, 0, 0, 0]).await;
+            assert!(server_task.await.is_ok());
+            assert!(viewer.recv().await.is_err());
+            assert!(is_cleaned(stream_name));
+        });
+    }
+
+    #[test]
+    fn play_missing_stream_gets_stream_not_found() {
+        smol::block_on(async {
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            // 不支持的命令回复_error
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("unknownCommand".to_owned()), Value::Number(2.0), Value::Null,
+            ]).await;
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !contains(&received, b"NetConnection.Call.Failed") {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before _error");
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert!(contains(&received, b"_error"));
+
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("play".to_owned()), Value::Number(4.0), Value::Null,
+                Value::String("live/test_not_found".to_owned()),
+            ]).await;
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]).await;
+            received.clear();
+            client.read_to_end(&mut received).await.unwrap();
+            assert!(server_task.await.is_ok());
+            assert!(contains(&received, b"NetStream.Play.StreamNotFound"));
+            assert!(!contains(&received, b"NetStream.Play.Start"));
+        });
+    }
+
+    #[test]
+    fn long_stream_name_in_stream_not_found() {
+        let stream = "a".repeat(300);
+        smol::block_on(async {
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            // connect之后服务端按4096分片
+            let command_object = Value::Object {
+                class_name: None,
+                entries: vec![Pair { key: "app".to_owned(), value: Value::String("live".to_owned()) }],
+            };
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("connect".to_owned()), Value::Number(1.0), command_object,
+            ]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("play".to_owned()), Value::Number(4.0), Value::Null,
+                Value::String(stream.clone()),
+            ]).await;
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]).await;
+
+            // description超过255字节，消息长度不能被截断
+            let mut ctx = RtmpContext::new(client);
+            let info = read_on_status(&mut ctx).await;
+            assert!(info.contains(&("code".to_owned(), "NetStream.Play.StreamNotFound".to_owned())));
+            assert!(info.contains(&("description".to_owned(), format!("stream live/{} is not found", stream))));
+            assert!(server_task.await.is_ok());
+        });
+    }
+
+    #[test]
+    fn rejected_long_stream_name_gets_clean_reply() {
+        let config = naming::NamingConfig { allowlist: Some(naming::parse_allowlist(r"live/cam\d+").unwrap()), ..Default::default() };
+        let stream_name = format!("live/{}", "b".repeat(300));
+        let error = config.normalize(&stream_name).unwrap_err();
+        smol::block_on(async {
+            let (client, server) = duplex();
+            let mut server_ctx = RtmpContext::new(server);
+            server_ctx.out_chunk_size = 128;
+            send_on_status(&mut server_ctx, "error", "NetStream.Publish.BadName", &error.to_string()).await.unwrap();
+
+            // 拒绝原因中带有完整的流名称，分成多个chunk
+            let mut client_ctx = RtmpContext::new(client);
+            let info = read_on_status(&mut client_ctx).await;
+            assert!(info.contains(&("code".to_owned(), "NetStream.Publish.BadName".to_owned())));
+            assert!(info.contains(&("description".to_owned(), format!("stream name not allowed: {}", stream_name))));
+        });
+    }
+
+    #[test]
+    fn utility_calls_and_ping_request_get_replies() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_stream_length";
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            publisher.set_metadata(RtmpMetaData { duration: 90.0, ..Default::default() });
+
+            let (mut client, _server_task) = connect(RtmpConfig::default()).await;
+
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("_checkbw".to_owned()), Value::Number(2.0), Value::Null,
+            ]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("getStreamLength".to_owned()), Value::Number(3.0), Value::Null,
+                Value::String(stream_name.to_owned()),
+            ]).await;
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0, 6, 0, 0, 0x30, 0x39]).await;
+
+            let mut expected_length = vec![];
+            Value::Number(3.0).write_to(&mut expected_length).unwrap();
+            Value::Null.write_to(&mut expected_length).unwrap();
+            Value::Number(90.0).write_to(&mut expected_length).unwrap();
+            let ping_response = [0, 7, 0, 0, 0x30, 0x39];
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !contains(&received, &expected_length) || !contains(&received, &ping_response) {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before replies");
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert!(contains(&received, b"_result"));
+            assert!(!contains(&received, b"NetConnection.Call.Failed"));
+            drop(publisher);
+        });
+    }
+
+    #[test]
+    fn player_notified_when_publisher_stops() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_unpublish_notify";
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            publisher.set_metadata(RtmpMetaData::default());
+
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("play".to_owned()), Value::Number(4.0), Value::Null,
+                Value::String(stream_name.to_owned()),
+            ]).await;
+            // set buffer length之后开始播放
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]).await;
+            let mut received = vec![];
+            let mut buf = [0; 4096];
+            while !contains(&received, b"NetStream.Play.Start") {
+                let n = client.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            // Play.Start之后才注册接收者
+            while publisher.subscriber_count() == 0 {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+
+            drop(publisher);
+            received.clear();
+            client.read_to_end(&mut received).await.unwrap();
+            assert!(server_task.await.is_ok());
+            assert!(contains(&received, b"NetStream.Play.UnpublishNotify"));
+            // Stream EOF，事件类型1，streamId=1
+            assert!(received.ends_with(&[0x04, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1]));
+        });
+    }
+
+    #[test]
+    fn player_starts_from_gop_cache() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_play_gop_cache";
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            publisher.set_metadata(RtmpMetaData::default());
+            publisher.push_message(ChunkMessageType::VideoMessage, 0, vec![0x17, 0x00, 0, 0, 0, 0x01]).await;
+            publisher.push_message(ChunkMessageType::VideoMessage, 1000, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]).await;
+            publisher.push_message(ChunkMessageType::VideoMessage, 1040, vec![0x27, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x41]).await;
+
+            let (mut client, _server_task) = connect(RtmpConfig::default()).await;
+            // connect之后服务端按4096分片
+            let command_object = Value::Object {
+                class_name: None,
+                entries: vec![Pair { key: "app".to_owned(), value: Value::String("live".to_owned()) }],
+            };
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("connect".to_owned()), Value::Number(1.0), command_object,
+            ]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("play".to_owned()), Value::Number(4.0), Value::Null,
+                Value::String("test_play_gop_cache".to_owned()),
+            ]).await;
+            send(&mut client, ChunkMessageType::UserControlMessage, 0, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]).await;
+
+            // 中途加入的播放者先收到缓存的关键帧，之后是同一个GOP的帧
+            let mut ctx = RtmpContext::new(client);
+            let mut frames = vec![];
+            while frames.len() < 2 {
+                let msg = RtmpMessage::read_from(&mut ctx).await.unwrap();
+                match msg.header.message_type {
+                    ChunkMessageType::SetChunkSize => ctx.chunk_size = BigEndian::read_u32(&msg.body),
+                    ChunkMessageType::VideoMessage if !msg.is_sequence_header() => frames.push(msg.is_video_key_frame()),
+                    _ => {}
+                }
+            }
+            assert_eq!(frames, vec![true, false]);
+            // RTMP播放者和其它输出一样出现在播放者统计中
+            let viewers = metrics().viewers(stream_name);
+            assert_eq!(viewers.len(), 1);
+            assert_eq!(viewers[0].output, "rtmp");
+            assert!(viewers[0].bytes_sent.load() > 0);
+            drop(publisher);
+        });
+    }
+
+    #[test]
+    fn reconnecting_publisher_takes_over_viewers() {
+        let stream_name = "live/test_takeover";
+        let config = RtmpConfig {
+            takeover_grace: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        smol::block_on(async {
+            let publish = || async {
+                let (mut client, server_task) = connect(config.clone()).await;
+                send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                    Value::String("publish".to_owned()), Value::Number(5.0), Value::Null,
+                    Value::String(stream_name.to_owned()), Value::String("live".to_owned()),
+                ]).await;
+                for _ in 0..100 {
+                    if drop_signal_map().contains_key(stream_name) {
+                        break;
+                    }
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+                (client, server_task)
+            };
+
+            let (mut client, server_task) = publish().await;
+            let viewer = subscribe(stream_name).unwrap();
+            send_amf0(&mut client, ChunkMessageType::AMF0DataMessage, 1, vec![
+                Value::String("@setDataFrame".to_owned()), Value::String("onMetaData".to_owned()),
+                Value::EcmaArray { entries: vec![] },
+            ]).await;
+            send(&mut client, ChunkMessageType::VideoMessage, 1, vec![0x17, 0x00, 0, 0, 0, 0x01]).await;
+            assert!(viewer.recv().await.unwrap().is_sequence_header());
+
+            // RTMP播放者
+            let (mut player, player_task) = connect(config.clone()).await;
+            send_amf0(&mut player, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("play".to_owned()), Value::Number(4.0), Value::Null,
+                Value::String(stream_name.to_owned()),
+            ]).await;
+            send(&mut player, ChunkMessageType::UserControlMessage, 0, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]).await;
+            for _ in 0..100 {
+                if eventbus_map().get(stream_name).map(|x| x.receiver_count()) == Some(2) {
+                    break;
+                }
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            let mut player_ctx = RtmpContext::new(player);
+            // 播放开始时先发送缓存的sequence header
+            assert_eq!(read_video(&mut player_ctx).await, (0, true));
+
+            send_at(&mut client, ChunkMessageType::VideoMessage, 1, 5000, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]).await;
+            send_at(&mut client, ChunkMessageType::VideoMessage, 1, 5040, vec![0x27, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x41]).await;
+            assert_eq!(read_video(&mut player_ctx).await, (0, false));
+            assert_eq!(read_video(&mut player_ctx).await, (40, false));
+            drop(client);
+            assert!(server_task.await.is_err());
+            // 推流者断开之后播放者仍然连接
+            assert!(eventbus_map().contains_key(stream_name));
+            assert!(!drop_signal_map().contains_key(stream_name));
+
+            let (mut client, server_task) = publish().await;
+            // 新的sequence header发送给原来的播放者
+            send(&mut client, ChunkMessageType::VideoMessage, 1, vec![0x17, 0x00, 0, 0, 0, 0x01]).await;
+            while !viewer.recv().await.unwrap().is_sequence_header() {}
+
+            // 新的推流者的时间戳从0开始，RTMP播放者接着之前的时间戳继续
+            send_at(&mut client, ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]).await;
+            send_at(&mut client, ChunkMessageType::VideoMessage, 1, 40, vec![0x27, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x41]).await;
+            assert_eq!(read_video(&mut player_ctx).await, (40, true));
+            assert_eq!(read_video(&mut player_ctx).await, (40, false));
+            assert_eq!(read_video(&mut player_ctx).await, (80, false));
+
+            // 接管之后超过等待时间也不会移除
+            Timer::after(Duration::from_millis(400)).await;
+            assert!(eventbus_map().contains_key(stream_name));
+
+            // 没有新的推流者接管时，播放者在等待时间之后结束
+            drop(client);
+            let _ = server_task.await;
+            while viewer.recv().await.is_ok() {}
+            assert!(player_task.await.is_ok());
+            assert!(is_cleaned(stream_name));
+        });
+    }
+
+    #[test]
+    fn viewer_waits_for_publisher() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_wait_publisher";
+        smol::block_on(async {
+            let viewer = smol::spawn(wait_for_publisher_timeout(stream_name, Some(Duration::from_secs(5))));
+            Timer::after(Duration::from_millis(50)).await;
+            assert!(stream_waiters_map().contains_key(stream_name));
+
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            // 推流开始但是还没有关键帧时继续等待
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9A], 0, false).await;
+            Timer::after(Duration::from_millis(50)).await;
+            assert!(stream_waiters_map().contains_key(stream_name));
+
+            publisher.push_video(&[0, 0, 0, 1, 0x65, 0x88], 40, true).await;
+            assert!(viewer.await);
+            assert!(!stream_waiters_map().contains_key(stream_name));
+        });
+    }
+
+    #[test]
+    fn viewer_stats_count_dropped_frames() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_viewer_stats";
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            let mut receiver = KeyFrameReceiver::subscribe(stream_name).unwrap();
+            let viewer = metrics().register_viewer(next_conn_id(), stream_name, "127.0.0.1:1234", "http-flv");
+            assert_eq!(metrics().viewers(stream_name).len(), 1);
+
+            // 关键帧之前的帧被跳过
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9A], 0, false).await;
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9B], 40, false).await;
+            publisher.push_video(&[0, 0, 0, 1, 0x65, 0x88], 80, true).await;
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9C], 120, false).await;
+            let msg = receiver.recv().await.unwrap();
+            assert!(msg.is_video_key_frame());
+            receiver.update_stats(&viewer);
+            assert_eq!(viewer.dropped.load(), 2);
+            assert_eq!(viewer.queued.load(), 1);
+
+            drop(viewer);
+            assert!(metrics().viewers(stream_name).is_empty());
+        });
+    }
+
+    #[test]
+    fn latest_viewer_keeps_newest_audio_and_video() {
+        use crate::publisher::StreamPublisher;
+
+        let stream_name = "live/test_latest_viewer";
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            let mut receiver = KeyFrameReceiver::subscribe_latest(stream_name).unwrap();
+
+            publisher.push_video(&[0, 0, 0, 1, 0x65, 0x88], 0, true).await;
+            publisher.push_message(ChunkMessageType::AudioMessage, 10, vec![0xAF, 0x01, 0x21]).await;
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9A], 40, false).await;
+            publisher.push_message(ChunkMessageType::AudioMessage, 50, vec![0xAF, 0x01, 0x22]).await;
+            publisher.push_video(&[0, 0, 0, 1, 0x41, 0x9B], 80, false).await;
+            // 读取跟不上时视频不会覆盖音频
+            assert_eq!(receiver.backlog(), 3);
+
+            let mut received = vec![];
+            for _ in 0..3 {
+                let msg = receiver.recv().await.unwrap();
+                received.push((msg.header.message_type, msg.header.timestamp));
+            }
+            assert_eq!(
+                received,
+                vec![
+                    (ChunkMessageType::VideoMessage, 0),
+                    (ChunkMessageType::AudioMessage, 50),
+                    (ChunkMessageType::VideoMessage, 80)
+                ]
+            );
+            assert_eq!(receiver.backlog(), 0);
+        });
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|x| x == needle)
+    }
+
+    fn is_cleaned(stream_name: &str) -> bool {
+        !eventbus_map().contains_key(stream_name)
+            && !video_header_map().contains_key(stream_name)
+            && !audio_header_map().contains_key(stream_name)
+            && !meta_data_map().contains_key(stream_name)
+            && !gop_cache_map().contains_key(stream_name)
+            && !key_frame_tracker_map().contains_key(stream_name)
+            && !last_timestamp_map().contains_key(stream_name)
+            && !drop_signal_map().contains_key(stream_name)
+            && !publisher_conn_map().contains_key(stream_name)
+    }
+
+    #[test]
+    fn play_timestamp_rebased_on_new_publisher() {
+        let video = |timestamp, body: &[u8]| RtmpMessage::new(ChunkMessageType::VideoMessage, 1, timestamp, body.to_vec());
+        let mut play_timestamp = PlayTimestamp::default();
+        // 占位画面循环了很久
+        assert_eq!(play_timestamp.rebase(&video(120_000, &[0x17, 0x01])), 0);
+        assert_eq!(play_timestamp.rebase(&video(120_040, &[0x27, 0x01])), 40);
+        // 回退的时间戳不会溢出
+        assert_eq!(play_timestamp.rebase(&video(100, &[0x27, 0x01])), 0);
+        assert_eq!(play_timestamp.rebase(&video(120_080, &[0x27, 0x01])), 80);
+        // 推流者交接之后从sequence header重新计算
+        assert_eq!(play_timestamp.rebase(&video(0, &[0x17, 0x00])), 80);
+        assert_eq!(play_timestamp.rebase(&video(0, &[0x17, 0x01])), 80);
+        assert_eq!(play_timestamp.rebase(&video(40, &[0x27, 0x01])), 120);
+    }
+
+    #[test]
+    fn clamp_backward_timestamps() {
+        let mut last = LastTimestamp::default();
+        assert_eq!(last.correct(ChunkMessageType::VideoMessage, 1000), None);
+        assert_eq!(last.correct(ChunkMessageType::VideoMessage, 900), Some(1001));
+        // 修正之后从修正的时间戳继续判断，相等的时间戳不需要修正
+        assert_eq!(last.correct(ChunkMessageType::VideoMessage, 950), Some(1002));
+        assert_eq!(last.correct(ChunkMessageType::VideoMessage, 1002), None);
+        assert_eq!(last.correct(ChunkMessageType::VideoMessage, 1040), None);
+        // 音频单独判断
+        assert_eq!(last.correct(ChunkMessageType::AudioMessage, 1020), None);
+        assert_eq!(last.correct(ChunkMessageType::AMF0DataMessage, 0), None);
+        // 回绕按前进处理
+        let mut last = LastTimestamp::default();
+        assert_eq!(last.correct(ChunkMessageType::AudioMessage, u32::MAX - 10), None);
+        assert_eq!(last.correct(ChunkMessageType::AudioMessage, 10), None);
+        assert_eq!(last.correct(ChunkMessageType::AudioMessage, u32::MAX), Some(11));
+    }
+
+    #[test]
+    fn first_key_frame_of_each_publish() {
+        let stream_name = "live/test_first_key_frame";
+        let key_frame = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 0, vec![0x17, 0x01, 0, 0, 0]);
+        let inter_frame = RtmpMessage::new(ChunkMessageType::VideoMessage, 1, 40, vec![0x27, 0x01, 0, 0, 0]);
+        assert!(!update_key_frame_tracker(stream_name, 0, "", &inter_frame));
+        assert!(update_key_frame_tracker(stream_name, 0, "", &key_frame));
+        assert!(!update_key_frame_tracker(stream_name, 0, "", &key_frame));
+        // 推流结束时移除，下次推流重新开始
+        key_frame_tracker_map().remove(stream_name);
+        assert!(update_key_frame_tracker(stream_name, 0, "", &key_frame));
+        key_frame_tracker_map().remove(stream_name);
+    }
+
+    #[test]
+    fn publisher_disconnect_cleans_maps() {
+        let stream_name = "live/test_clean_maps";
+        smol::block_on(async {
+            let (mut client, server_task) = connect(RtmpConfig::default()).await;
+
+            let command_object = Value::Object {
+                class_name: None,
+                entries: vec![Pair { key: "app".to_owned(), value: Value::String("live".to_owned()) }],
+            };
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 0, vec![
+                Value::String("connect".to_owned()), Value::Number(1.0), command_object,
+            ]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0CommandMessage, 1, vec![
+                Value::String("publish".to_owned()), Value::Number(5.0), Value::Null,
+                Value::String("test_clean_maps".to_owned()), Value::String("live".to_owned()),
+            ]).await;
+            send_amf0(&mut client, ChunkMessageType::AMF0DataMessage, 1, vec![
+                Value::String("@setDataFrame".to_owned()), Value::String("onMetaData".to_owned()),
+                Value::EcmaArray { entries: vec![Pair { key: "width".to_owned(), value: Value::Number(1280.0) }] },
+            ]).await;
+            send(&mut client, ChunkMessageType::VideoMessage, 1, vec![0x17, 0x00, 0, 0, 0, 0x01, 0x42, 0xC0, 0x1F, 0xFF, 0xE0, 0x01]).await;
+            send(&mut client, ChunkMessageType::AudioMessage, 1, vec![0xAF, 0x00, 0x12, 0x10]).await;
+            send(&mut client, ChunkMessageType::VideoMessage, 1, vec![0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]).await;
+
+            for _ in 0..100 {
+                if gop_cache_map().contains_key(stream_name) {
+                    break;
+                }
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            assert!(eventbus_map().contains_key(stream_name));
+            assert!(video_header_map().contains_key(stream_name));
+            assert!(audio_header_map().contains_key(stream_name));
+            assert!(meta_data_map().contains_key(stream_name));
+            assert!(gop_cache_map().contains_key(stream_name));
+            assert!(key_frame_tracker_map().contains_key(stream_name));
+            // 没有开启`--record`时不会自动录制
+            assert!(crate::recording::recording(stream_name).is_none());
+
+            drop(client);
+            let _ = server_task.await;
+            assert!(is_cleaned(stream_name));
+        });
+    }
+
+    #[test]
+    fn meta_data_as_flv_script_tag() {
+        let meta_data = RtmpMetaData { width: 1280.0, height: 720.0, frame_rate: 25.0, ..Default::default() };
+        let message = meta_data_message(&meta_data).unwrap();
+        let flv_tag = crate::protocol::flv::FlvTag::from_rtmp_message(&message, 0).unwrap();
+        assert_eq!(flv_tag.tag_type(), 0x12);
+        assert_eq!(flv_tag.timestamp(), 0);
+        assert_eq!(flv_tag.data_size() as usize, flv_tag.body().len());
+
+        let values = message.try_read_body_to_amf0().unwrap();
+        assert_eq!(values[0].try_as_str(), Some("onMetaData"));
+        let width = values[1].clone().try_into_pairs().unwrap().find(|(k, _)| k == "width").map(|(_, v)| v.try_as_f64());
+        assert_eq!(width, Some(Some(1280.0)));
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::lock::Mutex;
+use smol::net::{SocketAddr, TcpStream};
+use smol::stream::StreamExt;
+use smol::Task;
+
+use crate::naming;
+use crate::protocol::aac::AudioSpecificConfig;
+use crate::protocol::h264::Nalu;
+use crate::protocol::rtmp::ChunkMessageType;
+use crate::protocol::rtp::{interleaved_frame, RtpPacketizer};
+use crate::rtmp_server::{audio_header_map, eventbus_map, video_header_map, KeyFrameReceiver};
+use crate::util::{bind_tcp, next_conn_id, server_name, spawn_and_log_error};
+
+const VIDEO_PAYLOAD_TYPE: u8 = 96;
+const AUDIO_PAYLOAD_TYPE: u8 = 97;
+const VIDEO_TRACK_ID: u8 = 0;
+const AUDIO_TRACK_ID: u8 = 1;
+/// 请求头的大小上限
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+/// 请求体的大小上限，SET_PARAMETER等请求的请求体不会很大
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// RTSP服务，目前只支持RTP over TCP（interleaved）
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = bind_tcp(addr)?;
+    let addr = format!("rtsp://{}", listener.local_addr()?);
+    log::info!("RTSP Server is listening to {}", addr);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        spawn_and_log_error(accept(stream));
+    }
+    Ok(())
+}
+
+struct RtspRequest {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+impl RtspRequest {
+    fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(&key.to_lowercase()).map(String::as_str)
+    }
+
+    fn cseq(&self) -> &str {
+        self.header("CSeq").unwrap_or("0")
+    }
+
+    /// `rtsp://host:port/<stream_name>[/trackID=N]`中的stream_name，转换成内部名称，不合法时返回None
+    fn stream_name(&self) -> Option<String> {
+        let path = self
+            .url
+            .strip_prefix("rtsp://")
+            .and_then(|x| x.find('/').map(|i| &x[i..]))
+            .unwrap_or(&self.url);
+        let path = path.split('?').next().unwrap_or_default();
+        let path = match path.rfind("/trackID=") {
+            Some(i) => &path[..i],
+            None => path,
+        };
+        naming::normalize_path(path).ok()
+    }
+
+    fn track_id(&self) -> Option<u8> {
+        self.url
+            .split_once("trackID=")
+            .and_then(|(_, x)| x.parse().ok())
+    }
+}
+
+/// 一个RTSP会话中已经SETUP的轨道，值为RTP的interleaved channel
+#[derive(Default)]
+struct RtspSession {
+    stream_name: String,
+    video_channel: Option<u8>,
+    audio_channel: Option<u8>,
+}
+
+async fn accept(stream: TcpStream) -> anyhow::Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let conn_id = next_conn_id();
+    log::info!("[conn={}][RTSP] new connection from {}", conn_id, peer_addr);
+
+    let writer = Arc::new(Mutex::new(stream.clone()));
+    let mut reader = stream;
+    let mut buffer: Vec<u8> = vec![];
+    let session_id = format!("{:08X}", rand::random::<u32>());
+    let mut session = RtspSession::default();
+    // Task被drop时会停止推流
+    let mut play_task: Option<Task<anyhow::Result<()>>> = None;
+
+    while let Some(req) = read_request(&mut reader, &mut buffer).await? {
+        log::info!("[conn={}][RTSP][peer={}] {} {}", conn_id, peer_addr, req.method, req.url);
+        let response = match req.method.as_str() {
+            "OPTIONS" => response(&req, "200 OK", &[(
+                "Public",
+                "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string(),
+            )], ""),
+            "DESCRIBE" => {
+                match req.stream_name().and_then(|x| build_sdp(&x)) {
+                    Some(sdp) => response(&req, "200 OK", &[
+                        ("Content-Base", format!("{}/", req.url.trim_end_matches('/'))),
+                        ("Content-Type", "application/sdp".to_string()),
+                    ], &sdp),
+                    None => response(&req, "404 Not Found", &[], ""),
+                }
+            }
+            "SETUP" => {
+                let transport = req.header("Transport").unwrap_or_default().to_string();
+                let channel = parse_interleaved_channel(&transport);
+                match (transport.contains("RTP/AVP/TCP"), channel, req.stream_name()) {
+                    (true, Some(_), None) => response(&req, "404 Not Found", &[], ""),
+                    (true, Some(channel), Some(stream_name)) => {
+                        session.stream_name = stream_name;
+                        match req.track_id().unwrap_or(VIDEO_TRACK_ID) {
+                            AUDIO_TRACK_ID => session.audio_channel = Some(channel),
+                            _ => session.video_channel = Some(channel),
+                        }
+                        response(&req, "200 OK", &[
+                            ("Transport", format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel + 1)),
+                            ("Session", format!("{};timeout=60", session_id)),
+                        ], "")
+                    }
+                    _ => response(&req, "461 Unsupported Transport", &[], ""),
+                }
+            }
+            "PLAY" => {
+                // 已经在播放时不重复订阅
+                let receiver = match play_task {
+                    Some(_) => None,
+                    None => KeyFrameReceiver::subscribe(&session.stream_name),
+                };
+                match receiver {
+                    Some(receiver) => {
+                        play_task = Some(smol::spawn(play_loop(
+                            receiver,
+                            session.stream_name.clone(),
+                            session.video_channel,
+                            session.audio_channel,
+                            writer.clone(),
+                        )));
+                        response(&req, "200 OK", &[
+                            ("Session", session_id.clone()),
+                            ("Range", "npt=0.000-".to_string()),
+                        ], "")
+                    }
+                    None if play_task.is_some() => response(&req, "200 OK", &[("Session", session_id.clone())], ""),
+                    None => response(&req, "404 Not Found", &[], ""),
+                }
+            }
+            "TEARDOWN" => {
+                let res = response(&req, "200 OK", &[("Session", session_id.clone())], "");
+                writer.lock().await.write_all(res.as_bytes()).await?;
+                break;
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => {
+                response(&req, "200 OK", &[("Session", session_id.clone())], "")
+            }
+            _ => response(&req, "405 Method Not Allowed", &[], ""),
+        };
+        writer.lock().await.write_all(response.as_bytes()).await?;
+    }
+
+    std::mem::drop(play_task);
+    log::info!("[conn={}][RTSP] disconnected, peer={}, stream_name={}", conn_id, peer_addr, session.stream_name);
+    Ok(())
+}
+
+/// 读取一个RTSP请求，会跳过客户端发来的interleaved数据（如RTCP）
+///
+/// 连接关闭时返回None，请求头或者请求体超过上限时回复400并返回Err
+async fn read_request(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> anyhow::Result<Option<RtspRequest>> {
+    loop {
+        // interleaved帧：$ + channel + 2字节长度
+        if buffer.first() == Some(&b'$') {
+            if buffer.len() >= 4 {
+                let frame_len = 4 + u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+                if buffer.len() >= frame_len {
+                    buffer.drain(..frame_len);
+                    continue;
+                }
+            }
+        } else if let Some(pos) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buffer[..pos]).to_string();
+            let mut lines = head.lines();
+            let mut first_line = lines.next().unwrap_or_default().split_whitespace();
+            let method = first_line.next().unwrap_or_default().to_string();
+            let url = first_line.next().unwrap_or_default().to_string();
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+                .collect::<HashMap<String, String>>();
+
+            let content_length = headers
+                .get("content-length")
+                .and_then(|x| x.parse::<usize>().ok())
+                .unwrap_or(0);
+            if pos > MAX_HEAD_BYTES || content_length > MAX_BODY_BYTES {
+                return reject_request(stream, pos, content_length).await;
+            }
+            let total_len = pos + 4 + content_length;
+            if buffer.len() >= total_len {
+                buffer.drain(..total_len);
+                return Ok(Some(RtspRequest { method, url, headers }));
+            }
+        } else if buffer.len() > MAX_HEAD_BYTES {
+            return reject_request(stream, buffer.len(), 0).await;
+        }
+
+        let mut bytes = [0u8; 4096];
+        let n = stream.read(&mut bytes).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&bytes[..n]);
+    }
+}
+
+async fn reject_request(stream: &mut TcpStream, head_len: usize, content_length: usize) -> anyhow::Result<Option<RtspRequest>> {
+    stream.write_all(b"RTSP/1.0 400 Bad Request\r\n\r\n").await?;
+    Err(anyhow::anyhow!("rtsp request too large, head={}, content_length={}", head_len, content_length))
+}
+
+fn response(req: &RtspRequest, status: &str, headers: &[(&str, String)], body: &str) -> String {
+    let mut text = format!("RTSP/1.0 {}\r\nCSeq: {}\r\nServer: {}\r\n", status, req.cseq(), server_name());
+    for (k, v) in headers {
+        text += &format!("{}: {}\r\n", k, v);
+    }
+    if !body.is_empty() {
+        text += &format!("Content-Length: {}\r\n", body.len());
+    }
+    text += "\r\n";
+    text += body;
+    text
+}
+
+/// Transport头部中的`interleaved=0-1`，RTCP使用下一个channel，所以不能是255
+fn parse_interleaved_channel(transport: &str) -> Option<u8> {
+    transport
+        .split(';')
+        .find_map(|x| x.trim().strip_prefix("interleaved="))
+        .and_then(|x| x.split('-').next())
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x < u8::MAX)
+}
+
+/// 根据缓存的sps/pps和aac header生成SDP
+fn build_sdp(stream_name: &str) -> Option<String> {
+    if !eventbus_map().contains_key(stream_name) {
+        return None;
+    }
+    let video_header = video_header_map().get(stream_name)?.value().clone();
+
+    let mut sps_list = vec![];
+    let mut pps_list = vec![];
+    for nalu in Nalu::from_rtmp_message(&video_header) {
+        match nalu.get_nal_unit_type() {
+            Nalu::UNIT_TYPE_SPS => sps_list.push(nalu.payload().to_vec()),
+            Nalu::UNIT_TYPE_PPS => pps_list.push(nalu.payload().to_vec()),
+            _ => {}
+        }
+    }
+    let sps = sps_list.first().filter(|x| x.len() >= 4)?;
+    let sprop_parameter_sets = sps_list
+        .iter()
+        .chain(pps_list.iter())
+        .map(base64::encode)
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let mut sdp = format!(
+        "v=0\r\n\
+        o=- 0 0 IN IP4 0.0.0.0\r\n\
+        s={}\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        t=0 0\r\n\
+        a=control:*\r\n\
+        m=video 0 RTP/AVP {pt}\r\n\
+        a=rtpmap:{pt} H264/90000\r\n\
+        a=fmtp:{pt} packetization-mode=1;profile-level-id={:02X}{:02X}{:02X};sprop-parameter-sets={}\r\n\
+        a=control:trackID={}\r\n",
+        stream_name,
+        sps[1],
+        sps[2],
+        sps[3],
+        sprop_parameter_sets,
+        VIDEO_TRACK_ID,
+        pt = VIDEO_PAYLOAD_TYPE,
+    );
+
+    let audio_config = audio_header_map()
+        .get(stream_name)
+        .and_then(|x| AudioSpecificConfig::from_rtmp_message(x.value()).map(|c| (c, x.body[2..].to_vec())));
+    if let Some((config, config_bytes)) = audio_config {
+        let config_hex = config_bytes.iter().map(|x| format!("{:02X}", x)).collect::<String>();
+        sdp += &format!(
+            "m=audio 0 RTP/AVP {pt}\r\n\
+            a=rtpmap:{pt} MPEG4-GENERIC/{}/{}\r\n\
+            a=fmtp:{pt} streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config={}\r\n\
+            a=control:trackID={}\r\n",
+            config.sampling_frequency(),
+            config.channel_configuration,
+            config_hex,
+            AUDIO_TRACK_ID,
+            pt = AUDIO_PAYLOAD_TYPE,
+        );
+    }
+    Some(sdp)
+}
+
+/// 把RTMP消息打包成RTP，通过interleaved channel发送给客户端
+async fn play_loop(
+    mut receiver: KeyFrameReceiver,
+    stream_name: String,
+    video_channel: Option<u8>,
+    audio_channel: Option<u8>,
+    writer: Arc<Mutex<TcpStream>>,
+) -> anyhow::Result<()> {
+    let mut video_packetizer = RtpPacketizer::new(VIDEO_PAYLOAD_TYPE, rand::random());
+    let mut audio_packetizer = RtpPacketizer::new(AUDIO_PAYLOAD_TYPE, rand::random());
+    let sample_rate = audio_header_map()
+        .get(&stream_name)
+        .and_then(|x| AudioSpecificConfig::from_rtmp_message(x.value()))
+        .map(|x| x.sampling_frequency())
+        .unwrap_or(44100);
+    let parameter_sets = video_header_map()
+        .get(&stream_name)
+        .map(|x| Nalu::from_rtmp_message(x.value()))
+        .unwrap_or_default();
+
+    while let Some(msg) = receiver.recv().await {
+        let mut frames = vec![];
+        match msg.header.message_type {
+            ChunkMessageType::VideoMessage => {
+                let channel = match video_channel {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let nalus = msg.nalus();
+                let timestamp = msg.header.timestamp.wrapping_mul(90);
+                // 关键帧前补发sps/pps，保证客户端能够解码
+                let nalus = if nalus.iter().any(|x| x.is_key_frame) {
+                    parameter_sets.iter().chain(nalus.iter()).collect::<Vec<&Nalu>>()
+                } else {
+                    nalus.iter().collect()
+                };
+                let last_index = nalus.len().saturating_sub(1);
+                for (i, nalu) in nalus.into_iter().enumerate() {
+                    for packet in video_packetizer.pack_h264(nalu.payload(), timestamp, i == last_index) {
+                        frames.push(interleaved_frame(channel, &packet));
+                    }
+                }
+            }
+            ChunkMessageType::AudioMessage => {
+                let channel = match audio_channel {
+                    Some(x) => x,
+                    None => continue,
+                };
+                // 只转发AAC raw data
+                if msg.body.len() <= 2 || msg.body[0] >> 4 != 10 || msg.body[1] != 0x01 {
+                    continue;
+                }
+                let timestamp = (msg.header.timestamp as u64 * sample_rate as u64 / 1000) as u32;
+                let packet = audio_packetizer.pack_aac(&msg.body[2..], timestamp);
+                frames.push(interleaved_frame(channel, &packet));
+            }
+            _ => continue,
+        }
+
+        let mut stream = writer.lock().await;
+        for frame in frames {
+            stream.write_all(&frame).await?;
+        }
+    }
+    log::info!("[RTSP] stream closed, stream_name={}", stream_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publisher::StreamPublisher;
+    use smol::net::TcpListener;
+
+    #[test]
+    fn parse_interleaved_channel_range() {
+        assert_eq!(parse_interleaved_channel("RTP/AVP/TCP;unicast;interleaved=2-3"), Some(2));
+        assert_eq!(parse_interleaved_channel("RTP/AVP/TCP; interleaved=254-255"), Some(254));
+        assert_eq!(parse_interleaved_channel("RTP/AVP/TCP;unicast;interleaved=255-256"), None);
+        assert_eq!(parse_interleaved_channel("RTP/AVP/TCP;unicast;interleaved=x"), None);
+        assert_eq!(parse_interleaved_channel("RTP/AVP;unicast;client_port=5000-5001"), None);
+    }
+
+    #[test]
+    fn build_sdp_from_sequence_headers() {
+        let stream_name = "live/test_rtsp_sdp";
+        assert!(build_sdp(stream_name).is_none());
+        smol::block_on(async {
+            let publisher = StreamPublisher::create(stream_name).unwrap();
+            // 只有eventbus，还没有sps/pps
+            assert!(build_sdp(stream_name).is_none());
+
+            // SPS、PPS和IDR，Baseline@3.0
+            let frame = [0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80, 0, 0, 0, 1, 0x65, 0x88, 0x84];
+            publisher.push_video(&frame, 0, true).await;
+            let sdp = build_sdp(stream_name).unwrap();
+            assert!(sdp.contains("m=video 0 RTP/AVP 96\r\n"));
+            assert!(sdp.contains("a=fmtp:96 packetization-mode=1;profile-level-id=42C01E;sprop-parameter-sets=Z0LAHg==,aM48gA==\r\n"));
+            assert!(sdp.contains("a=control:trackID=0\r\n"));
+            assert!(!sdp.contains("m=audio"));
+
+            // AAC LC 44.1kHz stereo
+            publisher.push_message(ChunkMessageType::AudioMessage, 0, vec![0xAF, 0x00, 0x12, 0x10]).await;
+            let sdp = build_sdp(stream_name).unwrap();
+            assert!(sdp.contains("a=rtpmap:97 MPEG4-GENERIC/44100/2\r\n"));
+            assert!(sdp.contains("sizelength=13;indexlength=3;indexdeltalength=3;config=1210\r\n"));
+            assert!(sdp.ends_with("a=control:trackID=1\r\n"));
+        });
+    }
+
+    /// 没有结束的请求头和过大的Content-Length都回复400并断开，不会一直缓存
+    #[test]
+    fn reject_oversized_request() {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            for request in [
+                vec![b'a'; MAX_HEAD_BYTES + 1],
+                format!("SET_PARAMETER rtsp://host/live/test RTSP/1.0\r\nCSeq: 1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1).into_bytes(),
+            ] {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                let (mut server, _) = listener.accept().await.unwrap();
+                client.write_all(&request).await.unwrap();
+
+                let mut buffer = vec![];
+                assert!(read_request(&mut server, &mut buffer).await.is_err());
+                assert!(buffer.len() <= MAX_HEAD_BYTES + 1);
+                drop(server);
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                assert_eq!(response, "RTSP/1.0 400 Bad Request\r\n\r\n");
+            }
+        });
+    }
+
+    #[test]
+    fn read_request_skips_interleaved_frames() {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            client.write_all(&[b'$', 1, 0, 2, 0xAA, 0xBB]).await.unwrap();
+            client.write_all(b"OPTIONS rtsp://host/live/test RTSP/1.0\r\nCSeq: 2\r\n\r\n").await.unwrap();
+
+            let mut buffer = vec![];
+            let req = read_request(&mut server, &mut buffer).await.unwrap().unwrap();
+            assert_eq!(req.method, "OPTIONS");
+            assert_eq!(req.stream_name().as_deref(), Some("live/test"));
+            assert_eq!(req.cseq(), "2");
+            assert!(buffer.is_empty());
+        });
+    }
+}
@@ -0,0 +1,49 @@
+use crate::protocol::h264::Nalu;
+use crate::rtmp_server::{gop_cache_map, video_header_map};
+
+/// 是否编译了解码器，没有时接口返回501
+pub const SUPPORTED: bool = cfg!(feature = "openh264");
+
+/// 最近一个关键帧，带sps/pps的Annex B格式，流不存在或者还没有关键帧时返回None
+pub fn latest_key_frame(stream_name: &str) -> Option<Vec<u8>> {
+    let key_frame = gop_cache_map().get(stream_name)?.first()?.clone();
+    let header = video_header_map().get(stream_name)?.value().clone();
+
+    let mut bytes = vec![];
+    for nalu in Nalu::from_rtmp_message(&header).iter().chain(Nalu::from_rtmp_message(&key_frame).iter()) {
+        bytes.extend_from_slice(nalu.as_ref());
+    }
+    Some(bytes)
+}
+
+/// 解码一帧并编码成JPEG
+#[cfg(feature = "openh264")]
+pub fn encode_jpeg(annexb: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use openh264::decoder::Decoder;
+    use openh264::formats::YUVSource;
+
+    const QUALITY: u8 = 80;
+
+    let mut decoder = Decoder::new()?;
+    let yuv = match decoder.decode(annexb)? {
+        Some(yuv) => yuv,
+        // 解码器有缓冲时需要flush才能拿到图像
+        None => decoder
+            .flush_remaining()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no picture decoded"))?,
+    };
+    let (width, height) = yuv.dimensions();
+    let mut rgb = vec![0; width * height * 3];
+    yuv.write_rgb8(&mut rgb);
+
+    let mut jpeg = vec![];
+    jpeg_encoder::Encoder::new(&mut jpeg, QUALITY).encode(&rgb, width as u16, height as u16, jpeg_encoder::ColorType::Rgb)?;
+    Ok(jpeg)
+}
+
+#[cfg(not(feature = "openh264"))]
+pub fn encode_jpeg(_annexb: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow::anyhow!("thumbnail requires the openh264 feature"))
+}
@@ -0,0 +1,217 @@
+//! 外部进程转码，推流开始时为配置的流启动一个进程，stdin写入FLV，从stdout读取FLV并发布为新的流，例如
+//!
+//! ```text
+//! --transcode 'live/test=live/test_360p:ffmpeg -loglevel error -i pipe:0 -c:v libx264 -s 640x360 -c:a copy -f flv pipe:1'
+//! ```
+//!
+//! 需要编译`transcode` feature，转码程序需要另外安装。命令按空白分割，不经过shell
+
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+
+/// 是否编译了转码，没有时不能配置`--transcode`
+pub const SUPPORTED: bool = cfg!(feature = "transcode");
+
+/// 转码规则，`--transcode <stream>=<output>:<command>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscodeRule {
+    /// 输入的流名称，格式为`app/stream`
+    pub stream_name: String,
+    /// 转码结果发布的流名称
+    pub output: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl FromStr for TranscodeRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stream_name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expect <stream>=<output>:<command>, got {}", s))?;
+        let (output, command) = rest
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expect <stream>=<output>:<command>, got {}", s))?;
+        let mut command = command.split_whitespace().map(str::to_string);
+        let program = command.next().ok_or_else(|| anyhow::anyhow!("empty transcode command, {}", s))?;
+        let rule = Self {
+            stream_name: stream_name.trim_matches('/').to_string(),
+            output: output.trim_matches('/').to_string(),
+            program,
+            args: command.collect(),
+        };
+        if rule.stream_name.is_empty() || rule.output.is_empty() || rule.stream_name == rule.output {
+            return Err(anyhow::anyhow!("transcode output must be a different stream, {}", s));
+        }
+        Ok(rule)
+    }
+}
+
+static TRANSCODE_RULES: OnceCell<Vec<TranscodeRule>> = OnceCell::new();
+
+/// 启动时设置转码规则，只能设置一次
+pub fn init_transcode_rules(rules: Vec<TranscodeRule>) {
+    if TRANSCODE_RULES.set(rules).is_err() {
+        log::warn!("transcode rules has been initialized");
+    }
+}
+
+fn transcode_rules() -> &'static [TranscodeRule] {
+    TRANSCODE_RULES.get_or_init(Vec::new)
+}
+
+/// 推流开始时调用，按规则启动转码进程，进程退出或者推流结束时停止
+pub fn start_transcode(stream_name: &str) {
+    for rule in transcode_rules().iter().filter(|x| x.stream_name == stream_name) {
+        let rule = rule.clone();
+        smol::spawn(async move {
+            log::info!("[Transcode] start, stream_name={}, output={}, program={}", rule.stream_name, rule.output, rule.program);
+            match run(&rule).await {
+                Ok(()) => log::info!("[Transcode] stop, stream_name={}, output={}", rule.stream_name, rule.output),
+                Err(e) => log::warn!("[Transcode] failed, stream_name={}, output={}, {:?}", rule.stream_name, rule.output, e),
+            }
+        })
+        .detach();
+    }
+}
+
+#[cfg(feature = "transcode")]
+async fn run(rule: &TranscodeRule) -> anyhow::Result<()> {
+    use smol::process::{Command, Stdio};
+
+    // 和录制一样使用有界队列，转码进程跟不上时丢帧到下一个关键帧
+    let rx = crate::recording::subscribe(&rule.stream_name)
+        .ok_or_else(|| anyhow::anyhow!("stream {} not found", rule.stream_name))?;
+    let mut child = Command::new(&rule.program)
+        .args(&rule.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("failed to spawn {}, {}", rule.program, e))?;
+    let stdin = child.stdin.take().ok_or_else(|| anyhow::anyhow!("stdin is not piped"))?;
+    let stdout = child.stdout.take().ok_or_else(|| anyhow::anyhow!("stdout is not piped"))?;
+
+    // 推流结束时关闭stdin，进程输出完剩余的帧之后退出
+    futures::future::try_join(feed(rx, stdin, &rule.stream_name), republish(stdout, &rule.output)).await?;
+    let status = child.status().await?;
+    log::info!("[Transcode] {} exited, {}, stream_name={}", rule.program, status, rule.stream_name);
+    Ok(())
+}
+
+#[cfg(not(feature = "transcode"))]
+async fn run(_rule: &TranscodeRule) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("transcode requires the transcode feature"))
+}
+
+/// 输入流写成FLV
+#[cfg(feature = "transcode")]
+async fn feed<W>(
+    rx: smol::channel::Receiver<std::sync::Arc<crate::protocol::rtmp::RtmpMessage>>,
+    mut writer: W,
+    stream_name: &str,
+) -> anyhow::Result<()>
+where
+    W: smol::io::AsyncWrite + Unpin,
+{
+    use crate::protocol::flv::{encode_tag, FlvTimestamp, FLV_HEADER_WITH_TAG0};
+    use smol::io::AsyncWriteExt;
+
+    writer.write_all(&FLV_HEADER_WITH_TAG0).await?;
+    let mut flv_timestamp = FlvTimestamp::for_stream(stream_name);
+    while let Ok(msg) = rx.recv().await {
+        let timestamp = flv_timestamp.rebase(msg.header.timestamp);
+        writer.write_all(&encode_tag(&msg, timestamp)?).await?;
+        writer.flush().await?;
+    }
+    writer.close().await?;
+    Ok(())
+}
+
+/// 读取FLV发布为`output`，读到结尾时停止发布
+#[cfg(feature = "transcode")]
+async fn republish<R>(mut reader: R, output: &str) -> anyhow::Result<()>
+where
+    R: smol::io::AsyncRead + Unpin,
+{
+    use crate::protocol::flv::{read_header, read_tag};
+    use crate::protocol::rtmp::{ChunkMessageType, RtmpMetaData};
+    use crate::publisher::StreamPublisher;
+    use std::convert::TryFrom;
+
+    read_header(&mut reader).await?;
+    let publisher = StreamPublisher::create(output)?;
+    while let Some(msg) = read_tag(&mut reader).await? {
+        if msg.header.message_type != ChunkMessageType::AMF0DataMessage {
+            publisher.push_message(msg.header.message_type, msg.header.timestamp, msg.body).await;
+            continue;
+        }
+        let values = msg.try_read_body_to_amf0().unwrap_or_default();
+        if let (Some("onMetaData"), Some(value)) = (values.first().and_then(|x| x.try_as_str()), values.get(1)) {
+            publisher.set_metadata(RtmpMetaData::try_from(value)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_transcode_rule() {
+        let rule: TranscodeRule = "/live/test=live/test_sd:ffmpeg -i pipe:0  -f flv pipe:1".parse().unwrap();
+        assert_eq!(
+            rule,
+            TranscodeRule {
+                stream_name: "live/test".to_string(),
+                output: "live/test_sd".to_string(),
+                program: "ffmpeg".to_string(),
+                args: vec!["-i", "pipe:0", "-f", "flv", "pipe:1"].into_iter().map(String::from).collect(),
+            }
+        );
+        assert!("live/test".parse::<TranscodeRule>().is_err());
+        assert!("live/test=live/test_sd".parse::<TranscodeRule>().is_err());
+        assert!("live/test=live/test_sd: ".parse::<TranscodeRule>().is_err());
+        assert!("live/test=live/test:cat".parse::<TranscodeRule>().is_err());
+    }
+
+    /// `cat`原样输出FLV，输出流得到和输入相同的sequence header和关键帧
+    #[cfg(all(feature = "transcode", unix))]
+    #[test]
+    fn transcode_through_cat() {
+        use crate::publisher::StreamPublisher;
+        use crate::rtmp_server::{eventbus_map, gop_cache_map, video_header_map};
+        use std::time::Duration;
+
+        let rule: TranscodeRule = "live/test_transcode_src=live/test_transcode_out:cat".parse().unwrap();
+        smol::block_on(async {
+            let source = StreamPublisher::create(&rule.stream_name).unwrap();
+            let task = smol::spawn(async move { run(&rule).await });
+            while source.subscriber_count() == 0 {
+                smol::Timer::after(Duration::from_millis(10)).await;
+            }
+            // SPS、PPS和IDR
+            let frame = [0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80, 0, 0, 0, 1, 0x65, 0x88, 0x84];
+            source.push_video(&frame, 0, true).await;
+
+            let output = "live/test_transcode_out";
+            for _ in 0..100 {
+                if gop_cache_map().get(output).map(|x| !x.is_empty()).unwrap_or(false) {
+                    break;
+                }
+                smol::Timer::after(Duration::from_millis(20)).await;
+            }
+            let source_header = video_header_map().get("live/test_transcode_src").map(|x| x.body.clone());
+            assert_eq!(video_header_map().get(output).map(|x| x.body.clone()), source_header);
+            assert_eq!(gop_cache_map().get(output).unwrap()[0].body, gop_cache_map().get("live/test_transcode_src").unwrap()[0].body);
+
+            // 输入流结束后cat退出，输出流也停止
+            drop(source);
+            task.await.unwrap();
+            assert!(!eventbus_map().contains_key(output));
+        });
+    }
+}
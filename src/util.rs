@@ -1,13 +1,35 @@
 use chrono::Local;
 use rand::Rng;
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::future::Future;
 use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-pub fn init_logger() {
-    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info");
+use once_cell::sync::OnceCell;
+
+static DUMP_PACKETS: AtomicBool = AtomicBool::new(false);
+static CONN_ID: AtomicU64 = AtomicU64::new(1);
+static SERVER_NAME: OnceCell<String> = OnceCell::new();
+
+/// 初始化日志
+///
+/// `filter`使用env_logger的语法，例如`info,river::rtmp_server=warn`，
+/// 为None时读取`RUST_LOG`，默认`info`
+pub fn init_logger(filter: Option<&str>) {
+    let mut builder = match filter {
+        Some(filter) => {
+            let mut builder = env_logger::Builder::new();
+            builder.parse_filters(filter);
+            builder
+        }
+        None => env_logger::Builder::from_env(
+            env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+        ),
+    };
     // 设置日志打印格式
-    env_logger::Builder::from_env(env)
+    builder
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -30,11 +52,11 @@ pub fn bytes_hex_format(bytes: &[u8]) -> String {
     for byte in bytes {
         text += &format!("{:02X}", byte);
         if byte.is_ascii_graphic() {
-            arr[i % COLUMN] = byte.clone() as char;
+            arr[i % COLUMN] = *byte as char;
         } else {
             arr[i % COLUMN] = '.';
         }
-        text += &format!(" ");
+        text += " ";
         i += 1;
         // 每8列多一个空格
         if i % 8 == 0 {
@@ -71,8 +93,42 @@ pub fn bytes_hex_format(bytes: &[u8]) -> String {
     text
 }
 
+/// 分配递增的连接id，RTMP、HTTP和WebSocket连接共用，日志中的`[conn=<id>]`可以区分同一个地址的多次连接
+pub fn next_conn_id() -> u64 {
+    CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 启动时设置`--server-name`，只能设置一次
+pub fn init_server_name(name: String) {
+    if SERVER_NAME.set(name).is_err() {
+        log::warn!("server name has been initialized");
+    }
+}
+
+/// 配置的服务器名称，没有配置时为None，RTMP的`fmsVer`和onMetaData各自保留原来的默认值
+pub fn configured_server_name() -> Option<&'static str> {
+    SERVER_NAME.get().map(String::as_str)
+}
+
+/// HTTP和RTSP响应的`Server`头
+pub fn server_name() -> &'static str {
+    configured_server_name().unwrap_or("river")
+}
+
+/// 开启后`print_hex`总是输出报文内容
+pub fn set_dump_packets(enabled: bool) {
+    DUMP_PACKETS.store(enabled, Ordering::Relaxed);
+}
+
+/// 是否需要输出报文的十六进制内容，`--dump-packets`或者trace级别时开启
+pub fn dump_packets_enabled() -> bool {
+    DUMP_PACKETS.load(Ordering::Relaxed) || log::log_enabled!(log::Level::Trace)
+}
+
 pub fn print_hex(bytes: &[u8]) {
-    println!("{}", bytes_hex_format(bytes));
+    if dump_packets_enabled() {
+        println!("{}", bytes_hex_format(bytes));
+    }
 }
 
 /// 执行一个新协程，并且在错误时打印错误信息
@@ -89,6 +145,32 @@ where
     .detach();
 }
 
+/// 监听TCP端口，IPv6地址同时接受IPv4连接（dual-stack），不依赖系统的`bindv6only`设置
+pub fn bind_tcp(addr: SocketAddr) -> std::io::Result<smol::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    smol::net::TcpListener::try_from(std::net::TcpListener::from(socket))
+}
+
+/// 日志中显示的对端地址，dual-stack监听收到的IPv4连接显示为IPv4地址
+pub fn display_addr(addr: SocketAddr) -> String {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()).to_string(),
+            None => addr.to_string(),
+        },
+        IpAddr::V4(_) => addr.to_string(),
+    }
+}
+
 /// 生成随机字节数组
 pub fn gen_random_bytes(len: u32) -> Vec<u8> {
     let mut rng = rand::thread_rng();
@@ -98,3 +180,23 @@ pub fn gen_random_bytes(len: u32) -> Vec<u8> {
     }
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dual_stack_accepts_ipv4() {
+        smol::block_on(async {
+            let listener = match bind_tcp("[::]:0".parse().unwrap()) {
+                Ok(listener) => listener,
+                // 没有IPv6的环境
+                Err(_) => return,
+            };
+            let port = listener.local_addr().unwrap().port();
+            let client = smol::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let (_server, peer) = listener.accept().await.unwrap();
+            assert_eq!(display_addr(peer), client.local_addr().unwrap().to_string());
+        });
+    }
+}
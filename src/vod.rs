@@ -0,0 +1,319 @@
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use smol::fs::File;
+use smol::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use smol::net::TcpStream;
+
+use crate::http_common::header;
+use crate::protocol::{fmp4, ts};
+use crate::recording::RECORDINGS_DIR;
+use crate::util::server_name;
+
+/// moov和moof的长度上限，超过时认为不是录制的文件
+const MAX_BOX_LEN: u64 = 16 * 1024 * 1024;
+
+/// 点播录制的MP4和TS文件，`GET /vod/<stream>/<file>.mp4`，支持`Range`请求
+///
+/// `?t=<秒>`从这个时刻之前最近的关键帧开始播放，实际开始的时刻在`X-Start-Time`响应头中，
+/// 需要分片MP4或者TS，录制结束时整理过的MP4从头开始
+///
+/// `uri`是包含query的请求路径，`req`是完整的请求头
+pub async fn serve(stream: &mut TcpStream, uri: &str, req: &str) -> anyhow::Result<()> {
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let file_path = match path.strip_prefix("/vod/").and_then(resolve) {
+        Some(file_path) => file_path,
+        None => return write_head(stream, "400 Bad Request", &[], 0).await,
+    };
+    let start_time = match query.split('&').find_map(|x| x.strip_prefix("t=")).map(str::parse::<f64>) {
+        None => None,
+        Some(Ok(t)) if t.is_finite() && t >= 0.0 => Some(t),
+        Some(_) => return write_head(stream, "400 Bad Request", &[], 0).await,
+    };
+    let content_type = if file_path.extension().map(|x| x == "ts").unwrap_or(false) { "video/mp2t" } else { "video/mp4" };
+    let mut file = match smol::fs::File::open(&file_path).await {
+        Ok(file) => file,
+        Err(_) => return write_head(stream, "404 Not Found", &[], 0).await,
+    };
+    let file_len = file.metadata().await?.len();
+
+    if let Some(t) = start_time {
+        let position = match content_type {
+            "video/mp2t" => seek_ts(&mut file, file_len, t).await?,
+            _ => seek_fmp4(&mut file, file_len, t).await?,
+        }
+        .unwrap_or(StartPosition { head: 0, offset: 0, seconds: 0.0 });
+        log::info!("[VOD] file={}, t={}, start={:?}", file_path.display(), t, position);
+        let seconds = format!("{:.3}", position.seconds);
+        let headers = [("Content-Type", content_type), ("X-Start-Time", seconds.as_str())];
+        write_head(stream, "200 OK", &headers, position.head + file_len - position.offset).await?;
+        if req.starts_with("HEAD ") {
+            return Ok(());
+        }
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        smol::io::copy((&mut file).take(position.head), &mut *stream).await?;
+        file.seek(std::io::SeekFrom::Start(position.offset)).await?;
+        smol::io::copy(file, &mut *stream).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+
+    let range = header(req, "range").map(|x| parse_range(x, file_len));
+    let (status, range) = match range {
+        None => ("200 OK", 0..file_len),
+        Some(Some(range)) => ("206 Partial Content", range),
+        Some(None) => {
+            let content_range = format!("bytes */{}", file_len);
+            return write_head(stream, "416 Range Not Satisfiable", &[("Content-Range", &content_range)], 0).await;
+        }
+    };
+    log::info!("[VOD] {}, file={}, range={:?}", status, file_path.display(), range);
+
+    let content_range = format!("bytes {}-{}/{}", range.start, range.end.saturating_sub(1), file_len);
+    let mut headers = vec![("Content-Type", content_type), ("Accept-Ranges", "bytes")];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", &content_range));
+    }
+    write_head(stream, status, &headers, range.end - range.start).await?;
+    if req.starts_with("HEAD ") {
+        return Ok(());
+    }
+
+    file.seek(std::io::SeekFrom::Start(range.start)).await?;
+    smol::io::copy(file.take(range.end - range.start), &mut *stream).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// 请求路径转换成录制目录下的文件，只允许普通的路径片段和`.mp4`、`.ts`文件，防止目录穿越
+fn resolve(relative: &str) -> Option<PathBuf> {
+    if !(relative.ends_with(".mp4") || relative.ends_with(".ts")) || relative.contains('\\') {
+        return None;
+    }
+    let relative = Path::new(relative);
+    if !relative.components().all(|x| matches!(x, Component::Normal(_))) {
+        return None;
+    }
+    Some(Path::new(RECORDINGS_DIR).join(relative))
+}
+
+/// `?t=`定位的结果，先发送文件开头的`head`字节，再从`offset`发送到结尾
+#[derive(Debug, PartialEq)]
+struct StartPosition {
+    head: u64,
+    offset: u64,
+    /// 开始的关键帧距离文件中第一帧的秒数
+    seconds: f64,
+}
+
+/// 分片MP4按moof的tfdt定位，`head`是ftyp和moov，没有moof时返回None
+async fn seek_fmp4(file: &mut File, file_len: u64, t: f64) -> anyhow::Result<Option<StartPosition>> {
+    let mut timescale = None;
+    let mut first_moof = None;
+    let mut first_dts = None;
+    let mut found: Option<StartPosition> = None;
+    let mut pos = 0;
+    while pos + 8 <= file_len {
+        file.seek(std::io::SeekFrom::Start(pos)).await?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header[..8]).await?;
+        let (size, header_len) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => (file_len - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..]).await?;
+                (u64::from_be_bytes([header[8], header[9], header[10], header[11], header[12], header[13], header[14], header[15]]), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_len {
+            break;
+        }
+        let box_type = &header[4..8];
+        if box_type == b"moov" || box_type == b"moof" {
+            if size > MAX_BOX_LEN {
+                return Err(anyhow::anyhow!("box too large, size={}", size));
+            }
+            let mut payload = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut payload).await?;
+            if box_type == b"moov" {
+                timescale = fmp4::media_timescale(&payload);
+                pos += size;
+                continue;
+            }
+            let head = *first_moof.get_or_insert(pos);
+            if let (Some(timescale), Some((dts, true))) = (timescale, fmp4::fragment_start(&payload)) {
+                let first_dts = *first_dts.get_or_insert(dts);
+                let seconds = dts.saturating_sub(first_dts) as f64 / timescale as f64;
+                // 录制从关键帧开始，第一个关键帧之后不会再回到t之前
+                if found.is_some() && seconds > t {
+                    break;
+                }
+                found = Some(StartPosition { head, offset: pos, seconds });
+            }
+        }
+        pos += size;
+    }
+    Ok(found)
+}
+
+/// TS按关键帧的PCR定位，从关键帧之前的PAT开始发送，不需要`head`
+async fn seek_ts(file: &mut File, file_len: u64, t: f64) -> anyhow::Result<Option<StartPosition>> {
+    /// PCR为33位
+    const PCR_MASK: u64 = (1 << 33) - 1;
+
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    let mut reader = BufReader::with_capacity(ts::PACKET_LEN * 512, file);
+    let mut packet = [0; ts::PACKET_LEN];
+    let mut last_pat = None;
+    let mut first_pcr = None;
+    let mut found: Option<StartPosition> = None;
+    let mut pos = 0;
+    while pos + ts::PACKET_LEN as u64 <= file_len {
+        reader.read_exact(&mut packet).await?;
+        if ts::is_pat(&packet) {
+            last_pat = Some(pos);
+        } else if let (Some(pat), Some(pcr)) = (last_pat, ts::key_frame_pcr(&packet)) {
+            let first_pcr = *first_pcr.get_or_insert(pcr);
+            let seconds = (pcr.wrapping_sub(first_pcr) & PCR_MASK) as f64 / 90000.0;
+            if found.is_some() && seconds > t {
+                break;
+            }
+            found = Some(StartPosition { head: 0, offset: pat, seconds });
+        }
+        pos += ts::PACKET_LEN as u64;
+    }
+    Ok(found)
+}
+
+/// 解析单个`bytes=start-end`、`bytes=start-`或者`bytes=-suffix`，返回左闭右开的区间
+///
+/// 不支持多个区间，无法满足时返回None
+fn parse_range(value: &str, file_len: u64) -> Option<Range<u64>> {
+    let spec = value.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?.min(file_len);
+            file_len - suffix..file_len
+        }
+        (start, "") => start.parse::<u64>().ok()?..file_len,
+        (start, end) => start.parse::<u64>().ok()?..end.parse::<u64>().ok()?.saturating_add(1).min(file_len),
+    };
+    if range.start >= range.end {
+        return None;
+    }
+    Some(range)
+}
+
+async fn write_head(stream: &mut TcpStream, status: &str, headers: &[(&str, &str)], content_length: u64) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nServer: {}\r\nConnection: close\r\n", status, server_name());
+    for (key, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", content_length));
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_forms() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+        // 结束位置超过文件长度时截断
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(990..1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    /// 关键帧在0、1000、2000毫秒，中间是非关键帧
+    fn frames() -> impl Iterator<Item = (u32, bool)> {
+        (0..75).map(|i| (i * 40, i % 25 == 0))
+    }
+
+    async fn write_temp(name: &str, data: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("river_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        let file = File::open(&path).await.unwrap();
+        std::fs::remove_file(&path).ok();
+        file
+    }
+
+    #[test]
+    fn seek_fragmented_mp4_to_key_frame() {
+        use crate::protocol::fmp4::{Fmp4Encoder, Track};
+
+        let mut encoder = Fmp4Encoder::new(Track { duration: 40, timescale: 1000, ..Default::default() });
+        let mut data = encoder.init_segment();
+        let head = data.len() as u64;
+        let mut key_frame_offsets = vec![];
+        let mut previous_key_frame = false;
+        for (timestamp, key_frame) in frames() {
+            // 每帧一个分片，下一帧到达时输出
+            for bytes in encoder.push_frame(&[0, 0, 0, 1, if key_frame { 0x65 } else { 0x41 }], timestamp, 0, key_frame) {
+                if previous_key_frame {
+                    key_frame_offsets.push(data.len() as u64);
+                }
+                data.extend(bytes);
+            }
+            previous_key_frame = key_frame;
+        }
+        data.extend(encoder.flush().unwrap());
+        smol::block_on(async {
+            let len = data.len() as u64;
+            let mut file = write_temp("seek.mp4", &data).await;
+            let position = seek_fmp4(&mut file, len, 1.5).await.unwrap().unwrap();
+            assert_eq!(position, StartPosition { head, offset: key_frame_offsets[1], seconds: 1.0 });
+            let position = seek_fmp4(&mut file, len, 0.0).await.unwrap().unwrap();
+            assert_eq!(position, StartPosition { head, offset: head, seconds: 0.0 });
+            // 超过结尾时从最后一个关键帧开始
+            let position = seek_fmp4(&mut file, len, 100.0).await.unwrap().unwrap();
+            assert_eq!(position.offset, key_frame_offsets[2]);
+            // 只有init segment时无法定位
+            assert_eq!(seek_fmp4(&mut file, head, 1.0).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn seek_ts_to_key_frame() {
+        use crate::protocol::ts::TsMuxer;
+
+        let mut muxer = TsMuxer::new(false);
+        let mut data = vec![];
+        let mut key_frame_offsets = vec![];
+        for (timestamp, key_frame) in frames() {
+            if key_frame {
+                key_frame_offsets.push(data.len() as u64);
+            }
+            data.extend(muxer.video(&[0, 0, 0, 1, if key_frame { 0x65 } else { 0x41 }], timestamp + 500, timestamp + 500, key_frame));
+        }
+        smol::block_on(async {
+            let len = data.len() as u64;
+            let mut file = write_temp("seek.ts", &data).await;
+            let position = seek_ts(&mut file, len, 2.2).await.unwrap().unwrap();
+            assert_eq!(position, StartPosition { head: 0, offset: key_frame_offsets[2], seconds: 2.0 });
+            let position = seek_ts(&mut file, len, 0.5).await.unwrap().unwrap();
+            assert_eq!(position, StartPosition { head: 0, offset: 0, seconds: 0.0 });
+        });
+    }
+
+    #[test]
+    fn reject_path_traversal() {
+        assert_eq!(resolve("live/test/output.mp4"), Some(Path::new(RECORDINGS_DIR).join("live/test/output.mp4")));
+        assert_eq!(resolve("../secret.mp4"), None);
+        assert_eq!(resolve("live/../../secret.mp4"), None);
+        assert_eq!(resolve("/etc/secret.mp4"), None);
+        assert_eq!(resolve("live\\..\\secret.mp4"), None);
+        assert_eq!(resolve("live/test/output.flv"), None);
+        assert!(resolve("live/test/1700000000000.ts").is_some());
+    }
+}
@@ -0,0 +1,343 @@
+//! WHEP播放，浏览器通过WebRTC拉流
+//!
+//! - `POST /whep/<stream>`，请求体为SDP offer，返回`201 Created`和SDP answer，`Location`为会话地址
+//! - `DELETE /whep/sessions/<id>`，结束会话
+//!
+//! 不支持trickle ICE，answer中包含收集到的全部候选地址。目前只发送H.264视频，AAC需要转码成Opus，暂不支持
+
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::TcpStream;
+
+use crate::cors;
+use crate::http_common::header;
+use crate::naming;
+use crate::util::server_name;
+
+/// 是否编译了WebRTC，没有时接口返回501
+pub const SUPPORTED: bool = cfg!(feature = "webrtc");
+
+/// SDP offer的大小上限
+const MAX_OFFER_BYTES: usize = 64 * 1024;
+
+/// 处理`/whep/`下的请求，`head`是已经读取的请求数据，可能包含部分请求体
+pub async fn serve(stream: &mut TcpStream, method: &str, path: &str, head: &[u8]) -> anyhow::Result<()> {
+    let req = String::from_utf8_lossy(head);
+    let origin = cors::request_origin(&req);
+    let (status, headers, body) = match method {
+        // 浏览器跨域POST带`Content-Type: application/sdp`时会先发送预检请求
+        "OPTIONS" => ("204 No Content", "Access-Control-Allow-Methods: POST, DELETE, OPTIONS\r\n\
+        Access-Control-Allow-Headers: Content-Type\r\n".to_string(), String::new()),
+        "POST" => match path
+            .strip_prefix("/whep/")
+            .filter(|x| !x.is_empty() && !x.starts_with("sessions/"))
+            .and_then(|x| naming::normalize_path(x).ok())
+        {
+            Some(stream_name) => {
+                let offer = read_body(stream, head).await?;
+                let peer_addr = crate::util::display_addr(stream.peer_addr()?);
+                play(&stream_name, offer, &peer_addr).await
+            }
+            None => ("404 Not Found", String::new(), String::new()),
+        },
+        "DELETE" => match path.strip_prefix("/whep/sessions/").and_then(|x| x.parse().ok()) {
+            Some(_) if !SUPPORTED => not_supported(),
+            Some(session_id) if close_session(session_id).await => ("200 OK", String::new(), String::new()),
+            _ => ("404 Not Found", String::new(), String::new()),
+        },
+        _ => ("405 Method Not Allowed", String::new(), String::new()),
+    };
+    let content_type = if status.starts_with("201") { "application/sdp" } else { "text/plain" };
+    let response = format!("HTTP/1.1 {}\r\n\
+    Server: {}\r\n\
+    Content-Type: {}\r\n\
+    {}{}\
+    Connection: close\r\n\
+    Content-Length: {}\r\n\
+    \r\n{}", status, server_name(), content_type, cors::allow_origin_header(origin), headers, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn not_supported() -> (&'static str, String, String) {
+    ("501 Not Implemented", String::new(), "whep requires the webrtc feature".to_string())
+}
+
+/// 按`Content-Length`读取完整的请求体
+async fn read_body(stream: &mut TcpStream, head: &[u8]) -> anyhow::Result<String> {
+    let (req, mut body) = split_request(head);
+    let content_length = content_length(&req).min(MAX_OFFER_BYTES);
+    if body.len() < content_length {
+        let mut rest = vec![0; content_length - body.len()];
+        stream.read_exact(&mut rest).await?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(content_length);
+    Ok(String::from_utf8(body)?)
+}
+
+/// 拆分成请求头和已经读取的请求体
+fn split_request(data: &[u8]) -> (String, Vec<u8>) {
+    match data.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(pos) => (String::from_utf8_lossy(&data[..pos]).into_owned(), data[pos + 4..].to_vec()),
+        None => (String::from_utf8_lossy(data).into_owned(), vec![]),
+    }
+}
+
+fn content_length(req: &str) -> usize {
+    header(req, "content-length").and_then(|x| x.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(not(feature = "webrtc"))]
+async fn play(_stream_name: &str, _offer: String, _peer_addr: &str) -> (&'static str, String, String) {
+    not_supported()
+}
+
+#[cfg(not(feature = "webrtc"))]
+async fn close_session(_session_id: u64) -> bool {
+    false
+}
+
+#[cfg(feature = "webrtc")]
+async fn play(stream_name: &str, offer: String, peer_addr: &str) -> (&'static str, String, String) {
+    if crate::rtmp_server::eventbus_map().get(stream_name).is_none() {
+        return ("404 Not Found", String::new(), String::new());
+    }
+    match session::create_session(stream_name, offer, peer_addr).await {
+        Ok((session_id, answer)) => {
+            log::info!("[WHEP] session={} created, stream_name={}, peer={}", session_id, stream_name, peer_addr);
+            let headers = format!("Location: /whep/sessions/{}\r\nAccess-Control-Expose-Headers: Location\r\n", session_id);
+            ("201 Created", headers, answer)
+        }
+        Err(e) => {
+            log::warn!("[WHEP] failed to create session, stream_name={}, {:?}", stream_name, e);
+            ("400 Bad Request", String::new(), e.to_string())
+        }
+    }
+}
+
+#[cfg(feature = "webrtc")]
+async fn close_session(session_id: u64) -> bool {
+    session::close_session(session_id).await
+}
+
+#[cfg(feature = "webrtc")]
+mod session {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use dashmap::DashMap;
+    use once_cell::sync::OnceCell;
+    use rtc::media::Sample;
+    use rtc::peer_connection::configuration::media_engine::MIME_TYPE_H264;
+    use rtc::rtp_transceiver::rtp_sender::{RTCRtpCodec, RTCRtpCodecParameters, RTCRtpCodingParameters, RTCRtpEncodingParameters, RtpCodecKind};
+    use smol::Timer;
+    use webrtc::media_stream::track_local::static_sample::TrackLocalStaticSample;
+    use webrtc::media_stream::track_local::TrackLocal;
+    use webrtc::media_stream::MediaStreamTrack;
+    use webrtc::peer_connection::{
+        register_default_interceptors, MediaEngine, PeerConnection, PeerConnectionBuilder, PeerConnectionEventHandler,
+        RTCConfigurationBuilder, RTCIceGatheringState, RTCPeerConnectionState, RTCSessionDescription, Registry,
+    };
+
+    use crate::metrics::metrics;
+    use crate::protocol::h264::Nalu;
+    use crate::protocol::rtmp::ChunkMessageType;
+    use crate::rtmp_server::{video_header_map, KeyFrameReceiver};
+    use crate::util::{next_conn_id, spawn_and_log_error};
+
+    /// 等待ICE候选收集完成的最长时间
+    const GATHER_TIMEOUT: Duration = Duration::from_secs(3);
+    const PAYLOAD_TYPE: u8 = 102;
+
+    fn session_map() -> &'static DashMap<u64, Arc<dyn PeerConnection>> {
+        static INSTANCE: OnceCell<DashMap<u64, Arc<dyn PeerConnection>>> = OnceCell::new();
+        INSTANCE.get_or_init(DashMap::new)
+    }
+
+    struct Handler {
+        session_id: u64,
+        gathered: smol::channel::Sender<()>,
+    }
+
+    #[async_trait::async_trait]
+    impl PeerConnectionEventHandler for Handler {
+        async fn on_ice_gathering_state_change(&self, state: RTCIceGatheringState) {
+            if state == RTCIceGatheringState::Complete {
+                let _ = self.gathered.try_send(());
+            }
+        }
+
+        async fn on_connection_state_change(&self, state: RTCPeerConnectionState) {
+            log::info!("[WHEP] session={} connection state: {}", self.session_id, state);
+            if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                session_map().remove(&self.session_id);
+            }
+        }
+    }
+
+    fn h264_codec() -> RTCRtpCodec {
+        RTCRtpCodec {
+            mime_type: MIME_TYPE_H264.to_owned(),
+            clock_rate: 90000,
+            channels: 0,
+            sdp_fmtp_line: "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f".to_owned(),
+            rtcp_feedback: vec![],
+        }
+    }
+
+    /// 协商完成后返回会话id和SDP answer，同时开始发送视频
+    pub(super) async fn create_session(stream_name: &str, offer: String, peer_addr: &str) -> anyhow::Result<(u64, String)> {
+        let session_id = next_conn_id();
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_codec(
+            RTCRtpCodecParameters { rtp_codec: h264_codec(), payload_type: PAYLOAD_TYPE },
+            RtpCodecKind::Video,
+        )?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+        let (gathered, gather_complete) = smol::channel::bounded(1);
+        let pc = PeerConnectionBuilder::new()
+            .with_configuration(RTCConfigurationBuilder::new().build())
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_handler(Arc::new(Handler { session_id, gathered }))
+            .with_udp_addrs(vec!["0.0.0.0:0".to_string()])
+            .build()
+            .await?;
+        let pc: Arc<dyn PeerConnection> = Arc::new(pc);
+
+        let ssrc = rand::random::<u32>();
+        let track = Arc::new(TrackLocalStaticSample::new(
+            Instant::now(),
+            MediaStreamTrack::new(
+                format!("river-{}", session_id),
+                format!("river-video-{}", session_id),
+                stream_name.to_string(),
+                RtpCodecKind::Video,
+                vec![RTCRtpEncodingParameters {
+                    rtp_coding_parameters: RTCRtpCodingParameters { ssrc: Some(ssrc), ..Default::default() },
+                    codec: h264_codec(),
+                    ..Default::default()
+                }],
+            ),
+        )?);
+        let sender = pc.add_track(track.clone() as Arc<dyn TrackLocal>).await?;
+
+        pc.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+        let answer = pc.create_answer(None).await?;
+        pc.set_local_description(answer).await?;
+        smol::future::or(async { gather_complete.recv().await.ok() }, async {
+            Timer::after(GATHER_TIMEOUT).await;
+            log::warn!("[WHEP] session={} ice gathering timeout", session_id);
+            None
+        })
+        .await;
+        let answer = pc
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("no local description"))?
+            .sdp;
+        let payload_type = sender
+            .get_parameters()
+            .await?
+            .rtp_parameters
+            .codecs
+            .first()
+            .map(|x| x.payload_type)
+            .unwrap_or(PAYLOAD_TYPE);
+
+        session_map().insert(session_id, pc.clone());
+        let stream_name = stream_name.to_string();
+        let peer_addr = peer_addr.to_string();
+        spawn_and_log_error(async move {
+            let result = send_video(session_id, &stream_name, &peer_addr, track, ssrc, payload_type).await;
+            session_map().remove(&session_id);
+            pc.close().await?;
+            log::info!("[WHEP] session={} closed, stream_name={}", session_id, stream_name);
+            result
+        });
+        Ok((session_id, answer))
+    }
+
+    /// 从最近的关键帧开始发送，关键帧之前带上sps/pps，会话结束或者推流结束时返回
+    async fn send_video(
+        session_id: u64,
+        stream_name: &str,
+        peer_addr: &str,
+        track: Arc<TrackLocalStaticSample>,
+        ssrc: u32,
+        payload_type: u8,
+    ) -> anyhow::Result<()> {
+        let mut receiver = match KeyFrameReceiver::subscribe(stream_name) {
+            Some(receiver) => receiver.rate_limited(),
+            None => return Ok(()),
+        };
+        let viewer = metrics().register_viewer(session_id, stream_name, peer_addr, "whep");
+        let mut header = video_header_map().get(stream_name).map(|x| Nalu::from_rtmp_message(&x));
+        // 下一帧到达时才知道当前帧的时长，所以延迟一帧发送
+        let mut pending: Option<(Vec<u8>, u32)> = None;
+        while let Some(msg) = receiver.recv().await {
+            if !session_map().contains_key(&session_id) {
+                break;
+            }
+            receiver.update_stats(&viewer);
+            if msg.header.message_type != ChunkMessageType::VideoMessage || msg.is_video_end_of_sequence() {
+                continue;
+            }
+            if msg.is_sequence_header() {
+                header = Some(Nalu::from_rtmp_message(&msg));
+                continue;
+            }
+            let mut frame = vec![];
+            if msg.is_video_key_frame() {
+                for nalu in header.iter().flatten() {
+                    frame.extend_from_slice(nalu.as_ref());
+                }
+            }
+            for nalu in msg.nalus() {
+                frame.extend_from_slice(nalu.as_ref());
+            }
+
+            let timestamp = msg.header.timestamp;
+            if let Some((data, last_timestamp)) = pending.replace((frame, timestamp)) {
+                let sample = Sample {
+                    duration: Duration::from_millis(timestamp.saturating_sub(last_timestamp) as u64),
+                    data: data.into(),
+                    ..Sample::new(Instant::now())
+                };
+                viewer.bytes_sent.fetch_add(sample.data.len() as u64);
+                track.sample_writer(ssrc, payload_type).write_sample(&sample).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 关闭会话，不存在时返回false
+    pub(super) async fn close_session(session_id: u64) -> bool {
+        match session_map().remove(&session_id) {
+            Some((_, pc)) => {
+                if let Err(e) = pc.close().await {
+                    log::warn!("[WHEP] session={} close error, {:?}", session_id, e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_head_and_body() {
+        let data = b"POST /whep/live/test HTTP/1.1\r\nContent-Type: application/sdp\r\ncontent-length: 10\r\n\r\nv=0\r\no=-";
+        let (req, body) = split_request(data);
+        assert_eq!(content_length(&req), 10);
+        assert_eq!(body, b"v=0\r\no=-");
+        let (req, body) = split_request(b"OPTIONS /whep/live/test HTTP/1.1\r\n");
+        assert_eq!(content_length(&req), 0);
+        assert!(body.is_empty());
+    }
+}
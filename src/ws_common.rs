@@ -0,0 +1,244 @@
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::sink::SinkExt;
+use futures::stream::SplitStream;
+use futures::{Stream, StreamExt};
+use smol::channel::Sender;
+use smol::net::{SocketAddr, TcpStream};
+
+use crate::naming;
+
+/// WebSocket子协议，通过`Sec-WebSocket-Protocol`协商输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subprotocol {
+    /// 1字节的音视频标志 + H264 Annex B或者AAC ADTS
+    H264Mix,
+    /// fMP4 init segment + fragments
+    Fmp4,
+    /// 每一帧先发送JSON文本描述，再发送二进制数据
+    JsonMeta,
+}
+
+impl Subprotocol {
+    pub const ALL: [Subprotocol; 3] = [Subprotocol::H264Mix, Subprotocol::Fmp4, Subprotocol::JsonMeta];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subprotocol::H264Mix => "h264-mix",
+            Subprotocol::Fmp4 => "fmp4",
+            Subprotocol::JsonMeta => "json-meta",
+        }
+    }
+
+    /// 按客户端的顺序选择第一个支持的子协议，`h264-mix, fmp4`
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .map(|x| x.trim())
+            .find_map(|name| Subprotocol::ALL.iter().find(|x| x.as_str() == name).copied())
+    }
+}
+
+/// JSON字符串转义
+pub fn json_escape(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => text.push_str("\\\""),
+            '\\' => text.push_str("\\\\"),
+            c if (c as u32) < 0x20 => text.push_str(&format!("\\u{:04x}", c as u32)),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// AMF0值转换成JSON，onTextData等数据消息转发给浏览器时使用
+///
+/// 日期转换成毫秒时间戳，无法表示的数值和AMF3的值转换成null
+pub fn amf_to_json(value: &amf::amf0::Value) -> String {
+    use amf::amf0::Value;
+
+    let object = |entries: &[amf::Pair<String, Value>]| {
+        let fields = entries
+            .iter()
+            .map(|x| format!("\"{}\":{}", json_escape(&x.key), amf_to_json(&x.value)))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(","))
+    };
+    match value {
+        Value::Number(x) if x.is_finite() => x.to_string(),
+        Value::Boolean(x) => x.to_string(),
+        Value::String(x) | Value::XmlDocument(x) => format!("\"{}\"", json_escape(x)),
+        Value::Object { entries, .. } | Value::EcmaArray { entries } => object(entries),
+        Value::Array { entries } => format!("[{}]", entries.iter().map(amf_to_json).collect::<Vec<_>>().join(",")),
+        Value::Date { unix_time } => unix_time.as_millis().to_string(),
+        _ => "null".to_string(),
+    }
+}
+
+/// WebSocket请求路径的前缀，`/websocket/<app>/<stream>`或者`/ws/<app>/<stream>`
+const PATH_PREFIXES: [&str; 2] = ["/websocket/", "/ws/"];
+
+/// 关键帧预览的路径前缀，`/preview/<app>/<stream>`
+const PREVIEW_PREFIX: &str = "/preview/";
+
+/// 从请求路径中取出流名称，去掉结尾的`/`并进行URL解码之后转换成内部名称，没有流名称或者不合法时返回None
+pub fn stream_name_from_path(path: &str) -> Option<String> {
+    decode_stream_name(PATH_PREFIXES.iter().find_map(|x| path.strip_prefix(x))?)
+}
+
+/// 关键帧预览请求的流名称，不是`/preview/`开头或者没有流名称时返回None
+pub fn preview_stream_name_from_path(path: &str) -> Option<String> {
+    decode_stream_name(path.strip_prefix(PREVIEW_PREFIX)?)
+}
+
+fn decode_stream_name(stream_name: &str) -> Option<String> {
+    let stream_name = percent_decode(stream_name.trim_matches('/'))?;
+    if stream_name.is_empty() {
+        return None;
+    }
+    naming::normalize_path(&stream_name).ok()
+}
+
+/// URL解码，`%XX`转换成字节，格式错误或者不是UTF-8时返回None
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// 请求路径中没有流名称时，发送带原因的Close帧再断开
+pub async fn close_invalid_path(mut ws_stream: WebSocketStream<TcpStream>, path: &str, addr: SocketAddr) -> anyhow::Result<()> {
+    log::warn!("[WebSocket] invalid path={}, peer={}", path, addr);
+    let reason = format!("invalid path {}, expect /websocket/<app>/<stream> or /ws/<app>/<stream>", path);
+    let frame = CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    };
+    // 对端可能已经断开，关闭失败不需要处理
+    let _ = ws_stream.close(Some(frame)).await;
+    Ok(())
+}
+
+/// 发送循环中等待的事件
+enum Outgoing {
+    Frame(Option<Message>),
+    Control(Option<Message>),
+}
+
+/// 把媒体数据发送给WebSocket客户端，同时处理客户端发来的控制帧，二进制数据需要先转换成`Message::binary`
+///
+/// 客户端的Ping会回复Pong，收到Close或者连接断开时结束发送循环
+pub async fn send_until_closed<S>(
+    ws_stream: WebSocketStream<TcpStream>,
+    frames: S,
+    addr: SocketAddr,
+) -> anyhow::Result<()>
+where
+    S: Stream<Item = Message>,
+{
+    let (mut outgoing, incoming) = ws_stream.split();
+    let (control_tx, control_rx) = smol::channel::unbounded();
+    // Task被drop时会被取消，不会残留读协程
+    let _reader = smol::spawn(read_incoming(incoming, control_tx, addr));
+
+    futures::pin_mut!(frames);
+    loop {
+        let event = smol::future::or(
+            async { Outgoing::Frame(frames.next().await) },
+            async { Outgoing::Control(control_rx.recv().await.ok()) },
+        )
+            .await;
+
+        match event {
+            Outgoing::Frame(Some(msg)) => outgoing.send(msg).await?,
+            Outgoing::Control(Some(msg)) => outgoing.send(msg).await?,
+            Outgoing::Frame(None) => {
+                log::info!("[WebSocket] stream ended, peer={}", addr);
+                break;
+            }
+            Outgoing::Control(None) => {
+                log::info!("[WebSocket] closed by client, peer={}", addr);
+                break;
+            }
+        }
+    }
+
+    // 对端可能已经断开，关闭失败不需要处理
+    let _ = outgoing.close().await;
+    Ok(())
+}
+
+/// 读取客户端消息，Ping转换成Pong交给发送循环，Close或者出错时退出
+async fn read_incoming(
+    mut incoming: SplitStream<WebSocketStream<TcpStream>>,
+    control_tx: Sender<Message>,
+    addr: SocketAddr,
+) {
+    while let Some(msg) = incoming.next().await {
+        match msg {
+            Ok(Message::Ping(payload)) => {
+                log::debug!("[WebSocket] ping from {}", addr);
+                if control_tx.send(Message::Pong(payload)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Message::Close(frame)) => {
+                log::debug!("[WebSocket] close from {}, frame={:?}", addr, frame);
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::debug!("[WebSocket] read error from {}, {:?}", addr, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amf::amf0::Value;
+    use amf::Pair;
+
+    #[test]
+    fn text_data_to_json() {
+        let value = Value::EcmaArray {
+            entries: vec![
+                Pair { key: "text".to_owned(), value: Value::String("say \"hi\"".to_owned()) },
+                Pair { key: "trackid".to_owned(), value: Value::Number(1.0) },
+                Pair { key: "list".to_owned(), value: Value::Array { entries: vec![Value::Boolean(true), Value::Null] } },
+                Pair { key: "nan".to_owned(), value: Value::Number(f64::NAN) },
+            ],
+        };
+        assert_eq!(amf_to_json(&value), r#"{"text":"say \"hi\"","trackid":1,"list":[true,null],"nan":null}"#);
+    }
+
+    #[test]
+    fn stream_name_from_ws_path() {
+        assert_eq!(stream_name_from_path("/websocket/live/test").as_deref(), Some("live/test"));
+        assert_eq!(stream_name_from_path("/ws/live/test/").as_deref(), Some("live/test"));
+        assert_eq!(stream_name_from_path("/ws/live/%E6%B5%8B%E8%AF%95").as_deref(), Some("live/测试"));
+        assert_eq!(stream_name_from_path("/ws/"), None);
+        assert_eq!(stream_name_from_path("/live/test"), None);
+        assert_eq!(stream_name_from_path("/ws/live/%zz"), None);
+        assert_eq!(preview_stream_name_from_path("/preview/live/test").as_deref(), Some("live/test"));
+        assert_eq!(preview_stream_name_from_path("/ws/live/test"), None);
+        assert_eq!(stream_name_from_path("/preview/live/test"), None);
+    }
+}
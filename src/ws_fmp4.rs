@@ -1,18 +1,52 @@
 use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
-use async_tungstenite::tungstenite::Message;
 use crossbeam_utils::atomic::AtomicCell;
-use futures::sink::SinkExt;
-use futures::StreamExt;
-use smol::net::{SocketAddr, TcpListener, TcpStream};
+use futures::{stream, StreamExt};
+use smol::net::{SocketAddr, TcpStream};
 
 use crate::protocol::h264::Nalu;
-use crate::rtmp_server::{eventbus_map, video_header_map, meta_data_map};
-use crate::protocol::fmp4::{Fmp4Encoder, Track};
+use crate::protocol::rtmp::{RtmpMessage, RtmpMetaData};
+use crate::rtmp_server::{video_header_map, meta_data_map, wait_for_publisher, KeyFrameReceiver};
+use crate::protocol::fmp4::{is_changed_video_header, Fmp4Encoder, Track};
+use crate::ws_common::{close_invalid_path, send_until_closed, stream_name_from_path};
+use crate::cors;
+use crate::metrics::metrics;
+use crate::util::{bind_tcp, display_addr, next_conn_id};
+use crate::pacer::{PacedOutput, Pacer};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use once_cell::sync::OnceCell;
+use std::time::{Duration, Instant};
+
+static FRAGMENT_MS: OnceCell<u32> = OnceCell::new();
+
+/// 启动时设置fMP4分片的目标时长，0表示每帧一个分片
+pub fn init_fragment_ms(fragment_ms: u32) {
+    if FRAGMENT_MS.set(fragment_ms).is_err() {
+        log::warn!("fmp4 fragment duration has been initialized");
+    }
+}
+
+fn fragment_ms() -> u32 {
+    FRAGMENT_MS.get().copied().unwrap_or(0)
+}
+
+static PREVIEW_INTERVAL_MS: OnceCell<u32> = OnceCell::new();
+
+/// 启动时设置关键帧预览的最小间隔
+pub fn init_preview_interval_ms(interval_ms: u32) {
+    if PREVIEW_INTERVAL_MS.set(interval_ms).is_err() {
+        log::warn!("preview interval has been initialized");
+    }
+}
+
+fn preview_interval() -> Duration {
+    Duration::from_millis(PREVIEW_INTERVAL_MS.get().copied().unwrap_or(1000) as u64)
+}
 
 #[allow(unused)]
-pub async fn run_server(addr: String) -> anyhow::Result<()> {
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
     // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
+    let try_socket = bind_tcp(addr);
     let listener = try_socket.expect("Failed to bind");
     log::info!("Websocket Listening on: {}", addr);
 
@@ -26,23 +60,36 @@ pub async fn run_server(addr: String) -> anyhow::Result<()> {
 
 
 async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
-    log::info!("Incoming TCP connection from: {}", addr);
+    let conn_id = next_conn_id();
+    log::info!("[conn={}] Incoming TCP connection from: {}", conn_id, addr);
 
     let uri = AtomicCell::default();
+    #[allow(clippy::result_large_err)]
     let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse>{
+        cors::check_ws_origin(req)?;
         uri.store(req.uri().clone());
         Ok(res)
     };
 
     let ws_stream = async_tungstenite::accept_hdr_async(raw_stream, callback).await?;
-    let (mut outgoing, _incoming) = ws_stream.split();
 
     let uri = uri.take();
-    let stream_name = uri.path().strip_prefix("/websocket/")
-        .ok_or(anyhow::anyhow!("invalid uri path"))?;
-    log::info!("WebSocket connection established: {}, stream_name={}", addr, stream_name);
+    let stream_name = match stream_name_from_path(uri.path()) {
+        Some(stream_name) => stream_name,
+        None => return close_invalid_path(ws_stream, uri.path(), addr).await,
+    };
+    let stream_name = stream_name.as_str();
+    log::info!("[conn={}] WebSocket connection established: {}, stream_name={}", conn_id, addr, stream_name);
+
+    serve_fmp4(ws_stream, stream_name, addr, conn_id).await?;
 
+    log::info!("[conn={}] WebSocket disconnected: {}, stream_name={}", conn_id, addr, stream_name);
+    Ok(())
+}
 
+/// 发送`fmp4`格式，第一个消息为init segment
+pub(crate) async fn serve_fmp4(ws_stream: WebSocketStream<TcpStream>, stream_name: &str, addr: SocketAddr, conn_id: u64) -> anyhow::Result<()> {
+    wait_for_publisher(stream_name).await;
     let meta_data = meta_data_map()
         .get(stream_name)
         .map(|it| it.value().clone())
@@ -53,44 +100,116 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) -> anyhow::R
         .map(|it| it.value().clone())
         .ok_or_else(|| anyhow::anyhow!(format!("not found meta_data, stream={}", stream_name)))?;
 
-    let mut sps_list = vec![];
-    let mut pps_list = vec![];
-    let pioneer_nalus = Nalu::from_rtmp_message(&video_header);
-    for nalu in pioneer_nalus {
-        match nalu.get_nal_unit_type() {
-            Nalu::UNIT_TYPE_SPS => sps_list.push(nalu.as_ref().to_vec()),
-            Nalu::UNIT_TYPE_PPS => pps_list.push(nalu.as_ref().to_vec()),
-            _ => {}
-        }
-    }
-
-    let rx = eventbus_map()
-        .get(stream_name)
-        .map(|it| it.register_receiver())
+    // 从最近的关键帧开始，第一个分片可以直接解码
+    let rx = KeyFrameReceiver::subscribe(stream_name)
+        .map(|x| x.rate_limited())
         .ok_or_else(|| anyhow::anyhow!(format!("not found eventbus, stream={}", stream_name)))?;
 
-    let mut fmp4_encoder = Fmp4Encoder::new(Track {
-        duration: meta_data.duration as u32,
-        timescale: (meta_data.duration * meta_data.frame_rate) as u32,
-        width: meta_data.width as _,
-        height: meta_data.height as _,
-        sps_list,
-        pps_list,
-        ..Default::default()
-    });
+    let viewer = metrics().register_viewer(conn_id, stream_name, &display_addr(addr), "ws-fmp4");
+    let stats = viewer.stats();
+    let mut fmp4_encoder = Fmp4Encoder::with_fragment_ms(Track::from_metadata(&meta_data, &video_header), fragment_ms());
 
     // send video header
     let header = fmp4_encoder.init_segment();
-    outgoing.send(Message::binary(header)).await?;
 
-    while let Ok(msg) = rx.recv().await {
-        let nalus = Nalu::from_rtmp_message(&msg);
-        for nalu in nalus {
-            let bytes = fmp4_encoder.wrap_frame(nalu.as_ref(), nalu.is_key_frame);
-            outgoing.send(Message::binary(bytes)).await?;
-        }
-    }
-    log::info!("WebSocket disconnected: {}, stream_name={}", addr, stream_name);
-    Ok(())
+    let pacer = Pacer::for_output(PacedOutput::WsFmp4);
+    let rx = stream::unfold((rx, pacer, stats.clone()), |(mut rx, mut pacer, stats)| async move {
+        let msg = rx.recv().await?;
+        rx.update_stats(&stats);
+        pacer.wait(msg.header.timestamp).await;
+        Some((msg, (rx, pacer, stats)))
+    });
+    let mut video_header = video_header;
+    let stream_name = stream_name.to_owned();
+    let fragments = rx
+        .map(move |msg| {
+            // 推流者中途改变了sps/pps，发送新的init segment之后继续播放
+            if is_changed_video_header(&msg, &video_header) {
+                video_header = msg.as_ref().clone();
+                let meta_data = meta_data_map().get(&stream_name).map(|x| x.value().clone()).unwrap_or_else(|| meta_data.clone());
+                log::warn!("[conn={}] video header changed, send new init segment, stream_name={}", conn_id, stream_name);
+                return fmp4_encoder.reset_track(Track::from_metadata(&meta_data, &video_header));
+            }
+            if msg.is_sequence_header() {
+                return vec![];
+            }
+            // 一个消息的所有NALU是同一个采样，使用消息的时间戳
+            let data: Vec<u8> = msg.nalus().iter().flat_map(|x| x.as_ref().iter().copied()).collect();
+            if data.is_empty() {
+                return vec![];
+            }
+            fmp4_encoder.push_frame(&data, msg.header.timestamp, msg.composition_time(), msg.is_video_key_frame())
+        })
+        .flat_map(stream::iter);
+    let messages = stream::iter(vec![header]).chain(fragments).map(move |bytes| {
+        stats.bytes_sent.fetch_add(bytes.len() as u64);
+        Message::binary(bytes)
+    });
+    send_until_closed(ws_stream, messages, addr).await
+}
+
+/// 预览的轨道，每帧的时长为预览间隔，播放器按低帧率视频播放
+fn preview_track(meta_data: &RtmpMetaData, video_header: &RtmpMessage, interval: Duration) -> Track {
+    let track = Track::from_metadata(meta_data, video_header);
+    let duration = interval.as_micros() as u64 * track.timescale as u64 / 1_000_000;
+    Track { duration: duration as u32, ..track }
 }
 
+/// 关键帧预览，每隔`--preview-interval-ms`最多发送一个关键帧，适合同时显示很多路的监控墙
+///
+/// 每个消息都是init segment + 只有一个关键帧的分片，可以单独解码
+pub(crate) async fn serve_preview(ws_stream: WebSocketStream<TcpStream>, stream_name: &str, addr: SocketAddr, conn_id: u64) -> anyhow::Result<()> {
+    wait_for_publisher(stream_name).await;
+    let meta_data = meta_data_map()
+        .get(stream_name)
+        .map(|it| it.value().clone())
+        .ok_or_else(|| anyhow::anyhow!(format!("not found meta_data, stream={}", stream_name)))?;
+    let video_header = video_header_map()
+        .get(stream_name)
+        .map(|it| it.value().clone())
+        .ok_or_else(|| anyhow::anyhow!(format!("not found video header, stream={}", stream_name)))?;
+    let receiver = KeyFrameReceiver::subscribe(stream_name)
+        .ok_or_else(|| anyhow::anyhow!(format!("not found eventbus, stream={}", stream_name)))?;
+
+    let viewer = metrics().register_viewer(conn_id, stream_name, &display_addr(addr), "ws-preview");
+    let stats = viewer.stats();
+    let interval = preview_interval();
+    let encoder = Fmp4Encoder::new(preview_track(&meta_data, &video_header, interval));
+    let stream_name = stream_name.to_owned();
+    let state = (receiver, encoder, video_header, None::<Instant>);
+    let frames = stream::unfold(state, move |(mut receiver, mut encoder, mut video_header, mut last_sent)| {
+        let meta_data = meta_data.clone();
+        let stream_name = stream_name.clone();
+        let stats = stats.clone();
+        async move {
+            loop {
+                let msg = receiver.recv().await?;
+                receiver.update_stats(&stats);
+                if is_changed_video_header(&msg, &video_header) {
+                    video_header = msg.as_ref().clone();
+                    let meta_data = meta_data_map().get(&stream_name).map(|x| x.value().clone()).unwrap_or_else(|| meta_data.clone());
+                    encoder.reset_track(preview_track(&meta_data, &video_header, interval));
+                    continue;
+                }
+                if !msg.is_video_key_frame() || last_sent.map(|x| x.elapsed() < interval).unwrap_or(false) {
+                    continue;
+                }
+                // 只保留IDR，SPS/PPS已经在init segment中
+                let key_frame: Vec<u8> = msg.nalus()
+                    .iter()
+                    .filter(|x| x.get_nal_unit_type() == Nalu::UNIT_TYPE_IDR)
+                    .flat_map(|x| x.as_ref().iter().copied())
+                    .collect();
+                if key_frame.is_empty() {
+                    continue;
+                }
+                last_sent = Some(Instant::now());
+                let mut bytes = encoder.init_segment();
+                bytes.extend(encoder.wrap_frame(&key_frame, true));
+                stats.bytes_sent.fetch_add(bytes.len() as u64);
+                return Some((Message::binary(bytes), (receiver, encoder, video_header, last_sent)));
+            }
+        }
+    });
+    send_until_closed(ws_stream, frames, addr).await
+}
@@ -1,22 +1,29 @@
 use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
-use async_tungstenite::tungstenite::Message;
 use crossbeam_utils::atomic::AtomicCell;
-use futures::sink::SinkExt;
 use futures::StreamExt;
-use smol::net::{SocketAddr, TcpListener, TcpStream};
+use smol::net::{SocketAddr, TcpStream};
 
 use crate::protocol::h264::Nalu;
-use crate::rtmp_server::{eventbus_map, video_header_map, audio_header_map};
+use crate::rtmp_server::{video_header_map, audio_header_map, meta_data_map, is_timed_metadata, wait_for_publisher, KeyFrameReceiver};
 use crate::protocol::rtmp::{ChunkMessageType, RtmpMessage};
-use smol::channel::Receiver;
 use smol::stream::{Stream};
 use smol::stream;
-use crate::protocol::aac::{AAC, ADTS};
+use crate::protocol::aac::{AudioCodec, AAC, ADTS};
+use crate::ws_common::{amf_to_json, close_invalid_path, json_escape, send_until_closed, stream_name_from_path};
+use amf::amf0::Value;
+use crate::cors;
+use crate::util::{bind_tcp, display_addr, next_conn_id};
+use crate::metrics::{metrics, ViewerStats};
+use std::sync::Arc;
+use crate::pacer::{PacedOutput, Pacer};
+use crate::rate_limit::{latest_from_query, FrameDecimator};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
 
 #[allow(unused)]
-pub async fn run_server(addr: String) -> anyhow::Result<()> {
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
     // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
+    let try_socket = bind_tcp(addr);
     let listener = try_socket.expect("Failed to bind");
     log::info!("Websocket Listening on: {}", addr);
 
@@ -30,69 +37,141 @@ pub async fn run_server(addr: String) -> anyhow::Result<()> {
 
 
 async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
-    log::info!("Incoming TCP connection from: {}", addr);
+    let conn_id = next_conn_id();
+    log::info!("[conn={}] Incoming TCP connection from: {}", conn_id, addr);
 
     let uri = AtomicCell::default();
+    #[allow(clippy::result_large_err)]
     let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse>{
+        cors::check_ws_origin(req)?;
         uri.store(req.uri().clone());
         Ok(res)
     };
 
     let ws_stream = async_tungstenite::accept_hdr_async(raw_stream, callback).await?;
-    let (mut outgoing, _incoming) = ws_stream.split();
 
     let uri = uri.take();
-    let stream_name = uri.path().strip_prefix("/websocket/")
-        .ok_or(anyhow::anyhow!("invalid uri path"))?;
-    log::info!("WebSocket connection established: {}, stream_name={}", addr, stream_name);
-
-    // send video header
-    if let Some(header) = video_header_map().get(stream_name) {
-        for mix in Mix::from_rtmp_message(&header, &stream_name) {
-            outgoing.send(Message::binary(mix.to_bytes())).await?;
-        }
-    }
-
-    if let Some(el) = eventbus_map().get(stream_name) {
-        let rx = el.register_receiver();
-        std::mem::drop(el);
+    let stream_name = match stream_name_from_path(uri.path()) {
+        Some(stream_name) => stream_name,
+        None => return close_invalid_path(ws_stream, uri.path(), addr).await,
+    };
+    let stream_name = stream_name.as_str();
+    log::info!("[conn={}] WebSocket connection established: {}, stream_name={}", conn_id, addr, stream_name);
 
-        let rx = rtmp_rx_into_mix_rx(rx, stream_name.to_string());
-        futures::pin_mut!(rx);
+    let decimator = FrameDecimator::from_query(uri.query());
+    serve_h264_mix(ws_stream, stream_name, decimator, latest_from_query(uri.query()), addr, conn_id).await?;
 
-        while let Some(mix) = StreamExt::next(&mut rx).await {
-            outgoing.send(Message::binary(mix.to_bytes())).await?;
-        }
-    }
-    log::info!("WebSocket disconnected: {}, stream_name={}", addr, stream_name);
+    log::info!("[conn={}] WebSocket disconnected: {}, stream_name={}", conn_id, addr, stream_name);
     Ok(())
 }
 
-// 把RMTP流转换城MIX流，并保证首帧为关键帧
-fn rtmp_rx_into_mix_rx(rx: Receiver<RtmpMessage>, stream_name: String) -> impl Stream<Item=Mix> {
-    stream::unfold((rx, false, stream_name), |(rx, first_key_frame, stream_name)| async move {
-        while let Ok(msg) = rx.recv().await {
-            let mixes = Mix::from_rtmp_message(&msg, &stream_name);
-            if mixes.is_empty() {
-                continue;
-            }
+/// 发送`h264-mix`格式
+///
+/// 每个消息为1字节标志 + 4字节时间戳 + 媒体数据，标志0为视频（Annex B），1为音频（ADTS），
+/// 2为onTextData/onCuePoint（UTF-8 JSON，`{"name":"onTextData","data":{...}}`），3为音频（MP3帧），
+/// 时间戳是RTMP消息的时间戳，单位毫秒，大端序，sps/pps的时间戳为0
+pub(crate) async fn serve_h264_mix(
+    ws_stream: WebSocketStream<TcpStream>,
+    stream_name: &str,
+    decimator: Option<FrameDecimator>,
+    latest: bool,
+    addr: SocketAddr,
+    conn_id: u64,
+) -> anyhow::Result<()> {
+    wait_for_publisher(stream_name).await;
+    let viewer = metrics().register_viewer(conn_id, stream_name, &display_addr(addr), "ws-h264");
+    let stats = viewer.stats();
+    let mixes = mix_stream(stream_name, decimator, latest, stats.clone())?;
+    let messages = mixes.map(move |(timestamp, mix)| {
+        let bytes = mix.to_bytes(timestamp);
+        stats.bytes_sent.fetch_add(bytes.len() as u64);
+        Message::binary(bytes)
+    });
+    send_until_closed(ws_stream, messages, addr).await
+}
 
-            if first_key_frame {
-                return Some((stream::iter(mixes), (rx, first_key_frame, stream_name)));
-            }
+/// 发送`json-meta`格式
+///
+/// 连接后先发送一个描述流的JSON文本，之后每一帧先发送JSON文本，再发送不带标志字节的二进制数据，
+/// 例如`{"type":"video","timestamp":40,"keyFrame":false,"size":1024}`
+pub(crate) async fn serve_json_meta(
+    ws_stream: WebSocketStream<TcpStream>,
+    stream_name: &str,
+    decimator: Option<FrameDecimator>,
+    latest: bool,
+    addr: SocketAddr,
+    conn_id: u64,
+) -> anyhow::Result<()> {
+    wait_for_publisher(stream_name).await;
+    let (width, height, frame_rate) = meta_data_map()
+        .get(stream_name)
+        .map(|x| (x.width, x.height, x.frame_rate))
+        .unwrap_or_default();
+    let stream_info = format!(
+        "{{\"type\":\"stream\",\"stream\":\"{}\",\"width\":{},\"height\":{},\"frameRate\":{}}}",
+        json_escape(stream_name),
+        width,
+        height,
+        frame_rate
+    );
 
-            let mut mixes = mixes.into_iter().skip_while(|mix| !mix.is_key_frame()).collect::<Vec<Mix>>();
+    let viewer = metrics().register_viewer(conn_id, stream_name, &display_addr(addr), "ws-json-meta");
+    let stats = viewer.stats();
+    let mixes = mix_stream(stream_name, decimator, latest, stats.clone())?;
+    let messages = mixes.flat_map(move |(timestamp, mix)| {
+        let (kind, data) = match &mix {
+            Mix::Video(nalu) => ("video", nalu.as_ref().to_vec()),
+            Mix::Audio(aac) => ("audio", aac.to_bytes()),
+            Mix::Mp3(data) => ("audio", data.clone()),
+            Mix::Text(json) => ("text", json.as_bytes().to_vec()),
+        };
+        let meta = format!(
+            "{{\"type\":\"{}\",\"timestamp\":{},\"keyFrame\":{},\"size\":{}}}",
+            kind,
+            timestamp,
+            mix.is_key_frame(),
+            data.len()
+        );
+        stats.bytes_sent.fetch_add((meta.len() + data.len()) as u64);
+        stream::iter(vec![Message::text(meta), Message::binary(data)])
+    });
+    send_until_closed(ws_stream, stream::once(Message::text(stream_info)).chain(messages), addr).await
+}
 
-            // 消息堆积，丢弃视频非关键帧
-            if rx.len() > 30 {
-                mixes.retain(|x| x.is_audio() || x.is_key_frame());
-            }
+/// 先发送sps/pps，然后从最近的关键帧开始发送，附带RTMP时间戳
+fn mix_stream(
+    stream_name: &str,
+    decimator: Option<FrameDecimator>,
+    latest: bool,
+    stats: Arc<ViewerStats>,
+) -> anyhow::Result<impl Stream<Item=(u32, Mix)>> {
+    let mut header_mixes = vec![];
+    if let Some(header) = video_header_map().get(stream_name) {
+        header_mixes = Mix::from_rtmp_message(&header, stream_name).into_iter().map(|mix| (0, mix)).collect();
+    }
+
+    let receiver = if latest { KeyFrameReceiver::subscribe_latest(stream_name) } else { KeyFrameReceiver::subscribe(stream_name) };
+    let receiver = receiver
+        .map(|x| x.rate_limited().decimated(decimator))
+        .ok_or_else(|| anyhow::anyhow!(format!("not found eventbus, stream={}", stream_name)))?;
+
+    Ok(stream::iter(header_mixes).chain(rtmp_rx_into_mix_rx(receiver, stream_name.to_string(), stats)))
+}
 
+// 把RMTP流转换城MIX流，首帧为关键帧
+fn rtmp_rx_into_mix_rx(receiver: KeyFrameReceiver, stream_name: String, stats: Arc<ViewerStats>) -> impl Stream<Item=(u32, Mix)> {
+    let pacer = Pacer::for_output(PacedOutput::WsH264);
+    stream::unfold((receiver, pacer, stream_name, stats), |(mut receiver, mut pacer, stream_name, stats)| async move {
+        while let Some(msg) = receiver.recv().await {
+            receiver.update_stats(&stats);
+            let timestamp = msg.header.timestamp;
+            let mixes = Mix::from_rtmp_message(&msg, &stream_name);
             if mixes.is_empty() {
                 continue;
             }
-
-            return Some((stream::iter(mixes), (rx, true, stream_name)));
+            pacer.wait(timestamp).await;
+            let mixes = mixes.into_iter().map(move |mix| (timestamp, mix));
+            return Some((stream::iter(mixes), (receiver, pacer, stream_name, stats)));
         }
         None
     }).flatten()
@@ -102,26 +181,47 @@ fn rtmp_rx_into_mix_rx(rx: Receiver<RtmpMessage>, stream_name: String) -> impl S
 enum Mix {
     Video(Nalu),
     Audio(ADTS),
+    /// MP3帧自带头部，原样发送
+    Mp3(Vec<u8>),
+    /// 带时间戳的数据消息，转换成JSON
+    Text(String),
 }
 
 impl Mix {
     const VIDEO_FLAG: u8 = 0x00;
     const AUDIO_FLAG: u8 = 0x01;
+    const TEXT_FLAG: u8 = 0x02;
+    const MP3_FLAG: u8 = 0x03;
     pub fn from_rtmp_message(msg: &RtmpMessage, stream_name: &str) -> Vec<Self> {
         match msg.header.message_type {
             ChunkMessageType::VideoMessage => {
-                Nalu::from_rtmp_message(&msg).into_iter().map(Mix::Video).collect()
+                msg.nalus().into_iter().map(Mix::Video).collect()
+            }
+            // 推流时已经封装好的ADTS
+            ChunkMessageType::AudioMessage if msg.parsed_frame().is_some_and(|x| !x.adts.is_empty()) => {
+                msg.parsed_frame().unwrap().adts.iter().cloned().map(Mix::Audio).collect()
             }
-            ChunkMessageType::AudioMessage => {
-                if let Some(header) = audio_header_map().get(stream_name) {
-                    AAC::from_rtmp_message(&msg, header.value())
+            ChunkMessageType::AudioMessage => match AudioCodec::from_rtmp_message(msg) {
+                Some(AudioCodec::Aac) => match audio_header_map().get(stream_name) {
+                    Some(header) => AAC::from_rtmp_message(msg, header.value())
                         .into_iter()
-                        .map(|x| x.to_adts())
-                        .flatten()
+                        .filter_map(|x| x.to_adts())
                         .map(Mix::Audio)
-                        .collect()
-                } else {
-                    vec![]
+                        .collect(),
+                    None => vec![],
+                },
+                Some(AudioCodec::Mp3) if msg.body.len() > 1 => vec![Mix::Mp3(msg.body[1..].to_vec())],
+                // 不支持的格式不能封装成ADTS，直接跳过，推流时已经警告过
+                _ => vec![],
+            },
+            ChunkMessageType::AMF0DataMessage => {
+                let values = msg.try_read_body_to_amf0().unwrap_or_default();
+                match values.split_first() {
+                    Some((Value::String(name), data)) if is_timed_metadata(name) => {
+                        let data = data.first().map(amf_to_json).unwrap_or_else(|| "null".to_string());
+                        vec![Mix::Text(format!(r#"{{"name":"{}","data":{}}}"#, name, data))]
+                    }
+                    _ => vec![],
                 }
             }
             _ => vec![]
@@ -133,9 +233,8 @@ impl Mix {
     }
     #[allow(unused)]
     pub fn is_audio(&self) -> bool {
-        matches!(self, Mix::Audio(_))
+        matches!(self, Mix::Audio(_) | Mix::Mp3(_))
     }
-
     pub fn is_key_frame(&self) -> bool {
         if let Mix::Video(nalu) = self {
             nalu.is_key_frame
@@ -144,19 +243,96 @@ impl Mix {
         }
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        match self {
-            Mix::Video(nalu) => {
-                let mut bytes = vec![Mix::VIDEO_FLAG];
-                bytes.extend_from_slice(nalu.as_ref());
-                bytes
-            }
-            Mix::Audio(aac) => {
-                let mut bytes = vec![Mix::AUDIO_FLAG];
-                bytes.extend_from_slice(&aac.to_bytes());
-                bytes
-            }
-        }
+    fn to_bytes(&self, timestamp: u32) -> Vec<u8> {
+        let (flag, data) = match self {
+            Mix::Video(nalu) => (Mix::VIDEO_FLAG, nalu.as_ref().to_vec()),
+            Mix::Audio(aac) => (Mix::AUDIO_FLAG, aac.to_bytes()),
+            Mix::Mp3(data) => (Mix::MP3_FLAG, data.clone()),
+            Mix::Text(json) => (Mix::TEXT_FLAG, json.as_bytes().to_vec()),
+        };
+        let mut bytes = Vec::with_capacity(5 + data.len());
+        bytes.push(flag);
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amf::Pair;
+    use crate::protocol::aac::AudioSpecificConfig;
+
+    #[test]
+    fn text_data_mix() {
+        let mut body = vec![];
+        Value::String("onTextData".to_owned()).write_to(&mut body).unwrap();
+        Value::EcmaArray { entries: vec![Pair { key: "text".to_owned(), value: Value::String("hello".to_owned()) }] }
+            .write_to(&mut body)
+            .unwrap();
+        let msg = RtmpMessage::new(ChunkMessageType::AMF0DataMessage, 1, 1000, body);
+
+        let mixes = Mix::from_rtmp_message(&msg, "live/test_text_data");
+        assert_eq!(mixes.len(), 1);
+        let bytes = mixes[0].to_bytes(1000);
+        assert_eq!(&bytes[..5], &[Mix::TEXT_FLAG, 0, 0, 0x03, 0xE8]);
+        assert_eq!(&bytes[5..], br#"{"name":"onTextData","data":{"text":"hello"}}"#);
+    }
+
+    #[test]
+    fn audio_mix_by_codec() {
+        let stream_name = "live/test_audio_codec";
+        audio_header_map().insert(stream_name.to_string(), RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 0, vec![0xAF, 0x00, 0x12, 0x10]));
+
+        let aac = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 20, vec![0xAF, 0x01, 0x21, 0x00]);
+        let mixes = Mix::from_rtmp_message(&aac, stream_name);
+        assert!(matches!(mixes.as_slice(), [Mix::Audio(_)]));
+        // ADTS同步字
+        assert_eq!(&mixes[0].to_bytes(20)[5..7], &[0xFF, 0xF1]);
+
+        let mp3 = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 20, vec![0x2F, 0xFF, 0xFB, 0x90, 0x64]);
+        let mixes = Mix::from_rtmp_message(&mp3, stream_name);
+        assert_eq!(mixes.len(), 1);
+        assert_eq!(mixes[0].to_bytes(20), vec![Mix::MP3_FLAG, 0, 0, 0, 20, 0xFF, 0xFB, 0x90, 0x64]);
+
+        let speex = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 20, vec![0xB2, 0x00, 0x01]);
+        assert!(Mix::from_rtmp_message(&speex, stream_name).is_empty());
+        assert!(!speex.is_sequence_header());
+
+        assert_eq!(AudioCodec::from_meta_data("10"), Some(AudioCodec::Aac));
+        assert_eq!(AudioCodec::from_meta_data(".mp3"), Some(AudioCodec::Mp3));
+        assert_eq!(AudioCodec::from_meta_data("mp4a"), Some(AudioCodec::Aac));
+        assert_eq!(AudioCodec::from_meta_data(""), None);
+    }
+
+    /// ADTS头部使用流的AAC sequence header中的采样率和声道数，推流时解析和播放时解析的结果相同
+    #[test]
+    fn audio_mix_uses_stream_audio_config() {
+        let stream_name = "live/test_audio_config";
+        // AAC LC 48kHz 双声道
+        let header = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 0, vec![0xAF, 0x00, 0x11, 0x90]);
+        audio_header_map().insert(stream_name.to_string(), header.clone());
+
+        let mut aac = RtmpMessage::new(ChunkMessageType::AudioMessage, 1, 20, vec![0xAF, 0x01, 0x21, 0x00]);
+        let adts = match Mix::from_rtmp_message(&aac, stream_name).as_slice() {
+            [Mix::Audio(adts)] => adts.to_bytes(),
+            _ => panic!("expect one ADTS frame"),
+        };
+        // profile=1(LC)，sampling_frequency_index=3(48kHz)，channel_configuration=2
+        assert_eq!(adts[2], 0x4C);
+        assert_eq!(adts[3] >> 6, 2);
+        let config = AudioSpecificConfig::from_adts_header(&adts).unwrap().0;
+        assert_eq!(config, AudioSpecificConfig::from_rtmp_message(&header).unwrap());
+        assert_eq!(config.sampling_frequency(), 48000);
+
+        aac.attach_parsed_frame(Some(&header));
+        let parsed = match Mix::from_rtmp_message(&aac, stream_name).as_slice() {
+            [Mix::Audio(adts)] => adts.to_bytes(),
+            _ => panic!("expect one ADTS frame"),
+        };
+        assert_eq!(parsed, adts);
+        audio_header_map().remove(stream_name);
+    }
+}
@@ -0,0 +1,86 @@
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use async_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use crossbeam_utils::atomic::AtomicCell;
+use smol::net::{SocketAddr, TcpStream};
+
+use crate::rate_limit::{latest_from_query, FrameDecimator};
+use crate::util::{bind_tcp, next_conn_id, spawn_and_log_error};
+use crate::ws_common::{close_invalid_path, preview_stream_name_from_path, stream_name_from_path, Subprotocol};
+use crate::{cors, ws_fmp4, ws_h264};
+
+/// 统一的WebSocket入口`/ws/<stream>`，根据`Sec-WebSocket-Protocol`选择输出格式
+///
+/// 客户端没有声明子协议时使用`h264-mix`，`/preview/<stream>`只发送关键帧，间隔见`--preview-interval-ms`
+pub async fn run_server(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = bind_tcp(addr)?;
+    log::info!("WebSocket Server is listening to ws://{}/ws/", listener.local_addr()?);
+
+    while let Ok((stream, addr)) = listener.accept().await {
+        spawn_and_log_error(handle_connection(stream, addr));
+    }
+    Ok(())
+}
+
+async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+    let conn_id = next_conn_id();
+    log::info!("[conn={}][WebSocket] incoming TCP connection from: {}", conn_id, addr);
+
+    let uri = AtomicCell::default();
+    let subprotocol = AtomicCell::new(Subprotocol::H264Mix);
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, mut res: Response| -> Result<Response, ErrorResponse> {
+        cors::check_ws_origin(req)?;
+        uri.store(req.uri().clone());
+
+        let requested = req
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|x| x.to_str().ok());
+        if let Some(requested) = requested {
+            let chosen = Subprotocol::negotiate(requested).ok_or_else(|| {
+                let mut err = ErrorResponse::new(Some(format!("unsupported subprotocol: {}", requested)));
+                *err.status_mut() = StatusCode::BAD_REQUEST;
+                err
+            })?;
+            res.headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(chosen.as_str()));
+            subprotocol.store(chosen);
+        }
+        Ok(res)
+    };
+
+    let ws_stream = async_tungstenite::accept_hdr_async(raw_stream, callback).await?;
+
+    let uri = uri.take();
+    if let Some(stream_name) = preview_stream_name_from_path(uri.path()) {
+        log::info!("[conn={}][WebSocket] preview connection established: {}, stream_name={}", conn_id, addr, stream_name);
+        ws_fmp4::serve_preview(ws_stream, &stream_name, addr, conn_id).await?;
+        log::info!("[conn={}][WebSocket] preview disconnected: {}, stream_name={}", conn_id, addr, stream_name);
+        return Ok(());
+    }
+    let stream_name = match stream_name_from_path(uri.path()) {
+        Some(stream_name) => stream_name,
+        None => return close_invalid_path(ws_stream, uri.path(), addr).await,
+    };
+    let stream_name = stream_name.as_str();
+    let subprotocol = subprotocol.load();
+    log::info!(
+        "[conn={}][WebSocket] connection established: {}, stream_name={}, subprotocol={}",
+        conn_id,
+        addr,
+        stream_name,
+        subprotocol.as_str()
+    );
+
+    // fMP4的采样时长固定，丢帧后会加快播放，只有h264-mix和json-meta支持`?decimate=N`和`?latest=1`
+    let decimator = FrameDecimator::from_query(uri.query());
+    let latest = latest_from_query(uri.query());
+    match subprotocol {
+        Subprotocol::H264Mix => ws_h264::serve_h264_mix(ws_stream, stream_name, decimator, latest, addr, conn_id).await?,
+        Subprotocol::Fmp4 => ws_fmp4::serve_fmp4(ws_stream, stream_name, addr, conn_id).await?,
+        Subprotocol::JsonMeta => ws_h264::serve_json_meta(ws_stream, stream_name, decimator, latest, addr, conn_id).await?,
+    }
+
+    log::info!("[conn={}][WebSocket] disconnected: {}, stream_name={}", conn_id, addr, stream_name);
+    Ok(())
+}
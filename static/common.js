@@ -0,0 +1,40 @@
+/**
+ * 播放页面共用的函数，页面需要先定义`ctx`
+ */
+
+/**
+ * 流名称，优先使用`?stream=live/test`，否则使用路径`/live/test`
+ */
+function stream_name() {
+    const stream = new URLSearchParams(window.location.search).get('stream');
+    return stream || window.location.pathname.replace(/^\//, '');
+}
+
+/**
+ * 跳到缓冲区末尾，减少延迟
+ */
+function forward_latest_frame(video) {
+    if (video && video.buffered && video.buffered.length && video.buffered.end(0)) {
+        let latest = video.buffered.end(video.buffered.length - 1);
+        if (latest - video.currentTime > 0.2) {
+            video.currentTime = latest;
+        }
+    }
+}
+
+/**
+ * 从init segment的avcC中生成MSE需要的codec，例如`avc1.64001f`
+ */
+function avc_codec(init_segment) {
+    for (let i = 0; i + 8 < init_segment.length; i++) {
+        // 'avcC'
+        if (init_segment[i] === 0x61 && init_segment[i + 1] === 0x76
+            && init_segment[i + 2] === 0x63 && init_segment[i + 3] === 0x43) {
+            const hex = Array.from(init_segment.subarray(i + 5, i + 8))
+                .map(x => x.toString(16).padStart(2, '0'))
+                .join('');
+            return `avc1.${hex}`;
+        }
+    }
+    return 'avc1.42e01e';
+}
@@ -0,0 +1,205 @@
+//! 端到端测试：启动river，用内置的RTMP客户端推送生成的H.264+AAC，再用HTTP-FLV播放
+//!
+//! 需要启动子进程和监听端口，默认忽略，`cargo test --test pipeline -- --ignored`运行
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use amf::amf0::Value;
+use amf::Pair;
+use river::protocol::rtmp::ChunkMessageType;
+use river::rtmp_push::RtmpPublishClient;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::TcpStream;
+use smol::Timer;
+
+/// x264 1280x720 High@3.1
+const SPS: [u8; 26] = [
+    0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00,
+    0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+];
+const PPS: [u8; 4] = [0x68, 0xEB, 0xE3, 0xCB];
+
+/// 测试结束时结束子进程，删除工作目录
+struct Server {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn spawn_server(rtmp_port: u16, http_flv_port: u16) -> Server {
+    // 工作目录放在临时目录，避免运行时生成的文件写入仓库
+    let dir = std::env::temp_dir().join(format!("river_pipeline_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_river"))
+        .current_dir(&dir)
+        .args(["--bind", "127.0.0.1", "--http-player-port", "0", "--ws-h264-port", "0"])
+        .args(["--rtmp-port", &rtmp_port.to_string(), "--http-flv-port", &http_flv_port.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server { child, dir }
+}
+
+async fn wait_listening(port: u16) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+        assert!(Instant::now() < deadline, "port {} is not listening", port);
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+fn on_meta_data() -> Vec<u8> {
+    let mut body = vec![];
+    Value::String("@setDataFrame".to_string()).write_to(&mut body).unwrap();
+    Value::String("onMetaData".to_string()).write_to(&mut body).unwrap();
+    let entries = [("width", 1280.0), ("height", 720.0), ("framerate", 25.0), ("videocodecid", 7.0), ("audiocodecid", 10.0)]
+        .iter()
+        .map(|(key, value)| Pair { key: key.to_string(), value: Value::Number(*value) })
+        .collect();
+    Value::EcmaArray { entries }.write_to(&mut body).unwrap();
+    body
+}
+
+fn avc_sequence_header() -> Vec<u8> {
+    let mut body = vec![0x17, 0x00, 0, 0, 0, 0x01, SPS[1], SPS[2], SPS[3], 0xFF, 0xE1];
+    body.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+    body.extend_from_slice(&SPS);
+    body.push(0x01);
+    body.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+    body.extend_from_slice(&PPS);
+    body
+}
+
+/// 一个NALU的视频帧，关键帧为IDR，其他为非IDR的P帧
+fn video_frame(key_frame: bool) -> Vec<u8> {
+    let nalu: &[u8] = if key_frame { &[0x65, 0x88, 0x84, 0x00, 0x33, 0xFF] } else { &[0x41, 0x9A, 0x02, 0x04] };
+    let mut body = vec![if key_frame { 0x17 } else { 0x27 }, 0x01, 0, 0, 0];
+    body.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+    body.extend_from_slice(nalu);
+    body
+}
+
+/// 读取HTTP-FLV响应，去掉响应头和chunked编码，直到`done`返回true或者超时
+async fn read_flv(stream: &mut TcpStream, done: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let mut raw = vec![];
+    let mut buf = [0; 4096];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let flv = dechunk(&raw);
+        if done(&flv) || Instant::now() > deadline {
+            return flv;
+        }
+        let read = smol::future::or(async { stream.read(&mut buf).await.ok() }, async {
+            Timer::after(Duration::from_millis(200)).await;
+            Some(0)
+        });
+        match read.await {
+            Some(0) => continue,
+            Some(n) => raw.extend_from_slice(&buf[..n]),
+            None => return dechunk(&raw),
+        }
+    }
+}
+
+/// 只取出完整的chunk
+fn dechunk(raw: &[u8]) -> Vec<u8> {
+    let head_end = match raw.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(x) => x + 4,
+        None => return vec![],
+    };
+    let mut data = vec![];
+    let mut rest = &raw[head_end..];
+    while let Some(line_end) = rest.windows(2).position(|x| x == b"\r\n") {
+        let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+        if size == 0 || rest.len() < line_end + 2 + size + 2 {
+            break;
+        }
+        data.extend_from_slice(&rest[line_end + 2..line_end + 2 + size]);
+        rest = &rest[line_end + 2 + size + 2..];
+    }
+    data
+}
+
+/// FLV body中的(tag type, tag data)
+fn flv_tags(flv: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut tags = vec![];
+    // FLV header和第一个PreviousTagSize
+    let mut rest = flv.get(13..).unwrap_or_default();
+    while rest.len() >= 11 {
+        let size = u32::from_be_bytes([0, rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 11 + size + 4 {
+            break;
+        }
+        tags.push((rest[0], &rest[11..11 + size]));
+        rest = &rest[11 + size + 4..];
+    }
+    tags
+}
+
+#[test]
+#[ignore]
+fn publish_rtmp_and_play_http_flv() {
+    let (rtmp_port, http_flv_port) = (free_port(), free_port());
+    let _server = spawn_server(rtmp_port, http_flv_port);
+
+    smol::block_on(async {
+        wait_listening(rtmp_port).await;
+        wait_listening(http_flv_port).await;
+
+        let url = format!("rtmp://127.0.0.1:{}/live/pipeline", rtmp_port);
+        let mut client = RtmpPublishClient::connect(&url).await.unwrap();
+        client.send(ChunkMessageType::AMF0DataMessage, 0, on_meta_data()).await.unwrap();
+        client.send(ChunkMessageType::VideoMessage, 0, avc_sequence_header()).await.unwrap();
+        // AAC LC 44.1kHz stereo
+        client.send(ChunkMessageType::AudioMessage, 0, vec![0xAF, 0x00, 0x12, 0x10]).await.unwrap();
+        client.send(ChunkMessageType::VideoMessage, 0, video_frame(true)).await.unwrap();
+        client.send(ChunkMessageType::AudioMessage, 0, vec![0xAF, 0x01, 0x21, 0x10, 0x04]).await.unwrap();
+
+        let mut viewer = TcpStream::connect(("127.0.0.1", http_flv_port)).await.unwrap();
+        viewer.write_all(b"GET /live/pipeline HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        // 播放器加入之后继续推流，一秒25帧
+        let publish = async {
+            for i in 1..50u32 {
+                Timer::after(Duration::from_millis(40)).await;
+                client.send(ChunkMessageType::VideoMessage, i * 40, video_frame(i % 25 == 0)).await.unwrap();
+                client.send(ChunkMessageType::AudioMessage, i * 40, vec![0xAF, 0x01, 0x21, 0x10, 0x04]).await.unwrap();
+            }
+        };
+        let has_audio_and_key_frame = |flv: &[u8]| {
+            let tags = flv_tags(flv);
+            tags.iter().any(|(tag_type, data)| *tag_type == 8 && data[..2] == [0xAF, 0x01])
+                && tags.iter().any(|(tag_type, data)| *tag_type == 9 && data[..2] == [0x17, 0x01])
+        };
+        let (flv, _) = futures::future::join(read_flv(&mut viewer, has_audio_and_key_frame), publish).await;
+
+        assert!(flv.starts_with(b"FLV"), "no FLV header, received {} bytes", flv.len());
+        let tags = flv_tags(&flv);
+        let script = tags.iter().position(|(tag_type, _)| *tag_type == 18).expect("no script tag");
+        let sequence_header = tags
+            .iter()
+            .position(|(tag_type, data)| *tag_type == 9 && data[..2] == [0x17, 0x00])
+            .expect("no video sequence header");
+        let key_frame = tags
+            .iter()
+            .position(|(tag_type, data)| *tag_type == 9 && data[..2] == [0x17, 0x01])
+            .expect("no keyframe");
+        assert!(script < sequence_header && sequence_header < key_frame, "tags out of order: {:?}", tags);
+        assert!(tags.iter().any(|(tag_type, data)| *tag_type == 8 && data[..2] == [0xAF, 0x01]), "no audio");
+    });
+}